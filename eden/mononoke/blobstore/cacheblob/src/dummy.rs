@@ -35,6 +35,10 @@ impl LeaseOps for DummyLease {
     async fn wait_for_other_leases(&self, _key: &str) {}
 
     async fn release_lease(&self, _key: &str) {}
+
+    async fn is_leased(&self, _key: &str) -> Result<bool> {
+        Ok(false)
+    }
 }
 
 /// A dummy implementation of CacheOps that meets the letter of the spec, but uselessly
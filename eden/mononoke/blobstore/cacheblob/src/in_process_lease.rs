@@ -82,4 +82,8 @@ impl LeaseOps for InProcessLease {
             let _ = sender.send(());
         }
     }
+
+    async fn is_leased(&self, key: &str) -> Result<bool> {
+        Ok(self.leases.lock().await.contains_key(key))
+    }
 }
@@ -112,6 +112,10 @@ pub trait LeaseOps: fmt::Display + fmt::Debug + Send + Sync {
 
     /// Releases any leases held on `key`. The entry must transition from Leased to Empty.
     async fn release_lease(&self, key: &str);
+
+    /// Check whether a lease is currently held on `key`, by anyone, without trying to take it.
+    /// The answer may be stale as soon as it is returned.
+    async fn is_leased(&self, key: &str) -> Result<bool>;
 }
 
 /// A caching layer over a blobstore, using a cache defined by its CacheOps. The idea is that
@@ -373,4 +373,19 @@ impl LeaseOps for MemcacheOps {
         // will be released after a timeout.
         tokio::spawn(f);
     }
+
+    async fn is_leased(&self, key: &str) -> Result<bool> {
+        let mc_key = self.presence_keygen.key(key);
+        let bytes = match self.memcache.get(mc_key).await? {
+            Some(bytes) => bytes,
+            None => return Ok(false),
+        };
+        // A value that can't be deserialized is fixed up by the next release, so treat it as
+        // not leased rather than failing.
+        let state: LockState = match compact_protocol::deserialize(Vec::from(bytes)) {
+            Ok(state) => state,
+            Err(_) => return Ok(false),
+        };
+        Ok(matches!(state, LockState::locked_by(_)))
+    }
 }
@@ -22,6 +22,7 @@ use fbthrift::compact_protocol;
 use memcache::{KeyGen, MemcacheClient};
 use mercurial_types::{HgChangesetId, HgNodeHash};
 use mononoke_types::{ChangesetId, RepositoryId};
+use sql::Transaction;
 use stats::prelude::*;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
@@ -183,6 +184,17 @@ impl BonsaiHgMapping for CachingBonsaiHgMapping {
         self.mapping.add(ctx, entry).await
     }
 
+    async fn add_in_transaction(
+        &self,
+        ctx: &CoreContext,
+        entry: BonsaiHgMappingEntry,
+        transaction: Transaction,
+    ) -> Result<(Transaction, bool), Error> {
+        self.mapping
+            .add_in_transaction(ctx, entry, transaction)
+            .await
+    }
+
     async fn get(
         &self,
         ctx: &CoreContext,
@@ -9,7 +9,7 @@
 
 use std::sync::Arc;
 
-use anyhow::{Error, Result};
+use anyhow::{bail, Error, Result};
 use async_trait::async_trait;
 use auto_impl::auto_impl;
 use context::{CoreContext, PerfCounterType};
@@ -21,6 +21,7 @@ use rand::Rng;
 use rendezvous::{RendezVous, RendezVousOptions, RendezVousStats, TunablesRendezVousController};
 use sql::queries;
 use sql::Connection;
+use sql::Transaction;
 use sql_construct::{SqlConstruct, SqlConstructFromMetadataDatabaseConfig};
 use sql_ext::SqlConnections;
 use stats::prelude::*;
@@ -94,6 +95,20 @@ pub trait BonsaiHgMapping: Send + Sync {
 
     async fn add(&self, ctx: &CoreContext, entry: BonsaiHgMappingEntry) -> Result<bool, Error>;
 
+    /// Add a mapping entry as part of an existing SQL transaction.
+    ///
+    /// The entry becomes visible only once the caller commits the
+    /// transaction.  Mappings that are not backed by SQL do not support
+    /// this and return an error.
+    async fn add_in_transaction(
+        &self,
+        _ctx: &CoreContext,
+        _entry: BonsaiHgMappingEntry,
+        _transaction: Transaction,
+    ) -> Result<(Transaction, bool), Error> {
+        bail!("bonsai hg mapping is not transactional")
+    }
+
     async fn get(
         &self,
         ctx: &CoreContext,
@@ -365,6 +380,71 @@ impl BonsaiHgMapping for SqlBonsaiHgMapping {
         }
     }
 
+    async fn add_in_transaction(
+        &self,
+        ctx: &CoreContext,
+        entry: BonsaiHgMappingEntry,
+        transaction: Transaction,
+    ) -> Result<(Transaction, bool), Error> {
+        STATS::adds.add_value(1);
+        ctx.perf_counters()
+            .increment_counter(PerfCounterType::SqlWrites);
+
+        let BonsaiHgMappingEntry { hg_cs_id, bcs_id } = entry.clone();
+
+        if self.overwrite {
+            let (transaction, result) = ReplaceMapping::query_with_transaction(
+                transaction,
+                &[(&self.repo_id, &hg_cs_id, &bcs_id)],
+            )
+            .await?;
+            return Ok((transaction, result.affected_rows() >= 1));
+        }
+
+        let (transaction, result) = InsertMapping::query_with_transaction(
+            transaction,
+            &[(&self.repo_id, &hg_cs_id, &bcs_id)],
+        )
+        .await?;
+        if result.affected_rows() == 1 {
+            return Ok((transaction, true));
+        }
+
+        // The entry already exists, check that it agrees with ours.  This
+        // must happen within the transaction, as the caller may have
+        // written the existing entry earlier in the same transaction.
+        let tok: i32 = rand::thread_rng().gen();
+        let (transaction, by_hg_rows) = SelectMappingByHg::query_with_transaction(
+            transaction,
+            &self.repo_id,
+            &tok,
+            &[hg_cs_id],
+        )
+        .await?;
+        let (transaction, by_bcs_rows) = SelectMappingByBonsai::query_with_transaction(
+            transaction,
+            &self.repo_id,
+            &tok,
+            &[bcs_id],
+        )
+        .await?;
+
+        match by_hg_rows
+            .into_iter()
+            .chain(by_bcs_rows.into_iter())
+            .map(|(hg_cs_id, bcs_id, _)| (hg_cs_id, bcs_id))
+            .next()
+        {
+            Some(existing) if existing == (hg_cs_id, bcs_id) => Ok((transaction, false)),
+            Some((hg_cs_id, bcs_id)) => Err(ErrorKind::ConflictingEntries(
+                BonsaiHgMappingEntry { hg_cs_id, bcs_id },
+                entry,
+            )
+            .into()),
+            None => Err(ErrorKind::RaceConditionWithDelete(entry).into()),
+        }
+    }
+
     async fn get(
         &self,
         ctx: &CoreContext,
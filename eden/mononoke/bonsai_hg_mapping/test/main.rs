@@ -25,7 +25,9 @@ use mononoke_types::RepositoryId;
 use mononoke_types_mocks::changesetid as bonsai;
 use mononoke_types_mocks::repo::REPO_ZERO;
 use rendezvous::RendezVousOptions;
+use sql::Connection;
 use sql_construct::SqlConstruct;
+use sql_ext::{open_sqlite_in_memory, SqlConnections};
 
 use std::str::FromStr;
 use std::sync::{
@@ -457,6 +459,56 @@ async fn test_get_hg_in_range(fb: FacebookInit) {
     .await;
 }
 
+#[fbinit::test]
+async fn test_add_in_transaction(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let conn = open_sqlite_in_memory()?;
+    conn.execute_batch(SqlBonsaiHgMappingBuilder::CREATION_QUERY)?;
+    let conn = Connection::with_sqlite(conn);
+
+    let mapping =
+        SqlBonsaiHgMappingBuilder::from_sql_connections(SqlConnections::new_single(conn.clone()))
+            .build(REPO_ZERO, RendezVousOptions::for_test());
+
+    let entry = BonsaiHgMappingEntry {
+        hg_cs_id: hg::ONES_CSID,
+        bcs_id: bonsai::ONES_CSID,
+    };
+
+    let txn = conn.start_transaction().await?;
+    let (txn, added) = mapping.add_in_transaction(&ctx, entry.clone(), txn).await?;
+    assert!(added);
+    txn.commit().await?;
+    assert_eq!(
+        mapping.get_hg_from_bonsai(&ctx, bonsai::ONES_CSID).await?,
+        Some(hg::ONES_CSID)
+    );
+
+    // Adding the same entry again, e.g. from a concurrent derivation, succeeds.
+    let txn = conn.start_transaction().await?;
+    let (txn, added) = mapping.add_in_transaction(&ctx, entry.clone(), txn).await?;
+    assert!(!added);
+    txn.commit().await?;
+
+    // Adding a different value for the same changeset fails.
+    let conflicting_entry = BonsaiHgMappingEntry {
+        hg_cs_id: hg::TWOS_CSID,
+        bcs_id: bonsai::ONES_CSID,
+    };
+    let txn = conn.start_transaction().await?;
+    let result = mapping
+        .add_in_transaction(&ctx, conflicting_entry.clone(), txn)
+        .await
+        .map(|_| ())
+        .expect_err("Conflicting entries should have produced an error");
+    assert_matches!(
+        result.downcast::<ErrorKind>(),
+        Ok(ErrorKind::ConflictingEntries(ref e0, ref e1)) if e0 == &entry && e1 == &conflicting_entry
+    );
+
+    Ok(())
+}
+
 #[fbinit::test]
 async fn test_overwrite(fb: FacebookInit) -> Result<(), Error> {
     let mapping = SqlBonsaiHgMappingBuilder::with_sqlite_in_memory()
@@ -231,6 +231,32 @@ mod tests {
         assert_eq!(list, vec![(bcs_id, vec![])]);
     }
 
+    #[fbinit::test]
+    async fn test_derive_with_dependencies(fb: FacebookInit) -> Result<()> {
+        let repo = Linear::getrepo(fb).await;
+        let ctx = CoreContext::test_mock(fb);
+        let bcs = create_bonsai_changeset(vec![]);
+        let bcs_id = bcs.get_changeset_id();
+        save_bonsai_changesets(vec![bcs], ctx.clone(), &repo).await?;
+
+        let manager = repo.repo_derived_data().manager();
+        assert!(
+            manager
+                .fetch_derived::<RootUnodeManifestId>(&ctx, bcs_id, None)
+                .await?
+                .is_none()
+        );
+
+        derived_data::derive_with_dependencies::<RootFastlog>(&ctx, &repo, bcs_id).await?;
+        assert!(
+            manager
+                .fetch_derived::<RootUnodeManifestId>(&ctx, bcs_id, None)
+                .await?
+                .is_some()
+        );
+        Ok(())
+    }
+
     #[fbinit::test]
     async fn test_derive_single_commit_no_parents(fb: FacebookInit) {
         let repo = Linear::getrepo(fb).await;
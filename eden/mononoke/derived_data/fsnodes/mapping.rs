@@ -10,12 +10,12 @@ use std::collections::HashMap;
 use anyhow::{anyhow, Error, Result};
 use async_trait::async_trait;
 use blobstore::{Blobstore, BlobstoreGetData};
-use bytes::Bytes;
 use context::CoreContext;
-use derived_data::impl_bonsai_derived_via_manager;
+use derived_data::root_id::{self, deserialize_root_id, serialize_root_id};
+use derived_data::{impl_bonsai_derived_via_manager, BlobstoreRootIdMapping};
 use derived_data_manager::{dependencies, BonsaiDerivable, DerivationContext};
 use mononoke_types::{
-    BlobstoreBytes, BonsaiChangeset, ChangesetId, ContentId, FileType, FsnodeId, MPath,
+    BlobstoreBytes, BonsaiChangeset, ChangesetId, ContentId, DateTime, FileType, FsnodeId, MPath,
 };
 
 use crate::batch::derive_fsnode_in_batch;
@@ -39,7 +39,7 @@ impl TryFrom<BlobstoreBytes> for RootFsnodeId {
     type Error = Error;
 
     fn try_from(blob_bytes: BlobstoreBytes) -> Result<Self> {
-        FsnodeId::from_bytes(&blob_bytes.into_bytes()).map(RootFsnodeId)
+        deserialize_root_id(blob_bytes.as_bytes()).map(RootFsnodeId)
     }
 }
 
@@ -53,7 +53,7 @@ impl TryFrom<BlobstoreGetData> for RootFsnodeId {
 
 impl From<RootFsnodeId> for BlobstoreBytes {
     fn from(root_fsnode_id: RootFsnodeId) -> Self {
-        BlobstoreBytes::from_bytes(Bytes::copy_from_slice(root_fsnode_id.0.blake2().as_ref()))
+        serialize_root_id(&root_fsnode_id.0, RootFsnodeId::ROOT_ID_FORMAT)
     }
 }
 
@@ -88,6 +88,20 @@ impl BonsaiDerivable for RootFsnodeId {
         Ok(RootFsnodeId(fsnode_id))
     }
 
+    async fn derive_single_incremental(
+        ctx: &CoreContext,
+        derivation_ctx: &DerivationContext,
+        bonsai: BonsaiChangeset,
+        parent: Self,
+    ) -> Result<Self> {
+        // Fsnodes only depend on the files, so a changeset that doesn't
+        // change any has the same root fsnode as its parent.
+        if bonsai.file_changes_map().is_empty() {
+            return Ok(parent);
+        }
+        Self::derive_single(ctx, derivation_ctx, bonsai, vec![parent]).await
+    }
+
     async fn derive_batch(
         ctx: &CoreContext,
         derivation_ctx: &DerivationContext,
@@ -110,7 +124,14 @@ impl BonsaiDerivable for RootFsnodeId {
         changeset_id: ChangesetId,
     ) -> Result<()> {
         let key = format_key(derivation_ctx, changeset_id);
-        derivation_ctx.blobstore().put(ctx, key, self.into()).await
+        root_id::store_with_write_time(
+            ctx,
+            derivation_ctx.blobstore().as_ref(),
+            key,
+            &self.0,
+            Self::ROOT_ID_FORMAT,
+        )
+        .await
     }
 
     async fn fetch(
@@ -127,6 +148,25 @@ impl BonsaiDerivable for RootFsnodeId {
             .transpose()?)
     }
 
+    async fn fetch_with_write_time(
+        ctx: &CoreContext,
+        derivation_ctx: &DerivationContext,
+        changeset_id: ChangesetId,
+    ) -> Result<Option<(Self, Option<i64>)>> {
+        let key = format_key(derivation_ctx, changeset_id);
+        root_id::fetch_with_write_time(ctx, derivation_ctx.blobstore().as_ref(), &key).await
+    }
+
+    async fn touch(
+        ctx: &CoreContext,
+        derivation_ctx: &DerivationContext,
+        changeset_id: ChangesetId,
+    ) -> Result<bool> {
+        let key = format_key(derivation_ctx, changeset_id);
+        let now = DateTime::now().timestamp_secs();
+        root_id::touch(ctx, derivation_ctx.blobstore().as_ref(), &key, now).await
+    }
+
     fn from_thrift(data: thrift::DerivedData) -> Result<Self> {
         if let thrift::DerivedData::fsnode(thrift::DerivedDataFsnode::root_fsnode_id(id)) = data {
             FsnodeId::from_thrift(id).map(Self)
@@ -147,6 +187,14 @@ impl BonsaiDerivable for RootFsnodeId {
 
 impl_bonsai_derived_via_manager!(RootFsnodeId);
 
+impl BlobstoreRootIdMapping for RootFsnodeId {
+    type RootId = FsnodeId;
+
+    fn root_id(&self) -> &Self::RootId {
+        &self.0
+    }
+}
+
 pub(crate) fn get_file_changes(
     bcs: &BonsaiChangeset,
 ) -> Vec<(MPath, Option<(ContentId, FileType)>)> {
@@ -184,7 +232,9 @@ mod test {
     use manifest::Entry;
     use mercurial_derived_data::DeriveHgChangeset;
     use mercurial_types::{HgChangesetId, HgManifestId};
+    use repo_derived_data::RepoDerivedDataRef;
     use revset::AncestorsNodeStream;
+    use tests_utils::CreateCommitContext;
     use tokio::runtime::Runtime;
 
     async fn fetch_manifest_by_cs_id(
@@ -264,6 +314,39 @@ mod test {
             .unwrap();
     }
 
+    #[fbinit::test]
+    async fn test_derive_single_incremental(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo: BlobRepo = test_repo_factory::build_empty(fb)?;
+        let parent = CreateCommitContext::new_root(&ctx, &repo)
+            .add_file("dir/file", "content")
+            .commit()
+            .await?;
+        let empty = CreateCommitContext::new(&ctx, &repo, vec![parent])
+            .commit()
+            .await?;
+        let parent_fsnode = RootFsnodeId::derive(&ctx, &repo, parent).await?;
+
+        // The root fsnode of the parent is reused, and is the one a full
+        // derivation would produce.
+        let derivation_ctx = repo.repo_derived_data().manager().derivation_context(None);
+        let bonsai = empty.load(&ctx, repo.blobstore()).await?;
+        let incremental = RootFsnodeId::derive_single_incremental(
+            &ctx,
+            &derivation_ctx,
+            bonsai.clone(),
+            parent_fsnode.clone(),
+        )
+        .await?;
+        assert_eq!(incremental, parent_fsnode);
+        assert_eq!(
+            RootFsnodeId::derive_single(&ctx, &derivation_ctx, bonsai, vec![parent_fsnode]).await?,
+            incremental
+        );
+        assert_eq!(RootFsnodeId::derive(&ctx, &repo, empty).await?, incremental);
+        Ok(())
+    }
+
     #[fbinit::test]
     fn test_derive_data(fb: FacebookInit) {
         let runtime = Runtime::new().unwrap();
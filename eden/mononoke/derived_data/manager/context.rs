@@ -15,6 +15,7 @@ use cacheblob::MemWritesBlobstore;
 use context::CoreContext;
 use filenodes::Filenodes;
 use futures::future::try_join_all;
+use futures::stream::{self, StreamExt, TryStreamExt};
 use metaconfig_types::DerivedDataTypesConfig;
 use mononoke_types::{BonsaiChangeset, ChangesetId, RepositoryId};
 
@@ -74,6 +75,38 @@ impl DerivationContext {
         Ok(derived)
     }
 
+    /// Fetch previously derived data, along with the time at which it was
+    /// persisted, if known.
+    pub async fn fetch_derived_with_write_time<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+    ) -> Result<Option<(Derivable, Option<i64>)>>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        if let Some(rederivation) = self.rederivation.as_ref() {
+            if rederivation.needs_rederive(Derivable::NAME, csid) == Some(true) {
+                return Ok(None);
+            }
+        }
+        let derived = Derivable::fetch_with_write_time(ctx, self, csid).await?;
+        Ok(derived)
+    }
+
+    /// Mark previously derived data as just written, without deriving it
+    /// again.  Returns `false` if it wasn't derived.
+    pub async fn touch_derived<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+    ) -> Result<bool>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        Derivable::touch(ctx, self, csid).await
+    }
+
     /// Fetch a batch of previously derived data.
     pub async fn fetch_derived_batch<Derivable>(
         &self,
@@ -132,7 +165,7 @@ impl DerivationContext {
     where
         Derivable: BonsaiDerivable,
     {
-        try_join_all(bonsai.parents().map(|p| async move {
+        let fetches = bonsai.parents().map(|p| async move {
             self.fetch_unknown_dependency(&ctx, known, p)
                 .await
                 .with_context(|| {
@@ -142,8 +175,16 @@ impl DerivationContext {
                         bonsai.get_changeset_id(),
                     )
                 })
-        }))
-        .await
+        });
+        let max_concurrency = tunables::tunables().get_derived_data_max_parent_fetch_concurrency();
+        if max_concurrency > 0 {
+            stream::iter(fetches)
+                .buffered(max_concurrency as usize)
+                .try_collect()
+                .await
+        } else {
+            try_join_all(fetches).await
+        }
     }
 
     /// Fetch derived data value for changeset if it is not already known.
@@ -8,15 +8,20 @@
 use std::any::TypeId;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
+use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use context::CoreContext;
 use futures::future::try_join;
 use futures::stream::{self, StreamExt, TryStreamExt};
 use mononoke_types::{BonsaiChangeset, ChangesetId};
+use sql::Transaction;
 
 use crate::context::DerivationContext;
+use crate::error::{DerivationError, DerivedValueTooLarge};
+use crate::manager::derive::Rederivation;
+use crate::manager::DerivedDataManager;
 
 use derived_data_service_if::types::DerivedData;
 
@@ -64,6 +69,50 @@ pub trait BonsaiDerivable: Sized + Send + Sync + Clone + Debug + 'static {
         parents: Vec<Self>,
     ) -> Result<Self>;
 
+    /// Derive data for a changeset with a single parent, given the derived
+    /// data of that parent.
+    ///
+    /// This is preferred over `derive_single` when exactly one parent value
+    /// is available, which is the common case of linear history.  Types
+    /// that can reuse the unchanged parts of the parent value, deriving only
+    /// what the changeset modified, may override this with a cheaper
+    /// implementation.  The default calls `derive_single`.
+    async fn derive_single_incremental(
+        ctx: &CoreContext,
+        derivation_ctx: &DerivationContext,
+        bonsai: BonsaiChangeset,
+        parent: Self,
+    ) -> Result<Self> {
+        Self::derive_single(ctx, derivation_ctx, bonsai, vec![parent]).await
+    }
+
+    /// Size of this derived data once serialized for storage, if known.
+    ///
+    /// Values larger than the `derived_data_max_value_size` tunable are
+    /// rejected before being stored.  Types that don't report their size
+    /// are never limited.
+    fn serialized_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Check that this value, derived for `bonsai`, is valid before it is
+    /// stored.
+    ///
+    /// Implementations can reject values that break an invariant of the
+    /// type, so that derivation bugs fail derivation instead of storing
+    /// wrong data, e.g. while rolling out new derivation logic.  Failures
+    /// are returned as `DerivationError::ValidationFailed`.  Values derived
+    /// in batches by `backfill_batch` are validated too, and an invalid one
+    /// fails the batch.  The default accepts all values.
+    async fn validate(
+        &self,
+        _ctx: &CoreContext,
+        _derivation_ctx: &DerivationContext,
+        _bonsai: &BonsaiChangeset,
+    ) -> Result<()> {
+        Ok(())
+    }
+
     /// Derive data for a batch of changesets.
     ///
     /// This method may be overridden by BonsaiDerivable implementors if
@@ -99,7 +148,7 @@ pub trait BonsaiDerivable: Sized + Send + Sync + Clone + Debug + 'static {
             let parents = derivation_ctx
                 .fetch_unknown_parents(ctx, Some(&res), &bonsai)
                 .await?;
-            let derived = Self::derive_single(ctx, derivation_ctx, bonsai, parents).await?;
+            let derived = derive_from_parents(ctx, derivation_ctx, bonsai, parents).await?;
             res.insert(csid, derived);
         }
         Ok(res)
@@ -116,6 +165,11 @@ pub trait BonsaiDerivable: Sized + Send + Sync + Clone + Debug + 'static {
     /// `derive_single` or `derive_batch` have been flushed before calling
     /// this method, so it is safe to persistently store the mapping
     /// immediately.
+    ///
+    /// Multiple processes may derive the same changeset concurrently, so
+    /// this method must be idempotent: storing a value equal to the one
+    /// already stored must succeed.  Implementations should only fail if
+    /// a different value has already been stored.
     async fn store_mapping(
         self,
         ctx: &CoreContext,
@@ -123,6 +177,25 @@ pub trait BonsaiDerivable: Sized + Send + Sync + Clone + Debug + 'static {
         csid: ChangesetId,
     ) -> Result<()>;
 
+    /// Store this derived data as the mapped value for a given changeset
+    /// as part of an existing SQL transaction.
+    ///
+    /// This allows the mapping to be committed atomically with other
+    /// changes to the repo's state.  The mapping is only persisted once the
+    /// caller commits the returned transaction.
+    ///
+    /// Only derived data types whose mapping is stored in SQL can support
+    /// this.  The default implementation returns an error.
+    async fn store_mapping_in_transaction(
+        self,
+        _ctx: &CoreContext,
+        _derivation: &DerivationContext,
+        _csid: ChangesetId,
+        _transaction: Transaction,
+    ) -> Result<Transaction> {
+        Err(anyhow!("{} mapping is not transactional", Self::NAME))
+    }
+
     /// Fetch previously derived and persisted data.
     ///
     /// Returns None if the given changeset has not had derived data
@@ -133,6 +206,41 @@ pub trait BonsaiDerivable: Sized + Send + Sync + Clone + Debug + 'static {
         csid: ChangesetId,
     ) -> Result<Option<Self>>;
 
+    /// Fetch previously derived and persisted data, along with the time at
+    /// which it was persisted, in seconds since the epoch.
+    ///
+    /// The time is `None` if it is not known.  The default implementation
+    /// never knows it, implementations should override this if their
+    /// storage records it.
+    async fn fetch_with_write_time(
+        ctx: &CoreContext,
+        derivation: &DerivationContext,
+        csid: ChangesetId,
+    ) -> Result<Option<(Self, Option<i64>)>> {
+        Ok(Self::fetch(ctx, derivation, csid)
+            .await?
+            .map(|derived| (derived, None)))
+    }
+
+    /// Mark previously persisted data as just written, without deriving it
+    /// again, e.g. once an external check has confirmed it is still valid.
+    ///
+    /// This resets the write time returned by `fetch_with_write_time`, so
+    /// that the data is no longer considered stale.  Returns `false` if no
+    /// data was persisted for the changeset.  The default implementation
+    /// returns an error, implementations that record the write time should
+    /// override this.
+    async fn touch(
+        _ctx: &CoreContext,
+        _derivation: &DerivationContext,
+        _csid: ChangesetId,
+    ) -> Result<bool> {
+        Err(anyhow!(
+            "{} mapping does not record write times",
+            Self::NAME
+        ))
+    }
+
     /// Fetch a batch of previously derived data.
     ///
     /// This method may be overridden by BonsaiDerivable implementors if
@@ -161,6 +269,47 @@ pub trait BonsaiDerivable: Sized + Send + Sync + Clone + Debug + 'static {
     fn into_thrift(_data: Self) -> Result<DerivedData>;
 }
 
+/// Derive data for a single changeset from the derived data of its parents,
+/// using `derive_single_incremental` if it has a single parent.
+pub(crate) async fn derive_from_parents<Derivable>(
+    ctx: &CoreContext,
+    derivation_ctx: &DerivationContext,
+    bonsai: BonsaiChangeset,
+    mut parents: Vec<Derivable>,
+) -> Result<Derivable>
+where
+    Derivable: BonsaiDerivable,
+{
+    if parents.len() == 1 {
+        let parent = parents.pop().unwrap();
+        Derivable::derive_single_incremental(ctx, derivation_ctx, bonsai, parent).await
+    } else {
+        Derivable::derive_single(ctx, derivation_ctx, bonsai, parents).await
+    }
+}
+
+/// Check that `derived` isn't larger than the configured limit, so that
+/// it can be stored and read back.
+pub(crate) fn check_value_size<Derivable>(derived: &Derivable, csid: ChangesetId) -> Result<()>
+where
+    Derivable: BonsaiDerivable,
+{
+    let limit = tunables::tunables().get_derived_data_max_value_size();
+    if limit <= 0 {
+        return Ok(());
+    }
+    match derived.serialized_size() {
+        Some(size) if size > limit as usize => Err(DerivedValueTooLarge {
+            name: Derivable::NAME,
+            csid,
+            size,
+            limit: limit as usize,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
 #[async_trait]
 pub trait DerivationDependencies {
     /// Checks that all dependencies have been derived for this
@@ -171,6 +320,16 @@ pub trait DerivationDependencies {
         csid: ChangesetId,
         visited: &mut HashSet<TypeId>,
     ) -> Result<()>;
+
+    /// Derives all dependencies for this changeset, each one after its
+    /// own dependencies.
+    async fn derive_dependencies(
+        ctx: &CoreContext,
+        manager: &DerivedDataManager,
+        csid: ChangesetId,
+        rederivation: Option<Arc<dyn Rederivation>>,
+        visited: &mut HashSet<TypeId>,
+    ) -> Result<(), DerivationError>;
 }
 
 #[async_trait]
@@ -183,6 +342,16 @@ impl DerivationDependencies for () {
     ) -> Result<()> {
         Ok(())
     }
+
+    async fn derive_dependencies(
+        _ctx: &CoreContext,
+        _manager: &DerivedDataManager,
+        _csid: ChangesetId,
+        _rederivation: Option<Arc<dyn Rederivation>>,
+        _visited: &mut HashSet<TypeId>,
+    ) -> Result<(), DerivationError> {
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -209,6 +378,29 @@ where
             Rest::check_dependencies(ctx, derivation_ctx, csid, visited).await
         }
     }
+
+    async fn derive_dependencies(
+        ctx: &CoreContext,
+        manager: &DerivedDataManager,
+        csid: ChangesetId,
+        rederivation: Option<Arc<dyn Rederivation>>,
+        visited: &mut HashSet<TypeId>,
+    ) -> Result<(), DerivationError> {
+        if visited.insert(TypeId::of::<Derivable>()) {
+            Derivable::Dependencies::derive_dependencies(
+                ctx,
+                manager,
+                csid,
+                rederivation.clone(),
+                visited,
+            )
+            .await?;
+            manager
+                .derive::<Derivable>(ctx, csid, rederivation.clone())
+                .await?;
+        }
+        Rest::derive_dependencies(ctx, manager, csid, rederivation, visited).await
+    }
 }
 
 #[macro_export]
@@ -6,13 +6,40 @@
  */
 
 use anyhow::Error;
-use mononoke_types::RepositoryId;
+use mononoke_types::{ChangesetId, RepositoryId};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum DerivationError {
     #[error("Derivation of {0} is not enabled for repo={2} repoid={1}")]
     Disabled(&'static str, RepositoryId, String),
+    /// Derivation of this type was stopped with its `DerivationKillSwitch`.
+    #[error("Derivation of {0} was killed in this process")]
+    Killed(&'static str),
+    #[error("Derivation of {0} for {1} would not finish before the deadline ({2} underived)")]
+    WouldBlock(&'static str, ChangesetId, u64),
+    /// A parent of a changeset being derived is missing from the repo, e.g.
+    /// because the repo is only partially synced, so it can't be derived.
+    #[error("Parent {parent} of {csid} is missing from the repo")]
+    MissingParentChangeset {
+        csid: ChangesetId,
+        parent: ChangesetId,
+    },
+    /// The value derived for a changeset was rejected by
+    /// `BonsaiDerivable::validate`, so it was not stored.
+    #[error("Derived {0} for {1} failed validation")]
+    ValidationFailed(&'static str, ChangesetId, #[source] Error),
     #[error(transparent)]
     Error(#[from] Error),
 }
+
+/// A derived value is larger than the `derived_data_max_value_size` tunable
+/// allows, so it was not stored.
+#[derive(Debug, Error)]
+#[error("Derived {name} for {csid} is {size} bytes, larger than the limit of {limit} bytes")]
+pub struct DerivedValueTooLarge {
+    pub name: &'static str,
+    pub csid: ChangesetId,
+    pub size: usize,
+    pub limit: usize,
+}
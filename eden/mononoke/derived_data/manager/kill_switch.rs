@@ -0,0 +1,48 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref KILL_SWITCHES: Mutex<HashMap<String, Arc<AtomicBool>>> = Default::default();
+}
+
+/// Process-wide switch stopping the derivation of one derived data type.
+///
+/// While the switch is killed, derivation of the type fails immediately
+/// with `DerivationError::Killed` in every repo of this process, without
+/// needing a config change.  Derivations that already started are not
+/// interrupted.
+#[derive(Clone, Debug)]
+pub struct DerivationKillSwitch(Arc<AtomicBool>);
+
+impl DerivationKillSwitch {
+    /// The switch for the derived data type named `name`.  All calls with
+    /// the same name return the same switch.
+    pub fn get(name: &str) -> Self {
+        let mut switches = KILL_SWITCHES.lock().expect("lock poisoned");
+        DerivationKillSwitch(switches.entry(name.to_string()).or_default().clone())
+    }
+
+    /// Stop derivation of this type.
+    pub fn kill(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Allow derivation of this type again.
+    pub fn revive(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
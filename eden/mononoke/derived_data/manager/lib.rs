@@ -8,13 +8,16 @@
 pub mod context;
 pub mod derivable;
 pub mod error;
+pub mod kill_switch;
 pub mod lease;
 pub mod manager;
 
 pub use self::context::DerivationContext;
 pub use self::derivable::BonsaiDerivable;
-pub use self::error::DerivationError;
+pub use self::error::{DerivationError, DerivedValueTooLarge};
+pub use self::kill_switch::DerivationKillSwitch;
 pub use self::lease::DerivedDataLease;
+pub use self::manager::checkpoint::BackfillCheckpoint;
 pub use self::manager::derive::{BatchDeriveOptions, BatchDeriveStats, Rederivation};
 pub use self::manager::util::derived_data_service::{
     ArcDerivedDataManagerSet, DerivedDataManagerSet, DerivedDataServiceRepo,
@@ -22,7 +22,9 @@ use scuba_ext::MononokeScubaSampleBuilder;
 use crate::lease::DerivedDataLease;
 
 pub mod bubble;
+pub mod checkpoint;
 pub mod derive;
+pub mod fsck;
 pub mod logging;
 pub mod util;
 
@@ -165,6 +167,32 @@ impl DerivedDataManager {
         }
     }
 
+    // For dangerous-override: remove the bonsai-hg-mapping and filenodes,
+    // so that deriving the types whose mapping is stored in them fails
+    // instead of writing to them.
+    pub fn without_sql_mappings(&self) -> Self {
+        Self {
+            inner: Arc::new(DerivedDataManagerInner {
+                bonsai_hg_mapping: None,
+                filenodes: None,
+                ..self.inner.as_ref().clone()
+            }),
+        }
+    }
+
+    // For dangerous-override: allow replacement of the derivation service
+    pub fn with_replaced_derivation_service_client(
+        &self,
+        derivation_service_client: Option<Arc<dyn DerivationClient>>,
+    ) -> Self {
+        Self {
+            inner: Arc::new(DerivedDataManagerInner {
+                derivation_service_client,
+                ..self.inner.as_ref().clone()
+            }),
+        }
+    }
+
     pub fn with_replaced_config(
         &self,
         config_name: String,
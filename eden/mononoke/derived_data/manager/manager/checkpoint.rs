@@ -0,0 +1,155 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use context::CoreContext;
+use mononoke_types::ChangesetId;
+use serde::{Deserialize, Serialize};
+use topo_sort::sort_topological;
+
+use crate::derivable::BonsaiDerivable;
+use crate::error::DerivationError;
+use crate::manager::derive::{BatchDeriveOptions, Rederivation};
+
+use super::DerivedDataManager;
+
+/// Position of a long-running backfill.
+///
+/// The checkpoint records the topologically sorted list of changesets that
+/// were underived when the backfill started, and how many of them have been
+/// derived since.  The derived values themselves are in the mapping, so this
+/// is only needed to avoid walking the commit graph again after a restart.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BackfillCheckpoint {
+    /// Name of the derived data type being backfilled.
+    pub derivable_name: String,
+    /// Changesets to derive, in topological order.
+    pub frontier: Vec<ChangesetId>,
+    /// Number of changesets at the start of `frontier` that are derived.
+    pub completed: usize,
+}
+
+impl BackfillCheckpoint {
+    /// Changesets which still need to be derived.
+    pub fn remaining(&self) -> &[ChangesetId] {
+        &self.frontier[self.completed.min(self.frontier.len())..]
+    }
+
+    /// Returns `true` if every changeset in the frontier has been derived.
+    pub fn is_complete(&self) -> bool {
+        self.completed >= self.frontier.len()
+    }
+
+    /// Load a checkpoint previously written by `save`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read(path)
+            .with_context(|| format!("failed to read backfill checkpoint {}", path.display()))?;
+        serde_json::from_slice(&content)
+            .with_context(|| format!("invalid backfill checkpoint {}", path.display()))
+    }
+
+    /// Write the checkpoint to `path`.
+    ///
+    /// The checkpoint is written to a temporary file first and then renamed,
+    /// so a crash while saving leaves the previous checkpoint intact.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        // Append to the whole file name, as replacing the extension would
+        // give `path` itself if it already ends in `.tmp`.
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        fs::write(&tmp_path, serde_json::to_vec(self)?)
+            .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("failed to save backfill checkpoint {}", path.display()))?;
+        Ok(())
+    }
+}
+
+impl DerivedDataManager {
+    /// Create a checkpoint for backfilling all underived ancestors of `csid`.
+    pub async fn backfill_checkpoint<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> Result<BackfillCheckpoint, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let underived = self
+            .find_underived::<Derivable>(ctx, csid, None, rederivation)
+            .await?;
+        let frontier = sort_topological(&underived)
+            .ok_or_else(|| anyhow!("cycle in underived changesets of {}", csid))?;
+        Ok(BackfillCheckpoint {
+            derivable_name: Derivable::NAME.to_string(),
+            frontier,
+            completed: 0,
+        })
+    }
+
+    /// Backfill the remaining changesets of a checkpoint in batches of
+    /// `batch_size`, advancing the checkpoint after every batch.
+    ///
+    /// Changesets at the start of each batch that are already derived (for
+    /// example, because the process stopped after deriving a batch but
+    /// before the checkpoint was saved) are skipped.  If `save_path` is
+    /// provided, the checkpoint is saved there after every batch.
+    pub async fn resume_backfill<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        checkpoint: &mut BackfillCheckpoint,
+        batch_size: usize,
+        batch_options: BatchDeriveOptions,
+        save_path: Option<&Path>,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> Result<(), DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        if checkpoint.derivable_name != Derivable::NAME {
+            return Err(anyhow!(
+                "checkpoint is for {}, not {}",
+                checkpoint.derivable_name,
+                Derivable::NAME
+            )
+            .into());
+        }
+
+        while !checkpoint.is_complete() {
+            let batch = checkpoint
+                .remaining()
+                .iter()
+                .take(batch_size.max(1))
+                .copied()
+                .collect::<Vec<_>>();
+            let derived = self
+                .fetch_derived_batch::<Derivable>(ctx, batch.clone(), rederivation.clone())
+                .await?;
+            let already_derived = batch
+                .iter()
+                .take_while(|csid| derived.contains_key(csid))
+                .count();
+            let batch = batch[already_derived..].to_vec();
+            let batch_len = batch.len();
+            if !batch.is_empty() {
+                self.backfill_batch::<Derivable>(ctx, batch, batch_options, rederivation.clone())
+                    .await?;
+            }
+            checkpoint.completed += already_derived + batch_len;
+            if let Some(save_path) = save_path {
+                checkpoint.save(save_path)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -7,9 +7,11 @@
 
 use std::collections::{HashMap, HashSet};
 use std::future;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Error, Result};
 use async_recursion::async_recursion;
@@ -18,25 +20,54 @@ use borrowed::borrowed;
 use cloned::cloned;
 use context::CoreContext;
 use futures::future::{try_join, FutureExt, TryFutureExt};
-use futures::stream::{self, FuturesUnordered, StreamExt, TryStreamExt};
+use futures::stream::{self, FuturesUnordered, Stream, StreamExt, TryStreamExt};
 use futures::{join, select_biased};
 use futures_stats::{TimedFutureExt, TimedTryFutureExt};
-use mononoke_types::ChangesetId;
+use mononoke_types::{BonsaiChangeset, ChangesetId, DateTime};
 use slog::debug;
-use topo_sort::TopoSortedDagTraversal;
+use topo_sort::{sort_topological, TopoSortedDagTraversal};
+use tracing::Instrument;
 
 use crate::context::DerivationContext;
-use crate::derivable::{BonsaiDerivable, DerivationDependencies};
+use crate::derivable::{
+    check_value_size, derive_from_parents, BonsaiDerivable, DerivationDependencies,
+};
 use crate::error::DerivationError;
+use crate::kill_switch::DerivationKillSwitch;
 use crate::manager::util::DiscoveryStats;
 use derived_data_service_if::types::{DerivationType, DeriveSingle};
 
 use super::{DerivationAssignment, DerivedDataManager};
 
+/// Number of changesets to fetch in each chunk of `fetch_derived_stream`.
+const FETCH_STREAM_CHUNK_SIZE: usize = 1000;
+
+/// Number of chunks `fetch_derived_stream` fetches concurrently.
+const FETCH_STREAM_CONCURRENCY: usize = 4;
+
+/// Conservative estimate of the time taken to derive a single changeset,
+/// used by `derive_with_deadline` to decide whether the underived ancestors
+/// can be derived in time.
+const ESTIMATED_DERIVATION_TIME_PER_CHANGESET: Duration = Duration::from_millis(50);
+
+/// Number of changesets visited by `find_underived_inner` between yields to
+/// the runtime.  When everything it fetches is cached, the traversal never
+/// has to wait, and would otherwise starve the other tasks of the executor.
+const FIND_UNDERIVED_YIELD_INTERVAL: usize = 1000;
+
 #[derive(Clone, Copy)]
 pub enum BatchDeriveOptions {
-    Parallel { gap_size: Option<usize> },
+    Parallel {
+        gap_size: Option<usize>,
+    },
     Serial,
+    /// Like `Serial`, but once `max_in_memory` derived values are held in
+    /// memory, they are persisted to the mapping and dropped.  Children
+    /// that need them fetch them back from the mapping, trading reads for
+    /// bounded memory on very large batches.
+    SerialBounded {
+        max_in_memory: usize,
+    },
 }
 
 pub enum BatchDeriveStats {
@@ -126,13 +157,24 @@ impl DerivedDataManager {
         Ok(())
     }
 
+    fn lease_key<Derivable>(&self, csid: ChangesetId) -> String
+    where
+        Derivable: BonsaiDerivable,
+    {
+        format!("repo{}.{}.{}", self.repo_id(), Derivable::NAME, csid)
+    }
+
     /// Perform derivation for a single changeset.
     /// Will fail in case data for parents changeset wasn't derived
+    ///
+    /// If `bonsai` is provided, it is used for local derivation instead of
+    /// loading the changeset from the blobstore.
     pub async fn perform_single_derivation<Derivable>(
         &self,
         ctx: &CoreContext,
         derivation_ctx: &DerivationContext,
         csid: ChangesetId,
+        bonsai: Option<BonsaiChangeset>,
         discovery_stats: &Option<DiscoveryStats>,
     ) -> Result<(ChangesetId, Derivable)>
     where
@@ -171,7 +213,7 @@ impl DerivedDataManager {
                 }
             }
         }
-        self.perform_single_derivation_locally(&ctx, &derivation_ctx, csid, discovery_stats)
+        self.perform_single_derivation_locally(&ctx, &derivation_ctx, csid, bonsai, discovery_stats)
             .await
     }
 
@@ -180,6 +222,7 @@ impl DerivedDataManager {
         ctx: &CoreContext,
         derivation_ctx: &DerivationContext,
         csid: ChangesetId,
+        bonsai: Option<BonsaiChangeset>,
         discovery_stats: &Option<DiscoveryStats>,
     ) -> Result<(ChangesetId, Derivable)>
     where
@@ -194,12 +237,20 @@ impl DerivedDataManager {
             .log_with_msg("Waiting for derived data to be generated", None);
 
         debug!(ctx.logger(), "derive {} for {}", Derivable::NAME, csid);
-        let lease_key = format!("repo{}.{}.{}", self.repo_id(), Derivable::NAME, csid);
+        let lease_key = self.lease_key::<Derivable>(csid);
 
         let ctx = ctx.clone_and_reset();
 
         let (stats, result) = async {
-            let bonsai = csid.load(&ctx, self.repo_blobstore()).map_err(Error::from);
+            let bonsai = async {
+                match bonsai {
+                    Some(bonsai) => Ok(bonsai),
+                    None => csid
+                        .load(&ctx, self.repo_blobstore())
+                        .await
+                        .map_err(Error::from),
+                }
+            };
             let guard = async {
                 if derivation_ctx.needs_rederive::<Derivable>(csid) {
                     // We are rederiving this changeset, so do not try to take
@@ -238,11 +289,28 @@ impl DerivedDataManager {
                 derived_data_scuba.add("changeset", csid.to_string());
                 self.log_derivation_start::<Derivable>(&ctx, &mut derived_data_scuba, csid);
 
+                // Span covering both derivation and storing the mapping.
+                // Derivations of dependencies triggered by this derivation
+                // appear as its children.
+                let span = tracing::info_span!(
+                    "derive",
+                    derived_data_type = Derivable::NAME,
+                    changeset_id = %csid
+                );
+
                 let (derive_stats, derived) = async {
                     let bonsai = bonsai?;
                     let parents = derivation_ctx.fetch_parents(&ctx, &bonsai).await?;
-                    Derivable::derive_single(&ctx, derivation_ctx, bonsai, parents).await
+                    let derived = derive_from_parents::<Derivable>(
+                        &ctx,
+                        derivation_ctx,
+                        bonsai.clone(),
+                        parents,
+                    )
+                    .await?;
+                    Ok::<_, Error>((bonsai, derived))
                 }
+                .instrument(span.clone())
                 .timed()
                 .await;
 
@@ -254,13 +322,19 @@ impl DerivedDataManager {
                     derived.as_ref().err(),
                 );
 
-                let derived = derived?;
+                let (bonsai, derived) = derived?;
+                derived
+                    .validate(&ctx, derivation_ctx, &bonsai)
+                    .await
+                    .map_err(|err| DerivationError::ValidationFailed(Derivable::NAME, csid, err))?;
+                check_value_size(&derived, csid)?;
 
                 // We may now store the mapping, and flush the blobstore to
                 // ensure the mapping is persisted.
                 let (persist_stats, persisted) = derived
                     .clone()
                     .store_mapping(&ctx, derivation_ctx, csid)
+                    .instrument(span)
                     .timed()
                     .await;
 
@@ -289,29 +363,79 @@ impl DerivedDataManager {
     }
 
     /// Find ancestors of the target changeset that are underived.
+    ///
+    /// Changesets in `known_derived` are assumed to be derived without
+    /// checking the mapping, and the traversal stops at them.
     async fn find_underived_inner<Derivable>(
         &self,
         ctx: &CoreContext,
         csid: ChangesetId,
         limit: Option<u64>,
+        known_derived: Option<&HashSet<ChangesetId>>,
         derivation_ctx: &DerivationContext,
-    ) -> Result<HashMap<ChangesetId, Vec<ChangesetId>>>
+    ) -> Result<HashMap<ChangesetId, Vec<ChangesetId>>, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let (underived, _truncated) = self
+            .find_underived_until::<Derivable>(
+                ctx,
+                csid,
+                limit,
+                known_derived,
+                None,
+                derivation_ctx,
+            )
+            .await?;
+        Ok(underived)
+    }
+
+    /// Same as `find_underived_inner`, except that the traversal stops
+    /// visiting new changesets once `deadline` has passed.  Also returns
+    /// whether the traversal was stopped early, either by the deadline or
+    /// by `limit`, in which case the result may be incomplete.
+    async fn find_underived_until<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+        limit: Option<u64>,
+        known_derived: Option<&HashSet<ChangesetId>>,
+        deadline: Option<Instant>,
+        derivation_ctx: &DerivationContext,
+    ) -> Result<(HashMap<ChangesetId, Vec<ChangesetId>>, bool), DerivationError>
     where
         Derivable: BonsaiDerivable,
     {
         // Ensure we don't visit the same commit multiple times in mergy repos
         let visited: Mutex<HashSet<ChangesetId>> = Default::default();
-        borrowed!(visited);
+        let steps = AtomicUsize::new(0);
+        let truncated = AtomicBool::new(false);
+        borrowed!(visited, steps, truncated);
+        // Each commit is visited along with the child it was reached from, so
+        // that missing parents can be told apart from other failures.
         let underived_commits_parents: HashMap<ChangesetId, Vec<ChangesetId>> =
-            bounded_traversal::bounded_traversal_stream(100, Some(csid).into_iter(), {
-                move |csid| {
+            bounded_traversal::bounded_traversal_stream(100, Some((csid, None)).into_iter(), {
+                move |(csid, child): (ChangesetId, Option<ChangesetId>)| {
                     async move {
+                        if steps.fetch_add(1, Ordering::Relaxed) % FIND_UNDERIVED_YIELD_INTERVAL
+                            == FIND_UNDERIVED_YIELD_INTERVAL - 1
+                        {
+                            tokio::task::yield_now().await;
+                        }
                         if let Some(limit) = limit {
                             let visited = visited.lock().unwrap();
                             if visited.len() as u64 > limit {
-                                return Ok::<_, Error>((None, Vec::new()));
+                                truncated.store(true, Ordering::Relaxed);
+                                return Ok::<_, DerivationError>((None, Vec::new()));
                             }
                         }
+                        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                            truncated.store(true, Ordering::Relaxed);
+                            return Ok((None, Vec::new()));
+                        }
+                        if known_derived.map_or(false, |known| known.contains(&csid)) {
+                            return Ok((None, Vec::new()));
+                        }
                         if derivation_ctx
                             .fetch_derived::<Derivable>(ctx, csid)
                             .await?
@@ -319,17 +443,24 @@ impl DerivedDataManager {
                         {
                             Ok((None, Vec::new()))
                         } else {
-                            let parents = self
-                                .changesets()
-                                .get(ctx.clone(), csid)
-                                .await?
-                                .ok_or_else(|| anyhow!("changeset not found: {}", csid))?
-                                .parents;
+                            let parents = match self.changesets().get(ctx.clone(), csid).await? {
+                                Some(entry) => entry.parents,
+                                None => {
+                                    return Err(match child {
+                                        Some(child) => DerivationError::MissingParentChangeset {
+                                            csid: child,
+                                            parent: csid,
+                                        },
+                                        None => anyhow!("changeset not found: {}", csid).into(),
+                                    });
+                                }
+                            };
                             let mut visited = visited.lock().unwrap();
                             let parents_to_visit = parents
                                 .iter()
                                 .cloned()
                                 .filter(|p| visited.insert(*p))
+                                .map(|p| (p, Some(csid)))
                                 .collect::<Vec<_>>();
                             Ok((Some((csid, parents)), parents_to_visit))
                         }
@@ -354,27 +485,46 @@ impl DerivedDataManager {
             })
             .collect::<HashMap<_, _>>();
 
-        Ok(underived_commits_parents)
+        Ok((underived_commits_parents, truncated.into_inner()))
     }
 
     /// Find which ancestors of `csid` are not yet derived, and necessary for
     /// the derivation of `csid` to complete, and derive them.
+    ///
+    /// If `target_bonsai` is provided, it is the bonsai changeset of
+    /// `target_csid`, and is used instead of loading it again.
+    ///
+    /// Changesets in `shallow_boundary` are treated as derived, see
+    /// `derive_with_shallow_boundary`.
     async fn derive_underived<Derivable>(
         &self,
         ctx: &CoreContext,
         derivation_ctx: Arc<DerivationContext>,
         target_csid: ChangesetId,
+        mut target_bonsai: Option<BonsaiChangeset>,
+        shallow_boundary: Option<&HashSet<ChangesetId>>,
     ) -> Result<DerivationOutcome<Derivable>, DerivationError>
     where
         Derivable: BonsaiDerivable,
     {
         let (find_underived_stats, dag_traversal) = async {
-            self.find_underived_inner::<Derivable>(ctx, target_csid, None, derivation_ctx.as_ref())
-                .await
+            self.find_underived_inner::<Derivable>(
+                ctx,
+                target_csid,
+                None,
+                shallow_boundary,
+                derivation_ctx.as_ref(),
+            )
+            .await
         }
         .try_timed()
         .await?;
 
+        // Already derived data is still returned while the type is killed.
+        if !dag_traversal.is_empty() && DerivationKillSwitch::get(Derivable::NAME).is_killed() {
+            return Err(DerivationError::Killed(Derivable::NAME));
+        }
+
         let stats = Some(DiscoveryStats {
             find_underived_completion_time: find_underived_stats.completion_time,
             commits_discovered: dag_traversal.len() as u32,
@@ -391,15 +541,25 @@ impl DerivedDataManager {
                 cloned!(ctx, derivation_ctx);
                 let manager = self.clone();
                 let stats = stats.clone();
+                let bonsai = if csid == target_csid {
+                    target_bonsai.take()
+                } else {
+                    None
+                };
                 let derivation = async move {
                     manager
-                        .perform_single_derivation(&ctx, &derivation_ctx, csid, &stats)
+                        .perform_single_derivation(&ctx, &derivation_ctx, csid, bonsai, &stats)
                         .await
                 };
                 tokio::spawn(derivation).map_err(Error::from)
             }));
             if let Some(derivation_result) = derivations.try_next().await? {
-                let (derived_csid, derived) = derivation_result?;
+                // Errors such as validation failures keep their variant.
+                let (derived_csid, derived) =
+                    derivation_result.map_err(|err| match err.downcast::<DerivationError>() {
+                        Ok(err) => err,
+                        Err(err) => DerivationError::Error(err),
+                    })?;
                 if derived_csid == target_csid {
                     target_derived = Some(derived);
                 }
@@ -458,7 +618,7 @@ impl DerivedDataManager {
         self.check_enabled::<Derivable>()?;
         let derivation_ctx = self.derivation_context(rederivation);
         let underived = self
-            .find_underived_inner::<Derivable>(ctx, csid, limit, &derivation_ctx)
+            .find_underived_inner::<Derivable>(ctx, csid, limit, None, &derivation_ctx)
             .await?;
         Ok(underived.len() as u64)
     }
@@ -488,15 +648,73 @@ impl DerivedDataManager {
     {
         self.get_manager(ctx, csid)
             .await?
-            .find_underived_impl::<Derivable>(ctx, csid, limit, rederivation)
+            .find_underived_impl::<Derivable>(ctx, csid, limit, None, rederivation)
             .await
     }
 
+    /// Find which ancestors of `csid` are not yet derived, treating the
+    /// changesets in `known_derived` as derived.
+    ///
+    /// This is the same as `find_underived`, except that the traversal stops
+    /// at changesets in `known_derived` (for example, a watermark from a
+    /// previous run) without checking the mapping for them.  On incremental
+    /// runs where most history is known to be derived, this avoids walking
+    /// and checking most of the history.
+    pub async fn find_underived_since<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+        limit: Option<u64>,
+        known_derived: &HashSet<ChangesetId>,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> Result<HashMap<ChangesetId, Vec<ChangesetId>>>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        self.get_manager(ctx, csid)
+            .await?
+            .find_underived_impl::<Derivable>(ctx, csid, limit, Some(known_derived), rederivation)
+            .await
+    }
+
+    /// Find which ancestors of `csid` are not yet derived, spending at most
+    /// `budget` searching for them.
+    ///
+    /// Returns the underived changesets found in topological order, so that
+    /// ancestors come before their descendants, along with whether the
+    /// search was truncated because the budget ran out.  If it was, the
+    /// changesets found are those closest to `csid`, and some of their
+    /// ancestors may be underived but missing from the result.  This keeps
+    /// the latency of interactive commands bounded on repos with a deep
+    /// backlog.
+    pub async fn find_underived_with_budget<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+        budget: Option<Duration>,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> Result<(Vec<ChangesetId>, bool)>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let deadline = budget.map(|budget| Instant::now() + budget);
+        let manager = self.get_manager(ctx, csid).await?;
+        manager.check_enabled::<Derivable>()?;
+        let derivation_ctx = manager.derivation_context(rederivation);
+        let (underived, truncated) = manager
+            .find_underived_until::<Derivable>(ctx, csid, None, None, deadline, &derivation_ctx)
+            .await?;
+        let sorted = sort_topological(&underived)
+            .ok_or_else(|| anyhow!("commit graph has cycles while searching from {}", csid))?;
+        Ok((sorted, truncated))
+    }
+
     async fn find_underived_impl<Derivable>(
         &self,
         ctx: &CoreContext,
         csid: ChangesetId,
         limit: Option<u64>,
+        known_derived: Option<&HashSet<ChangesetId>>,
         rederivation: Option<Arc<dyn Rederivation>>,
     ) -> Result<HashMap<ChangesetId, Vec<ChangesetId>>>
     where
@@ -504,8 +722,9 @@ impl DerivedDataManager {
     {
         self.check_enabled::<Derivable>()?;
         let derivation_ctx = self.derivation_context(rederivation);
-        self.find_underived_inner::<Derivable>(ctx, csid, limit, &derivation_ctx)
-            .await
+        Ok(self
+            .find_underived_inner::<Derivable>(ctx, csid, limit, known_derived, &derivation_ctx)
+            .await?)
     }
 
     /// Derive or retrieve derived data for a changeset.
@@ -520,14 +739,204 @@ impl DerivedDataManager {
     {
         self.get_manager(ctx, csid)
             .await?
-            .derive_impl::<Derivable>(ctx, csid, rederivation)
+            .derive_impl::<Derivable>(ctx, csid, None, None, rederivation)
+            .await
+    }
+
+    /// Derive or retrieve derived data for a changeset, after deriving the
+    /// types it depends on.
+    ///
+    /// The types in `Derivable::Dependencies` are derived for `csid` first,
+    /// each one after its own dependencies, so that deriving `Derivable`
+    /// can rely on them being present.
+    pub async fn derive_with_dependencies<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> Result<Derivable, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        Derivable::Dependencies::derive_dependencies(
+            ctx,
+            self,
+            csid,
+            rederivation.clone(),
+            &mut HashSet::new(),
+        )
+        .await?;
+        self.derive::<Derivable>(ctx, csid, rederivation).await
+    }
+
+    /// Derive or retrieve derived data for a changeset in a repo where
+    /// some ancestors are missing, such as a partial clone.
+    ///
+    /// Changesets in `shallow_boundary` are treated as already derived:
+    /// their ancestors are neither visited nor derived, so their bonsai
+    /// changesets don't need to be available.  Their derived values are
+    /// fetched from the mapping when their children are derived, so they
+    /// must have been derived elsewhere.
+    pub async fn derive_with_shallow_boundary<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+        shallow_boundary: &HashSet<ChangesetId>,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> Result<Derivable, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        self.get_manager(ctx, csid)
+            .await?
+            .derive_impl::<Derivable>(ctx, csid, None, Some(shallow_boundary), rederivation)
+            .await
+    }
+
+    /// Derive or retrieve derived data for a changeset, using the bonsai
+    /// changeset provided by the caller.
+    ///
+    /// This is the same as `derive`, except that `bonsai` is not loaded
+    /// again from the blobstore when deriving the changeset itself, which
+    /// saves a read when deriving a changeset that was just created.
+    /// Underived ancestors are loaded as usual.
+    pub async fn derive_with_bonsai<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        bonsai: BonsaiChangeset,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> Result<Derivable, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let csid = bonsai.get_changeset_id();
+        self.get_manager(ctx, csid)
+            .await?
+            .derive_impl::<Derivable>(ctx, csid, Some(bonsai), None, rederivation)
+            .await
+    }
+
+    /// Derive or retrieve derived data for a changeset, unless derivation
+    /// would not finish before `deadline`.
+    ///
+    /// Before deriving, the underived ancestors of `csid` are searched for,
+    /// up to the number that could be derived before the deadline.  If the
+    /// search finds more than that, or has to stop before finding them all,
+    /// `DerivationError::WouldBlock` is returned without starting
+    /// derivation, so that latency-sensitive callers can fall back to a
+    /// slower path.  The deadline is not enforced once derivation has
+    /// started.
+    pub async fn derive_with_deadline<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+        deadline: Instant,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> Result<Derivable, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let max_underived =
+            (remaining.as_millis() / ESTIMATED_DERIVATION_TIME_PER_CHANGESET.as_millis()) as u64;
+        let manager = self.get_manager(ctx, csid).await?;
+        manager.check_enabled::<Derivable>()?;
+        let derivation_ctx = manager.derivation_context(rederivation.clone());
+        let (underived, truncated) = manager
+            .find_underived_until::<Derivable>(
+                ctx,
+                csid,
+                Some(max_underived),
+                None,
+                None,
+                &derivation_ctx,
+            )
+            .await?;
+        let underived = underived.len() as u64;
+        if truncated || underived > max_underived {
+            return Err(DerivationError::WouldBlock(
+                Derivable::NAME,
+                csid,
+                underived,
+            ));
+        }
+        manager
+            .derive_impl::<Derivable>(ctx, csid, None, None, rederivation)
             .await
     }
 
+    /// Derive the descendants of `csid` that are ancestors of `heads` and
+    /// not yet derived, including `csid` itself.
+    ///
+    /// This forward-fills the history built on top of `csid`, e.g. after
+    /// enabling a derived data type for old changesets.  Underived
+    /// ancestors of these descendants are derived too, as usual.  Returns
+    /// the number of descendants that were derived.
+    pub async fn derive_descendants<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+        heads: Vec<ChangesetId>,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> Result<usize, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let mut underived = HashSet::new();
+        for head in heads {
+            let found = self
+                .find_underived::<Derivable>(ctx, head, None, rederivation.clone())
+                .await?;
+            underived.extend(found.into_keys());
+        }
+
+        // `find_underived` drops the parents that are derived, which
+        // descendants of `csid` can have if `csid` is derived, so fetch
+        // the full parents.
+        let parents = self
+            .changesets()
+            .get_many(ctx.clone(), underived.into_iter().collect())
+            .await?
+            .into_iter()
+            .map(|entry| (entry.cs_id, entry.parents))
+            .collect::<HashMap<_, _>>();
+        let sorted = sort_topological(&parents)
+            .ok_or_else(|| anyhow!("cycle in the ancestors of the heads"))?;
+
+        let mut descendants = Vec::new();
+        let mut is_descendant = HashSet::new();
+        is_descendant.insert(csid);
+        for cs in sorted {
+            if let Some(cs_parents) = parents.get(&cs) {
+                if cs == csid || cs_parents.iter().any(|p| is_descendant.contains(p)) {
+                    is_descendant.insert(cs);
+                    descendants.push(cs);
+                }
+            }
+        }
+
+        // Deriving the descendants that have no children among them
+        // derives all the others.
+        let mut has_children = HashSet::new();
+        for cs in descendants.iter() {
+            has_children.extend(parents[cs].iter().copied());
+        }
+        for cs in descendants.iter() {
+            if !has_children.contains(cs) {
+                self.derive::<Derivable>(ctx, *cs, rederivation.clone())
+                    .await?;
+            }
+        }
+
+        Ok(descendants.len())
+    }
+
     async fn derive_impl<Derivable>(
         &self,
         ctx: &CoreContext,
         csid: ChangesetId,
+        bonsai: Option<BonsaiChangeset>,
+        shallow_boundary: Option<&HashSet<ChangesetId>>,
         rederivation: Option<Arc<dyn Rederivation>>,
     ) -> Result<Derivable, DerivationError>
     where
@@ -546,7 +955,7 @@ impl DerivedDataManager {
                 self.repo_id(),
                 self.repo_name().to_string(),
             )),
-            (stats, res) = self.derive_underived(ctx, Arc::new(derivation_ctx), csid).timed().fuse() => {
+            (stats, res) = self.derive_underived(ctx, Arc::new(derivation_ctx), csid, bonsai, shallow_boundary).timed().fuse() => {
                 if self.should_log_slow_derivation(stats.completion_time) {
                     self.log_slow_derivation(ctx, csid, &stats, &pc, &res);
                 }
@@ -593,7 +1002,9 @@ impl DerivedDataManager {
             (
                 csids,
                 future::ready(Ok(match batch_options {
-                    BatchDeriveOptions::Serial => BatchDeriveStats::Serial(vec![]),
+                    BatchDeriveOptions::Serial | BatchDeriveOptions::SerialBounded { .. } => {
+                        BatchDeriveStats::Serial(vec![])
+                    }
                     BatchDeriveOptions::Parallel { .. } => {
                         BatchDeriveStats::Parallel(Duration::ZERO)
                     }
@@ -602,6 +1013,9 @@ impl DerivedDataManager {
             )
         };
         self.check_enabled::<Derivable>()?;
+        if DerivationKillSwitch::get(Derivable::NAME).is_killed() {
+            return Err(DerivationError::Killed(Derivable::NAME));
+        }
         let mut derivation_ctx = self.derivation_context(rederivation.clone());
 
         // Enable write batching, so that writes are stored in memory
@@ -724,7 +1138,7 @@ impl DerivedDataManager {
                         derived_data_scuba.add("gap_size", gap_size);
                     }
                     let (stats, derived) =
-                        Derivable::derive_batch(ctx, derivation_ctx_ref, bonsais, gap_size)
+                        Derivable::derive_batch(ctx, derivation_ctx_ref, bonsais.clone(), gap_size)
                             .try_timed()
                             .await
                             .with_context(|| {
@@ -739,10 +1153,19 @@ impl DerivedDataManager {
                                     format!("failed to derive empty {} batch", Derivable::NAME)
                                 }
                             })?;
+                    self.validate_batch(ctx, derivation_ctx_ref, bonsais, &derived)
+                        .await?;
                     (BatchDeriveStats::Parallel(stats.completion_time), derived)
                 }
-                BatchDeriveOptions::Serial => {
+                BatchDeriveOptions::Serial | BatchDeriveOptions::SerialBounded { .. } => {
                     derived_data_scuba.add("parallel", false);
+                    let max_in_memory = match batch_options {
+                        BatchDeriveOptions::SerialBounded { max_in_memory } => {
+                            derived_data_scuba.add("max_in_memory", max_in_memory);
+                            Some(max_in_memory)
+                        }
+                        _ => None,
+                    };
                     let mut per_commit_stats = Vec::new();
                     let mut per_commit_derived = HashMap::new();
                     for bonsai in bonsais {
@@ -750,15 +1173,37 @@ impl DerivedDataManager {
                         let parents = derivation_ctx_ref
                             .fetch_unknown_parents(ctx, Some(&per_commit_derived), &bonsai)
                             .await?;
-                        let (stats, derived) =
-                            Derivable::derive_single(ctx, derivation_ctx_ref, bonsai, parents)
-                                .try_timed()
-                                .await
-                                .with_context(|| {
-                                    format!("failed to derive {} for {}", Derivable::NAME, csid)
-                                })?;
+                        let (stats, derived) = derive_from_parents::<Derivable>(
+                            ctx,
+                            derivation_ctx_ref,
+                            bonsai.clone(),
+                            parents,
+                        )
+                        .try_timed()
+                        .await
+                        .with_context(|| {
+                            format!("failed to derive {} for {}", Derivable::NAME, csid)
+                        })?;
+                        derived
+                            .validate(ctx, derivation_ctx_ref, &bonsai)
+                            .await
+                            .map_err(|err| {
+                                DerivationError::ValidationFailed(Derivable::NAME, csid, err)
+                            })?;
                         per_commit_stats.push((csid, stats.completion_time));
                         per_commit_derived.insert(csid, derived);
+                        if max_in_memory.map_or(false, |max| per_commit_derived.len() >= max) {
+                            // Persist the values derived so far so they
+                            // can be dropped.  Later changesets fetch them
+                            // back from the mapping if needed.
+                            derivation_ctx_ref.flush(ctx).await?;
+                            self.store_batch_mappings::<Derivable>(
+                                ctx,
+                                mem::take(&mut per_commit_derived),
+                                rederivation.as_ref(),
+                            )
+                            .await?;
+                        }
                     }
                     (
                         BatchDeriveStats::Serial(per_commit_stats),
@@ -774,33 +1219,12 @@ impl DerivedDataManager {
                 .add_future_stats(&stats)
                 .log_with_msg("Flushed derived blobs", None);
 
-            let mut derivation_ctx = self.derivation_context(rederivation.clone());
-            derivation_ctx.enable_write_batching();
             // Write all mapping values, and flush the blobstore to ensure they
             // are persisted.
-            let (persist_stats, persisted) = async {
-                let derivation_ctx_ref = &derivation_ctx;
-                let csids = stream::iter(derived.into_iter())
-                    .map(|(csid, derived)| async move {
-                        derived
-                            .store_mapping(ctx, &derivation_ctx_ref, csid)
-                            .await?;
-                        Ok::<_, Error>(csid)
-                    })
-                    .buffer_unordered(100)
-                    .try_collect::<Vec<_>>()
-                    .await?;
-
-                derivation_ctx.flush(ctx).await?;
-                if let Some(rederivation) = rederivation {
-                    for csid in csids {
-                        rederivation.mark_derived(Derivable::NAME, csid);
-                    }
-                }
-                Ok::<_, Error>(())
-            }
-            .timed()
-            .await;
+            let (persist_stats, persisted) = self
+                .store_batch_mappings::<Derivable>(ctx, derived, rederivation.as_ref())
+                .timed()
+                .await;
 
             self.log_mapping_insertion::<Derivable>(
                 &ctx,
@@ -829,11 +1253,78 @@ impl DerivedDataManager {
             result.as_ref().err(),
         );
 
-        let batch_stats = result?;
+        // Errors such as validation failures keep their variant.
+        let batch_stats = result.map_err(|err| match err.downcast::<DerivationError>() {
+            Ok(err) => err,
+            Err(err) => DerivationError::Error(err),
+        })?;
 
         Ok(batch_stats.append(secondary_derivation.await?)?)
     }
 
+    /// Validate the values derived for a batch of changesets, see
+    /// `BonsaiDerivable::validate`.
+    async fn validate_batch<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        derivation_ctx: &DerivationContext,
+        bonsais: Vec<BonsaiChangeset>,
+        derived: &HashMap<ChangesetId, Derivable>,
+    ) -> Result<()>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        stream::iter(bonsais)
+            .map(|bonsai| async move {
+                let csid = bonsai.get_changeset_id();
+                let derived = derived.get(&csid).ok_or_else(|| {
+                    anyhow!("{} missing from derived {} batch", csid, Derivable::NAME)
+                })?;
+                derived
+                    .validate(ctx, derivation_ctx, &bonsai)
+                    .await
+                    .map_err(|err| DerivationError::ValidationFailed(Derivable::NAME, csid, err))?;
+                Ok::<_, Error>(())
+            })
+            .buffered(100)
+            .try_for_each(|_| async { Ok(()) })
+            .await
+    }
+
+    /// Write the mapping values of a batch of derived changesets, which
+    /// must already have been validated and had their blobs flushed, and
+    /// flush the blobstore to ensure they are persisted.
+    async fn store_batch_mappings<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        derived: HashMap<ChangesetId, Derivable>,
+        rederivation: Option<&Arc<dyn Rederivation>>,
+    ) -> Result<()>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let mut derivation_ctx = self.derivation_context(rederivation.cloned());
+        derivation_ctx.enable_write_batching();
+        let derivation_ctx_ref = &derivation_ctx;
+        let csids = stream::iter(derived.into_iter())
+            .map(|(csid, derived)| async move {
+                check_value_size(&derived, csid)?;
+                derived.store_mapping(ctx, derivation_ctx_ref, csid).await?;
+                Ok::<_, Error>(csid)
+            })
+            .buffer_unordered(100)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        derivation_ctx.flush(ctx).await?;
+        if let Some(rederivation) = rederivation {
+            for csid in csids {
+                rederivation.mark_derived(Derivable::NAME, csid);
+            }
+        }
+        Ok(())
+    }
+
     /// Fetch derived data for a changeset if it has previously been derived.
     pub async fn fetch_derived<Derivable>(
         &self,
@@ -865,6 +1356,83 @@ impl DerivedDataManager {
         Ok(derived)
     }
 
+    /// Fetch derived data for a changeset if it has previously been derived
+    /// at most `max_staleness` ago.
+    ///
+    /// Data that was derived longer ago is treated as absent, so that callers
+    /// can derive it again, e.g. using a `Rederivation`.  Data for which the
+    /// derived data type doesn't record the time it was derived is never
+    /// considered stale.  Without a `max_staleness`, this is the same as
+    /// `fetch_derived`.
+    pub async fn fetch_derived_with_max_staleness<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+        max_staleness: Option<Duration>,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> Result<Option<Derivable>, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let max_staleness = match max_staleness {
+            Some(max_staleness) => max_staleness,
+            None => return self.fetch_derived(ctx, csid, rederivation).await,
+        };
+
+        let manager = self.get_manager(ctx, csid).await?;
+        manager.check_enabled::<Derivable>()?;
+        let derivation_ctx = manager.derivation_context(rederivation);
+        let derived = derivation_ctx
+            .fetch_derived_with_write_time::<Derivable>(ctx, csid)
+            .await?;
+
+        let oldest_write_time = DateTime::now().timestamp_secs() - max_staleness.as_secs() as i64;
+        Ok(derived.and_then(|(derived, write_time)| match write_time {
+            Some(write_time) if write_time < oldest_write_time => None,
+            _ => Some(derived),
+        }))
+    }
+
+    /// Mark the derived data for a changeset as fresh, without deriving it
+    /// again.
+    ///
+    /// This is for data that `fetch_derived_with_max_staleness` would
+    /// consider stale, but that has been confirmed to still be valid by
+    /// other means.  Returns `false` if the data wasn't derived.  Fails if
+    /// the derived data type doesn't record the time it was derived.
+    pub async fn touch_derived<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+    ) -> Result<bool, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let manager = self.get_manager(ctx, csid).await?;
+        manager.check_enabled::<Derivable>()?;
+        let derivation_ctx = manager.derivation_context(None);
+        Ok(derivation_ctx.touch_derived::<Derivable>(ctx, csid).await?)
+    }
+
+    /// Check whether some process currently holds the lease to derive data
+    /// for a changeset, without taking the lease or deriving anything.
+    ///
+    /// The answer is only a snapshot: the derivation may finish or start
+    /// as soon as this returns.
+    pub async fn is_derivation_in_progress<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+    ) -> Result<bool, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let manager = self.get_manager(ctx, csid).await?;
+        manager.check_enabled::<Derivable>()?;
+        let lease_key = manager.lease_key::<Derivable>(csid);
+        Ok(manager.lease().lease_ops().is_leased(&lease_key).await?)
+    }
+
     #[async_recursion]
     /// Fetch derived data for a batch of changesets if they have previously
     /// been derived.
@@ -904,6 +1472,193 @@ impl DerivedDataManager {
         derived.extend(secondary_derivation.await?);
         Ok(derived)
     }
+
+    /// Fetch derived data for a stream of changesets if they have previously
+    /// been derived.
+    ///
+    /// This is the same as `fetch_derived_batch`, except that the changesets
+    /// are fetched in chunks, and the results are yielded as each chunk
+    /// completes, so that callers processing very large numbers of
+    /// changesets do not need to hold all the results in memory at once.
+    /// A few chunks are fetched concurrently to keep the mapping's storage
+    /// busy.  Changesets for which the data has not previously been derived
+    /// are omitted.
+    pub fn fetch_derived_stream<'a, Derivable>(
+        &'a self,
+        ctx: &'a CoreContext,
+        csids: Vec<ChangesetId>,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> impl Stream<Item = Result<(ChangesetId, Derivable), DerivationError>> + 'a
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let chunks = csids
+            .chunks(FETCH_STREAM_CHUNK_SIZE)
+            .map(|chunk| chunk.to_vec())
+            .collect::<Vec<_>>();
+        stream::iter(chunks)
+            .map(move |chunk| {
+                cloned!(rederivation);
+                async move {
+                    self.fetch_derived_batch::<Derivable>(ctx, chunk, rederivation)
+                        .await
+                }
+            })
+            .buffered(FETCH_STREAM_CONCURRENCY)
+            .map_ok(|derived| stream::iter(derived.into_iter().map(Ok)))
+            .try_flatten()
+    }
+
+    /// Count how many of the given changesets have been derived.
+    ///
+    /// Returns `(derived, total)`.  Unlike `count_underived`, this only
+    /// checks the mapping for the given changesets in a single batch, and
+    /// does not traverse their ancestors, which makes it suitable for
+    /// coverage reporting.  No derivation is triggered.
+    pub async fn derivation_coverage<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csids: Vec<ChangesetId>,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> Result<(usize, usize), DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let csids = csids.into_iter().collect::<HashSet<_>>();
+        let total = csids.len();
+        let derived = self
+            .fetch_derived_batch::<Derivable>(ctx, csids.into_iter().collect(), rederivation)
+            .await?;
+        Ok((derived.len(), total))
+    }
+
+    /// Derive data for a single changeset using parent values supplied by
+    /// the caller, rather than fetching them from the mapping.
+    ///
+    /// This is useful for pipelines that have just derived the parents in
+    /// memory.  `parents` must be the derived values of the changeset's
+    /// parents, in order.  If `store_mapping` is set, the derived value is
+    /// also stored in the mapping.
+    pub async fn derive_with_parents<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        bonsai: BonsaiChangeset,
+        parents: Vec<Derivable>,
+        store_mapping: bool,
+        rederivation: Option<Arc<dyn Rederivation>>,
+    ) -> Result<Derivable, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        self.check_enabled::<Derivable>()?;
+        let csid = bonsai.get_changeset_id();
+        let parent_count = bonsai.parents().count();
+        if parents.len() != parent_count {
+            return Err(anyhow!(
+                "{} has {} parents, but {} parent values were provided",
+                csid,
+                parent_count,
+                parents.len(),
+            )
+            .into());
+        }
+
+        let derivation_ctx = self.derivation_context(rederivation);
+        let derived =
+            derive_from_parents::<Derivable>(ctx, &derivation_ctx, bonsai.clone(), parents).await?;
+        if store_mapping {
+            derived
+                .validate(ctx, &derivation_ctx, &bonsai)
+                .await
+                .map_err(|err| DerivationError::ValidationFailed(Derivable::NAME, csid, err))?;
+            check_value_size(&derived, csid)?;
+            derived
+                .clone()
+                .store_mapping(ctx, &derivation_ctx, csid)
+                .await?;
+            derivation_ctx.mark_derived::<Derivable>(csid);
+        }
+        Ok(derived)
+    }
+
+    /// Derive data for a changeset without storing anything in the mapping.
+    ///
+    /// The underived ancestors of `csid` are derived in memory, and their
+    /// values are discarded once the target has been derived, along with
+    /// the blobs written while deriving them.  If the target is already
+    /// derived, its value is fetched from the mapping.  This is useful for
+    /// computing a fallback value for a changeset without committing to it,
+    /// e.g. to compare against an experimental derivation.  The
+    /// dependencies of `Derivable` must already be derived.  Unless
+    /// `unconditional` is set, the derived data type must be enabled for
+    /// the repo.
+    pub async fn derive_transient<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+        unconditional: bool,
+    ) -> Result<Derivable, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        if !unconditional {
+            self.check_enabled::<Derivable>()?;
+        }
+        let mut derivation_ctx = self.derivation_context(None);
+        // The write cache is never flushed.
+        derivation_ctx.enable_write_batching();
+        let underived = self
+            .find_underived_inner::<Derivable>(ctx, csid, None, None, &derivation_ctx)
+            .await?;
+        let order = sort_topological(&underived)
+            .ok_or_else(|| anyhow!("commit graph has cycles while deriving {}", csid))?;
+
+        let mut derived = HashMap::new();
+        for underived_csid in order {
+            let bonsai = underived_csid
+                .load(ctx, derivation_ctx.blobstore())
+                .await
+                .map_err(Error::from)?;
+            let parents = derivation_ctx
+                .fetch_unknown_parents(ctx, Some(&derived), &bonsai)
+                .await?;
+            let value =
+                derive_from_parents::<Derivable>(ctx, &derivation_ctx, bonsai, parents).await?;
+            derived.insert(underived_csid, value);
+        }
+
+        match derived.remove(&csid) {
+            Some(value) => Ok(value),
+            None => Ok(derivation_ctx
+                .fetch_derived(ctx, csid)
+                .await?
+                .ok_or_else(|| anyhow!("failed to derive target"))?),
+        }
+    }
+
+    /// Derive data for a changeset as if it had the derived data of
+    /// `parents` for its parents, without persisting anything.
+    ///
+    /// The value is computed directly from `bonsai` and the caller-chosen
+    /// `parents`, which need not match the actual parents of the
+    /// changeset, so it can be used to preview alternative histories.
+    /// Blobs written during derivation are kept in memory and dropped, and
+    /// the mapping is not touched.
+    pub async fn derive_speculative<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        bonsai: BonsaiChangeset,
+        parents: Vec<Derivable>,
+    ) -> Result<Derivable, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        self.check_enabled::<Derivable>()?;
+        let mut derivation_ctx = self.derivation_context(None);
+        // The write cache is never flushed.
+        derivation_ctx.enable_write_batching();
+        Ok(derive_from_parents::<Derivable>(ctx, &derivation_ctx, bonsai, parents).await?)
+    }
 }
 
 pub(super) struct DerivationOutcome<Derivable> {
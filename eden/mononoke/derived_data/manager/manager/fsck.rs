@@ -0,0 +1,58 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use std::collections::HashSet;
+
+use context::CoreContext;
+use mononoke_types::ChangesetId;
+
+use crate::derivable::BonsaiDerivable;
+use crate::error::DerivationError;
+
+use super::DerivedDataManager;
+
+/// Number of mappings to check for existence in a single batch.
+const ORPHAN_CHECK_BATCH_SIZE: usize = 1000;
+
+impl DerivedDataManager {
+    /// Find mappings for this derived data type that refer to changesets
+    /// which are no longer present in the repo (e.g. after a strip).
+    ///
+    /// `candidates` are the changesets whose mappings should be checked,
+    /// typically enumerated from the store backing the mapping.  Any
+    /// candidate in `csids_known_present` is skipped, and the remainder are
+    /// checked for a mapping in batches.  Returns the candidates which have
+    /// a mapping but are not known to be present.
+    pub async fn find_orphaned_mappings<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        candidates: Vec<ChangesetId>,
+        csids_known_present: &HashSet<ChangesetId>,
+    ) -> Result<Vec<ChangesetId>, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        let unknown = candidates
+            .into_iter()
+            .filter(|csid| !csids_known_present.contains(csid))
+            .collect::<Vec<_>>();
+
+        let mut orphans = Vec::new();
+        for batch in unknown.chunks(ORPHAN_CHECK_BATCH_SIZE) {
+            let derived = self
+                .fetch_derived_batch::<Derivable>(ctx, batch.to_vec(), None)
+                .await?;
+            orphans.extend(
+                batch
+                    .iter()
+                    .filter(|csid| derived.contains_key(csid))
+                    .copied(),
+            );
+        }
+        Ok(orphans)
+    }
+}
@@ -503,6 +503,10 @@ where
         {
             Ok(id) => Ok(id.hg_changeset_id()),
             Err(err @ DerivationError::Disabled(..)) => Err(err.into()),
+            Err(err @ DerivationError::Killed(..)) => Err(err.into()),
+            Err(err @ DerivationError::WouldBlock(..)) => Err(err.into()),
+            Err(err @ DerivationError::MissingParentChangeset { .. }) => Err(err.into()),
+            Err(err @ DerivationError::ValidationFailed(..)) => Err(err.into()),
             Err(DerivationError::Error(err)) => Err(err),
         };
         STATS::generate_hg_from_bonsai_total_latency_ms
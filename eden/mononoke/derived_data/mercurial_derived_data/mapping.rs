@@ -21,6 +21,7 @@ use futures::future::try_join_all;
 use mercurial_types::HgChangesetId;
 use mononoke_types::{BonsaiChangeset, ChangesetId};
 use slog::debug;
+use sql::Transaction;
 use stats::prelude::*;
 use std::collections::HashMap;
 
@@ -176,6 +177,27 @@ impl BonsaiDerivable for MappedHgChangesetId {
         Ok(())
     }
 
+    async fn store_mapping_in_transaction(
+        self,
+        ctx: &CoreContext,
+        derivation_ctx: &DerivationContext,
+        changeset_id: ChangesetId,
+        transaction: Transaction,
+    ) -> Result<Transaction> {
+        let (transaction, _) = derivation_ctx
+            .bonsai_hg_mapping()?
+            .add_in_transaction(
+                ctx,
+                BonsaiHgMappingEntry {
+                    hg_cs_id: self.0,
+                    bcs_id: changeset_id,
+                },
+                transaction,
+            )
+            .await?;
+        Ok(transaction)
+    }
+
     async fn fetch(
         ctx: &CoreContext,
         derivation_ctx: &DerivationContext,
@@ -10,12 +10,13 @@ use std::collections::HashMap;
 use anyhow::{anyhow, Error, Result};
 use async_trait::async_trait;
 use blobstore::{Blobstore, BlobstoreGetData};
-use bytes::Bytes;
 use context::CoreContext;
-use derived_data::impl_bonsai_derived_via_manager;
+use derived_data::root_id::{self, deserialize_root_id, serialize_root_id};
+use derived_data::{impl_bonsai_derived_via_manager, BlobstoreRootIdMapping};
 use derived_data_manager::{dependencies, BonsaiDerivable, DerivationContext};
 use mononoke_types::{
-    BlobstoreBytes, BonsaiChangeset, ChangesetId, ContentId, FileType, MPath, SkeletonManifestId,
+    BlobstoreBytes, BonsaiChangeset, ChangesetId, ContentId, DateTime, FileType, MPath,
+    SkeletonManifestId,
 };
 
 use crate::batch::derive_skeleton_manifests_in_batch;
@@ -39,7 +40,7 @@ impl TryFrom<BlobstoreBytes> for RootSkeletonManifestId {
     type Error = Error;
 
     fn try_from(blob_bytes: BlobstoreBytes) -> Result<Self> {
-        SkeletonManifestId::from_bytes(&blob_bytes.into_bytes()).map(RootSkeletonManifestId)
+        deserialize_root_id(blob_bytes.as_bytes()).map(RootSkeletonManifestId)
     }
 }
 
@@ -53,9 +54,10 @@ impl TryFrom<BlobstoreGetData> for RootSkeletonManifestId {
 
 impl From<RootSkeletonManifestId> for BlobstoreBytes {
     fn from(root_skeleton_manifest_id: RootSkeletonManifestId) -> Self {
-        BlobstoreBytes::from_bytes(Bytes::copy_from_slice(
-            root_skeleton_manifest_id.0.blake2().as_ref(),
-        ))
+        serialize_root_id(
+            &root_skeleton_manifest_id.0,
+            RootSkeletonManifestId::ROOT_ID_FORMAT,
+        )
     }
 }
 
@@ -112,7 +114,14 @@ impl BonsaiDerivable for RootSkeletonManifestId {
         changeset_id: ChangesetId,
     ) -> Result<()> {
         let key = format_key(derivation_ctx, changeset_id);
-        derivation_ctx.blobstore().put(ctx, key, self.into()).await
+        root_id::store_with_write_time(
+            ctx,
+            derivation_ctx.blobstore().as_ref(),
+            key,
+            &self.0,
+            Self::ROOT_ID_FORMAT,
+        )
+        .await
     }
 
     async fn fetch(
@@ -129,6 +138,25 @@ impl BonsaiDerivable for RootSkeletonManifestId {
             .transpose()?)
     }
 
+    async fn fetch_with_write_time(
+        ctx: &CoreContext,
+        derivation_ctx: &DerivationContext,
+        changeset_id: ChangesetId,
+    ) -> Result<Option<(Self, Option<i64>)>> {
+        let key = format_key(derivation_ctx, changeset_id);
+        root_id::fetch_with_write_time(ctx, derivation_ctx.blobstore().as_ref(), &key).await
+    }
+
+    async fn touch(
+        ctx: &CoreContext,
+        derivation_ctx: &DerivationContext,
+        changeset_id: ChangesetId,
+    ) -> Result<bool> {
+        let key = format_key(derivation_ctx, changeset_id);
+        let now = DateTime::now().timestamp_secs();
+        root_id::touch(ctx, derivation_ctx.blobstore().as_ref(), &key, now).await
+    }
+
     fn from_thrift(data: thrift::DerivedData) -> Result<Self> {
         if let thrift::DerivedData::skeleton_manifest(
             thrift::DerivedDataSkeletonManifest::root_skeleton_manifest_id(id),
@@ -154,6 +182,14 @@ impl BonsaiDerivable for RootSkeletonManifestId {
 
 impl_bonsai_derived_via_manager!(RootSkeletonManifestId);
 
+impl BlobstoreRootIdMapping for RootSkeletonManifestId {
+    type RootId = SkeletonManifestId;
+
+    fn root_id(&self) -> &Self::RootId {
+        &self.0
+    }
+}
+
 pub(crate) fn get_file_changes(
     bcs: &BonsaiChangeset,
 ) -> Vec<(MPath, Option<(ContentId, FileType)>)> {
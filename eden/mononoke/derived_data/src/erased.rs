@@ -0,0 +1,126 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Type-erased handles for derived data types.
+//!
+//! `BonsaiDerived` is not object safe, so it can't be used to store
+//! several derived data types in a single collection.  `erase` wraps a
+//! derived data type in a `DynDerivable`, which can be, so that a generic
+//! deriver can hold a `Vec<Box<dyn DynDerivable>>` of all the configured
+//! types and derive each of them in turn.
+
+use std::collections::HashSet;
+use std::marker::PhantomData;
+
+use blobrepo::BlobRepo;
+use context::CoreContext;
+use futures::future::{try_join_all, BoxFuture, FutureExt};
+use mononoke_types::ChangesetId;
+
+use crate::{BonsaiDerived, DeriveError};
+
+/// Object-safe handle for a derived data type.
+pub trait DynDerivable: Send + Sync {
+    /// Name of the derived data type.
+    fn name(&self) -> &'static str;
+
+    /// Derive this type of derived data for a changeset, discarding the
+    /// derived value.
+    fn derive_dyn<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        repo: &'a BlobRepo,
+        csid: ChangesetId,
+    ) -> BoxFuture<'a, Result<(), DeriveError>>;
+
+    /// Returns `true` if this type of derived data has already been derived
+    /// for a changeset.  This never triggers derivation.
+    fn is_derived_dyn<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        repo: &'a BlobRepo,
+        csid: ChangesetId,
+    ) -> BoxFuture<'a, Result<bool, DeriveError>>;
+}
+
+/// `DynDerivable` implementation for a particular `BonsaiDerived` type.
+///
+/// This doesn't hold a value of the derived data type, so the phantom data
+/// is a function pointer type to keep this `Send` and `Sync` regardless.
+struct ErasedDerivable<Derived>(PhantomData<fn() -> Derived>);
+
+impl<Derived: BonsaiDerived> DynDerivable for ErasedDerivable<Derived> {
+    fn name(&self) -> &'static str {
+        Derived::DERIVABLE_NAME
+    }
+
+    fn derive_dyn<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        repo: &'a BlobRepo,
+        csid: ChangesetId,
+    ) -> BoxFuture<'a, Result<(), DeriveError>> {
+        async move {
+            Derived::derive(ctx, repo, csid).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn is_derived_dyn<'a>(
+        &'a self,
+        ctx: &'a CoreContext,
+        repo: &'a BlobRepo,
+        csid: ChangesetId,
+    ) -> BoxFuture<'a, Result<bool, DeriveError>> {
+        async move { Derived::is_derived(ctx, repo, &csid).await }.boxed()
+    }
+}
+
+/// Erase the type of a derived data type.
+pub fn erase<Derived: BonsaiDerived>() -> Box<dyn DynDerivable> {
+    Box::new(ErasedDerivable::<Derived>(PhantomData))
+}
+
+/// Returns the names of the derived data types in `derivables` that have
+/// already been derived for a changeset.
+///
+/// Each type's mapping is checked for the changeset, without triggering
+/// derivation, which gives a view of the derivation status of a single
+/// commit.
+pub async fn derived_types_present(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    csid: ChangesetId,
+    derivables: &[Box<dyn DynDerivable>],
+) -> Result<HashSet<&'static str>, DeriveError> {
+    let present = try_join_all(derivables.iter().map(|derivable| async move {
+        let derived = derivable.is_derived_dyn(ctx, repo, csid).await?;
+        Ok::<_, DeriveError>(derived.then(|| derivable.name()))
+    }))
+    .await?;
+    Ok(present.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync + ?Sized>() {}
+
+    #[test]
+    fn test_send_sync() {
+        assert_send_sync::<dyn DynDerivable>();
+        assert_send_sync::<Box<dyn DynDerivable>>();
+        assert_send_sync::<Vec<Box<dyn DynDerivable>>>();
+        assert_send_sync::<DeriveError>();
+        // The handle is `Send` and `Sync` even if the derived type isn't.
+        assert_send_sync::<ErasedDerivable<Rc<()>>>();
+    }
+}
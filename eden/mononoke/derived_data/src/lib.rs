@@ -38,6 +38,14 @@
 //! mapping implementation that uses the configuration on the repository,
 //! provided the derived data type is enabled for that repository.
 //!
+//! ### BlobstoreRootIdMapping
+//!
+//! Many derived data types store the id of a root blob (e.g. a manifest) in
+//! their mapping.  The `BlobstoreRootIdMapping` trait exposes that id, so
+//! callers can obtain it directly from derivation.  The format used to
+//! store the id in the mapping is chosen by `ROOT_ID_FORMAT`; see the
+//! `root_id` module.
+//!
 //! ## Usage
 //!
 //! The usual usage for deriving a particular derived data type in a
@@ -52,17 +60,43 @@
 //! manager.backfill_batch::<DerivedDataType>(ctx, cs_ids.clone(), BatchDeriveOptions, None).await?;
 //! let values: Vec<DerivedDataType> = manager.fetch_derived_batch(ctx, cs_ids, None).await?;
 //! ```
+//!
+//! Request handlers that only need to use the derived value can use
+//! `with_derived`:
+//!
+//! ```ignore
+//! let root = with_derived(ctx, repo, cs_id, |unode: &RootUnodeManifestId| {
+//!     Ok::<_, Error>(unode.manifest_unode_id().clone())
+//! })
+//! .await?;
+//! ```
+//!
+//! Standalone tools that don't have a server context can use
+//! `derive_offline`, which derives with a context that logs nothing:
+//!
+//! ```ignore
+//! let value: DerivedDataType = derive_offline(fb, &repo, cs_id).await?;
+//! ```
 
 use anyhow::Error;
 use async_trait::async_trait;
 use blobrepo::BlobRepo;
 use context::{CoreContext, SessionClass};
-use mononoke_types::ChangesetId;
+use derived_data_manager::{BatchDeriveOptions, BatchDeriveStats, BonsaiDerivable};
+use fbinit::FacebookInit;
+use futures::stream::{self, StreamExt};
+use mononoke_types::{BonsaiChangeset, ChangesetId};
+use repo_blobstore::RepoBlobstore;
+use repo_derived_data::RepoDerivedDataRef;
 
 pub mod batch;
+pub mod erased;
+pub mod root_id;
 
 pub use derived_data_manager::DerivationError as DeriveError;
+pub use erased::{derived_types_present, erase, DynDerivable};
 pub use metaconfig_types::DerivedDataTypesConfig;
+pub use root_id::{RootIdFormat, RootIdSerialization};
 
 pub mod macro_export {
     pub use super::{BonsaiDerived, DeriveError};
@@ -126,6 +160,259 @@ pub trait BonsaiDerived: Sized + Send + Sync + Clone + 'static {
         csid: &ChangesetId,
         limit: u64,
     ) -> Result<u64, DeriveError>;
+
+    /// Returns how many of `csids` already have derived data, along with
+    /// the total number of distinct changesets checked, as
+    /// `(derived, total)`.
+    ///
+    /// This only checks the mapping in a single batch and never triggers
+    /// derivation, so it is much cheaper than `count_underived` for coverage
+    /// reporting.
+    async fn derivation_coverage(
+        ctx: &CoreContext,
+        repo: &BlobRepo,
+        csids: &[ChangesetId],
+    ) -> Result<(usize, usize), DeriveError>;
+
+    /// Returns `true` if some process currently holds the lease to derive
+    /// this changeset.
+    ///
+    /// This only probes the lease, it never takes it or triggers
+    /// derivation, and the answer may be out of date by the time it is
+    /// returned.
+    async fn is_derivation_in_progress(
+        ctx: &CoreContext,
+        repo: &BlobRepo,
+        csid: ChangesetId,
+    ) -> Result<bool, DeriveError>;
+}
+
+/// Trait for derived data types whose mapped value is the id of a root
+/// blob, such as the root of a manifest.
+#[async_trait]
+pub trait BlobstoreRootIdMapping: BonsaiDerived {
+    type RootId: RootIdSerialization + Clone + Send + Sync + 'static;
+
+    /// The format used when storing root ids in the mapping.  Entries
+    /// written in any format remain readable, so this can be changed
+    /// without rewriting the existing mapping.
+    const ROOT_ID_FORMAT: RootIdFormat = RootIdFormat::Raw;
+
+    /// The id of the root blob of this derived data.
+    fn root_id(&self) -> &Self::RootId;
+
+    /// Derive data for a changeset and return the id of its root blob.
+    ///
+    /// As the mapped value is the root id itself, this doesn't need another
+    /// mapping lookup after derivation.
+    async fn derive_root_id(
+        ctx: &CoreContext,
+        repo: &BlobRepo,
+        csid: ChangesetId,
+    ) -> Result<Self::RootId, DeriveError> {
+        Ok(Self::derive(ctx, repo, csid).await?.root_id().clone())
+    }
+}
+
+/// Derive `D` for a changeset (if it is not already derived) and run `f`
+/// with the derived value.
+///
+/// Derivation errors are converted into the error type of `f`, so handlers
+/// can use a single error type for both deriving and using the data.
+pub async fn with_derived<D, F, T, E>(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    csid: ChangesetId,
+    f: F,
+) -> Result<T, E>
+where
+    D: BonsaiDerived,
+    F: FnOnce(&D) -> Result<T, E>,
+    E: From<DeriveError>,
+{
+    let derived = D::derive(ctx, repo, csid).await?;
+    f(&derived)
+}
+
+/// Derive `Derivable` for a changeset, reading and writing blobs through
+/// `blobstore` instead of the repo's blobstore.
+///
+/// The blobstore must contain the bonsai changesets being derived.  Mappings
+/// stored in the blobstore are written to it too, so this can be used for
+/// isolated derivation tests, or to shadow-write derived data elsewhere.
+/// Remote derivation is disabled for the call, as the derivation service
+/// would write to the repo's blobstore.
+///
+/// Types whose mapping is stored in SQL, such as `MappedHgChangesetId` in
+/// the bonsai-hg mapping, can't be redirected, so deriving them, or types
+/// depending on them, fails rather than writing to the repo's mapping.
+pub async fn derive_with_blobstore<Derivable: BonsaiDerivable>(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    csid: ChangesetId,
+    blobstore: RepoBlobstore,
+) -> Result<Derivable, DeriveError> {
+    repo.repo_derived_data()
+        .manager()
+        .with_replaced_blobstore(blobstore)
+        .without_sql_mappings()
+        .with_replaced_derivation_service_client(None)
+        .derive::<Derivable>(ctx, csid, None)
+        .await
+}
+
+/// Compare the `Derivable` values stored for `csids` in two blobstores.
+///
+/// Returns, for each changeset, whether the values fetched from
+/// `blobstore_a` and `blobstore_b` are equal.  Changesets that are derived
+/// in neither are reported as equal.  Nothing is derived, so combined with
+/// `derive_with_blobstore` into a scratch blobstore, this can validate a new
+/// derivation algorithm against the values stored for the repo.
+pub async fn compare_derivations<Derivable>(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    csids: Vec<ChangesetId>,
+    blobstore_a: RepoBlobstore,
+    blobstore_b: RepoBlobstore,
+) -> Result<Vec<(ChangesetId, bool)>, DeriveError>
+where
+    Derivable: BonsaiDerivable + PartialEq,
+{
+    let manager = repo.repo_derived_data().manager();
+    let (derived_a, derived_b) = futures::try_join!(
+        manager
+            .with_replaced_blobstore(blobstore_a)
+            .fetch_derived_batch::<Derivable>(ctx, csids.clone(), None),
+        manager
+            .with_replaced_blobstore(blobstore_b)
+            .fetch_derived_batch::<Derivable>(ctx, csids.clone(), None),
+    )?;
+    Ok(csids
+        .into_iter()
+        .map(|csid| (csid, derived_a.get(&csid) == derived_b.get(&csid)))
+        .collect())
+}
+
+/// Derive `Derivable` for a changeset the caller already has the bonsai of.
+///
+/// The bonsai is used directly to derive the changeset itself, saving a
+/// blobstore read right after creating it.  Underived ancestors are fetched
+/// as usual.
+pub async fn derive_with_bonsai<Derivable: BonsaiDerivable>(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    bonsai: BonsaiChangeset,
+) -> Result<Derivable, DeriveError> {
+    repo.repo_derived_data()
+        .manager()
+        .derive_with_bonsai::<Derivable>(ctx, bonsai, None)
+        .await
+}
+
+/// Derive `Derivable` for a changeset from a standalone tool, without
+/// having to set up a `CoreContext`.
+///
+/// Derivation uses `CoreContext::new_for_offline_tool`, so nothing is
+/// logged.  Callers that want logs should build a context with
+/// `CoreContext::new_with_logger` and use `RepoDerivedData::derive`.
+pub async fn derive_offline<Derivable: BonsaiDerivable>(
+    fb: FacebookInit,
+    repo: &BlobRepo,
+    csid: ChangesetId,
+) -> Result<Derivable, DeriveError> {
+    let ctx = CoreContext::new_for_offline_tool(fb);
+    repo.repo_derived_data()
+        .derive::<Derivable>(&ctx, csid)
+        .await
+}
+
+/// Derive `Derivable` for `csid` and its descendants that are ancestors of
+/// `heads` and not yet derived.
+///
+/// Returns the number of descendants, including `csid`, that were derived.
+pub async fn derive_descendants<Derivable: BonsaiDerivable>(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    csid: ChangesetId,
+    heads: Vec<ChangesetId>,
+) -> Result<usize, DeriveError> {
+    repo.repo_derived_data()
+        .manager()
+        .derive_descendants::<Derivable>(ctx, csid, heads, None)
+        .await
+}
+
+/// Derive `Derivable` for a changeset, after deriving the types it depends
+/// on, in dependency order.
+pub async fn derive_with_dependencies<Derivable: BonsaiDerivable>(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    csid: ChangesetId,
+) -> Result<Derivable, DeriveError> {
+    repo.repo_derived_data()
+        .manager()
+        .derive_with_dependencies::<Derivable>(ctx, csid, None)
+        .await
+}
+
+/// Backfill `Derivable` for batches of changesets in several repos,
+/// working on up to `concurrency` repos at a time.
+///
+/// Each job is a repo and a batch of its changesets in topological order,
+/// which is backfilled with `DerivedDataManager::backfill_batch`, so the
+/// ancestors and dependencies of each batch must already be derived.  The
+/// results are returned in the order of `jobs`, and a failure in one repo
+/// doesn't stop the others.
+pub async fn derive_across_repos<Derivable: BonsaiDerivable>(
+    ctx: &CoreContext,
+    jobs: Vec<(BlobRepo, Vec<ChangesetId>)>,
+    batch_options: BatchDeriveOptions,
+    concurrency: usize,
+) -> Vec<Result<BatchDeriveStats, DeriveError>> {
+    stream::iter(jobs)
+        .map(|(repo, csids)| async move {
+            repo.repo_derived_data()
+                .manager()
+                .backfill_batch::<Derivable>(ctx, csids, batch_options, None)
+                .await
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+/// Derive `Derivable` for a changeset without writing to its mapping.
+///
+/// Underived ancestors are derived in memory only, so repeated calls redo
+/// the work.  Unless `unconditional` is set, the derived data type must be
+/// enabled for the repo.
+pub async fn derive_transient<Derivable: BonsaiDerivable>(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    csid: ChangesetId,
+    unconditional: bool,
+) -> Result<Derivable, DeriveError> {
+    repo.repo_derived_data()
+        .manager()
+        .derive_transient::<Derivable>(ctx, csid, unconditional)
+        .await
+}
+
+/// Derive `Derivable` for a changeset against a caller-chosen set of parent
+/// values, without persisting the result.
+///
+/// This is useful to preview what the derived data would be if the
+/// changeset had different parents.
+pub async fn derive_speculative<Derivable: BonsaiDerivable>(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    bonsai: BonsaiChangeset,
+    parents: Vec<Derivable>,
+) -> Result<Derivable, DeriveError> {
+    repo.repo_derived_data()
+        .manager()
+        .derive_speculative::<Derivable>(ctx, bonsai, parents)
+        .await
 }
 
 #[macro_export]
@@ -168,6 +455,26 @@ macro_rules! impl_bonsai_derived_via_manager {
                     .count_underived::<Self>(ctx, *csid, Some(limit))
                     .await
             }
+
+            async fn derivation_coverage(
+                ctx: &$crate::macro_export::CoreContext,
+                repo: &$crate::macro_export::BlobRepo,
+                csids: &[$crate::macro_export::ChangesetId],
+            ) -> Result<(usize, usize), $crate::macro_export::DeriveError> {
+                $crate::macro_export::RepoDerivedDataRef::repo_derived_data(repo)
+                    .derivation_coverage::<Self>(ctx, csids.to_vec())
+                    .await
+            }
+
+            async fn is_derivation_in_progress(
+                ctx: &$crate::macro_export::CoreContext,
+                repo: &$crate::macro_export::BlobRepo,
+                csid: $crate::macro_export::ChangesetId,
+            ) -> Result<bool, $crate::macro_export::DeriveError> {
+                $crate::macro_export::RepoDerivedDataRef::repo_derived_data(repo)
+                    .is_derivation_in_progress::<Self>(ctx, csid)
+                    .await
+            }
         }
     };
 }
@@ -0,0 +1,247 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Serialization of the root ids stored in the mappings of
+//! `BlobstoreRootIdMapping` types.
+//!
+//! Root ids were originally stored as the raw bytes of their hash, without
+//! any tag.  Other formats are written after a tag byte, so that a mapping
+//! can switch to a new format while the entries written in the old one
+//! remain readable.
+//!
+//! Entries can also record the time at which they were written, in seconds
+//! since the epoch, as a tagged prefix to the id in any of these formats.
+//! Entries written without it have no known write time.
+//!
+//! This also provides the storing and fetching shared by the mappings of
+//! these types.
+
+use anyhow::{bail, Error, Result};
+use blobstore::{Blobstore, BlobstoreGetData};
+use bytes::Bytes;
+use context::CoreContext;
+use fbthrift::compact_protocol;
+use mononoke_types::{BlobstoreBytes, DateTime, FsnodeId, ManifestUnodeId, SkeletonManifestId};
+
+/// Length of a root id stored in the `Raw` format.  Tagged formats are
+/// never this long, which is how raw ids are told apart.
+const RAW_LEN: usize = 32;
+
+const THRIFT_TAG: u8 = 1;
+
+const WRITE_TIME_TAG: u8 = 2;
+
+/// Length of the write time that follows `WRITE_TIME_TAG`.
+const WRITE_TIME_LEN: usize = 8;
+
+/// Format of a root id stored in a mapping.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RootIdFormat {
+    /// The raw bytes of the hash, untagged.  This is the format of the
+    /// entries written before formats were introduced.
+    Raw,
+    /// The thrift representation of the id, using the compact protocol.
+    Thrift,
+}
+
+impl Default for RootIdFormat {
+    fn default() -> Self {
+        RootIdFormat::Raw
+    }
+}
+
+/// Root ids that can be stored in any `RootIdFormat`.
+pub trait RootIdSerialization: Sized {
+    fn to_raw(&self) -> Bytes;
+
+    fn from_raw(bytes: &[u8]) -> Result<Self>;
+
+    fn to_thrift(&self) -> Bytes;
+
+    fn from_thrift(bytes: &[u8]) -> Result<Self>;
+}
+
+/// Serialize `id` in `format`, tagging it unless the format is `Raw`.
+pub fn serialize_root_id<Id: RootIdSerialization>(id: &Id, format: RootIdFormat) -> BlobstoreBytes {
+    match format {
+        RootIdFormat::Raw => BlobstoreBytes::from_bytes(id.to_raw()),
+        RootIdFormat::Thrift => {
+            let thrift = id.to_thrift();
+            let mut bytes = Vec::with_capacity(1 + thrift.len());
+            bytes.push(THRIFT_TAG);
+            bytes.extend_from_slice(&thrift);
+            BlobstoreBytes::from_bytes(bytes)
+        }
+    }
+}
+
+/// Serialize `id` in `format`, recording that it was written at
+/// `write_time`, in seconds since the epoch.
+pub fn serialize_root_id_with_write_time<Id: RootIdSerialization>(
+    id: &Id,
+    format: RootIdFormat,
+    write_time: i64,
+) -> BlobstoreBytes {
+    with_write_time(serialize_root_id(id, format).as_bytes(), write_time)
+}
+
+/// Prefix a serialized root id with `write_time`.
+fn with_write_time(id: &[u8], write_time: i64) -> BlobstoreBytes {
+    let mut bytes = Vec::with_capacity(1 + WRITE_TIME_LEN + id.len());
+    bytes.push(WRITE_TIME_TAG);
+    bytes.extend_from_slice(&write_time.to_be_bytes());
+    bytes.extend_from_slice(id);
+    BlobstoreBytes::from_bytes(bytes)
+}
+
+/// Split the write time off a serialized root id, if it records one.
+fn split_write_time(bytes: &[u8]) -> Result<(Option<i64>, &[u8])> {
+    if bytes.len() != RAW_LEN {
+        if let Some((&WRITE_TIME_TAG, rest)) = bytes.split_first() {
+            if rest.len() < WRITE_TIME_LEN {
+                bail!("truncated root id write time");
+            }
+            let (write_time, id) = rest.split_at(WRITE_TIME_LEN);
+            let write_time = i64::from_be_bytes(write_time.try_into()?);
+            return Ok((Some(write_time), id));
+        }
+    }
+    Ok((None, bytes))
+}
+
+/// Deserialize a root id serialized by `serialize_root_id` or
+/// `serialize_root_id_with_write_time` in any format.
+pub fn deserialize_root_id<Id: RootIdSerialization>(bytes: &[u8]) -> Result<Id> {
+    let (_write_time, bytes) = split_write_time(bytes)?;
+    if bytes.len() == RAW_LEN {
+        return Id::from_raw(bytes);
+    }
+    match bytes.split_first() {
+        Some((&THRIFT_TAG, thrift)) => Id::from_thrift(thrift),
+        Some((tag, _)) => bail!("unknown root id format tag {}", tag),
+        None => bail!("empty root id"),
+    }
+}
+
+/// Write `id` as the mapping entry at `key`, recording the current time as
+/// its write time.
+pub async fn store_with_write_time<Id: RootIdSerialization>(
+    ctx: &CoreContext,
+    blobstore: &dyn Blobstore,
+    key: String,
+    id: &Id,
+    format: RootIdFormat,
+) -> Result<()> {
+    let now = DateTime::now().timestamp_secs();
+    blobstore
+        .put(ctx, key, serialize_root_id_with_write_time(id, format, now))
+        .await
+}
+
+/// Fetch the mapping entry stored at `key`, along with the time at which it
+/// was written or last touched, in seconds since the epoch, if known.
+pub async fn fetch_with_write_time<V>(
+    ctx: &CoreContext,
+    blobstore: &dyn Blobstore,
+    key: &str,
+) -> Result<Option<(V, Option<i64>)>>
+where
+    V: TryFrom<BlobstoreGetData, Error = Error>,
+{
+    let blob = match blobstore.get(ctx, key).await? {
+        Some(blob) => blob,
+        None => return Ok(None),
+    };
+    let (write_time, _id) = split_write_time(blob.as_raw_bytes())?;
+    Ok(Some((blob.try_into()?, write_time)))
+}
+
+/// Record that the mapping entry stored at `key` was confirmed valid at
+/// `time`, in seconds since the epoch, so that `fetch_with_write_time`
+/// reports it as written then.  Returns `false` if there is no entry.
+///
+/// The entry is written again with the new time, so this only has an
+/// effect if the blobstore allows overwriting keys, as is also needed to
+/// store re-derived data.  A touch racing with a re-derivation of the entry
+/// may write back the previous value.
+pub async fn touch(
+    ctx: &CoreContext,
+    blobstore: &dyn Blobstore,
+    key: &str,
+    time: i64,
+) -> Result<bool> {
+    let blob = match blobstore.get(ctx, key).await? {
+        Some(blob) => blob,
+        None => return Ok(false),
+    };
+    let (_write_time, id) = split_write_time(blob.as_raw_bytes())?;
+    blobstore
+        .put(ctx, key.to_string(), with_write_time(id, time))
+        .await?;
+    Ok(true)
+}
+
+macro_rules! impl_root_id_serialization {
+    ($id:ty) => {
+        impl RootIdSerialization for $id {
+            fn to_raw(&self) -> Bytes {
+                Bytes::copy_from_slice(self.blake2().as_ref())
+            }
+
+            fn from_raw(bytes: &[u8]) -> Result<Self> {
+                <$id>::from_bytes(bytes)
+            }
+
+            fn to_thrift(&self) -> Bytes {
+                compact_protocol::serialize(&self.into_thrift())
+            }
+
+            fn from_thrift(bytes: &[u8]) -> Result<Self> {
+                <$id>::from_thrift(compact_protocol::deserialize(bytes)?)
+            }
+        }
+    };
+}
+
+impl_root_id_serialization!(FsnodeId);
+impl_root_id_serialization!(ManifestUnodeId);
+impl_root_id_serialization!(SkeletonManifestId);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mononoke_types::hash::Blake2;
+
+    #[test]
+    fn test_root_id_formats() -> Result<()> {
+        let id = FsnodeId::new(Blake2::from_byte_array([7; 32]));
+        for format in [RootIdFormat::Raw, RootIdFormat::Thrift] {
+            let bytes = serialize_root_id(&id, format);
+            assert_eq!(bytes.len() == RAW_LEN, format == RootIdFormat::Raw);
+            assert_eq!(deserialize_root_id::<FsnodeId>(bytes.as_bytes())?, id);
+        }
+        assert!(deserialize_root_id::<FsnodeId>(&[0xff, 0]).is_err());
+        assert!(deserialize_root_id::<FsnodeId>(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_id_write_time() -> Result<()> {
+        let id = FsnodeId::new(Blake2::from_byte_array([7; 32]));
+        for format in [RootIdFormat::Raw, RootIdFormat::Thrift] {
+            let bytes = serialize_root_id_with_write_time(&id, format, 1234);
+            assert_eq!(split_write_time(bytes.as_bytes())?.0, Some(1234));
+            assert_eq!(deserialize_root_id::<FsnodeId>(bytes.as_bytes())?, id);
+
+            let bytes = serialize_root_id(&id, format);
+            assert_eq!(split_write_time(bytes.as_bytes())?.0, None);
+        }
+        assert!(deserialize_root_id::<FsnodeId>(&[WRITE_TIME_TAG, 0]).is_err());
+        Ok(())
+    }
+}
@@ -21,7 +21,7 @@ use derived_data_manager::{dependencies, BonsaiDerivable, DerivationContext};
 
 use derived_data_service_if::types as thrift;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct DerivedGeneration {
     pub generation: u64,
 }
@@ -58,19 +58,30 @@ impl BonsaiDerivable for DerivedGeneration {
     type Dependencies = dependencies![];
 
     async fn derive_single(
-        _ctx: &CoreContext,
-        _derivation_ctx: &DerivationContext,
+        ctx: &CoreContext,
+        derivation_ctx: &DerivationContext,
         bonsai: BonsaiChangeset,
         parents: Vec<Self>,
     ) -> Result<Self> {
-        if let Some(delay_str) = bonsai
-            .extra()
-            .collect::<HashMap<_, _>>()
-            .get("test-derive-delay")
-        {
+        let extra = bonsai.extra().collect::<HashMap<_, _>>();
+        if let Some(delay_str) = extra.get("test-derive-delay") {
             let delay = std::str::from_utf8(delay_str)?.parse::<f64>()?;
             tokio::time::sleep(Duration::from_secs_f64(delay)).await;
         }
+        if let Some(blob) = extra.get("test-derive-write-blob") {
+            derivation_ctx
+                .blobstore()
+                .put(
+                    ctx,
+                    format!(
+                        "repo{}.test_generation_blob.{}",
+                        derivation_ctx.repo_id(),
+                        bonsai.get_changeset_id(),
+                    ),
+                    BlobstoreBytes::from_bytes(Bytes::copy_from_slice(blob)),
+                )
+                .await?;
+        }
         let mut generation = 1;
         for parent in parents {
             if parent.generation >= generation {
@@ -81,6 +92,48 @@ impl BonsaiDerivable for DerivedGeneration {
         Ok(derived)
     }
 
+    async fn derive_single_incremental(
+        ctx: &CoreContext,
+        derivation_ctx: &DerivationContext,
+        bonsai: BonsaiChangeset,
+        parent: Self,
+    ) -> Result<Self> {
+        if bonsai
+            .extra()
+            .any(|(key, _)| key == "test-derive-record-incremental")
+        {
+            derivation_ctx
+                .blobstore()
+                .put(
+                    ctx,
+                    format!(
+                        "repo{}.test_generation_incremental.{}",
+                        derivation_ctx.repo_id(),
+                        bonsai.get_changeset_id(),
+                    ),
+                    BlobstoreBytes::from_bytes(Bytes::new()),
+                )
+                .await?;
+        }
+        Self::derive_single(ctx, derivation_ctx, bonsai, vec![parent]).await
+    }
+
+    async fn validate(
+        &self,
+        _ctx: &CoreContext,
+        _derivation_ctx: &DerivationContext,
+        bonsai: &BonsaiChangeset,
+    ) -> Result<()> {
+        if bonsai.extra().any(|(key, _)| key == "test-derive-invalid") {
+            bail!("{} is marked as invalid", bonsai.get_changeset_id());
+        }
+        Ok(())
+    }
+
+    fn serialized_size(&self) -> Option<usize> {
+        Some(self.generation.to_string().len())
+    }
+
     async fn store_mapping(
         self,
         ctx: &CoreContext,
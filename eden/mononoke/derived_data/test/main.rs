@@ -5,18 +5,22 @@
  * GNU General Public License version 2.
  */
 
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Error, Result};
 use async_trait::async_trait;
-use blobrepo::BlobRepo;
+use blobrepo::{save_bonsai_changesets, BlobRepo};
 use blobstore::Blobstore;
 use blobstore::BlobstoreBytes;
+use blobstore::Loadable;
 use bookmarks::{BookmarkName, BookmarksRef};
 use bytes::Bytes;
 use cacheblob::LeaseOps;
-use changesets::ChangesetsRef;
+use changesets::{
+    ChangesetEntry, ChangesetInsert, Changesets, ChangesetsArc, ChangesetsRef, SortOrder,
+};
 use cloned::cloned;
 use context::CoreContext;
 use fbinit::FacebookInit;
@@ -26,16 +30,29 @@ use fixtures::{
     MergeUneven, UnsharedMergeEven, UnsharedMergeUneven,
 };
 use futures::future::BoxFuture;
+use futures::stream::{BoxStream, TryStreamExt};
 use futures_stats::{TimedFutureExt, TimedTryFutureExt};
 use lock_ext::LockExt;
-use maplit::hashmap;
-use mononoke_types::{ChangesetId, MPath, RepositoryId};
+use maplit::{hashmap, hashset};
+use mercurial_derived_data::MappedHgChangesetId;
+use mononoke_types::{
+    ChangesetId, ChangesetIdPrefix, ChangesetIdsResolvedFromPrefix, MPath, RepositoryId,
+};
 use repo_blobstore::RepoBlobstoreRef;
 use repo_derived_data::{RepoDerivedDataArc, RepoDerivedDataRef};
+use skeleton_manifest::RootSkeletonManifestId;
+use tempfile::TempDir;
 use tests_utils::CreateCommitContext;
 use tunables::{override_tunables, MononokeTunables};
 
-use derived_data_manager::{BonsaiDerivable, DerivationError};
+use derived_data::{
+    compare_derivations, derive_across_repos, derive_descendants, derive_offline,
+    derive_speculative, derive_transient, derive_with_blobstore, derive_with_bonsai,
+};
+use derived_data_manager::{
+    BackfillCheckpoint, BatchDeriveOptions, BonsaiDerivable, DerivationError, DerivationKillSwitch,
+    DerivedValueTooLarge,
+};
 use derived_data_test_derived_generation::{make_test_repo_factory, DerivedGeneration};
 
 async fn derive_for_master(
@@ -268,6 +285,10 @@ impl LeaseOps for FailingLease {
     async fn wait_for_other_leases(&self, _key: &str) {}
 
     async fn release_lease(&self, _key: &str) {}
+
+    async fn is_leased(&self, _key: &str) -> Result<bool> {
+        Err(anyhow!("error"))
+    }
 }
 
 #[fbinit::test]
@@ -311,6 +332,65 @@ async fn test_always_failing_lease(fb: FacebookInit) -> Result<(), Error> {
     Ok(())
 }
 
+#[fbinit::test]
+async fn test_is_derivation_in_progress(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo = make_test_repo_factory(fb).build()?;
+    Linear::initrepo(fb, &repo).await;
+
+    let master = repo
+        .bookmarks()
+        .get(ctx.clone(), &BookmarkName::new("master")?)
+        .await?
+        .expect("master should be set");
+
+    let lease = repo.repo_derived_data().lease();
+    let lease_key = format!(
+        "repo{}.{}.{}",
+        repo.get_repoid().id(),
+        DerivedGeneration::NAME,
+        master
+    );
+
+    assert!(
+        !repo
+            .repo_derived_data()
+            .is_derivation_in_progress::<DerivedGeneration>(&ctx, master)
+            .await?
+    );
+
+    // Probing the lease while it is held doesn't take it.
+    assert!(lease.try_add_put_lease(&lease_key).await?);
+    assert!(
+        repo.repo_derived_data()
+            .is_derivation_in_progress::<DerivedGeneration>(&ctx, master)
+            .await?
+    );
+    assert!(
+        repo.repo_derived_data()
+            .is_derivation_in_progress::<DerivedGeneration>(&ctx, master)
+            .await?
+    );
+
+    lease.release_lease(&lease_key).await;
+    assert!(
+        !repo
+            .repo_derived_data()
+            .is_derivation_in_progress::<DerivedGeneration>(&ctx, master)
+            .await?
+    );
+
+    // Probing never derives anything.
+    assert!(
+        repo.repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, master)
+            .await?
+            .is_none()
+    );
+
+    Ok(())
+}
+
 #[fbinit::test]
 async fn test_parallel_derivation(fb: FacebookInit) -> Result<(), Error> {
     let ctx = CoreContext::test_mock(fb);
@@ -424,3 +504,1491 @@ async fn test_cancelling_slow_derivation(fb: FacebookInit) -> Result<(), Error>
 
     Ok(())
 }
+
+#[fbinit::test]
+async fn test_derive_with_blobstore(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let shadow_repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    // Create the same commit in both repos, so that the shadow blobstore
+    // contains the bonsai changeset.
+    let mut csids = Vec::new();
+    for repo in [&repo, &shadow_repo] {
+        let csid = CreateCommitContext::new_root(&ctx, repo)
+            .add_file("file", "content")
+            .commit()
+            .await?;
+        csids.push(csid);
+    }
+    assert_eq!(csids[0], csids[1]);
+    let csid = csids[0];
+
+    let derived = derive_with_blobstore::<DerivedGeneration>(
+        &ctx,
+        &repo,
+        csid,
+        shadow_repo.repo_blobstore().clone(),
+    )
+    .await?;
+    assert_eq!(derived.generation, 1);
+
+    // The derived data was only written to the shadow blobstore.
+    assert!(
+        shadow_repo
+            .repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, csid)
+            .await?
+            .is_some()
+    );
+    assert!(
+        repo.repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, csid)
+            .await?
+            .is_none()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_with_blobstore_sql_mapping(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let shadow_repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let mut csids = Vec::new();
+    for repo in [&repo, &shadow_repo] {
+        let csid = CreateCommitContext::new_root(&ctx, repo)
+            .add_file("file", "content")
+            .commit()
+            .await?;
+        csids.push(csid);
+    }
+    let csid = csids[0];
+
+    // Hg changesets are mapped in the repo's bonsai-hg mapping, which can't
+    // be redirected to the shadow blobstore.
+    assert!(
+        derive_with_blobstore::<MappedHgChangesetId>(
+            &ctx,
+            &repo,
+            csid,
+            shadow_repo.repo_blobstore().clone(),
+        )
+        .await
+        .is_err()
+    );
+    assert!(
+        repo.repo_derived_data()
+            .fetch_derived::<MappedHgChangesetId>(&ctx, csid)
+            .await?
+            .is_none()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_compare_derivations(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let scratch_repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let mut csids = Vec::new();
+    for repo in [&repo, &scratch_repo] {
+        let root = CreateCommitContext::new_root(&ctx, repo)
+            .add_file("file", "root")
+            .commit()
+            .await?;
+        let child = CreateCommitContext::new(&ctx, repo, vec![root])
+            .add_file("file", "child")
+            .commit()
+            .await?;
+        csids = vec![root, child];
+    }
+    let (root, child) = (csids[0], csids[1]);
+
+    repo.repo_derived_data()
+        .derive::<DerivedGeneration>(&ctx, child)
+        .await?;
+    derive_with_blobstore::<DerivedGeneration>(
+        &ctx,
+        &repo,
+        root,
+        scratch_repo.repo_blobstore().clone(),
+    )
+    .await?;
+    // The scratch value for the child differs.
+    scratch_repo
+        .repo_blobstore()
+        .put(
+            &ctx,
+            format!("repo0.test_generation.{}", child),
+            BlobstoreBytes::from_bytes(Bytes::from_static(b"42")),
+        )
+        .await?;
+
+    let compared = compare_derivations::<DerivedGeneration>(
+        &ctx,
+        &repo,
+        csids,
+        repo.repo_blobstore().clone(),
+        scratch_repo.repo_blobstore().clone(),
+    )
+    .await?;
+    assert_eq!(compared, vec![(root, true), (child, false)]);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_transient(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    Linear::initrepo(fb, &repo).await;
+
+    let master = repo
+        .bookmarks()
+        .get(ctx.clone(), &BookmarkName::new("master")?)
+        .await?
+        .expect("master should be set");
+    let expected = repo
+        .changesets()
+        .get(ctx.clone(), master)
+        .await?
+        .expect("changeset should exist")
+        .gen;
+
+    let derived = derive_transient::<DerivedGeneration>(&ctx, &repo, master, false).await?;
+    assert_eq!(derived.generation, expected);
+
+    // Nothing was written to the mapping.
+    assert!(
+        repo.repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, master)
+            .await?
+            .is_none()
+    );
+
+    // Once derived, the stored value is returned.
+    repo.repo_derived_data()
+        .derive::<DerivedGeneration>(&ctx, master)
+        .await?;
+    let derived = derive_transient::<DerivedGeneration>(&ctx, &repo, master, false).await?;
+    assert_eq!(derived.generation, expected);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_transient_writes_no_blobs(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .add_extra("test-derive-write-blob", "root")
+        .commit()
+        .await?;
+    let child = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "child")
+        .add_extra("test-derive-write-blob", "child")
+        .commit()
+        .await?;
+    let blob_key = |csid: ChangesetId| {
+        format!(
+            "repo{}.test_generation_blob.{}",
+            repo.get_repoid().id(),
+            csid
+        )
+    };
+
+    let derived = derive_transient::<DerivedGeneration>(&ctx, &repo, child, false).await?;
+    assert_eq!(derived.generation, 2);
+    for csid in [root, child] {
+        assert!(
+            repo.repo_blobstore()
+                .get(&ctx, &blob_key(csid))
+                .await?
+                .is_none()
+        );
+    }
+
+    // Deriving for real writes them.
+    repo.repo_derived_data()
+        .derive::<DerivedGeneration>(&ctx, child)
+        .await?;
+    for csid in [root, child] {
+        assert!(
+            repo.repo_blobstore()
+                .get(&ctx, &blob_key(csid))
+                .await?
+                .is_some()
+        );
+    }
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_offline(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    Linear::initrepo(fb, &repo).await;
+
+    let master = repo
+        .bookmarks()
+        .get(ctx.clone(), &BookmarkName::new("master")?)
+        .await?
+        .expect("master should be set");
+    let expected = repo
+        .changesets()
+        .get(ctx.clone(), master)
+        .await?
+        .expect("changeset should exist")
+        .gen;
+
+    let derived = derive_offline::<DerivedGeneration>(fb, &repo, master).await?;
+    assert_eq!(derived.generation, expected);
+
+    // The value is stored like any other derivation.
+    assert_eq!(
+        repo.repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, master)
+            .await?,
+        Some(derived)
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_speculative(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let child = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "child")
+        .commit()
+        .await?;
+    let bonsai = child.load(&ctx, repo.repo_blobstore()).await?;
+
+    // The derived value is computed against the given parents.
+    let derived = derive_speculative::<DerivedGeneration>(
+        &ctx,
+        &repo,
+        bonsai.clone(),
+        vec![DerivedGeneration { generation: 10 }],
+    )
+    .await?;
+    assert_eq!(derived.generation, 11);
+    let derived = derive_speculative::<DerivedGeneration>(&ctx, &repo, bonsai, Vec::new()).await?;
+    assert_eq!(derived.generation, 1);
+
+    // Neither the changeset nor its real parent were derived.
+    for csid in [root, child] {
+        assert!(
+            repo.repo_derived_data()
+                .fetch_derived::<DerivedGeneration>(&ctx, csid)
+                .await?
+                .is_none()
+        );
+    }
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_with_bonsai(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let bonsai = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "child")
+        .create_commit_object()
+        .await?
+        .freeze()?;
+    let csid = bonsai.get_changeset_id();
+    save_bonsai_changesets(vec![bonsai.clone()], ctx.clone(), &repo).await?;
+
+    // The parent is derived along the way.
+    let derived = derive_with_bonsai::<DerivedGeneration>(&ctx, &repo, bonsai).await?;
+    assert_eq!(derived.generation, 2);
+    assert_eq!(
+        repo.repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, csid)
+            .await?
+            .map(|derived| derived.generation),
+        Some(2)
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_max_parent_fetch_concurrency(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("root", "root")
+        .commit()
+        .await?;
+    let mut parents = vec![root];
+    for i in 0..4 {
+        let parent = CreateCommitContext::new(&ctx, &repo, vec![root])
+            .add_file(format!("file{}", i).as_str(), "content")
+            .commit()
+            .await?;
+        parents.push(parent);
+    }
+    let merge = CreateCommitContext::new(&ctx, &repo, parents)
+        .commit()
+        .await?;
+
+    let tunables = MononokeTunables::default();
+    tunables.update_ints(&hashmap! {
+        "derived_data_max_parent_fetch_concurrency".to_string() => 1,
+    });
+    override_tunables(Some(Arc::new(tunables)));
+
+    // Parents are fetched one at a time, but all of them are still used.
+    let derived = repo
+        .repo_derived_data()
+        .derive::<DerivedGeneration>(&ctx, merge)
+        .await;
+    override_tunables(None);
+    assert_eq!(derived?.generation, 3);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_max_value_size(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let mut csids = Vec::new();
+    let mut parents = Vec::new();
+    for i in 0..10 {
+        let csid = CreateCommitContext::new(&ctx, &repo, parents)
+            .add_file("file", format!("content{}", i).as_str())
+            .commit()
+            .await?;
+        csids.push(csid);
+        parents = vec![csid];
+    }
+
+    let tunables = MononokeTunables::default();
+    tunables.update_ints(&hashmap! {
+        "derived_data_max_value_size".to_string() => 1,
+    });
+    override_tunables(Some(Arc::new(tunables)));
+
+    // Generations up to 9 fit in a single byte, but not 10.
+    let small = repo
+        .repo_derived_data()
+        .derive::<DerivedGeneration>(&ctx, csids[8])
+        .await;
+    let large = repo
+        .repo_derived_data()
+        .derive::<DerivedGeneration>(&ctx, csids[9])
+        .await;
+    override_tunables(None);
+    assert_eq!(small?.generation, 9);
+    match large {
+        Err(DerivationError::Error(e)) => {
+            let too_large = e
+                .downcast_ref::<DerivedValueTooLarge>()
+                .expect("value should be too large");
+            assert_eq!(too_large.csid, csids[9]);
+            assert_eq!(too_large.size, 2);
+        }
+        other => panic!("unexpected result: {:?}", other.map(|d| d.generation)),
+    }
+    assert!(
+        repo.repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, csids[9])
+            .await?
+            .is_none()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_validation_failed(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let valid = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "content")
+        .commit()
+        .await?;
+    let invalid = CreateCommitContext::new(&ctx, &repo, vec![valid])
+        .add_file("file", "content2")
+        .add_extra("test-derive-invalid", "1")
+        .commit()
+        .await?;
+
+    let res = repo
+        .repo_derived_data()
+        .derive::<DerivedGeneration>(&ctx, invalid)
+        .await;
+    match res {
+        Err(DerivationError::ValidationFailed(name, csid, _)) => {
+            assert_eq!(name, DerivedGeneration::NAME);
+            assert_eq!(csid, invalid);
+        }
+        other => panic!("unexpected result: {:?}", other.map(|d| d.generation)),
+    }
+
+    // The parent was valid, so it was stored, but not the invalid value.
+    let derived_data = repo.repo_derived_data();
+    assert!(
+        derived_data
+            .fetch_derived::<DerivedGeneration>(&ctx, valid)
+            .await?
+            .is_some()
+    );
+    assert!(
+        derived_data
+            .fetch_derived::<DerivedGeneration>(&ctx, invalid)
+            .await?
+            .is_none()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_backfill_batch_serial_bounded(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let mut csids = Vec::new();
+    let mut parents = Vec::new();
+    for i in 0..10 {
+        let csid = CreateCommitContext::new(&ctx, &repo, parents)
+            .add_file("file", format!("content{}", i).as_str())
+            .commit()
+            .await?;
+        csids.push(csid);
+        parents = vec![csid];
+    }
+
+    // Backfilling requires the ancestors of the batch to be derived.
+    repo.repo_derived_data()
+        .derive::<DerivedGeneration>(&ctx, csids[0])
+        .await?;
+
+    // Values are spilled to the mapping every 3 changesets, so later
+    // changesets have to fetch their parent back from it.
+    repo.repo_derived_data()
+        .manager()
+        .backfill_batch::<DerivedGeneration>(
+            &ctx,
+            csids[1..].to_vec(),
+            BatchDeriveOptions::SerialBounded { max_in_memory: 3 },
+            None,
+        )
+        .await?;
+
+    for (i, csid) in csids.into_iter().enumerate() {
+        let derived = repo
+            .repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, csid)
+            .await?
+            .expect("changeset should be derived");
+        assert_eq!(derived.generation, i as u64 + 1);
+    }
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_backfill_batch_validation_failed(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let invalid = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "invalid")
+        .add_extra("test-derive-invalid", "1")
+        .commit()
+        .await?;
+
+    let res = repo
+        .repo_derived_data()
+        .manager()
+        .backfill_batch::<DerivedGeneration>(
+            &ctx,
+            vec![root, invalid],
+            BatchDeriveOptions::Parallel { gap_size: None },
+            None,
+        )
+        .await;
+    match res {
+        Err(DerivationError::ValidationFailed(name, csid, _)) => {
+            assert_eq!(name, DerivedGeneration::NAME);
+            assert_eq!(csid, invalid);
+        }
+        Err(err) => panic!("unexpected error: {:?}", err),
+        Ok(_) => panic!("invalid value should not be stored"),
+    }
+    assert!(
+        repo.repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, invalid)
+            .await?
+            .is_none()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_resume_backfill(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let mut csids = Vec::new();
+    let mut parents = Vec::new();
+    for i in 0..5 {
+        let mut commit = CreateCommitContext::new(&ctx, &repo, parents)
+            .add_file("file", format!("content{}", i).as_str());
+        if i == 0 {
+            // Deriving this changeset again would fail.
+            commit = commit.add_extra("test-derive-invalid", "1");
+        }
+        let csid = commit.commit().await?;
+        csids.push(csid);
+        parents = vec![csid];
+    }
+
+    let manager = repo.repo_derived_data().manager();
+    let mut checkpoint = manager
+        .backfill_checkpoint::<DerivedGeneration>(&ctx, csids[4], None)
+        .await?;
+    assert_eq!(checkpoint.frontier, csids);
+    assert_eq!(checkpoint.remaining(), &csids[..]);
+
+    // The first changeset was derived after the checkpoint was last saved,
+    // so it is skipped rather than derived again.
+    repo.repo_blobstore()
+        .put(
+            &ctx,
+            format!(
+                "repo{}.test_generation.{}",
+                repo.get_repoid().id(),
+                csids[0]
+            ),
+            BlobstoreBytes::from_bytes(Bytes::from_static(b"1")),
+        )
+        .await?;
+
+    // Checkpoints ending in `.tmp` are saved through another file.
+    let tempdir = TempDir::new()?;
+    let save_path = tempdir.path().join("checkpoint.tmp");
+    manager
+        .resume_backfill::<DerivedGeneration>(
+            &ctx,
+            &mut checkpoint,
+            2,
+            BatchDeriveOptions::Serial,
+            Some(&save_path),
+            None,
+        )
+        .await?;
+    assert!(checkpoint.is_complete());
+    assert_eq!(checkpoint.completed, csids.len());
+    for (i, csid) in csids.iter().enumerate() {
+        let derived = repo
+            .repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, *csid)
+            .await?
+            .expect("changeset should be derived");
+        assert_eq!(derived.generation, i as u64 + 1);
+    }
+
+    assert_eq!(BackfillCheckpoint::load(&save_path)?, checkpoint);
+    assert_eq!(std::fs::read_dir(tempdir.path())?.count(), 1);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_resume_backfill_wrong_type(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+
+    let mut checkpoint = BackfillCheckpoint {
+        derivable_name: "other".to_string(),
+        frontier: vec![root],
+        completed: 0,
+    };
+    let res = repo
+        .repo_derived_data()
+        .manager()
+        .resume_backfill::<DerivedGeneration>(
+            &ctx,
+            &mut checkpoint,
+            1,
+            BatchDeriveOptions::Serial,
+            None,
+            None,
+        )
+        .await;
+    match res {
+        Err(DerivationError::Error(err)) => assert!(
+            err.to_string().contains("checkpoint is for other"),
+            "unexpected error: {:?}",
+            err
+        ),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(checkpoint.completed, 0);
+    assert!(
+        repo.repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, root)
+            .await?
+            .is_none()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_across_repos(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo1: BlobRepo = make_test_repo_factory(fb).build()?;
+    let repo2: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let root1 = CreateCommitContext::new_root(&ctx, &repo1)
+        .add_file("file", "root1")
+        .commit()
+        .await?;
+    let child1 = CreateCommitContext::new(&ctx, &repo1, vec![root1])
+        .add_file("file", "child1")
+        .commit()
+        .await?;
+    let root2 = CreateCommitContext::new_root(&ctx, &repo2)
+        .add_file("file", "root2")
+        .commit()
+        .await?;
+    let child2 = CreateCommitContext::new(&ctx, &repo2, vec![root2])
+        .add_file("file", "child2")
+        .commit()
+        .await?;
+
+    // The parent of `child2` is not derived, so backfilling it fails
+    // without affecting the other repo.
+    let results = derive_across_repos::<DerivedGeneration>(
+        &ctx,
+        vec![
+            (repo1.clone(), vec![root1, child1]),
+            (repo2.clone(), vec![child2]),
+        ],
+        BatchDeriveOptions::Serial,
+        2,
+    )
+    .await;
+    assert_eq!(results.len(), 2);
+    assert!(results[0].is_ok());
+    assert!(results[1].is_err());
+
+    let derived = repo1
+        .repo_derived_data()
+        .fetch_derived::<DerivedGeneration>(&ctx, child1)
+        .await?
+        .expect("changeset should be derived");
+    assert_eq!(derived.generation, 2);
+    assert!(
+        repo2
+            .repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, child2)
+            .await?
+            .is_none()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_with_shallow_boundary(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let mut csids = Vec::new();
+    let mut parents = Vec::new();
+    for i in 0..5 {
+        let csid = CreateCommitContext::new(&ctx, &repo, parents)
+            .add_file("file", format!("content{}", i).as_str())
+            .commit()
+            .await?;
+        csids.push(csid);
+        parents = vec![csid];
+    }
+    let manager = repo.repo_derived_data().manager();
+    let boundary = hashset! { csids[2] };
+
+    // The boundary is treated as derived, so its ancestors are left alone
+    // and deriving fails as its value isn't available.
+    assert!(
+        manager
+            .derive_with_shallow_boundary::<DerivedGeneration>(&ctx, csids[4], &boundary, None)
+            .await
+            .is_err()
+    );
+    for csid in &csids[..3] {
+        assert!(
+            manager
+                .fetch_derived::<DerivedGeneration>(&ctx, *csid, None)
+                .await?
+                .is_none()
+        );
+    }
+
+    // Once the boundary value is available, only the local portion is
+    // derived on top of it.
+    DerivedGeneration { generation: 100 }
+        .store_mapping(&ctx, &manager.derivation_context(None), csids[2])
+        .await?;
+    let derived = manager
+        .derive_with_shallow_boundary::<DerivedGeneration>(&ctx, csids[4], &boundary, None)
+        .await?;
+    assert_eq!(derived.generation, 102);
+    assert!(
+        manager
+            .fetch_derived::<DerivedGeneration>(&ctx, csids[0], None)
+            .await?
+            .is_none()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_descendants(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    // root - a - b - c
+    //         \
+    //          d
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let a = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "a")
+        .commit()
+        .await?;
+    let b = CreateCommitContext::new(&ctx, &repo, vec![a])
+        .add_file("file", "b")
+        .commit()
+        .await?;
+    let c = CreateCommitContext::new(&ctx, &repo, vec![b])
+        .add_file("file", "c")
+        .commit()
+        .await?;
+    let d = CreateCommitContext::new(&ctx, &repo, vec![a])
+        .add_file("file", "d")
+        .commit()
+        .await?;
+
+    repo.repo_derived_data()
+        .derive::<DerivedGeneration>(&ctx, a)
+        .await?;
+
+    // Only the descendants of `b` are derived, not its sibling `d`.
+    let count = derive_descendants::<DerivedGeneration>(&ctx, &repo, b, vec![c, d]).await?;
+    assert_eq!(count, 2);
+    for (csid, derived) in [(b, true), (c, true), (d, false)] {
+        assert_eq!(
+            repo.repo_derived_data()
+                .fetch_derived::<DerivedGeneration>(&ctx, csid)
+                .await?
+                .is_some(),
+            derived
+        );
+    }
+
+    // Descendants of a derived changeset are found too.
+    let count = derive_descendants::<DerivedGeneration>(&ctx, &repo, a, vec![c, d]).await?;
+    assert_eq!(count, 1);
+    assert!(
+        repo.repo_derived_data()
+            .fetch_derived::<DerivedGeneration>(&ctx, d)
+            .await?
+            .is_some()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_find_underived_with_budget(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let manager = repo.repo_derived_data().manager();
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let a = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "a")
+        .commit()
+        .await?;
+    let b = CreateCommitContext::new(&ctx, &repo, vec![a])
+        .add_file("file", "b")
+        .commit()
+        .await?;
+
+    let (underived, truncated) = manager
+        .find_underived_with_budget::<DerivedGeneration>(&ctx, b, None, None)
+        .await?;
+    assert_eq!(underived, vec![root, a, b]);
+    assert!(!truncated);
+
+    let (underived, truncated) = manager
+        .find_underived_with_budget::<DerivedGeneration>(&ctx, b, Some(Duration::ZERO), None)
+        .await?;
+    assert!(underived.is_empty());
+    assert!(truncated);
+
+    manager.derive::<DerivedGeneration>(&ctx, a, None).await?;
+    let (underived, truncated) = manager
+        .find_underived_with_budget::<DerivedGeneration>(
+            &ctx,
+            b,
+            Some(Duration::from_secs(60)),
+            None,
+        )
+        .await?;
+    assert_eq!(underived, vec![b]);
+    assert!(!truncated);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_with_deadline(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let manager = repo.repo_derived_data().manager();
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let a = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "a")
+        .commit()
+        .await?;
+
+    // Nothing can be derived before a deadline that has already passed.
+    match manager
+        .derive_with_deadline::<DerivedGeneration>(&ctx, a, Instant::now(), None)
+        .await
+    {
+        Err(DerivationError::WouldBlock(name, csid, underived)) => {
+            assert_eq!(name, DerivedGeneration::NAME);
+            assert_eq!(csid, a);
+            assert!(underived > 0);
+        }
+        other => panic!("unexpected result: {:?}", other.map(|d| d.generation)),
+    }
+    assert!(
+        manager
+            .fetch_derived::<DerivedGeneration>(&ctx, root, None)
+            .await?
+            .is_none()
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let derived = manager
+        .derive_with_deadline::<DerivedGeneration>(&ctx, a, deadline, None)
+        .await?;
+    assert_eq!(derived.generation, 2);
+
+    // Already derived data is returned whatever the deadline.
+    let derived = manager
+        .derive_with_deadline::<DerivedGeneration>(&ctx, a, Instant::now(), None)
+        .await?;
+    assert_eq!(derived.generation, 2);
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_with_deadline_merge(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let manager = repo.repo_derived_data().manager();
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let mut parents = Vec::new();
+    for i in 0..4 {
+        let parent = CreateCommitContext::new(&ctx, &repo, vec![root])
+            .add_file(MPath::new(format!("file{}", i))?, "parent")
+            .commit()
+            .await?;
+        parents.push(parent);
+    }
+    let merge = CreateCommitContext::new(&ctx, &repo, parents)
+        .add_file("file", "merge")
+        .commit()
+        .await?;
+
+    // The deadline leaves time for at most two changesets.  The merge alone
+    // has more parents than that, so the search for underived changesets
+    // stops before reaching them, and must not be taken as complete.
+    let deadline = Instant::now() + Duration::from_millis(120);
+    match manager
+        .derive_with_deadline::<DerivedGeneration>(&ctx, merge, deadline, None)
+        .await
+    {
+        Err(DerivationError::WouldBlock(name, csid, _underived)) => {
+            assert_eq!(name, DerivedGeneration::NAME);
+            assert_eq!(csid, merge);
+        }
+        other => panic!("unexpected result: {:?}", other.map(|d| d.generation)),
+    }
+    assert!(
+        manager
+            .fetch_derived::<DerivedGeneration>(&ctx, merge, None)
+            .await?
+            .is_none()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_find_underived_since(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let manager = repo.repo_derived_data().manager();
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let a = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "a")
+        .commit()
+        .await?;
+    let b = CreateCommitContext::new(&ctx, &repo, vec![a])
+        .add_file("file", "b")
+        .commit()
+        .await?;
+
+    let underived = manager
+        .find_underived::<DerivedGeneration>(&ctx, b, None, None)
+        .await?;
+    assert_eq!(underived.len(), 3);
+
+    // The traversal stops at known derived changesets, even though their
+    // mappings are missing.
+    let underived = manager
+        .find_underived_since::<DerivedGeneration>(&ctx, b, None, &hashset! { a }, None)
+        .await?;
+    assert_eq!(underived, hashmap! { b => vec![] });
+
+    let underived = manager
+        .find_underived_since::<DerivedGeneration>(&ctx, b, None, &hashset! { root }, None)
+        .await?;
+    assert_eq!(underived, hashmap! { a => vec![], b => vec![a] });
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derivation_coverage(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let manager = repo.repo_derived_data().manager();
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let a = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "a")
+        .commit()
+        .await?;
+    let b = CreateCommitContext::new(&ctx, &repo, vec![a])
+        .add_file("file", "b")
+        .commit()
+        .await?;
+
+    assert_eq!(
+        manager
+            .derivation_coverage::<DerivedGeneration>(&ctx, vec![root, a, b], None)
+            .await?,
+        (0, 3)
+    );
+
+    manager.derive::<DerivedGeneration>(&ctx, a, None).await?;
+    // Duplicates are only counted once, and nothing is derived.
+    assert_eq!(
+        manager
+            .derivation_coverage::<DerivedGeneration>(&ctx, vec![root, a, b, b], None)
+            .await?,
+        (2, 3)
+    );
+    assert!(
+        manager
+            .fetch_derived::<DerivedGeneration>(&ctx, b, None)
+            .await?
+            .is_none()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_with_parents(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let manager = repo.repo_derived_data().manager();
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let a = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "a")
+        .commit()
+        .await?;
+    let bonsai = a.load(&ctx, repo.repo_blobstore()).await?;
+
+    // The number of parent values must match the parents of the changeset.
+    assert!(
+        manager
+            .derive_with_parents::<DerivedGeneration>(&ctx, bonsai.clone(), vec![], true, None)
+            .await
+            .is_err()
+    );
+    let parents = vec![
+        DerivedGeneration { generation: 10 },
+        DerivedGeneration { generation: 20 },
+    ];
+    assert!(
+        manager
+            .derive_with_parents::<DerivedGeneration>(&ctx, bonsai.clone(), parents, true, None)
+            .await
+            .is_err()
+    );
+    assert!(
+        manager
+            .fetch_derived::<DerivedGeneration>(&ctx, a, None)
+            .await?
+            .is_none()
+    );
+
+    // The supplied parent values are used instead of deriving the parents.
+    let parents = vec![DerivedGeneration { generation: 10 }];
+    let derived = manager
+        .derive_with_parents::<DerivedGeneration>(&ctx, bonsai.clone(), parents, false, None)
+        .await?;
+    assert_eq!(derived.generation, 11);
+    assert!(
+        manager
+            .fetch_derived::<DerivedGeneration>(&ctx, a, None)
+            .await?
+            .is_none()
+    );
+
+    let parents = vec![DerivedGeneration { generation: 10 }];
+    manager
+        .derive_with_parents::<DerivedGeneration>(&ctx, bonsai, parents, true, None)
+        .await?;
+    assert_eq!(
+        manager
+            .fetch_derived::<DerivedGeneration>(&ctx, a, None)
+            .await?,
+        Some(DerivedGeneration { generation: 11 })
+    );
+    assert!(
+        manager
+            .fetch_derived::<DerivedGeneration>(&ctx, root, None)
+            .await?
+            .is_none()
+    );
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_fetch_derived_stream(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let manager = repo.repo_derived_data().manager();
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let a = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "a")
+        .commit()
+        .await?;
+    let b = CreateCommitContext::new(&ctx, &repo, vec![a])
+        .add_file("file", "b")
+        .commit()
+        .await?;
+    manager.derive::<DerivedGeneration>(&ctx, a, None).await?;
+
+    // Underived changesets are omitted, and nothing is derived.
+    let derived = manager
+        .fetch_derived_stream::<DerivedGeneration>(&ctx, vec![root, a, b], None)
+        .try_collect::<HashMap<_, _>>()
+        .await?;
+    assert_eq!(
+        derived,
+        hashmap! {
+            root => DerivedGeneration { generation: 1 },
+            a => DerivedGeneration { generation: 2 },
+        }
+    );
+    assert!(
+        manager
+            .fetch_derived::<DerivedGeneration>(&ctx, b, None)
+            .await?
+            .is_none()
+    );
+
+    let derived = manager
+        .fetch_derived_stream::<DerivedGeneration>(&ctx, vec![], None)
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert!(derived.is_empty());
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_find_orphaned_mappings(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let manager = repo.repo_derived_data().manager();
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let a = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "a")
+        .commit()
+        .await?;
+    let b = CreateCommitContext::new(&ctx, &repo, vec![a])
+        .add_file("file", "b")
+        .commit()
+        .await?;
+    manager.derive::<DerivedGeneration>(&ctx, a, None).await?;
+
+    // `a` has a mapping but isn't known to be present, while `b` has no
+    // mapping to be orphaned.
+    let orphans = manager
+        .find_orphaned_mappings::<DerivedGeneration>(&ctx, vec![root, a, b], &hashset! { root })
+        .await?;
+    assert_eq!(orphans, vec![a]);
+
+    let orphans = manager
+        .find_orphaned_mappings::<DerivedGeneration>(
+            &ctx,
+            vec![root, a, b],
+            &hashset! { root, a, b },
+        )
+        .await?;
+    assert!(orphans.is_empty());
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_derive_single_incremental(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    // root - a - merge
+    //  \         /
+    //   b ------
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .add_extra("test-derive-record-incremental", "1")
+        .commit()
+        .await?;
+    let a = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "a")
+        .add_extra("test-derive-record-incremental", "1")
+        .commit()
+        .await?;
+    let b = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("other", "b")
+        .add_extra("test-derive-record-incremental", "1")
+        .commit()
+        .await?;
+    let merge = CreateCommitContext::new(&ctx, &repo, vec![a, b])
+        .add_extra("test-derive-record-incremental", "1")
+        .commit()
+        .await?;
+
+    let derived = repo
+        .repo_derived_data()
+        .derive::<DerivedGeneration>(&ctx, merge)
+        .await?;
+    assert_eq!(derived.generation, 3);
+
+    // Only changesets with a single parent are derived incrementally.
+    for (csid, incremental) in [(root, false), (a, true), (b, true), (merge, false)] {
+        let key = format!(
+            "repo{}.test_generation_incremental.{}",
+            repo.get_repoid().id(),
+            csid
+        );
+        assert_eq!(
+            repo.repo_blobstore().get(&ctx, &key).await?.is_some(),
+            incremental
+        );
+    }
+
+    Ok(())
+}
+
+/// Changesets that behave as if `hidden` was missing from the repo.
+struct HidingChangesets {
+    inner: Arc<dyn Changesets>,
+    hidden: ChangesetId,
+}
+
+#[async_trait]
+impl Changesets for HidingChangesets {
+    fn repo_id(&self) -> RepositoryId {
+        self.inner.repo_id()
+    }
+
+    async fn add(&self, ctx: CoreContext, cs: ChangesetInsert) -> Result<bool, Error> {
+        self.inner.add(ctx, cs).await
+    }
+
+    async fn get(
+        &self,
+        ctx: CoreContext,
+        cs_id: ChangesetId,
+    ) -> Result<Option<ChangesetEntry>, Error> {
+        if cs_id == self.hidden {
+            return Ok(None);
+        }
+        self.inner.get(ctx, cs_id).await
+    }
+
+    async fn get_many(
+        &self,
+        ctx: CoreContext,
+        cs_ids: Vec<ChangesetId>,
+    ) -> Result<Vec<ChangesetEntry>, Error> {
+        let cs_ids = cs_ids
+            .into_iter()
+            .filter(|cs_id| *cs_id != self.hidden)
+            .collect();
+        self.inner.get_many(ctx, cs_ids).await
+    }
+
+    async fn get_many_by_prefix(
+        &self,
+        ctx: CoreContext,
+        cs_prefix: ChangesetIdPrefix,
+        limit: usize,
+    ) -> Result<ChangesetIdsResolvedFromPrefix, Error> {
+        self.inner.get_many_by_prefix(ctx, cs_prefix, limit).await
+    }
+
+    fn prime_cache(&self, ctx: &CoreContext, changesets: &[ChangesetEntry]) {
+        self.inner.prime_cache(ctx, changesets)
+    }
+
+    async fn enumeration_bounds(
+        &self,
+        ctx: &CoreContext,
+        read_from_master: bool,
+        known_heads: Vec<ChangesetId>,
+    ) -> Result<Option<(u64, u64)>> {
+        self.inner
+            .enumeration_bounds(ctx, read_from_master, known_heads)
+            .await
+    }
+
+    fn list_enumeration_range(
+        &self,
+        ctx: &CoreContext,
+        min_id: u64,
+        max_id: u64,
+        sort_and_limit: Option<(SortOrder, u64)>,
+        read_from_master: bool,
+    ) -> BoxStream<'_, Result<(ChangesetId, u64), Error>> {
+        self.inner
+            .list_enumeration_range(ctx, min_id, max_id, sort_and_limit, read_from_master)
+    }
+}
+
+#[fbinit::test]
+async fn test_missing_parent_changeset(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let a = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "a")
+        .commit()
+        .await?;
+    let b = CreateCommitContext::new(&ctx, &repo, vec![a])
+        .add_file("file", "b")
+        .commit()
+        .await?;
+
+    // `a` is missing from the repo, as if it was only partially synced.
+    let changesets = Arc::new(HidingChangesets {
+        inner: repo.changesets_arc(),
+        hidden: a,
+    });
+    let derived_data = repo
+        .repo_derived_data()
+        .with_replaced_changesets(changesets);
+
+    match derived_data.derive::<DerivedGeneration>(&ctx, b).await {
+        Err(DerivationError::MissingParentChangeset { csid, parent }) => {
+            assert_eq!(csid, b);
+            assert_eq!(parent, a);
+        }
+        other => panic!("unexpected result: {:?}", other.map(|d| d.generation)),
+    }
+
+    let err = derived_data
+        .manager()
+        .find_underived::<DerivedGeneration>(&ctx, b, None, None)
+        .await
+        .expect_err("find_underived should fail");
+    assert!(matches!(
+        err.downcast_ref::<DerivationError>(),
+        Some(DerivationError::MissingParentChangeset { csid, parent })
+            if *csid == b && *parent == a
+    ));
+
+    // A missing target isn't reported as a missing parent.
+    let err = derived_data
+        .derive::<DerivedGeneration>(&ctx, a)
+        .await
+        .expect_err("deriving a missing changeset should fail");
+    assert!(!matches!(
+        err,
+        DerivationError::MissingParentChangeset { .. }
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_derivation_kill_switch() {
+    // Switches are process-wide, so this doesn't use a type derived by the
+    // other tests running concurrently.
+    let switch = DerivationKillSwitch::get("test_kill_switch");
+    assert!(!switch.is_killed());
+
+    DerivationKillSwitch::get("test_kill_switch").kill();
+    assert!(switch.is_killed());
+    assert!(!DerivationKillSwitch::get("test_other_kill_switch").is_killed());
+
+    switch.revive();
+    assert!(!DerivationKillSwitch::get("test_kill_switch").is_killed());
+}
+
+#[fbinit::test]
+async fn test_derive_killed(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let repo: BlobRepo = make_test_repo_factory(fb).build()?;
+    let manager = repo.repo_derived_data().manager();
+
+    let root = CreateCommitContext::new_root(&ctx, &repo)
+        .add_file("file", "root")
+        .commit()
+        .await?;
+    let a = CreateCommitContext::new(&ctx, &repo, vec![root])
+        .add_file("file", "a")
+        .commit()
+        .await?;
+    let b = CreateCommitContext::new(&ctx, &repo, vec![a])
+        .add_file("file", "b")
+        .commit()
+        .await?;
+    manager
+        .derive::<RootSkeletonManifestId>(&ctx, root, None)
+        .await?;
+
+    // Switches are process-wide, so this kills a type that the other tests
+    // don't derive.
+    let switch = DerivationKillSwitch::get(RootSkeletonManifestId::NAME);
+    switch.kill();
+    let derived = manager
+        .derive::<RootSkeletonManifestId>(&ctx, root, None)
+        .await;
+    let killed = manager
+        .derive::<RootSkeletonManifestId>(&ctx, a, None)
+        .await;
+    let killed_batch = manager
+        .backfill_batch::<RootSkeletonManifestId>(
+            &ctx,
+            vec![a, b],
+            BatchDeriveOptions::Serial,
+            None,
+        )
+        .await;
+    switch.revive();
+
+    // Already derived data is still returned.
+    derived?;
+    assert!(matches!(
+        killed,
+        Err(DerivationError::Killed(name)) if name == RootSkeletonManifestId::NAME
+    ));
+    assert!(matches!(
+        killed_batch,
+        Err(DerivationError::Killed(name)) if name == RootSkeletonManifestId::NAME
+    ));
+    assert!(
+        manager
+            .fetch_derived::<RootSkeletonManifestId>(&ctx, a, None)
+            .await?
+            .is_none()
+    );
+
+    manager
+        .derive::<RootSkeletonManifestId>(&ctx, b, None)
+        .await?;
+
+    Ok(())
+}
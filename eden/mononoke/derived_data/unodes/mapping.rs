@@ -9,15 +9,16 @@ use crate::derive::{derive_unode_manifest, derive_unode_manifest_stack};
 use anyhow::{anyhow, Context, Error, Result};
 use async_trait::async_trait;
 use blobstore::{Blobstore, BlobstoreGetData, Loadable};
-use bytes::Bytes;
 use context::CoreContext;
 use derived_data::batch::{split_bonsais_in_linear_stacks, FileConflicts};
-use derived_data::impl_bonsai_derived_via_manager;
+use derived_data::root_id::{self, deserialize_root_id, serialize_root_id};
+use derived_data::{impl_bonsai_derived_via_manager, BlobstoreRootIdMapping};
 use derived_data_manager::{dependencies, BonsaiDerivable, DerivationContext};
 use futures::{future::try_join_all, TryFutureExt};
 use metaconfig_types::UnodeVersion;
 use mononoke_types::{
-    BlobstoreBytes, BonsaiChangeset, ChangesetId, ContentId, FileType, MPath, ManifestUnodeId,
+    BlobstoreBytes, BonsaiChangeset, ChangesetId, ContentId, DateTime, FileType, MPath,
+    ManifestUnodeId,
 };
 use slog::debug;
 use stats::prelude::*;
@@ -43,7 +44,7 @@ impl TryFrom<BlobstoreBytes> for RootUnodeManifestId {
     type Error = Error;
 
     fn try_from(blob_bytes: BlobstoreBytes) -> Result<Self> {
-        ManifestUnodeId::from_bytes(&blob_bytes.into_bytes()).map(RootUnodeManifestId)
+        deserialize_root_id(blob_bytes.as_bytes()).map(RootUnodeManifestId)
     }
 }
 
@@ -57,7 +58,7 @@ impl TryFrom<BlobstoreGetData> for RootUnodeManifestId {
 
 impl From<RootUnodeManifestId> for BlobstoreBytes {
     fn from(root_mf_id: RootUnodeManifestId) -> Self {
-        BlobstoreBytes::from_bytes(Bytes::copy_from_slice(root_mf_id.0.blake2().as_ref()))
+        serialize_root_id(&root_mf_id.0, RootUnodeManifestId::ROOT_ID_FORMAT)
     }
 }
 
@@ -177,7 +178,14 @@ impl BonsaiDerivable for RootUnodeManifestId {
         changeset_id: ChangesetId,
     ) -> Result<()> {
         let key = format_key(derivation_ctx, changeset_id);
-        derivation_ctx.blobstore().put(ctx, key, self.into()).await
+        root_id::store_with_write_time(
+            ctx,
+            derivation_ctx.blobstore().as_ref(),
+            key,
+            &self.0,
+            Self::ROOT_ID_FORMAT,
+        )
+        .await
     }
 
     async fn fetch(
@@ -192,6 +200,25 @@ impl BonsaiDerivable for RootUnodeManifestId {
         }
     }
 
+    async fn fetch_with_write_time(
+        ctx: &CoreContext,
+        derivation_ctx: &DerivationContext,
+        changeset_id: ChangesetId,
+    ) -> Result<Option<(Self, Option<i64>)>> {
+        let key = format_key(derivation_ctx, changeset_id);
+        root_id::fetch_with_write_time(ctx, derivation_ctx.blobstore().as_ref(), &key).await
+    }
+
+    async fn touch(
+        ctx: &CoreContext,
+        derivation_ctx: &DerivationContext,
+        changeset_id: ChangesetId,
+    ) -> Result<bool> {
+        let key = format_key(derivation_ctx, changeset_id);
+        let now = DateTime::now().timestamp_secs();
+        root_id::touch(ctx, derivation_ctx.blobstore().as_ref(), &key, now).await
+    }
+
     fn from_thrift(data: thrift::DerivedData) -> Result<Self> {
         if let thrift::DerivedData::unode(thrift::DerivedDataUnode::root_unode_manifest_id(id)) =
             data
@@ -217,6 +244,14 @@ impl BonsaiDerivable for RootUnodeManifestId {
 // For existing users of BonsaiDerived.
 impl_bonsai_derived_via_manager!(RootUnodeManifestId);
 
+impl BlobstoreRootIdMapping for RootUnodeManifestId {
+    type RootId = ManifestUnodeId;
+
+    fn root_id(&self) -> &Self::RootId {
+        &self.0
+    }
+}
+
 pub(crate) fn get_file_changes(
     bcs: &BonsaiChangeset,
 ) -> Vec<(MPath, Option<(ContentId, FileType)>)> {
@@ -234,12 +269,12 @@ pub(crate) fn get_file_changes(
 mod test {
     use super::*;
     use blobrepo::BlobRepo;
-    use blobstore::Loadable;
+    use blobstore::{Loadable, PutBehaviour};
     use bookmarks::BookmarkName;
     use borrowed::borrowed;
     use cloned::cloned;
-    use derived_data::BonsaiDerived;
-    use derived_data_manager::BatchDeriveOptions;
+    use derived_data::{derived_types_present, erase, with_derived, BonsaiDerived};
+    use derived_data_manager::{BatchDeriveOptions, Rederivation};
     use derived_data_test_utils::iterate_all_manifest_entries;
     use fbinit::FacebookInit;
     use fixtures::TestRepoFixture;
@@ -249,11 +284,17 @@ mod test {
     };
     use futures::{compat::Stream01CompatExt, Future, Stream, TryStreamExt};
     use manifest::Entry;
-    use mercurial_derived_data::DeriveHgChangeset;
+    use maplit::hashset;
+    use memblob::Memblob;
+    use mercurial_derived_data::{DeriveHgChangeset, MappedHgChangesetId};
     use mercurial_types::{HgChangesetId, HgManifestId};
     use mononoke_types::ChangesetId;
     use repo_derived_data::RepoDerivedDataRef;
     use revset::AncestorsNodeStream;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use test_repo_factory::TestRepoFactory;
     use tests_utils::CreateCommitContext;
 
     async fn fetch_manifest_by_cs_id(
@@ -367,6 +408,242 @@ mod test {
         }
     }
 
+    #[fbinit::test]
+    async fn test_derive_root_id(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo: BlobRepo = test_repo_factory::build_empty(fb)?;
+        let bcs_id = CreateCommitContext::new_root(&ctx, &repo)
+            .add_file("file", "content")
+            .commit()
+            .await?;
+
+        let root_id = RootUnodeManifestId::derive_root_id(&ctx, &repo, bcs_id).await?;
+        let derived = RootUnodeManifestId::derive(&ctx, &repo, bcs_id).await?;
+        assert_eq!(&root_id, derived.manifest_unode_id());
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_with_derived(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo: BlobRepo = test_repo_factory::build_empty(fb)?;
+        let bcs_id = CreateCommitContext::new_root(&ctx, &repo)
+            .add_file("file", "content")
+            .commit()
+            .await?;
+
+        let root_id = with_derived(&ctx, &repo, bcs_id, |root: &RootUnodeManifestId| {
+            Ok::<_, Error>(root.manifest_unode_id().clone())
+        })
+        .await?;
+        assert!(RootUnodeManifestId::is_derived(&ctx, &repo, &bcs_id).await?);
+        assert_eq!(
+            root_id,
+            RootUnodeManifestId::derive_root_id(&ctx, &repo, bcs_id).await?
+        );
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_derived_types_present(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo: BlobRepo = test_repo_factory::build_empty(fb)?;
+        let bcs_id = CreateCommitContext::new_root(&ctx, &repo)
+            .add_file("file", "content")
+            .commit()
+            .await?;
+        let derivables = vec![
+            erase::<RootUnodeManifestId>(),
+            erase::<MappedHgChangesetId>(),
+        ];
+
+        assert!(
+            derived_types_present(&ctx, &repo, bcs_id, &derivables)
+                .await?
+                .is_empty()
+        );
+
+        RootUnodeManifestId::derive(&ctx, &repo, bcs_id).await?;
+        assert_eq!(
+            derived_types_present(&ctx, &repo, bcs_id, &derivables).await?,
+            hashset! {RootUnodeManifestId::DERIVABLE_NAME}
+        );
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_touch_derived(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        // Touching overwrites the mapping entry.
+        let repo: BlobRepo = TestRepoFactory::new(fb)?
+            .with_blobstore(Arc::new(Memblob::new(PutBehaviour::Overwrite)))
+            .build()?;
+        let bcs_id = CreateCommitContext::new_root(&ctx, &repo)
+            .add_file("file", "content")
+            .commit()
+            .await?;
+        let manager = repo.repo_derived_data().manager();
+        let max_staleness = Some(Duration::from_secs(3600));
+
+        assert!(
+            !manager
+                .touch_derived::<RootUnodeManifestId>(&ctx, bcs_id)
+                .await?
+        );
+
+        let root = RootUnodeManifestId::derive(&ctx, &repo, bcs_id).await?;
+        let derivation_ctx = manager.derivation_context(None);
+        let key = format_key(&derivation_ctx, bcs_id);
+        let two_hours_ago = DateTime::now().timestamp_secs() - 7200;
+        assert!(
+            root_id::touch(
+                &ctx,
+                derivation_ctx.blobstore().as_ref(),
+                &key,
+                two_hours_ago
+            )
+            .await?
+        );
+        assert_eq!(
+            manager
+                .fetch_derived_with_max_staleness::<RootUnodeManifestId>(
+                    &ctx,
+                    bcs_id,
+                    max_staleness,
+                    None
+                )
+                .await?,
+            None
+        );
+
+        // Touching it now makes it fresh again, and leaves the value as is.
+        assert!(
+            manager
+                .touch_derived::<RootUnodeManifestId>(&ctx, bcs_id)
+                .await?
+        );
+        assert_eq!(
+            manager
+                .fetch_derived_with_max_staleness::<RootUnodeManifestId>(
+                    &ctx,
+                    bcs_id,
+                    max_staleness,
+                    None
+                )
+                .await?,
+            Some(root)
+        );
+        Ok(())
+    }
+
+    /// Rederives each of a set of changesets once.
+    struct RederiveOnce(Mutex<HashSet<ChangesetId>>);
+
+    impl Rederivation for RederiveOnce {
+        fn needs_rederive(&self, _derivable_name: &str, csid: ChangesetId) -> Option<bool> {
+            Some(self.0.lock().unwrap().contains(&csid))
+        }
+
+        fn mark_derived(&self, _derivable_name: &str, csid: ChangesetId) {
+            self.0.lock().unwrap().remove(&csid);
+        }
+    }
+
+    #[fbinit::test]
+    async fn test_fetch_derived_with_max_staleness(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        // Re-derived values have to overwrite the existing ones.
+        let repo: BlobRepo = TestRepoFactory::new(fb)?
+            .with_blobstore(Arc::new(Memblob::new(PutBehaviour::Overwrite)))
+            .build()?;
+        let bcs_id = CreateCommitContext::new_root(&ctx, &repo)
+            .add_file("file", "content")
+            .commit()
+            .await?;
+        let manager = repo.repo_derived_data().manager();
+        let max_staleness = Some(Duration::from_secs(3600));
+
+        assert_eq!(
+            manager
+                .fetch_derived_with_max_staleness::<RootUnodeManifestId>(
+                    &ctx,
+                    bcs_id,
+                    max_staleness,
+                    None
+                )
+                .await?,
+            None
+        );
+
+        let root = RootUnodeManifestId::derive(&ctx, &repo, bcs_id).await?;
+        assert_eq!(
+            manager
+                .fetch_derived_with_max_staleness::<RootUnodeManifestId>(
+                    &ctx,
+                    bcs_id,
+                    max_staleness,
+                    None
+                )
+                .await?,
+            Some(root)
+        );
+
+        // Once written two hours ago, the value is stale.
+        let derivation_ctx = manager.derivation_context(None);
+        let two_hours_ago = DateTime::now().timestamp_secs() - 7200;
+        derivation_ctx
+            .blobstore()
+            .put(
+                &ctx,
+                format_key(&derivation_ctx, bcs_id),
+                root_id::serialize_root_id_with_write_time(
+                    &root.0,
+                    RootUnodeManifestId::ROOT_ID_FORMAT,
+                    two_hours_ago,
+                ),
+            )
+            .await?;
+        assert_eq!(
+            manager
+                .fetch_derived_with_max_staleness::<RootUnodeManifestId>(
+                    &ctx,
+                    bcs_id,
+                    max_staleness,
+                    None
+                )
+                .await?,
+            None
+        );
+        // It is still returned without a bound.
+        assert_eq!(
+            manager
+                .fetch_derived::<RootUnodeManifestId>(&ctx, bcs_id, None)
+                .await?,
+            Some(root)
+        );
+
+        // Deriving it again makes it fresh.
+        let rederivation = Arc::new(RederiveOnce(Mutex::new(hashset! {bcs_id})));
+        assert_eq!(
+            manager
+                .derive::<RootUnodeManifestId>(&ctx, bcs_id, Some(rederivation))
+                .await?,
+            root
+        );
+        assert_eq!(
+            manager
+                .fetch_derived_with_max_staleness::<RootUnodeManifestId>(
+                    &ctx,
+                    bcs_id,
+                    max_staleness,
+                    None
+                )
+                .await?,
+            Some(root)
+        );
+        Ok(())
+    }
+
     #[fbinit::test]
     async fn test_unode_derivation_on_multiple_repos(fb: FacebookInit) {
         verify_repo(fb, || Linear::getrepo(fb)).await;
@@ -68,6 +68,25 @@ pub const POSSIBLE_DERIVED_TYPES: &[&str] = &[
 
 pub const DEFAULT_BACKFILLING_CONFIG_NAME: &str = "backfilling";
 
+/// Names of all the derived data types compiled into this build.
+pub fn all_derived_data_names() -> &'static [&'static str] {
+    POSSIBLE_DERIVED_TYPES
+}
+
+/// Check that `name` is the name of a derived data type compiled into this
+/// build, listing the valid names in the error if it is not.
+pub fn check_derived_data_name(name: &str) -> Result<(), Error> {
+    if all_derived_data_names().contains(&name) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Unknown derived data type: {}, valid types are: {}",
+            name,
+            all_derived_data_names().join(", ")
+        ))
+    }
+}
+
 lazy_static! {
     // TODO: come up with a better way to maintain these dependencies T77090285
     pub static ref DERIVED_DATA_DEPS: HashMap<&'static str, Vec<&'static str>> = {
@@ -366,6 +385,7 @@ pub fn derived_data_utils(
     name: impl AsRef<str>,
 ) -> Result<Arc<dyn DerivedUtils>, Error> {
     let name = name.as_ref();
+    check_derived_data_name(name)?;
     let derived_data_config = repo.get_derived_data_config();
     let types_config = if derived_data_config.is_enabled(name) {
         repo.get_active_derived_data_types_config()
@@ -975,6 +995,16 @@ mod tests {
         (graph, nodes)
     }
 
+    #[test]
+    fn test_check_derived_data_name() {
+        assert!(check_derived_data_name(RootUnodeManifestId::NAME).is_ok());
+        let err = check_derived_data_name("unode").unwrap_err().to_string();
+        assert!(err.contains("unode"));
+        for name in all_derived_data_names() {
+            assert!(err.contains(name));
+        }
+    }
+
     #[fbinit::test]
     async fn test_build_derive_graph(fb: FacebookInit) -> Result<(), Error> {
         let ctx = CoreContext::test_mock(fb);
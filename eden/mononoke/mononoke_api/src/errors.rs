@@ -99,6 +99,12 @@ impl From<DeriveError> for MononokeError {
     fn from(e: DeriveError) -> Self {
         match e {
             e @ DeriveError::Disabled(..) => MononokeError::NotAvailable(e.to_string()),
+            e @ DeriveError::Killed(..) => MononokeError::NotAvailable(e.to_string()),
+            e @ DeriveError::WouldBlock(..) => MononokeError::NotAvailable(e.to_string()),
+            e @ DeriveError::MissingParentChangeset { .. } => {
+                MononokeError::NotAvailable(e.to_string())
+            }
+            e @ DeriveError::ValidationFailed(..) => MononokeError::from(Error::from(e)),
             DeriveError::Error(e) => MononokeError::from(e),
         }
     }
@@ -185,4 +185,34 @@ impl RepoDerivedData {
             .fetch_derived::<Derivable>(ctx, csid, None)
             .await
     }
+
+    /// Count how many of the given changesets have been derived using the
+    /// default manager, returning `(derived, total)`.
+    pub async fn derivation_coverage<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csids: Vec<ChangesetId>,
+    ) -> Result<(usize, usize), DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        self.manager
+            .derivation_coverage::<Derivable>(ctx, csids, None)
+            .await
+    }
+
+    /// Check whether derivation of a changeset is currently in progress
+    /// using the default manager.
+    pub async fn is_derivation_in_progress<Derivable>(
+        &self,
+        ctx: &CoreContext,
+        csid: ChangesetId,
+    ) -> Result<bool, DerivationError>
+    where
+        Derivable: BonsaiDerivable,
+    {
+        self.manager
+            .is_derivation_in_progress::<Derivable>(ctx, csid)
+            .await
+    }
 }
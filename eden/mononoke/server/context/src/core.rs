@@ -47,6 +47,17 @@ impl CoreContext {
         session.new_context(logger, MononokeScubaSampleBuilder::with_discard())
     }
 
+    /// Context for standalone tools and scripts that run outside of a
+    /// server, e.g. to derive data offline.
+    ///
+    /// Nothing is logged and scuba samples are discarded.  Perf counters
+    /// still work, but nothing reports them unless the tool reads them via
+    /// `perf_counters()`.
+    pub fn new_for_offline_tool(fb: FacebookInit) -> Self {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        Self::new_with_logger(fb, logger)
+    }
+
     pub fn test_mock(fb: FacebookInit) -> Self {
         let session = SessionContainer::new_with_defaults(fb);
 
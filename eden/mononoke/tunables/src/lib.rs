@@ -184,6 +184,16 @@ pub struct MononokeTunables {
     // how many commits will be derived at once.
     derived_data_parallel_derivation_buffer: AtomicI64,
 
+    // Limits how many of the parents of a commit have their derived data
+    // fetched at once when deriving it.  Set to 0 to fetch all of them at
+    // once, which may spike the load for merges with many parents.
+    derived_data_max_parent_fetch_concurrency: AtomicI64,
+
+    // Largest serialized size, in bytes, of a derived value that may be
+    // stored.  Derivation of larger values fails instead.  Set to 0 for no
+    // limit.
+    derived_data_max_value_size: AtomicI64,
+
     // Tunables to disable derived data derivation either for the full repo
     // or for specific derived data types inside a repo
     all_derived_data_disabled: TunableBoolByRepo,
@@ -22,8 +22,14 @@ pub enum CommitError {
     #[error("{0} is required for opening commits")]
     OpenRequirements(&'static str),
 
+    #[error("{0} backend is not available in this build")]
+    BackendUnavailable(&'static str),
+
     #[error("unable to read {0}: `{1}")]
     FileReadError(&'static str, std::io::Error),
+
+    #[error("segments are corrupt: {}", .0.join("; "))]
+    CorruptSegments(Vec<String>),
 }
 
 impl From<std::io::Error> for CommitError {
@@ -50,6 +56,7 @@ impl From<zstore::Error> for CommitError {
     }
 }
 
+#[cfg(feature = "git")]
 impl From<gitdag::git2::Error> for CommitError {
     fn from(err: gitdag::git2::Error) -> Self {
         anyhow::Error::from(err).into()
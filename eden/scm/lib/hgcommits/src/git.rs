@@ -41,6 +41,9 @@ use crate::Result;
 use crate::StreamCommitText;
 use crate::StripCommits;
 
+/// Prefix of the git references tracking the visible heads of metalog.
+const VISIBLEHEADS_PREFIX: &str = "refs/visibleheads/";
+
 /// Git Commits with segments index.
 ///
 /// Use segmented changelog for the commit graph algorithms and IdMap.
@@ -60,6 +63,7 @@ pub struct GitSegmentedCommits {
     dag: GitDag,
     dag_path: PathBuf,
     git_path: PathBuf,
+    ref_filter: Option<Vec<String>>,
 }
 
 impl GitSegmentedCommits {
@@ -74,9 +78,37 @@ impl GitSegmentedCommits {
             dag,
             dag_path,
             git_path,
+            ref_filter: None,
         })
     }
 
+    /// Only sync git references matching one of `patterns` with metalog.
+    ///
+    /// A pattern is either a full reference name, or a prefix followed by `*`,
+    /// like `refs/heads/*`. References not matching any pattern are neither
+    /// imported to metalog nor created, updated or deleted when writing
+    /// metalog back to git references. The `refs/visibleheads/*` references,
+    /// which track the draft commits, are always synced.
+    pub fn with_ref_filter(mut self, patterns: Vec<String>) -> Self {
+        self.ref_filter = Some(patterns);
+        self
+    }
+
+    fn is_ref_selected(&self, name: &str) -> bool {
+        if name.starts_with(VISIBLEHEADS_PREFIX) {
+            return true;
+        }
+        match &self.ref_filter {
+            None => true,
+            Some(patterns) => patterns
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => name.starts_with(prefix),
+                    None => name == pattern,
+                }),
+        }
+    }
+
     /// Rewrite metalog bookmarks, remotenames to match git references.
     /// The reverse of `metalog_to_git_references`, used at the start of a transaction.
     pub fn git_references_to_metalog(&self, metalog: &mut MetaLog) -> Result<()> {
@@ -87,6 +119,9 @@ impl GitSegmentedCommits {
         let mut visibleheads = Vec::new();
 
         for (name, vertex) in refs {
+            if !self.is_ref_selected(name) {
+                continue;
+            }
             let names: Vec<&str> = name.splitn(3, '/').collect();
             let id = match HgId::from_slice(vertex.as_ref()) {
                 Ok(id) => id,
@@ -159,7 +194,7 @@ impl GitSegmentedCommits {
             if let Some(encoded) = metalog.get("visibleheads")? {
                 let decoded = refencode::decode_visibleheads(&encoded)?;
                 for hgid in decoded {
-                    let name = format!("refs/visibleheads/{}", hgid.to_hex());
+                    let name = format!("{}{}", VISIBLEHEADS_PREFIX, hgid.to_hex());
                     refs.insert(name, hgid_to_git_oid(hgid));
                 }
             }
@@ -182,7 +217,7 @@ impl GitSegmentedCommits {
                 };
                 handled_ref_names.insert(name.to_string());
                 // Only care about refs/* names. Skip HEAD or FETCH_HEAD.
-                if !name.starts_with("refs/") {
+                if !name.starts_with("refs/") || !self.is_ref_selected(name) {
                     continue;
                 }
                 let expected_oid = expected_refs.get(name);
@@ -198,7 +233,7 @@ impl GitSegmentedCommits {
                 }
             }
             for (name, oid) in expected_refs {
-                if handled_ref_names.contains(name.as_str()) {
+                if handled_ref_names.contains(name.as_str()) || !self.is_ref_selected(&name) {
                     continue;
                 }
                 repo.reference(&name, oid, true, &reflog_message)?;
@@ -440,3 +475,85 @@ fn to_hg_text(commit: &git2::Commit) -> Bytes {
 fn hgid_to_git_oid(id: HgId) -> git2::Oid {
     git2::Oid::from_bytes(id.as_ref()).expect("HgId should convert to git2::Oid")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(repo: &git2::Repository, reference: &str, message: &str) -> git2::Oid {
+        let sig = git2::Signature::new("test", "test@example.com", &git2::Time::new(0, 0)).unwrap();
+        let tree_id = repo.treebuilder(None).unwrap().write().unwrap();
+        let tree = repo.find_tree(tree_id).unwrap();
+        let oid = repo.commit(None, &sig, &sig, message, &tree, &[]).unwrap();
+        repo.reference(reference, oid, true, message).unwrap();
+        oid
+    }
+
+    fn target(repo: &git2::Repository, reference: &str) -> Option<git2::Oid> {
+        repo.find_reference(reference)
+            .ok()
+            .and_then(|reference| reference.target())
+    }
+
+    #[test]
+    fn test_ref_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let git_dir = dir.path().join("git");
+        let repo = git2::Repository::init_bare(&git_dir).unwrap();
+        let main = commit(&repo, "refs/heads/main", "main");
+        let feature = commit(&repo, "refs/remotes/origin/feature", "feature");
+        let draft = commit(&repo, "refs/tags/draft", "draft");
+        let draft_ref = format!("{}{}", VISIBLEHEADS_PREFIX, draft);
+        repo.reference(&draft_ref, draft, true, "draft").unwrap();
+
+        let commits = GitSegmentedCommits::new(&git_dir, &dir.path().join("segments"))
+            .unwrap()
+            .with_ref_filter(vec!["refs/heads/*".to_string()]);
+        let mut metalog = MetaLog::open(dir.path().join("metalog"), None).unwrap();
+        commits.git_references_to_metalog(&mut metalog).unwrap();
+
+        // Only the selected references and the visible heads are imported.
+        let hgid = |oid: git2::Oid| HgId::from_slice(oid.as_bytes()).unwrap();
+        let bookmarks = refencode::decode_bookmarks(&metalog.get("bookmarks").unwrap().unwrap());
+        assert_eq!(
+            bookmarks.unwrap(),
+            [("main".to_string(), hgid(main))]
+                .into_iter()
+                .collect::<BTreeMap<_, _>>()
+        );
+        let remotenames =
+            refencode::decode_remotenames(&metalog.get("remotenames").unwrap().unwrap());
+        assert!(remotenames.unwrap().is_empty());
+        let visibleheads =
+            refencode::decode_visibleheads(&metalog.get("visibleheads").unwrap().unwrap());
+        assert_eq!(visibleheads.unwrap(), vec![hgid(draft)]);
+
+        // Unselected references are neither created, updated nor deleted.
+        let bookmarks = [
+            ("main".to_string(), hgid(feature)),
+            ("new".to_string(), hgid(main)),
+        ]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+        let remotenames = [("origin/other".to_string(), hgid(main))]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        metalog
+            .set("bookmarks", &refencode::encode_bookmarks(&bookmarks))
+            .unwrap();
+        metalog
+            .set("remotenames", &refencode::encode_remotenames(&remotenames))
+            .unwrap();
+        let mut opts = metalog::CommitOptions::default();
+        opts.message = "test";
+        metalog.commit(opts).unwrap();
+        commits.metalog_to_git_references(&metalog).unwrap();
+
+        assert_eq!(target(&repo, "refs/heads/main"), Some(feature));
+        assert_eq!(target(&repo, "refs/heads/new"), Some(main));
+        assert_eq!(target(&repo, "refs/remotes/origin/feature"), Some(feature));
+        assert_eq!(target(&repo, "refs/remotes/origin/other"), None);
+        assert_eq!(target(&repo, "refs/tags/draft"), Some(draft));
+        assert_eq!(target(&repo, &draft_ref), Some(draft));
+    }
+}
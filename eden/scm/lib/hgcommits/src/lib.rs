@@ -161,6 +161,7 @@ impl DagCommits for HybridCommits {}
 impl DagCommits for MemHgCommits {}
 impl DagCommits for RevlogCommits {}
 impl DagCommits for DoubleWriteCommits {}
+#[cfg(feature = "git")]
 impl DagCommits for GitSegmentedCommits {}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -186,6 +187,7 @@ pub struct ParentlessHgCommit {
 
 mod doublewrite;
 pub(crate) mod errors;
+#[cfg(feature = "git")]
 mod git;
 mod hgsha1commits;
 mod hybrid;
@@ -197,6 +199,7 @@ mod utils;
 
 pub use doublewrite::DoubleWriteCommits;
 pub use errors::CommitError as Error;
+#[cfg(feature = "git")]
 pub use git::GitSegmentedCommits;
 pub use hgsha1commits::HgCommits;
 pub use hybrid::HybridCommits;
@@ -132,6 +132,12 @@ impl Drop for StreamEncoderHC {
 /// Decompress into a preallocated buffer. The size of `dest` must
 /// match what [decompress_size] returns.
 pub fn decompress_into(data: &[u8], dest: &mut [u8]) -> Result<()> {
+    decompress_block_into(data.get(HEADER_LEN..).unwrap_or_default(), dest)
+}
+
+/// Decompress a raw lz4 block, without the size header, into a preallocated
+/// buffer. The size of `dest` must match the size of the uncompressed data.
+fn decompress_block_into(data: &[u8], dest: &mut [u8]) -> Result<()> {
     let stream = StreamDecoder(unsafe { LZ4_createStreamDecode() });
     if stream.0.is_null() {
         return Err(LZ4Error::Generic {
@@ -140,7 +146,6 @@ pub fn decompress_into(data: &[u8], dest: &mut [u8]) -> Result<()> {
         .into());
     }
     if !dest.is_empty() {
-        let data = &data[HEADER_LEN..];
         let source = data.as_ptr();
         let read: i32 = check_error(unsafe {
             LZ4_decompress_safe_continue(
@@ -206,6 +211,23 @@ pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
     Ok(dest)
 }
 
+/// Decompress a raw lz4 block produced by [compress_block]. `size` must be
+/// the size of the uncompressed data.
+pub fn decompress_block(data: &[u8], size: usize) -> Result<Vec<u8>> {
+    let mut dest = vec![0; size];
+    decompress_block_into(data, &mut dest)?;
+    Ok(dest)
+}
+
+/// Compress into a raw lz4 block, without the size header used by
+/// [compress]. The caller must record the uncompressed size, as it is needed
+/// by [decompress_block].
+pub fn compress_block(data: &[u8]) -> Result<Vec<u8>> {
+    let mut compressed = compress(data)?;
+    compressed.drain(..HEADER_LEN);
+    Ok(compressed)
+}
+
 pub fn compresshc(data: &[u8]) -> Result<Vec<u8>> {
     let max_compressed_size = (check_error(unsafe { LZ4_compressBound(data.len() as i32) })?
         + HEADER_LEN as i32) as usize;
@@ -298,9 +320,23 @@ mod tests {
         assert_eq!(data, &*decompressed);
     }
 
+    #[test]
+    fn test_block_roundtrip() {
+        let data = &b"\x00\x01\x02hello world long string easy easy easy easy compress\xF0\xFA"[..];
+        let compressed = compress_block(data).unwrap();
+        assert_eq!(compressed[..], compress(data).unwrap()[HEADER_LEN..]);
+        let decompressed = decompress_block(&compressed, data.len()).unwrap();
+        assert_eq!(data, &*decompressed);
+    }
+
     quickcheck! {
         fn test_quickcheck_roundtrip(data: Vec<u8>) -> bool {
             check_roundtrip(&data).1
         }
+
+        fn test_quickcheck_block_roundtrip(data: Vec<u8>) -> bool {
+            let compressed = compress_block(&data).unwrap();
+            decompress_block(&compressed, data.len()).unwrap() == data
+        }
     }
 }
@@ -5,6 +5,7 @@
  * GNU General Public License version 2.
  */
 
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fs;
 use std::path::Path;
@@ -15,12 +16,15 @@ use edenapi::EdenApi;
 use hgcommits::DagCommits;
 use hgcommits::DoubleWriteCommits;
 use hgcommits::Error as CommitError;
+#[cfg(feature = "git")]
 use hgcommits::GitSegmentedCommits;
 use hgcommits::HybridCommits;
 use hgcommits::RevlogCommits;
 use metalog::MetaLog;
 use parking_lot::RwLock;
 
+use crate::errors::StoreValidationError;
+
 static REQUIREMENTS_PATH: &str = "requires";
 
 static HG_COMMITS_PATH: &str = "hgcommits/v1";
@@ -37,26 +41,244 @@ static DOUBLE_WRITE_BACKEND_LOG: &str = "doublewrite";
 static RUST_BACKEND_LOG: &str = "rustrevlog";
 
 static GIT_FILE: &str = "gitdir";
+static REVLOG_INDEX_PATH: &str = "00changelog.i";
+
+/// Commit backends that `open_dag_commits` may open.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CommitBackendKind {
+    Git,
+    Lazy,
+    DoubleWrite,
+    Revlog,
+}
+
+static AVAILABLE_BACKENDS: &[CommitBackendKind] = &[
+    #[cfg(feature = "git")]
+    CommitBackendKind::Git,
+    CommitBackendKind::Lazy,
+    CommitBackendKind::DoubleWrite,
+    CommitBackendKind::Revlog,
+];
+
+/// Observer of the backend selected by `open_dag_commits`, e.g. to count the backends in use
+/// with a metrics system other than `tracing`.
+pub trait BackendObserver {
+    fn on_backend_selected(&self, kind: CommitBackendKind, store_path: &Path);
+}
+
+/// The default `BackendObserver`, which logs the backend with `tracing`.
+pub struct TracingBackendObserver;
+
+impl BackendObserver for TracingBackendObserver {
+    fn on_backend_selected(&self, kind: CommitBackendKind, _store_path: &Path) {
+        log_backend(kind);
+    }
+}
+
+/// Commit backends that were compiled into this build.
+pub fn available_backends() -> &'static [CommitBackendKind] {
+    AVAILABLE_BACKENDS
+}
+
+/// Options of `open_dag_commits`.
+#[derive(Default)]
+pub struct OpenOptions<'a> {
+    /// For git backed stores, restricts which git references are synced with metalog, see
+    /// `GitSegmentedCommits::with_ref_filter`. All references are synced if it is `None`.
+    pub git_ref_filter: Option<Vec<String>>,
+
+    /// Whether to check the segments of the backends built on segmented changelog when they are
+    /// opened, so that a corrupt store fails when opened with `CommitError::CorruptSegments`
+    /// instead of failing some later query. This walks all the segments, so it is off by default.
+    pub validate_segments: bool,
 
+    /// Observer to report the selected backend to before it is opened. It is logged with
+    /// `TracingBackendObserver` if it is `None`.
+    pub observer: Option<&'a dyn BackendObserver>,
+}
+
+/// Open the commits of the store at `store_path`, using the backend listed in its requirements.
 pub fn open_dag_commits(
     store_path: &Path,
     metalog: Arc<RwLock<MetaLog>>,
     eden_api: Arc<dyn EdenApi>,
+    options: OpenOptions,
 ) -> Result<Box<dyn DagCommits + Send + 'static>, CommitError> {
     let store_requirements = get_store_requirements(store_path)
         .map_err(|err| CommitError::FileReadError("requirements file", err))?;
-    if store_requirements.contains(&GIT_STORE_REQUIREMENT.to_string()) {
-        log_backend(GIT_BACKEND_LOG);
-        return open_git(store_path, metalog);
+    let kind = if store_requirements.contains(&GIT_STORE_REQUIREMENT.to_string()) {
+        CommitBackendKind::Git
     } else if store_requirements.contains(&LAZY_STORE_REQUIREMENT.to_string()) {
-        log_backend(LAZY_BACKEND_LOG);
-        return open_hybrid(store_path, eden_api);
+        CommitBackendKind::Lazy
     } else if store_requirements.contains(&DOUBLE_WRITE_REQUIREMENT.to_string()) {
-        log_backend(DOUBLE_WRITE_BACKEND_LOG);
-        return open_double(store_path);
+        CommitBackendKind::DoubleWrite
+    } else {
+        CommitBackendKind::Revlog
+    };
+    options
+        .observer
+        .unwrap_or(&TracingBackendObserver)
+        .on_backend_selected(kind, store_path);
+    let commits = match kind {
+        CommitBackendKind::Git => open_git(store_path, metalog, options.git_ref_filter)?,
+        CommitBackendKind::Lazy => open_hybrid(store_path, eden_api)?,
+        CommitBackendKind::DoubleWrite => open_double(store_path)?,
+        CommitBackendKind::Revlog => return Ok(Box::new(RevlogCommits::new(store_path)?)),
+    };
+    if options.validate_segments {
+        check_segments(commits.as_ref())?;
+    }
+    Ok(commits)
+}
+
+/// Open the commits of the store at `store_path` with the `kind` backend, without reading the
+/// requirements of the store.
+///
+/// This saves parsing the requirements file when all stores are known to use the same backend.
+/// Opening fails if the store doesn't have the data used by `kind`.
+pub fn open_dag_commits_typed(
+    store_path: &Path,
+    metalog: Arc<RwLock<MetaLog>>,
+    eden_api: Arc<dyn EdenApi>,
+    git_ref_filter: Option<Vec<String>>,
+    kind: CommitBackendKind,
+) -> Result<Box<dyn DagCommits + Send + 'static>, CommitError> {
+    match kind {
+        CommitBackendKind::Git => {
+            log_backend(CommitBackendKind::Git);
+            open_git(store_path, metalog, git_ref_filter)
+        }
+        CommitBackendKind::Lazy => {
+            check_paths_exist(&segmented_store_paths(store_path))?;
+            log_backend(CommitBackendKind::Lazy);
+            open_hybrid(store_path, eden_api)
+        }
+        CommitBackendKind::DoubleWrite => {
+            check_paths_exist(&segmented_store_paths(store_path))?;
+            log_backend(CommitBackendKind::DoubleWrite);
+            open_double(store_path)
+        }
+        CommitBackendKind::Revlog => {
+            log_backend(CommitBackendKind::Revlog);
+            Ok(Box::new(RevlogCommits::new(store_path)?))
+        }
+    }
+}
+
+/// Open every commit backend compiled into this build that the store at `store_path` has data
+/// for, so that the answers of different backends can be compared.
+///
+/// Backends whose data is absent are skipped. The lazy backend fetches commits from the server,
+/// so it is only opened if the store requires it.
+pub fn open_all_available_backends(
+    store_path: &Path,
+    metalog: Arc<RwLock<MetaLog>>,
+    eden_api: Arc<dyn EdenApi>,
+    git_ref_filter: Option<Vec<String>>,
+) -> Result<HashMap<CommitBackendKind, Box<dyn DagCommits + Send + 'static>>, CommitError> {
+    let store_requirements = get_store_requirements(store_path)
+        .map_err(|err| CommitError::FileReadError("requirements file", err))?;
+    let has_segments =
+        calculate_segments_path(store_path).exists() && store_path.join(HG_COMMITS_PATH).exists();
+    let has_revlog = store_path.join(REVLOG_INDEX_PATH).exists();
+
+    let mut backends = HashMap::new();
+    for kind in available_backends() {
+        let commits = match kind {
+            CommitBackendKind::Git => match calculate_git_path(store_path) {
+                Ok(git_path) if git_path.exists() => {
+                    open_git(store_path, metalog.clone(), git_ref_filter.clone())?
+                }
+                _ => continue,
+            },
+            CommitBackendKind::Lazy => {
+                if !has_segments
+                    || !store_requirements.contains(&LAZY_STORE_REQUIREMENT.to_string())
+                {
+                    continue;
+                }
+                open_hybrid(store_path, eden_api.clone())?
+            }
+            CommitBackendKind::DoubleWrite => {
+                if !has_segments || !has_revlog {
+                    continue;
+                }
+                open_double(store_path)?
+            }
+            CommitBackendKind::Revlog => {
+                if !has_revlog {
+                    continue;
+                }
+                Box::new(RevlogCommits::new(store_path)?)
+            }
+        };
+        backends.insert(*kind, commits);
+    }
+    Ok(backends)
+}
+
+/// Check that `store_path` looks like a store that `open_dag_commits` can open.
+///
+/// This checks that the store has a requirements file, and that the files and directories used
+/// by the backend it requires exist. The backend itself isn't opened.
+pub fn validate_store(store_path: &Path) -> Result<(), StoreValidationError> {
+    let store_requirements = get_store_requirements(store_path).map_err(|err| {
+        StoreValidationError::RequirementsReadError(store_path.join(REQUIREMENTS_PATH), err)
+    })?;
+    let (requirement, required_paths) =
+        if store_requirements.contains(&GIT_STORE_REQUIREMENT.to_string()) {
+            let git_path = calculate_git_path(store_path).map_err(|_| {
+                StoreValidationError::MissingPath(GIT_STORE_REQUIREMENT, store_path.join(GIT_FILE))
+            })?;
+            (GIT_STORE_REQUIREMENT, vec![git_path])
+        } else if store_requirements.contains(&LAZY_STORE_REQUIREMENT.to_string()) {
+            (LAZY_STORE_REQUIREMENT, segmented_store_paths(store_path))
+        } else if store_requirements.contains(&DOUBLE_WRITE_REQUIREMENT.to_string()) {
+            (DOUBLE_WRITE_REQUIREMENT, segmented_store_paths(store_path))
+        } else {
+            return Ok(());
+        };
+
+    match required_paths.into_iter().find(|path| !path.exists()) {
+        Some(path) => Err(StoreValidationError::MissingPath(requirement, path)),
+        None => Ok(()),
+    }
+}
+
+/// Same as `validate_store`, but only tells whether the store is valid.
+pub fn is_valid_store(store_path: &Path) -> bool {
+    validate_store(store_path).is_ok()
+}
+
+/// Paths used by the backends built on segmented changelog.
+fn segmented_store_paths(store_path: &Path) -> Vec<PathBuf> {
+    vec![
+        calculate_segments_path(store_path),
+        store_path.join(HG_COMMITS_PATH),
+    ]
+}
+
+fn check_paths_exist(paths: &[PathBuf]) -> Result<(), CommitError> {
+    match paths.iter().find(|path| !path.exists()) {
+        Some(path) => Err(CommitError::FileReadError(
+            "commit store",
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check the structure of the segments of `commits`, without looking at the commits themselves.
+fn check_segments(commits: &(dyn DagCommits + Send + 'static)) -> Result<(), CommitError> {
+    let problems = async_runtime::block_on(commits.check_segments())?;
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(CommitError::CorruptSegments(problems))
     }
-    log_backend(RUST_BACKEND_LOG);
-    Ok(Box::new(RevlogCommits::new(store_path)?))
 }
 
 fn get_store_requirements(store_path: &Path) -> Result<HashSet<String>, std::io::Error> {
@@ -64,33 +286,93 @@ fn get_store_requirements(store_path: &Path) -> Result<HashSet<String>, std::io:
     Ok(store_requirements.split('\n').map(String::from).collect())
 }
 
-fn log_backend(backend: &str) {
+fn log_backend(kind: CommitBackendKind) {
+    let backend = match kind {
+        CommitBackendKind::Git => GIT_BACKEND_LOG,
+        CommitBackendKind::Lazy => LAZY_BACKEND_LOG,
+        CommitBackendKind::DoubleWrite => DOUBLE_WRITE_BACKEND_LOG,
+        CommitBackendKind::Revlog => RUST_BACKEND_LOG,
+    };
     tracing::info!(target: "changelog_info", changelog_backend=AsRef::<str>::as_ref(&backend));
 }
 
+#[cfg(feature = "git")]
 fn open_git(
     store_path: &Path,
     metalog: Arc<RwLock<MetaLog>>,
+    ref_filter: Option<Vec<String>>,
 ) -> Result<Box<dyn DagCommits + Send + 'static>, CommitError> {
     let git_path =
         calculate_git_path(store_path).map_err(|err| CommitError::FileReadError("gitdir", err))?;
     let segments_path = calculate_segments_path(store_path);
-    let git_segmented_commits = GitSegmentedCommits::new(&git_path, &segments_path)?;
+    let mut git_segmented_commits = GitSegmentedCommits::new(&git_path, &segments_path)?;
+    if let Some(patterns) = ref_filter {
+        git_segmented_commits = git_segmented_commits.with_ref_filter(patterns);
+    }
     git_segmented_commits.git_references_to_metalog(&mut metalog.write())?;
     Ok(Box::new(git_segmented_commits))
 }
 
+#[cfg(not(feature = "git"))]
+fn open_git(
+    _store_path: &Path,
+    _metalog: Arc<RwLock<MetaLog>>,
+    _ref_filter: Option<Vec<String>>,
+) -> Result<Box<dyn DagCommits + Send + 'static>, CommitError> {
+    Err(CommitError::BackendUnavailable("git"))
+}
+
 fn open_double(store_path: &Path) -> Result<Box<dyn DagCommits + Send + 'static>, CommitError> {
-    let segments_path = calculate_segments_path(store_path);
-    let hg_commits_path = store_path.join(HG_COMMITS_PATH);
-    let double_commits = DoubleWriteCommits::new(
-        store_path,
-        segments_path.as_path(),
-        hg_commits_path.as_path(),
-    )?;
+    let double_commits = DoubleWriteCommitsBuilder::new(store_path).build()?;
     Ok(Box::new(double_commits))
 }
 
+/// Builder of `DoubleWriteCommits`, for stores that don't use the standard layout.
+///
+/// By default, the segments, hgcommits and revlog are at their standard paths in `store_path`.
+pub struct DoubleWriteCommitsBuilder {
+    segments_path: PathBuf,
+    hg_commits_path: PathBuf,
+    revlog_path: PathBuf,
+}
+
+impl DoubleWriteCommitsBuilder {
+    pub fn new(store_path: impl AsRef<Path>) -> Self {
+        let store_path = store_path.as_ref();
+        Self {
+            segments_path: calculate_segments_path(store_path),
+            hg_commits_path: store_path.join(HG_COMMITS_PATH),
+            revlog_path: store_path.to_path_buf(),
+        }
+    }
+
+    /// Directory of the segmented changelog and its IdMap.
+    pub fn segments_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.segments_path = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Directory of the commit texts stored in hg format.
+    pub fn hg_commits_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.hg_commits_path = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Directory containing the `00changelog` revlog.
+    pub fn revlog_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.revlog_path = path.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn build(self) -> Result<DoubleWriteCommits, CommitError> {
+        DoubleWriteCommits::new(
+            self.revlog_path.as_path(),
+            self.segments_path.as_path(),
+            self.hg_commits_path.as_path(),
+        )
+    }
+}
+
 fn open_hybrid(
     store_path: &Path,
     eden_api: Arc<dyn EdenApi>,
@@ -129,3 +411,102 @@ fn get_path_from_file(store_path: &Path, target_file: &str) -> Result<PathBuf, s
     let path_file = store_path.join(target_file);
     fs::read_to_string(path_file).map(PathBuf::from)
 }
+
+#[cfg(test)]
+mod tests {
+    use hgcommits::DescribeBackend;
+
+    use super::*;
+
+    #[test]
+    fn test_validate_store() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store_path = tmp.path();
+        assert!(matches!(
+            validate_store(store_path),
+            Err(StoreValidationError::RequirementsReadError(..))
+        ));
+
+        fs::write(store_path.join(REQUIREMENTS_PATH), "lazychangelog\n").unwrap();
+        match validate_store(store_path) {
+            Err(StoreValidationError::MissingPath(requirement, path)) => {
+                assert_eq!(requirement, LAZY_STORE_REQUIREMENT);
+                assert_eq!(path, calculate_segments_path(store_path));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(!is_valid_store(store_path));
+
+        fs::create_dir_all(calculate_segments_path(store_path)).unwrap();
+        fs::create_dir_all(store_path.join(HG_COMMITS_PATH)).unwrap();
+        assert!(is_valid_store(store_path));
+
+        fs::write(store_path.join(REQUIREMENTS_PATH), "git-store\n").unwrap();
+        assert!(!is_valid_store(store_path));
+        fs::write(store_path.join(GIT_FILE), "git").unwrap();
+        assert!(!is_valid_store(store_path));
+        fs::create_dir(store_path.join("git")).unwrap();
+        assert!(is_valid_store(store_path));
+    }
+    #[test]
+    fn test_double_write_commits_builder() {
+        let tmp = tempfile::tempdir().unwrap();
+        let segments_path = tmp.path().join("custom/segments");
+        let hg_commits_path = tmp.path().join("custom/hgcommits");
+        let revlog_path = tmp.path().join("custom/revlog");
+        fs::create_dir_all(&revlog_path).unwrap();
+
+        let commits = DoubleWriteCommitsBuilder::new(tmp.path().join("store"))
+            .segments_path(&segments_path)
+            .hg_commits_path(&hg_commits_path)
+            .revlog_path(&revlog_path)
+            .build()
+            .unwrap();
+        let description = commits.describe_backend();
+        for path in [&segments_path, &hg_commits_path, &revlog_path] {
+            assert!(description.contains(&path.display().to_string()));
+        }
+        assert!(!tmp.path().join("store").join(SEGMENTS_PATH).exists());
+    }
+
+    #[test]
+    fn test_backend_observer() {
+        struct Recorder(std::sync::Mutex<Vec<(CommitBackendKind, PathBuf)>>);
+
+        impl BackendObserver for Recorder {
+            fn on_backend_selected(&self, kind: CommitBackendKind, store_path: &Path) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((kind, store_path.to_path_buf()));
+            }
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let store_path = tmp.path();
+        fs::write(store_path.join(REQUIREMENTS_PATH), "doublewritechangelog\n").unwrap();
+        let metalog = Arc::new(RwLock::new(
+            MetaLog::open(store_path.join("metalog"), None).unwrap(),
+        ));
+        let eden_api: Arc<dyn EdenApi> =
+            Arc::new(eagerepo::EagerRepo::open(&store_path.join("eager")).unwrap());
+        let recorder = Recorder(Default::default());
+        let options = OpenOptions {
+            observer: Some(&recorder),
+            ..Default::default()
+        };
+        open_dag_commits(store_path, metalog, eden_api, options).unwrap();
+        assert_eq!(
+            recorder.0.into_inner().unwrap(),
+            vec![(CommitBackendKind::DoubleWrite, store_path.to_path_buf())]
+        );
+    }
+
+    #[test]
+    fn test_check_segments() {
+        let tmp = tempfile::tempdir().unwrap();
+        let commits: Box<dyn DagCommits + Send + 'static> =
+            Box::new(DoubleWriteCommitsBuilder::new(tmp.path()).build().unwrap());
+        check_segments(commits.as_ref()).unwrap();
+    }
+}
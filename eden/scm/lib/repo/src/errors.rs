@@ -31,3 +31,12 @@ pub enum InitError {
     #[error("config loading error: `{0}`")]
     ConfigLoadingError(anyhow::Error),
 }
+
+#[derive(Error, Debug)]
+pub enum StoreValidationError {
+    #[error("unable to read requirements of store `{0}`: `{1}`")]
+    RequirementsReadError(PathBuf, std::io::Error),
+
+    #[error("store requires `{0}`, but `{1}` does not exist")]
+    MissingPath(&'static str, PathBuf),
+}
@@ -13,4 +13,14 @@ pub mod errors;
 mod init;
 pub mod repo;
 
+pub use commits::available_backends;
+pub use commits::is_valid_store;
+pub use commits::open_all_available_backends;
 pub use commits::open_dag_commits;
+pub use commits::open_dag_commits_typed;
+pub use commits::validate_store;
+pub use commits::BackendObserver;
+pub use commits::CommitBackendKind;
+pub use commits::DoubleWriteCommitsBuilder;
+pub use commits::OpenOptions;
+pub use commits::TracingBackendObserver;
@@ -31,6 +31,7 @@ use storemodel::TreeStore;
 use util::path::absolute;
 
 use crate::commits::open_dag_commits;
+use crate::commits::OpenOptions;
 use crate::errors;
 use crate::init;
 
@@ -279,7 +280,14 @@ impl Repo {
             None => {
                 let metalog = self.metalog()?;
                 let eden_api = self.eden_api()?;
-                let commits = open_dag_commits(&self.store_path, metalog, eden_api)?;
+                let options = OpenOptions {
+                    git_ref_filter: self.config.get_opt::<Vec<String>>("git", "importrefs")?,
+                    validate_segments: self
+                        .config
+                        .get_or_default("commits", "validate-segments-on-open")?,
+                    ..Default::default()
+                };
+                let commits = open_dag_commits(&self.store_path, metalog, eden_api, options)?;
                 let commits = Arc::new(RwLock::new(commits));
                 self.dag_commits = Some(commits.clone());
                 Ok(commits)
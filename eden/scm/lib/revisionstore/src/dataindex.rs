@@ -9,6 +9,8 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::Cursor;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
 use std::path::Path;
 
@@ -21,11 +23,16 @@ use memmap::MmapOptions;
 use thiserror::Error;
 use types::HgId;
 
+use crate::fanouttable::get_fanout_index;
 use crate::fanouttable::FanoutTable;
 use crate::sliceext::SliceExt;
 
 const ENTRY_LEN: usize = 40;
 const SMALL_FANOUT_CUTOFF: usize = 8192; // 2^16 / 8
+/// `contains_many` scans the whole index instead of bisecting for each key once there is at least
+/// one key per this many index entries, as the bisections would then read most of the index
+/// anyway, but in a random order which is much slower when the index isn't in the page cache.
+const SCAN_ENTRIES_PER_KEY: usize = 64;
 
 #[derive(Debug, Error)]
 #[error("DataIndex Error: {0:?}")]
@@ -38,7 +45,7 @@ struct DataIndexOptions {
     large: bool,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DeltaLocation {
     pub delta_base: Option<HgId>,
     pub offset: u64,
@@ -199,12 +206,28 @@ impl DataIndex {
         let mmap = unsafe { MmapOptions::new().len(len as usize).map(&file)? };
         let options = DataIndexOptions::read(&mut Cursor::new(&mmap))?;
         let fanout_size = FanoutTable::get_size(options.large);
+        if (len as usize) < 2 + fanout_size {
+            return Err(DataIndexError(format!(
+                "dataidx '{:?}' is truncated, its fanout table needs {} bytes but has {}",
+                path.to_str().unwrap_or("<unknown>"),
+                fanout_size,
+                (len as usize).saturating_sub(2),
+            ))
+            .into());
+        }
         let mut index_start = 2 + fanout_size;
 
         // Version one records the number of entries in the index
         if options.version == 1 {
             index_start += 8;
         }
+        if (len as usize) < index_start {
+            return Err(DataIndexError(format!(
+                "dataidx '{:?}' is truncated, it ends before the entry count",
+                path.to_str().unwrap_or("<unknown>")
+            ))
+            .into());
+        }
 
         Ok(DataIndex {
             mmap,
@@ -265,6 +288,12 @@ impl DataIndex {
         Ok(())
     }
 
+    /// Size in bytes of the index `write` produces for `len` entries.
+    pub fn written_size(len: usize) -> u64 {
+        let fanout_size = FanoutTable::get_size(len > SMALL_FANOUT_CUTOFF);
+        (2 + fanout_size + 8 + len * ENTRY_LEN) as u64
+    }
+
     pub fn get_entry(&self, hgid: &HgId) -> Result<Option<IndexEntry>> {
         let (start, end) = FanoutTable::get_bounds(self.get_fanout_slice(), hgid)?;
         let start = start + self.index_start;
@@ -281,12 +310,80 @@ impl DataIndex {
             .map(Some)
     }
 
+    /// Number of entries in the index.
+    pub fn len(&self) -> usize {
+        (self.mmap.len() - self.index_start) / ENTRY_LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns, for each of `hgids`, whether it is in the index.
+    ///
+    /// Large sets of keys are looked up with a single sequential scan of the index, smaller ones
+    /// by bisecting for each of them.
+    pub fn contains_many(&self, hgids: &[&HgId]) -> Result<Vec<bool>> {
+        if hgids.len().saturating_mul(SCAN_ENTRIES_PER_KEY) >= self.len() {
+            Ok(self.contains_many_scan(hgids))
+        } else {
+            self.contains_many_bisect(hgids)
+        }
+    }
+
+    fn contains_many_bisect(&self, hgids: &[&HgId]) -> Result<Vec<bool>> {
+        hgids
+            .iter()
+            .map(|hgid| Ok(self.get_entry(hgid)?.is_some()))
+            .collect()
+    }
+
+    fn contains_many_scan(&self, hgids: &[&HgId]) -> Vec<bool> {
+        let mut order: Vec<usize> = (0..hgids.len()).collect();
+        order.sort_unstable_by_key(|i| hgids[*i]);
+
+        // Both the index entries and the keys are sorted, walk them together.
+        let mut entries = self.mmap[self.index_start..]
+            .chunks_exact(ENTRY_LEN)
+            .peekable();
+        let mut found = vec![false; hgids.len()];
+        for i in order {
+            let key = hgids[i].as_ref();
+            while entries.next_if(|entry| &entry[..20] < key).is_some() {}
+            found[i] = entries.peek().map_or(false, |entry| &entry[..20] == key);
+        }
+        found
+    }
+
+    /// Returns the entries of this index whose hgid is also in `other`, in hgid order.
+    ///
+    /// Both indexes are sorted by hgid, so they are walked together in a single pass.
+    pub fn shared_entries(&self, other: &DataIndex) -> Result<Vec<IndexEntry>> {
+        let mut theirs = other.mmap[other.index_start..]
+            .chunks_exact(ENTRY_LEN)
+            .peekable();
+        let mut shared = Vec::new();
+        for entry in self.mmap[self.index_start..].chunks_exact(ENTRY_LEN) {
+            let key = &entry[..20];
+            while theirs.next_if(|other| &other[..20] < key).is_some() {}
+            if theirs.peek().map_or(false, |other| &other[..20] == key) {
+                shared.push(IndexEntry::read(entry)?);
+            }
+        }
+        Ok(shared)
+    }
+
     pub fn read_entry(&self, offset: usize) -> Result<IndexEntry> {
         let offset = offset + self.index_start;
         let raw_entry = self.mmap.get_err(offset..offset + ENTRY_LEN)?;
         IndexEntry::read(raw_entry)
     }
 
+    /// The entry at `position` in the sorted index.
+    pub fn nth_entry(&self, position: usize) -> Result<IndexEntry> {
+        self.read_entry(position * ENTRY_LEN)
+    }
+
     fn binary_search(&self, key: &HgId, slice: &[u8]) -> Option<usize> {
         let size = slice.len() / ENTRY_LEN;
         // Cast the slice into an array of entry buffers so we can bisect across them
@@ -303,6 +400,163 @@ impl DataIndex {
     }
 }
 
+/// Writes a data index from entries added in sorted order, without holding the whole index in
+/// memory like `DataIndex::write` does.
+///
+/// The number of entries must be known up front to pick the size of the fanout table, and entries
+/// must be added in strictly increasing hgid order, so that the fanout table can be computed as
+/// they are added. Entries are written as they are added. The locations of delta bases, which may
+/// come later in the index, are filled in by `finish` from a second pass over the same entries,
+/// looking each base up in the written index. Only the fanout table is kept in memory.
+pub struct DataIndexWriter<W> {
+    writer: W,
+    /// Position of the index header in `writer`.
+    start: u64,
+    large: bool,
+    len: usize,
+    count: usize,
+    last: Option<HgId>,
+    fanout: Vec<Option<u32>>,
+}
+
+impl<W: Read + Write + Seek> DataIndexWriter<W> {
+    /// Start writing an index of `len` entries at the current position of `writer`.
+    pub fn new(mut writer: W, len: usize) -> Result<Self> {
+        let start = writer.seek(SeekFrom::Current(0))?;
+        let options = DataIndexOptions {
+            version: 1,
+            large: len > SMALL_FANOUT_CUTOFF,
+        };
+        options.write(&mut writer)?;
+
+        // Reserve room for the fanout table and the number of entries.
+        let fanout_size = FanoutTable::get_size(options.large);
+        writer.write_all(&vec![0; fanout_size])?;
+        writer.write_u64::<BigEndian>(len as u64)?;
+
+        Ok(DataIndexWriter {
+            writer,
+            start,
+            large: options.large,
+            len,
+            count: 0,
+            last: None,
+            fanout: vec![None; fanout_size / 4],
+        })
+    }
+
+    /// Add the entry for `hgid`, which must be greater than the hgids of the entries already
+    /// added.
+    pub fn add(&mut self, hgid: &HgId, location: &DeltaLocation) -> Result<()> {
+        if self.count >= self.len {
+            return Err(DataIndexError(format!(
+                "more than the {} expected entries were added",
+                self.len
+            ))
+            .into());
+        }
+        if self.last.as_ref().map_or(false, |last| last >= hgid) {
+            return Err(DataIndexError(format!("entry {} was added out of order", hgid)).into());
+        }
+
+        let offset = (self.count * ENTRY_LEN) as u32;
+        let prefix = get_fanout_index(FanoutTable::get_size(self.large), hgid)? as usize;
+        self.fanout[prefix].get_or_insert(offset);
+
+        let delta_base_offset = match location.delta_base {
+            None => DeltaBaseOffset::FullText,
+            // Resolved by `finish`, once all the entries are written.
+            Some(_) => DeltaBaseOffset::Missing,
+        };
+        IndexEntry::new(
+            hgid.clone(),
+            delta_base_offset,
+            location.offset,
+            location.size,
+        )
+        .write(&mut self.writer)?;
+
+        self.count += 1;
+        self.last = Some(hgid.clone());
+        Ok(())
+    }
+
+    /// Fill in the fanout table and the delta bases, and return the writer, positioned at the
+    /// end of the index.
+    ///
+    /// `entries` must be the entries that were added, in the same order. Entries without a delta
+    /// base are skipped, so that they don't need to be read again.
+    pub fn finish<'a>(
+        mut self,
+        entries: impl IntoIterator<Item = (&'a HgId, &'a DeltaLocation)>,
+    ) -> Result<W> {
+        if self.count != self.len {
+            return Err(DataIndexError(format!(
+                "{} entries were added, but {} were expected",
+                self.count, self.len
+            ))
+            .into());
+        }
+        let end = self.writer.seek(SeekFrom::Current(0))?;
+
+        self.writer.seek(SeekFrom::Start(self.start + 2))?;
+        let fanout = std::mem::take(&mut self.fanout);
+        FanoutTable::write_offsets(&mut self.writer, fanout)?;
+
+        let index_start = self.start + 2 + FanoutTable::get_size(self.large) as u64 + 8;
+        let mut count = 0;
+        let mut buf = [0u8; 20];
+        for (entry, (hgid, location)) in entries.into_iter().enumerate() {
+            count += 1;
+            let base = match location.delta_base {
+                Some(base) if entry < self.count => base,
+                _ => continue,
+            };
+            let entry_start = index_start + (entry * ENTRY_LEN) as u64;
+            self.writer.seek(SeekFrom::Start(entry_start))?;
+            self.writer.read_exact(&mut buf)?;
+            if buf.as_ref() != hgid.as_ref() {
+                return Err(
+                    DataIndexError(format!("entry {} was not added at {}", hgid, entry)).into(),
+                );
+            }
+            if let Some(base_entry) = self.find(index_start, &base)? {
+                let offset = (base_entry * ENTRY_LEN) as i32;
+                self.writer.seek(SeekFrom::Start(entry_start + 20))?;
+                self.writer.write_i32::<BigEndian>(offset)?;
+            }
+        }
+        if count != self.count {
+            return Err(DataIndexError(format!(
+                "{} entries were added, but {} were passed to finish",
+                self.count, count
+            ))
+            .into());
+        }
+
+        self.writer.seek(SeekFrom::Start(end))?;
+        Ok(self.writer)
+    }
+
+    /// Bisect the written entries for `hgid`, returning its index.
+    fn find(&mut self, index_start: u64, hgid: &HgId) -> Result<Option<usize>> {
+        let (mut low, mut high) = (0, self.count);
+        let mut buf = [0u8; 20];
+        while low < high {
+            let mid = low + (high - low) / 2;
+            self.writer
+                .seek(SeekFrom::Start(index_start + (mid * ENTRY_LEN) as u64))?;
+            self.writer.read_exact(&mut buf)?;
+            match buf.as_ref().cmp(hgid.as_ref()) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(mid)),
+            }
+        }
+        Ok(None)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use quickcheck::quickcheck;
@@ -329,6 +583,77 @@ mod tests {
         DataIndexOptions::read(&mut Cursor::new(buf)).expect_err("invalid read");
     }
 
+    #[test]
+    fn test_truncated_fanout() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        values.insert(
+            HgId::random(&mut rng),
+            DeltaLocation {
+                delta_base: None,
+                offset: 1,
+                size: 2,
+            },
+        );
+        let mut buf = Vec::new();
+        DataIndex::write(&mut buf, &values).unwrap();
+
+        let fanout_size = FanoutTable::get_size(false);
+        for len in [2, 2 + fanout_size / 2, 2 + fanout_size + 4] {
+            let mut file = NamedTempFile::new().unwrap();
+            file.write_all(&buf[..len]).unwrap();
+            let err = DataIndex::new(file.path()).err().expect("truncated index");
+            assert!(err.to_string().contains("truncated"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_streaming_writer() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        let hgids: Vec<HgId> = (0..1000).map(|_| HgId::random(&mut rng)).collect();
+        for (i, hgid) in hgids.iter().enumerate() {
+            values.insert(
+                hgid.clone(),
+                DeltaLocation {
+                    // Bases before and after the entry in the index, and missing ones.
+                    delta_base: match i % 4 {
+                        0 => None,
+                        1 => Some(hgids[(i + 1) % hgids.len()]),
+                        2 => Some(hgids[i - 1]),
+                        _ => Some(HgId::random(&mut rng)),
+                    },
+                    offset: i as u64,
+                    size: 1,
+                },
+            );
+        }
+        let mut expected = Vec::new();
+        DataIndex::write(&mut expected, &values).unwrap();
+
+        let mut sorted: Vec<_> = values.iter().collect();
+        sorted.sort_by_key(|(hgid, _)| *hgid);
+        let mut writer = DataIndexWriter::new(Cursor::new(Vec::new()), sorted.len()).unwrap();
+        for (hgid, location) in sorted.iter() {
+            writer.add(hgid, location).unwrap();
+        }
+        // Entries must be added in order.
+        let (first, location) = sorted[0];
+        assert!(writer.add(first, location).is_err());
+        let written = writer.finish(sorted.iter().copied()).unwrap().into_inner();
+        assert_eq!(written, expected);
+
+        let writer = DataIndexWriter::new(Cursor::new(Vec::new()), 2).unwrap();
+        assert!(writer.finish(sorted.iter().copied()).is_err());
+
+        // The second pass must be over the entries that were added.
+        let mut writer = DataIndexWriter::new(Cursor::new(Vec::new()), sorted.len()).unwrap();
+        for (hgid, location) in sorted.iter() {
+            writer.add(hgid, location).unwrap();
+        }
+        assert!(writer.finish(sorted.iter().skip(1).copied()).is_err());
+    }
+
     #[test]
     fn test_missing_delta_base() {
         let mut rng = ChaChaRng::from_seed([0u8; 32]);
@@ -368,6 +693,34 @@ mod tests {
         assert!(index.get_entry(&other).unwrap().is_none());
     }
 
+    #[test]
+    fn test_contains_many() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        for i in 0..1000 {
+            values.insert(
+                HgId::random(&mut rng),
+                DeltaLocation {
+                    delta_base: None,
+                    offset: i,
+                    size: 1,
+                },
+            );
+        }
+        let index = make_index(&values);
+        assert_eq!(index.len(), 1000);
+
+        let present: Vec<HgId> = values.keys().take(20).cloned().collect();
+        let missing: Vec<HgId> = (0..20).map(|_| HgId::random(&mut rng)).collect();
+        let hgids: Vec<&HgId> = present.iter().chain(missing.iter()).collect();
+        let expected: Vec<bool> = (0..40).map(|i| i < 20).collect();
+
+        assert_eq!(index.contains_many_scan(&hgids), expected);
+        assert_eq!(index.contains_many_bisect(&hgids).unwrap(), expected);
+        assert_eq!(index.contains_many(&hgids).unwrap(), expected);
+        assert_eq!(index.contains_many(&hgids[..1]).unwrap(), vec![true]);
+    }
+
     quickcheck! {
         fn test_header_serialization(version: u8, large: bool) -> bool {
             let version = version % 2;
@@ -27,7 +27,7 @@
 //!                <filename>
 //!                <hgid: 20 byte>
 //!                <deltabasenode: 20 byte>
-//!                <delta len: 8 byte unsigned int>
+//!                <delta len: 8 byte unsigned int>         [2]
 //!                <delta>
 //!                <metadata-list len: 4 byte unsigned int> [1]
 //!                <metadata-list>                          [1]
@@ -78,12 +78,18 @@
 //!
 //! ```
 //! [1]: new in version 1.
+//! [2]: the most significant bit is set if the delta is compressed as a raw lz4 block prefixed
+//!      with its VLQ-encoded uncompressed size, instead of the default lz4 python frame. Only the
+//!      Rust implementation can read such entries.
 
 use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fmt;
+use std::fs;
 use std::fs::File;
 use std::io::Cursor;
 use std::io::Read;
+use std::io::Write;
 use std::mem::drop;
 use std::mem::take;
 use std::path::Path;
@@ -91,45 +97,166 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::format_err;
+use anyhow::Context;
 use anyhow::Error;
 use anyhow::Result;
 use byteorder::BigEndian;
+use byteorder::LittleEndian;
 use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use lz4_pyframe::compress;
+use lz4_pyframe::compress_block;
 use lz4_pyframe::decompress;
+use lz4_pyframe::decompress_block;
 use memmap::Mmap;
 use memmap::MmapOptions;
 use minibytes::Bytes;
 use mpatch::mpatch::get_full_text;
+use tempfile::NamedTempFile;
 use thiserror::Error;
 use types::HgId;
 use types::Key;
+use types::Parents;
 use types::RepoPath;
 use util::path::remove_file;
+use vlqencoding::VLQDecodeAt;
+use vlqencoding::VLQEncode;
 
 use crate::dataindex::DataIndex;
 use crate::dataindex::DeltaBaseOffset;
+use crate::dataindex::IndexEntry;
 use crate::datastore::Delta;
 use crate::datastore::HgIdDataStore;
+use crate::datastore::HgIdMutableDeltaStore;
 use crate::datastore::Metadata;
 use crate::datastore::StoreResult;
 use crate::localstore::ExtStoredPolicy;
 use crate::localstore::LocalStore;
 use crate::localstore::StoreFromPath;
+use crate::mutabledatapack::MutableDataPack;
+use crate::mutabledatapack::PackHasher;
+use crate::mutablepack::make_readonly;
+use crate::mutablepack::persist;
 use crate::repack::Repackable;
 use crate::repack::ToKeys;
 use crate::sliceext::SliceExt;
 use crate::types::StoreKey;
 
+/// Counters recording the depth of the delta chains resolved by reads.
+///
+/// Monitoring derives the average chain depth as `depth / chains`, which is used to detect packs
+/// with degraded read performance and to schedule repacks.
+pub(crate) struct DeltaChainCounters {
+    chains: &'static str,
+    depth: &'static str,
+}
+
+impl DeltaChainCounters {
+    pub(crate) fn record(&self, depth: usize) {
+        hg_metrics::increment_counter(self.chains, 1);
+        hg_metrics::increment_counter(self.depth, depth);
+    }
+}
+
+pub(crate) static DATAPACK_DELTA_CHAINS: DeltaChainCounters = DeltaChainCounters {
+    chains: "revisionstore.datapack.delta_chains",
+    depth: "revisionstore.datapack.delta_chain_depth",
+};
+
+pub(crate) static MUTABLE_DATAPACK_DELTA_CHAINS: DeltaChainCounters = DeltaChainCounters {
+    chains: "revisionstore.mutabledatapack.delta_chains",
+    depth: "revisionstore.mutabledatapack.delta_chain_depth",
+};
+
+/// Size of the chunks hashed and written by `DataPack::copy_to`.
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
 #[derive(Debug, Error)]
 #[error("Datapack Error: {0:?}")]
 struct DataPackError(String);
 
-#[derive(Clone, PartialEq)]
+/// A datapack was written with a format version newer than this build can read.
+#[derive(Debug, Error)]
+#[error("pack version {version} not supported by this build (max supported {max_supported})")]
+pub struct UnsupportedDataPackVersion {
+    pub version: u8,
+    pub max_supported: u8,
+}
+
+/// The index next to a datapack doesn't describe its entries, e.g. because it was left behind by
+/// another pack with the same name after a failed repack.
+#[derive(Debug, Error)]
+#[error("datapack index {index_path:?} doesn't match its pack: {reason}")]
+pub struct MismatchedDataIndex {
+    pub index_path: PathBuf,
+    pub reason: String,
+}
+
+/// The fulltext reconstructed for a datapack entry doesn't hash to the hgid of its key.
+#[derive(Debug, Error)]
+#[error("content of {key} hashes to {actual}")]
+pub struct MismatchedEntryHash {
+    pub key: Key,
+    pub actual: HgId,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum DataPackVersion {
     Zero,
     One,
 }
 
+/// How the delta of a datapack entry is compressed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataPackCompression {
+    /// lz4 with the python framing, readable by both the Python and Rust implementations.
+    PyFrame,
+    /// Raw lz4 block with a smaller header, only readable by the Rust implementation.
+    Block,
+}
+
+/// Hash of the content of a datapack, used as its file name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataPackHash {
+    /// Readable by both the Python and Rust implementations.
+    Sha1,
+    /// Only readable by the Rust implementation.
+    Sha256,
+}
+
+impl DataPackHash {
+    /// Length of the hex encoded hash, as found in pack file names.
+    pub fn name_len(&self) -> usize {
+        match self {
+            DataPackHash::Sha1 => 40,
+            DataPackHash::Sha256 => 64,
+        }
+    }
+
+    /// Guess the hash used to name a pack from its hex encoded name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        if !name.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        [DataPackHash::Sha1, DataPackHash::Sha256]
+            .iter()
+            .copied()
+            .find(|hash| hash.name_len() == name.len())
+    }
+}
+
+impl Default for DataPackHash {
+    fn default() -> Self {
+        DataPackHash::Sha1
+    }
+}
+
+/// Set on the delta length of entries compressed with `DataPackCompression::Block`.
+const BLOCK_COMPRESSION_FLAG: u64 = 1 << 63;
+
+/// Largest input lz4 accepts, `LZ4_MAX_INPUT_SIZE` in lz4.h.
+const LZ4_MAX_INPUT_SIZE: u64 = 0x7E00_0000;
+
 pub struct DataPack {
     mmap: Mmap,
     version: DataPackVersion,
@@ -140,25 +267,60 @@ pub struct DataPack {
     extstored_policy: ExtStoredPolicy,
 }
 
+/// Difference in logical content between two datapacks, see `DataPack::diff`.
+#[derive(Debug, Default, PartialEq)]
+pub struct PackDiff {
+    /// Keys only present in the other pack.
+    pub added: Vec<Key>,
+    /// Keys only present in this pack.
+    pub removed: Vec<Key>,
+    /// Keys present in both packs, but whose fulltext differs.
+    pub changed: Vec<Key>,
+}
+
+impl PackDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// An entry of a datapack as stored on disk, see `DataPack::raw_entry`.
+#[derive(Debug)]
+pub struct RawEntry<'a> {
+    /// The delta, still compressed.
+    pub compressed_delta: &'a [u8],
+    /// How `compressed_delta` is compressed.
+    pub compression: DataPackCompression,
+    /// The node the delta applies to, `None` for a fulltext.
+    pub delta_base: Option<HgId>,
+    pub metadata: Metadata,
+}
+
 pub struct DataEntry<'a> {
     offset: u64,
     filename: &'a RepoPath,
     hgid: HgId,
     delta_base: Option<HgId>,
     compressed_data: &'a [u8],
+    compression: DataPackCompression,
     data: RefCell<Option<Bytes>>,
     metadata: Metadata,
     next_offset: u64,
 }
 
 impl DataPackVersion {
+    /// The most recent version this build can read and write.
+    pub const LATEST: DataPackVersion = DataPackVersion::One;
+
     fn new(value: u8) -> Result<Self> {
         match value {
             0 => Ok(DataPackVersion::Zero),
             1 => Ok(DataPackVersion::One),
-            _ => {
-                Err(DataPackError(format!("invalid datapack version number '{:?}'", value)).into())
+            _ => Err(UnsupportedDataPackVersion {
+                version: value,
+                max_supported: DataPackVersion::LATEST.into(),
             }
+            .into()),
         }
     }
 }
@@ -172,7 +334,84 @@ impl From<DataPackVersion> for u8 {
     }
 }
 
+impl Default for DataPackCompression {
+    fn default() -> Self {
+        DataPackCompression::PyFrame
+    }
+}
+
+impl DataPackCompression {
+    pub(crate) fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            DataPackCompression::PyFrame => Ok(compress(data)?),
+            DataPackCompression::Block => {
+                let block = compress_block(data)?;
+                let mut buf = Vec::with_capacity(block.len() + 10);
+                buf.write_vlq(data.len())?;
+                buf.extend_from_slice(&block);
+                Ok(buf)
+            }
+        }
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            DataPackCompression::PyFrame => Ok(decompress(data)?),
+            DataPackCompression::Block => {
+                let (size, offset): (usize, usize) = data.read_vlq_at(0)?;
+                Ok(decompress_block(data.get_err(offset..)?, size)?)
+            }
+        }
+    }
+
+    /// Header of a delta of `len` bytes stored as a single run of lz4 literals, i.e. not actually
+    /// compressed. Unlike `compress`, its length doesn't depend on the content of the delta, so
+    /// the delta can be written after it as it is read. Readers decompress it like any other
+    /// delta.
+    pub(crate) fn literals_header(&self, len: u64) -> Result<Vec<u8>> {
+        if len > LZ4_MAX_INPUT_SIZE {
+            return Err(
+                DataPackError(format!("delta is too large for lz4 ({} bytes)", len)).into(),
+            );
+        }
+        let mut header = Vec::with_capacity(16);
+        match self {
+            DataPackCompression::PyFrame => header.write_u32::<LittleEndian>(len as u32)?,
+            DataPackCompression::Block => header.write_vlq(len as usize)?,
+        }
+        // An empty delta is compressed to an empty lz4 block.
+        if len > 0 {
+            // The literal length is in the high 4 bits of the token, continued by bytes of 255
+            // and a last byte smaller than 255 once it reaches 15.
+            if len < 15 {
+                header.write_u8((len as u8) << 4)?;
+            } else {
+                header.write_u8(0xf0)?;
+                let mut rest = len - 15;
+                while rest >= 255 {
+                    header.write_u8(255)?;
+                    rest -= 255;
+                }
+                header.write_u8(rest as u8)?;
+            }
+        }
+        Ok(header)
+    }
+
+    /// Flag the compressed delta length so readers know how to decompress it.
+    pub(crate) fn flag_delta_len(&self, len: u64) -> u64 {
+        match self {
+            DataPackCompression::PyFrame => len,
+            DataPackCompression::Block => len | BLOCK_COMPRESSION_FLAG,
+        }
+    }
+}
+
 impl<'a> DataEntry<'a> {
+    /// Parse the entry starting at `offset` in `buf`.
+    ///
+    /// Version 0 entries have no metadata section and are given default
+    /// metadata, so that packs written before version 1 remain readable.
     pub fn new(buf: &'a [u8], offset: u64, version: DataPackVersion) -> Result<Self> {
         let mut cur = Cursor::new(buf);
         cur.set_position(offset);
@@ -200,6 +439,14 @@ impl<'a> DataEntry<'a> {
         };
 
         let delta_len = cur.read_u64::<BigEndian>()?;
+        let (compression, delta_len) = if delta_len & BLOCK_COMPRESSION_FLAG != 0 {
+            (
+                DataPackCompression::Block,
+                delta_len & !BLOCK_COMPRESSION_FLAG,
+            )
+        } else {
+            (DataPackCompression::PyFrame, delta_len)
+        };
         let compressed_data =
             buf.get_err(cur.position() as usize..(cur.position() + delta_len) as usize)?;
         let data = RefCell::new(None);
@@ -207,10 +454,9 @@ impl<'a> DataEntry<'a> {
         cur.set_position(cur_pos + delta_len);
 
         // Metadata
-        let metadata = if version == DataPackVersion::One {
-            Metadata::read(&mut cur)?
-        } else {
-            Default::default()
+        let metadata = match version {
+            DataPackVersion::Zero => Default::default(),
+            DataPackVersion::One => Metadata::read(&mut cur)?,
         };
 
         let next_offset = cur.position();
@@ -221,6 +467,7 @@ impl<'a> DataEntry<'a> {
             hgid,
             delta_base,
             compressed_data,
+            compression,
             data,
             metadata,
             next_offset,
@@ -246,7 +493,7 @@ impl<'a> DataEntry<'a> {
     pub fn delta(&self) -> Result<Bytes> {
         let mut cell = self.data.borrow_mut();
         if cell.is_none() {
-            *cell = Some(decompress(&self.compressed_data)?.into());
+            *cell = Some(self.compression.decompress(&self.compressed_data)?.into());
         }
 
         Ok(cell.as_ref().unwrap().clone())
@@ -255,6 +502,31 @@ impl<'a> DataEntry<'a> {
     pub fn metadata(&self) -> &Metadata {
         &self.metadata
     }
+
+    pub fn compression(&self) -> DataPackCompression {
+        self.compression
+    }
+
+    /// Length of the delta as stored in the pack, known without decompressing it.
+    pub fn compressed_len(&self) -> usize {
+        self.compressed_data.len()
+    }
+
+    /// Ratio of the decompressed to the compressed length of the delta, higher is better.
+    ///
+    /// Unlike `compressed_len`, this decompresses the delta.
+    pub fn compression_ratio(&self) -> Result<f64> {
+        let compressed_len = self.compressed_len();
+        if compressed_len == 0 {
+            return Ok(1.0);
+        }
+        Ok(self.delta()?.len() as f64 / compressed_len as f64)
+    }
+
+    /// The delta as stored in the pack, before decompression.
+    pub(crate) fn compressed_data(&self) -> &'a [u8] {
+        self.compressed_data
+    }
 }
 
 impl<'a> fmt::Debug for DataEntry<'a> {
@@ -296,9 +568,14 @@ impl DataPack {
         }
 
         let mmap = unsafe { MmapOptions::new().len(len as usize).map(&file)? };
-        let version = DataPackVersion::new(mmap[0])?;
+        let version = DataPackVersion::new(mmap[0]).with_context(|| {
+            format!(
+                "cannot open datapack '{:?}'",
+                path.to_str().unwrap_or("<unknown>")
+            )
+        })?;
         let index_path = path.with_extension("dataidx");
-        Ok(DataPack {
+        let pack = DataPack {
             mmap,
             version,
             index: DataIndex::new(&index_path)?,
@@ -306,7 +583,51 @@ impl DataPack {
             pack_path,
             index_path,
             extstored_policy,
-        })
+        };
+        pack.check_index()?;
+        Ok(pack)
+    }
+
+    /// Check that the index belongs to this pack, by looking up its first and last entries in the
+    /// pack. Serving reads from a foreign index would return the wrong data at its offsets.
+    fn check_index(&self) -> Result<()> {
+        let mismatch = |reason: String| MismatchedDataIndex {
+            index_path: self.index_path.clone(),
+            reason,
+        };
+
+        let len = self.index.len();
+        if len == 0 {
+            if self.mmap.len() > 1 {
+                return Err(mismatch("the index is empty".to_string()).into());
+            }
+            return Ok(());
+        }
+
+        for position in [0, len - 1] {
+            let index_entry = self.index.nth_entry(position)?;
+            let end = index_entry
+                .pack_entry_offset()
+                .checked_add(index_entry.pack_entry_size());
+            if end.map_or(true, |end| end > self.mmap.len() as u64) {
+                return Err(mismatch(format!(
+                    "entry {} is past the end of the pack",
+                    index_entry.hgid()
+                ))
+                .into());
+            }
+            let hgid = self
+                .read_entry(index_entry.pack_entry_offset())
+                .map(|entry| entry.hgid().clone());
+            if hgid.ok().as_ref() != Some(index_entry.hgid()) {
+                return Err(mismatch(format!(
+                    "entry {} isn't at its offset in the pack",
+                    index_entry.hgid()
+                ))
+                .into());
+            }
+        }
+        Ok(())
     }
 
     pub fn len(&self) -> usize {
@@ -314,13 +635,146 @@ impl DataPack {
     }
 
     pub fn read_entry(&self, offset: u64) -> Result<DataEntry> {
-        DataEntry::new(self.mmap.as_ref(), offset, self.version.clone())
+        DataEntry::new(self.mmap.as_ref(), offset, self.version)
+    }
+
+    /// Returns the stored entry for `key` without decompressing its delta, so that it can be
+    /// copied verbatim to another store.
+    ///
+    /// The compressed delta is borrowed from the memory mapped pack.
+    pub fn raw_entry(&self, key: &Key) -> Result<Option<RawEntry>> {
+        let index_entry = match self.index.get_entry(&key.hgid)? {
+            None => return Ok(None),
+            Some(entry) => entry,
+        };
+
+        let entry = self.read_entry(index_entry.pack_entry_offset())?;
+        if self.extstored_policy == ExtStoredPolicy::Ignore && entry.metadata.is_lfs() {
+            return Ok(None);
+        }
+
+        Ok(Some(RawEntry {
+            compressed_delta: entry.compressed_data(),
+            compression: entry.compression(),
+            delta_base: entry.delta_base().clone(),
+            metadata: entry.metadata().clone(),
+        }))
+    }
+
+    /// Whether all of `keys` are in this pack.
+    ///
+    /// Large sets of keys are checked by scanning the index sequentially, which is much faster
+    /// than looking each of them up when the index isn't in the page cache.
+    pub fn contains_all(&self, keys: &[Key]) -> Result<bool> {
+        let hgids: Vec<&HgId> = keys.iter().map(|k| &k.hgid).collect();
+        Ok(self
+            .index
+            .contains_many(&hgids)?
+            .into_iter()
+            .all(|found| found))
+    }
+
+    /// The hash used to name this pack, or `None` if its name isn't a known hash.
+    pub fn name_hash(&self) -> Option<DataPackHash> {
+        DataPackHash::from_name(self.base_path.file_name()?.to_str()?)
     }
 
     pub fn base_path(&self) -> &Path {
         &self.base_path
     }
 
+    /// Copy this pack and its index into `dir`, returning the base path of the copy.
+    ///
+    /// The pack content is hashed while being copied and checked against the pack name, so a
+    /// corrupted pack is never propagated. The index is derived from the pack and copied as is.
+    pub fn copy_to(&self, dir: &Path) -> Result<PathBuf> {
+        let hash = self.name_hash().ok_or_else(|| {
+            DataPackError(format!(
+                "cannot verify datapack '{:?}', its name isn't a content hash",
+                self.base_path
+            ))
+        })?;
+        // `name_hash` only succeeds for valid UTF-8 file names.
+        let name = self.base_path.file_name().unwrap().to_str().unwrap();
+
+        let mut hasher = PackHasher::new(hash);
+        let mut packfile = NamedTempFile::new_in(dir)?;
+        for chunk in self.mmap.chunks(COPY_CHUNK_SIZE) {
+            hasher.input(chunk);
+            packfile.write_all(chunk)?;
+        }
+        let actual = hasher.result_hex();
+        if !actual.eq_ignore_ascii_case(name) {
+            return Err(DataPackError(format!(
+                "datapack '{:?}' is corrupted, its content hashes to {}",
+                self.base_path, actual
+            ))
+            .into());
+        }
+
+        let mut indexfile = NamedTempFile::new_in(dir)?;
+        indexfile.write_all(&fs::read(&self.index_path)?)?;
+
+        for file in [&packfile, &indexfile] {
+            let mut perms = file.as_file().metadata()?.permissions();
+            make_readonly(&mut perms);
+            file.as_file().set_permissions(perms)?;
+        }
+
+        let base_path = dir.join(name);
+        persist(packfile, base_path.with_extension("datapack"))?;
+        persist(indexfile, base_path.with_extension("dataidx"))?;
+        Ok(base_path)
+    }
+
+    /// Write a copy of this pack into `dir` without the entries of `exclude`, returning the base
+    /// path of the copy.
+    ///
+    /// This is meant to redact content, unlike a repack. The entries whose delta base is
+    /// excluded are stored as fulltexts, so that the copy doesn't contain any delta against
+    /// removed content. The copy is always a version 1 pack. Fails if all entries are excluded.
+    pub fn rewrite_excluding(&self, dir: &Path, exclude: &HashSet<HgId>) -> Result<PathBuf> {
+        let mutpack = MutableDataPack::new(dir, DataPackVersion::One);
+        for entry in self.iter_by_index() {
+            let entry = entry?;
+            if exclude.contains(entry.hgid()) {
+                continue;
+            }
+
+            let key = Key::new(entry.filename().to_owned(), entry.hgid().clone());
+            let delta = match entry.delta_base() {
+                Some(base) if exclude.contains(base) => {
+                    let fulltext = self.get_fulltext(&key)?.ok_or_else(|| {
+                        DataPackError(format!(
+                            "cannot rebuild the fulltext of {:?} from {:?}",
+                            key, self.base_path
+                        ))
+                    })?;
+                    Delta {
+                        data: fulltext.into(),
+                        base: None,
+                        key,
+                    }
+                }
+                base => Delta {
+                    data: entry.delta()?,
+                    base: base.map(|base| Key::new(key.path.clone(), base)),
+                    key,
+                },
+            };
+            mutpack.add(&delta, entry.metadata())?;
+        }
+
+        match mutpack.flush()? {
+            Some(paths) => Ok(paths[0].clone()),
+            None => Err(DataPackError(format!(
+                "all the entries of {:?} are excluded",
+                self.base_path
+            ))
+            .into()),
+        }
+    }
+
     pub fn pack_path(&self) -> &Path {
         &self.pack_path
     }
@@ -329,42 +783,237 @@ impl DataPack {
         &self.index_path
     }
 
+    /// Iterate over the keys of the entries in pack order, like `to_keys`, for full scans such as
+    /// repacks or verification.
+    ///
+    /// The kernel is advised that the pack is read sequentially while the iterator is alive, so
+    /// that it prefetches cold packs aggressively. The advice is only given on unix platforms.
+    pub fn iter_sequential(&self) -> impl Iterator<Item = Result<Key>> + '_ {
+        self.advise(Advice::Sequential);
+        let mut iter = DataPackIterator::new(self);
+        iter.advised = true;
+        iter
+    }
+
+    /// Iterate over the entries in index order, i.e. sorted by hgid.
+    ///
+    /// Two packs iterated this way can be merge-joined, e.g. to diff or dedup them, without
+    /// buffering their keys. Unlike `iter_sequential`, the entries are read at random offsets in
+    /// the pack, which is slower for cold packs.
+    pub fn iter_by_index(&self) -> impl Iterator<Item = Result<DataEntry>> + '_ {
+        (0..self.index.len()).map(move |position| {
+            let index_entry = self.index.nth_entry(position)?;
+            self.read_entry(index_entry.pack_entry_offset())
+        })
+    }
+
+    #[cfg(unix)]
+    fn advise(&self, advice: Advice) {
+        let advice = match advice {
+            Advice::Normal => libc::MADV_NORMAL,
+            Advice::Sequential => libc::MADV_SEQUENTIAL,
+        };
+        // The advice is only a hint, reads are correct regardless of whether it was taken.
+        unsafe {
+            libc::madvise(
+                self.mmap.as_ptr() as *mut libc::c_void,
+                self.mmap.len(),
+                advice,
+            );
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn advise(&self, _advice: Advice) {}
+
     pub(crate) fn get_delta_chain(&self, key: &Key) -> Result<Option<Vec<Delta>>> {
-        let mut chain: Vec<Delta> = Default::default();
-        let mut next_entry = match self.index.get_entry(&key.hgid)? {
+        let mut deltas = self.get_delta_chain_stream(key);
+        let chain = deltas.by_ref().collect::<Result<Vec<_>>>()?;
+        if chain.is_empty() || deltas.ignored {
+            return Ok(None);
+        }
+
+        DATAPACK_DELTA_CHAINS.record(chain.len());
+        Ok(Some(chain))
+    }
+
+    /// Same as `get_delta_chain`, but the returned chain always ends with a fulltext.
+    ///
+    /// If the chain reaches a delta whose base isn't in this pack, the fulltext of that base is
+    /// fetched from `fallback` and appended to the chain. Without a fallback, or if it doesn't
+    /// have the base either, an error naming the missing base is returned instead of a chain that
+    /// can't be applied.
+    pub fn get_delta_chain_with_fallback(
+        &self,
+        key: &Key,
+        fallback: Option<&dyn HgIdDataStore>,
+    ) -> Result<Option<Vec<Delta>>> {
+        let mut chain = match self.get_delta_chain(key)? {
+            Some(chain) => chain,
+            None => return Ok(None),
+        };
+        let last = chain.last().expect("delta chains are never empty");
+        if last.is_fulltext() {
+            return Ok(Some(chain));
+        }
+
+        let child = last.key.clone();
+        let base = last
+            .base
+            .clone()
+            .expect("deltas that aren't fulltexts have a base");
+        let fulltext = match fallback {
+            Some(fallback) => fallback.get_fulltext(&base)?,
+            None => None,
+        };
+        match fulltext {
+            Some(fulltext) => {
+                chain.push(Delta {
+                    data: fulltext.into(),
+                    base: None,
+                    key: base,
+                });
+                Ok(Some(chain))
+            }
+            None => Err(DataPackError(format!(
+                "delta base {} of {} is missing from {:?}",
+                base, child, self.base_path
+            ))
+            .into()),
+        }
+    }
+
+    /// Same as `get_delta_chain`, but yields the deltas one at a time while following the base
+    /// links, so that memory stays bounded for deep chains.
+    ///
+    /// Nothing is yielded if `key` isn't in this pack. With `ExtStoredPolicy::Ignore`, the
+    /// iteration stops at the first LFS entry, while `get_delta_chain` would return `None`.
+    pub fn get_delta_chain_stream(&self, key: &Key) -> DeltaChainIter<'_> {
+        DeltaChainIter {
+            pack: self,
+            next_entry: self.index.get_entry(&key.hgid).transpose(),
+            depth: 0,
+            ignored: false,
+        }
+    }
+
+    /// Number of deltas in the chain of `key` within this pack, or `None` if the key isn't in
+    /// this pack.
+    ///
+    /// The chain is followed in the index only, so no delta is read.
+    pub(crate) fn delta_chain_len(&self, key: &Key) -> Result<Option<usize>> {
+        let mut entry = match self.index.get_entry(&key.hgid)? {
             None => return Ok(None),
             Some(entry) => entry,
         };
-        loop {
-            // Due to either storage corruption, or wrongly added data to the datapack, we could
-            // end up in an unbounded loop due to a never ending delta chain. Let's avoid this and
-            // thus error out if the delta chain is overly long.
-            if chain.len() > 1000 {
+        let mut len = 1;
+        while let DeltaBaseOffset::Offset(offset) = entry.delta_base_offset() {
+            // Same bound as `DeltaChainIter`.
+            if len > 1000 {
                 return Err(format_err!("Delta chain too long"));
             }
+            entry = self.index.read_entry(offset as usize)?;
+            len += 1;
+        }
+        Ok(Some(len))
+    }
 
-            let data_entry = self.read_entry(next_entry.pack_entry_offset())?;
-            if self.extstored_policy == ExtStoredPolicy::Ignore && data_entry.metadata.is_lfs() {
-                return Ok(None);
+    /// Compare the logical content of this pack with `other`.
+    ///
+    /// Entries are compared by their reconstructed fulltext, so two packs storing the same
+    /// revisions with different delta chains are considered equal.
+    pub fn diff(&self, other: &DataPack) -> Result<PackDiff> {
+        let ours = self
+            .to_keys()
+            .into_iter()
+            .collect::<Result<HashSet<Key>>>()?;
+        let theirs = other
+            .to_keys()
+            .into_iter()
+            .collect::<Result<HashSet<Key>>>()?;
+
+        let mut diff = PackDiff::default();
+        for key in ours.iter() {
+            if !theirs.contains(key) {
+                diff.removed.push(key.clone());
+                continue;
             }
 
-            chain.push(Delta {
-                data: data_entry.delta()?,
-                base: data_entry
-                    .delta_base()
-                    .map(|delta_base| Key::new(data_entry.filename.to_owned(), delta_base.clone())),
-                key: Key::new(data_entry.filename.to_owned(), data_entry.hgid().clone()),
-            });
-
-            if let DeltaBaseOffset::Offset(offset) = next_entry.delta_base_offset() {
-                next_entry = self.index.read_entry(offset as usize)?;
-            } else {
-                break;
+            let ours = self.get(StoreKey::hgid(key.clone()))?;
+            let theirs = other.get(StoreKey::hgid(key.clone()))?;
+            if ours != theirs {
+                diff.changed.push(key.clone());
             }
         }
+        diff.added = theirs.difference(&ours).cloned().collect();
 
-        Ok(Some(chain))
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        Ok(diff)
+    }
+
+    /// Returns true if both packs contain the same keys with the same fulltexts.
+    pub fn content_equals(&self, other: &DataPack) -> Result<bool> {
+        Ok(self.diff(other)?.is_empty())
     }
+
+    /// Check that the fulltext of `key` hashes to its hgid.
+    ///
+    /// Datapacks don't store parents, so they must be passed in, usually from the matching
+    /// history pack. Unlike the pack checksum, this catches entries whose stored bytes are
+    /// wrong from the start. LFS entries only store a pointer, which can't be verified here.
+    pub fn verify_entry(&self, key: &Key, parents: Parents) -> Result<()> {
+        let index_entry = self
+            .index
+            .get_entry(&key.hgid)?
+            .ok_or_else(|| format_err!("{:?} not found in datapack", key))?;
+        if self
+            .read_entry(index_entry.pack_entry_offset())?
+            .metadata
+            .is_lfs()
+        {
+            return Err(format_err!(
+                "{:?} is stored in LFS and can't be verified",
+                key
+            ));
+        }
+
+        let data = match self.get(StoreKey::hgid(key.clone()))? {
+            StoreResult::Found(data) => data,
+            StoreResult::NotFound(_) => {
+                return Err(format_err!("{:?} not found in datapack", key));
+            }
+        };
+        let actual = HgId::from_content(&data, parents);
+        if actual != key.hgid {
+            return Err(MismatchedEntryHash {
+                key: key.clone(),
+                actual,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Returns the keys present in both `a` and `b`, sorted by hgid.
+///
+/// Presence is decided from the indexes alone, which are merged in a single pass. Indexes don't
+/// record paths though, so the path of each shared key is read from the header of its entry in
+/// `a`.
+pub fn shared_keys(a: &DataPack, b: &DataPack) -> Result<Vec<Key>> {
+    a.index
+        .shared_entries(&b.index)?
+        .into_iter()
+        .map(|entry| {
+            let data_entry = a.read_entry(entry.pack_entry_offset())?;
+            Ok(Key::new(
+                data_entry.filename.to_owned(),
+                entry.hgid().clone(),
+            ))
+        })
+        .collect()
 }
 
 impl HgIdDataStore for DataPack {
@@ -428,13 +1077,23 @@ impl StoreFromPath for DataPack {
 
 impl LocalStore for DataPack {
     fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        let hgids: Vec<&HgId> = keys
+            .iter()
+            .filter_map(|k| match k {
+                StoreKey::HgId(k) => Some(&k.hgid),
+                StoreKey::Content(_, _) => None,
+            })
+            .collect();
+        let mut found = match self.index.contains_many(&hgids) {
+            Ok(found) => found.into_iter(),
+            // Like for a single failed lookup, consider all the keys missing.
+            Err(_) => vec![false; hgids.len()].into_iter(),
+        };
+
         Ok(keys
             .iter()
             .filter(|k| match k {
-                StoreKey::HgId(k) => match self.index.get_entry(&k.hgid) {
-                    Ok(None) | Err(_) => true,
-                    Ok(Some(_)) => false,
-                },
+                StoreKey::HgId(_) => !found.next().unwrap_or(false),
                 StoreKey::Content(_, _) => true,
             })
             .cloned()
@@ -470,9 +1129,71 @@ impl Repackable for DataPack {
     }
 }
 
+/// Iterator over the delta chain of a key, see `DataPack::get_delta_chain_stream`.
+pub struct DeltaChainIter<'a> {
+    pack: &'a DataPack,
+    next_entry: Option<Result<IndexEntry>>,
+    depth: usize,
+    /// Whether the iteration stopped on an LFS entry ignored by the `ExtStoredPolicy`.
+    ignored: bool,
+}
+
+impl<'a> DeltaChainIter<'a> {
+    fn read_delta(&mut self, index_entry: IndexEntry) -> Result<Option<Delta>> {
+        // Due to either storage corruption, or wrongly added data to the datapack, we could
+        // end up in an unbounded loop due to a never ending delta chain. Let's avoid this and
+        // thus error out if the delta chain is overly long.
+        if self.depth > 1000 {
+            return Err(format_err!("Delta chain too long"));
+        }
+
+        let data_entry = self.pack.read_entry(index_entry.pack_entry_offset())?;
+        if self.pack.extstored_policy == ExtStoredPolicy::Ignore && data_entry.metadata.is_lfs() {
+            self.ignored = true;
+            return Ok(None);
+        }
+
+        let delta = Delta {
+            data: data_entry.delta()?,
+            base: data_entry
+                .delta_base()
+                .map(|delta_base| Key::new(data_entry.filename.to_owned(), delta_base.clone())),
+            key: Key::new(data_entry.filename.to_owned(), data_entry.hgid().clone()),
+        };
+        self.depth += 1;
+
+        if let DeltaBaseOffset::Offset(offset) = index_entry.delta_base_offset() {
+            self.next_entry = Some(self.pack.index.read_entry(offset as usize));
+        }
+        Ok(Some(delta))
+    }
+}
+
+impl<'a> Iterator for DeltaChainIter<'a> {
+    type Item = Result<Delta>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // On errors, `next_entry` is left empty, which stops the iteration.
+        match self.next_entry.take()? {
+            Ok(index_entry) => self.read_delta(index_entry).transpose(),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Expected access pattern of the memory mapped pack, see `DataPack::advise`.
+#[derive(Clone, Copy)]
+enum Advice {
+    Normal,
+    Sequential,
+}
+
 struct DataPackIterator<'a> {
     pack: &'a DataPack,
     offset: u64,
+    /// Whether the pack was advised to be read sequentially, which is reverted once the
+    /// iteration is over.
+    advised: bool,
 }
 
 impl<'a> DataPackIterator<'a> {
@@ -480,6 +1201,15 @@ impl<'a> DataPackIterator<'a> {
         DataPackIterator {
             pack,
             offset: 1, // Start after the header byte
+            advised: false,
+        }
+    }
+}
+
+impl<'a> Drop for DataPackIterator<'a> {
+    fn drop(&mut self) {
+        if self.advised {
+            self.pack.advise(Advice::Normal);
         }
     }
 }
@@ -509,13 +1239,17 @@ impl<'a> Iterator for DataPackIterator<'a> {
 
 #[cfg(test)]
 pub mod tests {
+    use std::collections::HashMap;
     use std::rc::Rc;
 
+    use byteorder::WriteBytesExt;
+    use lz4_pyframe::compress;
     use quickcheck::quickcheck;
     use tempfile::TempDir;
     use types::testutil::*;
 
     use super::*;
+    use crate::dataindex::DeltaLocation;
     use crate::datastore::Delta;
     use crate::datastore::HgIdMutableDeltaStore;
     use crate::datastore::Metadata;
@@ -532,37 +1266,187 @@ pub mod tests {
         DataPack::new(&path, ExtStoredPolicy::Use).unwrap()
     }
 
+    /// Write a version 0 datapack, which has no metadata section, by hand
+    /// since `MutableDataPack` refuses to create them.
+    fn make_v0_datapack(tempdir: &TempDir, deltas: &[Delta]) -> DataPack {
+        let mut pack = vec![0u8];
+        let mut mem_index = HashMap::new();
+        for delta in deltas {
+            let offset = pack.len() as u64;
+            let path_slice = delta.key.path.as_byte_slice();
+            let compressed = compress(&delta.data).unwrap();
+            pack.write_u16::<BigEndian>(path_slice.len() as u16)
+                .unwrap();
+            pack.extend_from_slice(path_slice);
+            pack.extend_from_slice(delta.key.hgid.as_ref());
+            pack.extend_from_slice(
+                delta
+                    .base
+                    .as_ref()
+                    .map_or_else(|| HgId::null_id(), |k| &k.hgid)
+                    .as_ref(),
+            );
+            pack.write_u64::<BigEndian>(compressed.len() as u64)
+                .unwrap();
+            pack.extend_from_slice(&compressed);
+            mem_index.insert(
+                delta.key.hgid.clone(),
+                DeltaLocation {
+                    delta_base: delta.base.as_ref().map(|k| k.hgid.clone()),
+                    offset,
+                    size: pack.len() as u64 - offset,
+                },
+            );
+        }
+
+        let base = tempdir.path().join("v0");
+        std::fs::write(base.with_extension("datapack"), &pack).unwrap();
+        let mut index = File::create(base.with_extension("dataidx")).unwrap();
+        DataIndex::write(&mut index, &mem_index).unwrap();
+
+        DataPack::new(&base, ExtStoredPolicy::Use).unwrap()
+    }
+
     #[test]
-    fn test_get_missing() {
-        let tempdir = TempDir::new().unwrap();
+    fn test_copy_to() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let revisions = vec![(
+            Delta {
+                data: Bytes::from(&[1, 2, 3, 4][..]),
+                base: None,
+                key: key("a", "1"),
+            },
+            Default::default(),
+        )];
+        let pack = make_datapack(&tempdir, &revisions);
+
+        let destdir = TempDir::new()?;
+        let copied = pack.copy_to(destdir.path())?;
+        assert_eq!(copied.file_name(), pack.base_path().file_name());
+        assert_eq!(
+            fs::read(copied.with_extension("datapack"))?,
+            fs::read(pack.pack_path())?
+        );
+
+        let copy = DataPack::new(&copied, ExtStoredPolicy::Use)?;
+        assert_eq!(
+            copy.get_delta_chain(&revisions[0].0.key)?,
+            Some(vec![revisions[0].0.clone()])
+        );
+
+        // Copying again over an existing pack isn't an error.
+        assert_eq!(pack.copy_to(destdir.path())?, copied);
+        Ok(())
+    }
 
+    #[test]
+    fn test_copy_to_corrupted() -> Result<()> {
+        let tempdir = TempDir::new()?;
         let revisions = vec![(
             Delta {
                 data: Bytes::from(&[1, 2, 3, 4][..]),
-                base: Some(key("a", "1")),
-                key: key("a", "2"),
+                base: None,
+                key: key("a", "1"),
             },
             Default::default(),
         )];
         let pack = make_datapack(&tempdir, &revisions);
-        for &(ref delta, ref _metadata) in revisions.iter() {
-            let missing = pack.get_missing(&[StoreKey::from(&delta.key)]).unwrap();
-            assert_eq!(missing.len(), 0);
-        }
 
-        let not = key("b", "3");
-        let missing = pack.get_missing(&vec![StoreKey::from(&not)]).unwrap();
-        assert_eq!(missing, vec![StoreKey::from(not)]);
+        // Rename the pack so its name no longer matches its content.
+        let renamed = tempdir.path().join("0".repeat(40));
+        fs::copy(pack.pack_path(), renamed.with_extension("datapack"))?;
+        fs::copy(pack.index_path(), renamed.with_extension("dataidx"))?;
+        let corrupted = DataPack::new(&renamed, ExtStoredPolicy::Use)?;
+
+        let destdir = TempDir::new()?;
+        assert!(corrupted.copy_to(destdir.path()).is_err());
+        assert_eq!(fs::read_dir(destdir.path())?.count(), 0);
+        Ok(())
     }
 
     #[test]
-    fn test_get_meta() {
-        let tempdir = TempDir::new().unwrap();
+    fn test_invalid_version() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let base = tempdir.path().join("v2");
+        std::fs::write(base.with_extension("datapack"), &[2u8])?;
+        let mut index = File::create(base.with_extension("dataidx"))?;
+        DataIndex::write(&mut index, &HashMap::new())?;
+
+        let err = DataPack::new(&base, ExtStoredPolicy::Use).unwrap_err();
+        let unsupported = err.downcast_ref::<UnsupportedDataPackVersion>().unwrap();
+        assert_eq!(unsupported.version, 2);
+        assert_eq!(unsupported.max_supported, 1);
+        Ok(())
+    }
 
-        let revisions = vec![
-            (
-                Delta {
-                    data: Bytes::from(&[1, 2, 3, 4][..]),
+    #[test]
+    fn test_read_v0() -> Result<()> {
+        let tempdir = TempDir::new()?;
+
+        let base = Delta {
+            data: Bytes::from(&b"base"[..]),
+            base: None,
+            key: key("a", "1"),
+        };
+        let delta = Delta {
+            data: Bytes::from(&[1, 2, 3, 4][..]),
+            base: Some(base.key.clone()),
+            key: key("a", "2"),
+        };
+        let pack = make_v0_datapack(&tempdir, &[base.clone(), delta.clone()]);
+        assert!(pack.version == DataPackVersion::Zero);
+
+        assert_eq!(
+            pack.get(StoreKey::hgid(base.key.clone()))?,
+            StoreResult::Found(b"base".to_vec())
+        );
+        assert_eq!(
+            pack.get_meta(StoreKey::hgid(delta.key.clone()))?,
+            StoreResult::Found(Metadata::default())
+        );
+        assert_eq!(
+            pack.get_delta_chain(&delta.key)?.unwrap(),
+            vec![delta.clone(), base.clone()]
+        );
+        assert_eq!(
+            pack.to_keys().into_iter().collect::<Result<Vec<Key>>>()?,
+            vec![base.key, delta.key]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_missing() {
+        let tempdir = TempDir::new().unwrap();
+
+        let revisions = vec![(
+            Delta {
+                data: Bytes::from(&[1, 2, 3, 4][..]),
+                base: Some(key("a", "1")),
+                key: key("a", "2"),
+            },
+            Default::default(),
+        )];
+        let pack = make_datapack(&tempdir, &revisions);
+        for &(ref delta, ref _metadata) in revisions.iter() {
+            let missing = pack.get_missing(&[StoreKey::from(&delta.key)]).unwrap();
+            assert_eq!(missing.len(), 0);
+        }
+
+        let not = key("b", "3");
+        let missing = pack.get_missing(&vec![StoreKey::from(&not)]).unwrap();
+        assert_eq!(missing, vec![StoreKey::from(not)]);
+    }
+
+    #[test]
+    fn test_get_meta() {
+        let tempdir = TempDir::new().unwrap();
+
+        let revisions = vec![
+            (
+                Delta {
+                    data: Bytes::from(&[1, 2, 3, 4][..]),
                     base: Some(key("a", "1")),
                     key: key("a", "2"),
                 },
@@ -618,6 +1502,333 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn test_contains_all() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let revisions = vec![
+            (
+                Delta {
+                    data: Bytes::from(&[1, 2, 3][..]),
+                    base: None,
+                    key: key("a", "1"),
+                },
+                Default::default(),
+            ),
+            (
+                Delta {
+                    data: Bytes::from(&[4, 5][..]),
+                    base: None,
+                    key: key("b", "2"),
+                },
+                Default::default(),
+            ),
+        ];
+        let pack = make_datapack(&tempdir, &revisions);
+
+        assert!(pack.contains_all(&[])?);
+        assert!(pack.contains_all(&[key("a", "1"), key("b", "2")])?);
+        assert!(!pack.contains_all(&[key("a", "1"), key("c", "3")])?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_entry() -> Result<()> {
+        let tempdir = TempDir::new()?;
+
+        let delta = Delta {
+            data: Bytes::from(&[1, 2, 3, 4][..]),
+            base: Some(key("a", "1")),
+            key: key("a", "2"),
+        };
+        let meta = Metadata {
+            flags: Some(1),
+            size: Some(4),
+        };
+        let pack = make_datapack(&tempdir, &vec![(delta.clone(), meta.clone())]);
+
+        let raw = pack.raw_entry(&delta.key)?.unwrap();
+        assert_eq!(raw.delta_base, Some(key("a", "1").hgid));
+        assert_eq!(raw.metadata, meta);
+        assert_eq!(raw.compression, DataPackCompression::PyFrame);
+        assert_eq!(
+            raw.compression.decompress(raw.compressed_delta)?,
+            vec![1, 2, 3, 4]
+        );
+
+        assert!(pack.raw_entry(&key("b", "3"))?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_by_index() -> Result<()> {
+        let tempdir = TempDir::new()?;
+
+        let revisions = ["3", "1", "2"]
+            .iter()
+            .map(|node| {
+                (
+                    Delta {
+                        data: Bytes::copy_from_slice(node.as_bytes()),
+                        base: None,
+                        key: key("a", node),
+                    },
+                    Default::default(),
+                )
+            })
+            .collect();
+        let pack = make_datapack(&tempdir, &revisions);
+
+        let entries = pack
+            .iter_by_index()
+            .map(|entry| {
+                let entry = entry?;
+                Ok((*entry.hgid(), entry.delta()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            entries,
+            vec![
+                (key("a", "1").hgid, Bytes::from(&b"1"[..])),
+                (key("a", "2").hgid, Bytes::from(&b"2"[..])),
+                (key("a", "3").hgid, Bytes::from(&b"3"[..])),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_fulltext() -> Result<()> {
+        let tempdir = TempDir::new()?;
+
+        let revisions = vec![
+            (
+                Delta {
+                    data: Bytes::from(&b"base"[..]),
+                    base: None,
+                    key: key("a", "1"),
+                },
+                Default::default(),
+            ),
+            (
+                Delta {
+                    // Replace the 4 bytes of the base with "child".
+                    data: Bytes::from(&b"\0\0\0\0\0\0\0\x04\0\0\0\x05child"[..]),
+                    base: Some(key("a", "1")),
+                    key: key("a", "2"),
+                },
+                Default::default(),
+            ),
+        ];
+        let pack = make_datapack(&tempdir, &revisions);
+
+        assert!(revisions[0].0.is_fulltext());
+        assert!(!revisions[1].0.is_fulltext());
+        assert_eq!(pack.get_fulltext(&key("a", "1"))?, Some(b"base".to_vec()));
+        assert_eq!(pack.get_fulltext(&key("a", "2"))?, Some(b"child".to_vec()));
+        assert_eq!(pack.get_fulltext(&key("a", "3"))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_delta_chain_with_fallback() -> Result<()> {
+        let base_dir = TempDir::new()?;
+        let base = (
+            Delta {
+                data: Bytes::from(&b"base"[..]),
+                base: None,
+                key: key("a", "1"),
+            },
+            Default::default(),
+        );
+        let base_pack = make_datapack(&base_dir, &vec![base.clone()]);
+
+        let tempdir = TempDir::new()?;
+        let child = (
+            Delta {
+                // Replace the 4 bytes of the base with "child".
+                data: Bytes::from(&b"\0\0\0\0\0\0\0\x04\0\0\0\x05child"[..]),
+                base: Some(key("a", "1")),
+                key: key("a", "2"),
+            },
+            Default::default(),
+        );
+        let pack = make_datapack(&tempdir, &vec![child.clone()]);
+
+        let err = pack
+            .get_delta_chain_with_fallback(&child.0.key, None)
+            .unwrap_err();
+        assert!(err.to_string().contains(&key("a", "1").to_string()));
+
+        let chain = pack
+            .get_delta_chain_with_fallback(&child.0.key, Some(&base_pack))?
+            .unwrap();
+        assert_eq!(chain, vec![child.0.clone(), base.0.clone()]);
+
+        // Complete chains don't need the fallback.
+        assert_eq!(
+            base_pack.get_delta_chain_with_fallback(&base.0.key, None)?,
+            Some(vec![base.0])
+        );
+        assert_eq!(
+            pack.get_delta_chain_with_fallback(&key("a", "3"), None)?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_excluding() -> Result<()> {
+        let tempdir = TempDir::new()?;
+
+        let revisions = vec![
+            (
+                Delta {
+                    data: Bytes::from(&b"base"[..]),
+                    base: None,
+                    key: key("a", "1"),
+                },
+                Default::default(),
+            ),
+            (
+                Delta {
+                    // Replace the 4 bytes of the base with "child".
+                    data: Bytes::from(&b"\0\0\0\0\0\0\0\x04\0\0\0\x05child"[..]),
+                    base: Some(key("a", "1")),
+                    key: key("a", "2"),
+                },
+                Default::default(),
+            ),
+            (
+                Delta {
+                    // Replace the 5 bytes of the child with "grand".
+                    data: Bytes::from(&b"\0\0\0\0\0\0\0\x05\0\0\0\x05grand"[..]),
+                    base: Some(key("a", "2")),
+                    key: key("a", "3"),
+                },
+                Default::default(),
+            ),
+            (
+                Delta {
+                    data: Bytes::from(&b"other"[..]),
+                    base: None,
+                    key: key("b", "4"),
+                },
+                Default::default(),
+            ),
+        ];
+        let pack = make_datapack(&tempdir, &revisions);
+
+        let outdir = TempDir::new()?;
+        let exclude = vec![key("a", "1").hgid].into_iter().collect();
+        let path = pack.rewrite_excluding(outdir.path(), &exclude)?;
+        let rewritten = DataPack::new(&path, ExtStoredPolicy::Use)?;
+
+        assert_eq!(rewritten.get_fulltext(&key("a", "1"))?, None);
+        assert_eq!(
+            rewritten.get_delta_chain(&key("a", "2"))?,
+            Some(vec![Delta {
+                data: Bytes::from(&b"child"[..]),
+                base: None,
+                key: key("a", "2"),
+            }])
+        );
+        // Deltas against kept entries are kept.
+        assert_eq!(
+            rewritten.raw_entry(&key("a", "3"))?.unwrap().delta_base,
+            Some(key("a", "2").hgid)
+        );
+        assert_eq!(
+            rewritten.get_fulltext(&key("a", "3"))?,
+            Some(b"grand".to_vec())
+        );
+        assert_eq!(
+            rewritten.get_fulltext(&key("b", "4"))?,
+            Some(b"other".to_vec())
+        );
+
+        let exclude = revisions.iter().map(|(d, _)| d.key.hgid).collect();
+        assert!(pack.rewrite_excluding(outdir.path(), &exclude).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_entry() -> Result<()> {
+        let tempdir = TempDir::new()?;
+
+        let base = Key::new(
+            repo_path_buf("a"),
+            HgId::from_content(b"base", Parents::None),
+        );
+        let child_parents = Parents::One(base.hgid.clone());
+        let child = Key::new(
+            repo_path_buf("a"),
+            HgId::from_content(b"child", child_parents.clone()),
+        );
+        let revisions = vec![
+            (
+                Delta {
+                    data: Bytes::from(&b"base"[..]),
+                    base: None,
+                    key: base.clone(),
+                },
+                Default::default(),
+            ),
+            (
+                Delta {
+                    // Replace the 4 bytes of the base with "child".
+                    data: Bytes::from(&b"\0\0\0\0\0\0\0\x04\0\0\0\x05child"[..]),
+                    base: Some(base.clone()),
+                    key: child.clone(),
+                },
+                Default::default(),
+            ),
+            (
+                Delta {
+                    data: Bytes::from(&b"corrupt"[..]),
+                    base: None,
+                    key: key("b", "1"),
+                },
+                Default::default(),
+            ),
+        ];
+        let pack = make_datapack(&tempdir, &revisions);
+
+        pack.verify_entry(&base, Parents::None)?;
+        pack.verify_entry(&child, child_parents)?;
+
+        let err = pack.verify_entry(&child, Parents::None).unwrap_err();
+        assert!(err.is::<MismatchedEntryHash>());
+        let err = pack
+            .verify_entry(&key("b", "1"), Parents::None)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MismatchedEntryHash>().unwrap().actual,
+            HgId::from_content(b"corrupt", Parents::None)
+        );
+        assert!(pack.verify_entry(&key("c", "1"), Parents::None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_compression_ratio() -> Result<()> {
+        let tempdir = TempDir::new()?;
+
+        let delta = Delta {
+            data: Bytes::from(vec![0; 1000]),
+            base: None,
+            key: key("a", "1"),
+        };
+        let pack = make_datapack(&tempdir, &vec![(delta, Default::default())]);
+
+        let entry = pack.read_entry(1)?;
+        assert!(entry.compressed_len() < 1000);
+        assert_eq!(
+            entry.compression_ratio()?,
+            1000.0 / entry.compressed_len() as f64
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_get_delta_chain_multiple() {
         let tempdir = TempDir::new().unwrap();
@@ -667,6 +1878,78 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn test_get_delta_chain_stream() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let revisions = vec![
+            (
+                Delta {
+                    data: Bytes::from(&[1, 2, 3, 4][..]),
+                    base: None,
+                    key: key("a", "1"),
+                },
+                Default::default(),
+            ),
+            (
+                Delta {
+                    data: Bytes::from(&[5, 6][..]),
+                    base: Some(key("a", "1")),
+                    key: key("a", "2"),
+                },
+                Default::default(),
+            ),
+        ];
+        let pack = make_datapack(&tempdir, &revisions);
+
+        let mut deltas = pack.get_delta_chain_stream(&revisions[1].0.key);
+        assert_eq!(deltas.next().transpose()?, Some(revisions[1].0.clone()));
+        assert_eq!(deltas.next().transpose()?, Some(revisions[0].0.clone()));
+        assert!(deltas.next().is_none());
+
+        assert!(pack.get_delta_chain_stream(&key("a", "3")).next().is_none());
+        Ok(())
+    }
+
+    fn counter_value(name: &str) -> usize {
+        hg_metrics::summarize()
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map_or(0, |(_, value)| value)
+    }
+
+    #[test]
+    fn test_delta_chain_depth_counters() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let revisions = vec![
+            (
+                Delta {
+                    data: Bytes::from(&[1, 2, 3, 4][..]),
+                    base: None,
+                    key: key("a", "1"),
+                },
+                Default::default(),
+            ),
+            (
+                Delta {
+                    data: Bytes::from(&[1, 2, 3, 4][..]),
+                    base: Some(key("a", "1")),
+                    key: key("a", "2"),
+                },
+                Default::default(),
+            ),
+        ];
+        let pack = make_datapack(&tempdir, &revisions);
+
+        let chains = counter_value(DATAPACK_DELTA_CHAINS.chains);
+        let depth = counter_value(DATAPACK_DELTA_CHAINS.depth);
+        pack.get_delta_chain(&revisions[1].0.key)?;
+
+        // Other tests may read packs concurrently, so only check a lower bound.
+        assert!(counter_value(DATAPACK_DELTA_CHAINS.chains) >= chains + 1);
+        assert!(counter_value(DATAPACK_DELTA_CHAINS.depth) >= depth + 2);
+        Ok(())
+    }
+
     #[test]
     fn test_iter() {
         let tempdir = TempDir::new().unwrap();
@@ -703,6 +1986,179 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_iter_sequential() {
+        let tempdir = TempDir::new().unwrap();
+
+        let revisions = vec![
+            (
+                Delta {
+                    data: Bytes::from(&[1, 2, 3, 4][..]),
+                    base: Some(key("a", "1")),
+                    key: key("a", "2"),
+                },
+                Default::default(),
+            ),
+            (
+                Delta {
+                    data: Bytes::from(&[1, 2, 3, 4][..]),
+                    base: Some(key("a", "3")),
+                    key: key("a", "4"),
+                },
+                Default::default(),
+            ),
+        ];
+
+        let pack = make_datapack(&tempdir, &revisions);
+        assert_eq!(
+            pack.iter_sequential()
+                .collect::<Result<Vec<Key>>>()
+                .unwrap(),
+            revisions
+                .iter()
+                .map(|d| d.0.key.clone())
+                .collect::<Vec<Key>>()
+        );
+    }
+
+    #[test]
+    fn test_diff() -> Result<()> {
+        let base = Delta {
+            data: Bytes::from(&b"base"[..]),
+            base: None,
+            key: key("a", "1"),
+        };
+        let fulltext = Delta {
+            data: Bytes::from(&b"text"[..]),
+            base: None,
+            key: key("a", "2"),
+        };
+        // The same content as `fulltext`, stored as a delta against `base`.
+        let delta = Delta {
+            data: Bytes::from(&b"\0\0\0\0\0\0\0\x04\0\0\0\x04text"[..]),
+            base: Some(base.key.clone()),
+            key: key("a", "2"),
+        };
+        let other = Delta {
+            data: Bytes::from(&b"other"[..]),
+            base: None,
+            key: key("b", "3"),
+        };
+
+        let tempdir1 = TempDir::new()?;
+        let pack1 = make_datapack(
+            &tempdir1,
+            &vec![
+                (base.clone(), Default::default()),
+                (fulltext.clone(), Default::default()),
+            ],
+        );
+        let tempdir2 = TempDir::new()?;
+        let pack2 = make_datapack(
+            &tempdir2,
+            &vec![
+                (base.clone(), Default::default()),
+                (delta.clone(), Default::default()),
+            ],
+        );
+        assert!(pack1.content_equals(&pack2)?);
+
+        let changed = Delta {
+            data: Bytes::from(&b"changed"[..]),
+            ..fulltext.clone()
+        };
+        let tempdir3 = TempDir::new()?;
+        let pack3 = make_datapack(
+            &tempdir3,
+            &vec![
+                (changed.clone(), Default::default()),
+                (other.clone(), Default::default()),
+            ],
+        );
+        assert!(!pack1.content_equals(&pack3)?);
+        assert_eq!(
+            pack1.diff(&pack3)?,
+            PackDiff {
+                added: vec![other.key],
+                removed: vec![base.key],
+                changed: vec![fulltext.key],
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mismatched_index() -> Result<()> {
+        let revision = |path: &str, node: &str| {
+            (
+                Delta {
+                    data: Bytes::from(node.as_bytes().to_vec()),
+                    base: None,
+                    key: key(path, node),
+                },
+                Default::default(),
+            )
+        };
+
+        let tempdir1 = TempDir::new()?;
+        let pack1 = make_datapack(&tempdir1, &vec![revision("a", "1"), revision("b", "2")]);
+        let tempdir2 = TempDir::new()?;
+        let pack2 = make_datapack(&tempdir2, &vec![revision("c", "3")]);
+
+        // Pair the first pack with the index of the second one.
+        let tempdir3 = TempDir::new()?;
+        let base_path = tempdir3.path().join(pack1.base_path().file_name().unwrap());
+        fs::copy(pack1.pack_path(), base_path.with_extension("datapack"))?;
+        fs::copy(pack2.index_path(), base_path.with_extension("dataidx"))?;
+
+        let err = DataPack::new(&base_path, ExtStoredPolicy::Use)
+            .err()
+            .expect("mismatched index should be detected");
+        let mismatch = err.downcast_ref::<MismatchedDataIndex>().unwrap();
+        assert_eq!(mismatch.index_path, base_path.with_extension("dataidx"));
+
+        // The original pairs still open.
+        DataPack::new(pack1.base_path(), ExtStoredPolicy::Use)?;
+        DataPack::new(pack2.base_path(), ExtStoredPolicy::Use)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_keys() -> Result<()> {
+        let revision = |path: &str, node: &str| {
+            (
+                Delta {
+                    data: Bytes::from(node.as_bytes().to_vec()),
+                    base: None,
+                    key: key(path, node),
+                },
+                Default::default(),
+            )
+        };
+
+        let tempdir1 = TempDir::new()?;
+        let pack1 = make_datapack(
+            &tempdir1,
+            &vec![revision("a", "1"), revision("a", "2"), revision("b", "3")],
+        );
+        let tempdir2 = TempDir::new()?;
+        let pack2 = make_datapack(
+            &tempdir2,
+            &vec![revision("a", "2"), revision("b", "3"), revision("c", "4")],
+        );
+
+        let mut expected = vec![key("a", "2"), key("b", "3")];
+        expected.sort_by(|a, b| a.hgid.cmp(&b.hgid));
+        assert_eq!(shared_keys(&pack1, &pack2)?, expected);
+        assert_eq!(shared_keys(&pack2, &pack1)?, expected);
+
+        let tempdir3 = TempDir::new()?;
+        let pack3 = make_datapack(&tempdir3, &vec![revision("c", "4")]);
+        assert_eq!(shared_keys(&pack1, &pack3)?, vec![]);
+        Ok(())
+    }
+
     #[test]
     fn test_delete() {
         let tempdir = TempDir::new().unwrap();
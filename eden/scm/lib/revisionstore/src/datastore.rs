@@ -13,6 +13,7 @@ use std::str::{self};
 use std::sync::Arc;
 
 use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
 use edenapi_types::FileEntry;
 use edenapi_types::TreeEntry;
@@ -41,6 +42,13 @@ pub struct Delta {
     pub key: Key,
 }
 
+impl Delta {
+    /// Whether `data` is the fulltext of `key` rather than a delta against `base`.
+    pub fn is_fulltext(&self) -> bool {
+        self.base.as_ref().map_or(true, |base| base.hgid.is_null())
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum StoreResult<T> {
     Found(T),
@@ -60,6 +68,12 @@ pub trait HgIdDataStore: LocalStore + Send + Sync {
     fn get(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>>;
     fn get_meta(&self, key: StoreKey) -> Result<StoreResult<Metadata>>;
     fn refresh(&self) -> Result<()>;
+
+    /// Fulltext of `key`, with its whole delta chain resolved and applied, or `None` if this
+    /// store doesn't have it.
+    fn get_fulltext(&self, key: &Key) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(StoreKey::hgid(key.clone()))?.into())
+    }
 }
 
 /// The `RemoteDataStore` trait indicates that data can fetched over the network. Care must be
@@ -81,6 +95,17 @@ pub trait HgIdMutableDeltaStore: HgIdDataStore + Send + Sync {
     fn add(&self, delta: &Delta, metadata: &Metadata) -> Result<()>;
     fn flush(&self) -> Result<Option<Vec<PathBuf>>>;
 
+    /// Add all the entries to the store, stopping at the first one that fails.
+    ///
+    /// Stores that need to lock on every `add` should override this to only take the lock once.
+    fn add_many(&self, entries: &[(Delta, Metadata)]) -> Result<()> {
+        for (i, (delta, metadata)) in entries.iter().enumerate() {
+            self.add(delta, metadata)
+                .with_context(|| format!("failed to add entry {}", i))?;
+        }
+        Ok(())
+    }
+
     fn add_file(&self, entry: &FileEntry) -> Result<()> {
         let delta = Delta {
             data: entry.data()?.into(),
@@ -182,6 +207,10 @@ impl<T: HgIdMutableDeltaStore + ?Sized, U: Deref<Target = T> + Send + Sync> HgId
     fn flush(&self) -> Result<Option<Vec<PathBuf>>> {
         T::flush(self)
     }
+
+    fn add_many(&self, entries: &[(Delta, Metadata)]) -> Result<()> {
+        T::add_many(self, entries)
+    }
 }
 
 /// Implement `ContentDataStore` for all types that can be `Deref` into a `ContentDataStore`.
@@ -347,6 +376,20 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_delta_is_fulltext() {
+        let mut delta = Delta {
+            data: Bytes::from(&b"data"[..]),
+            base: None,
+            key: key("a", "2"),
+        };
+        assert!(delta.is_fulltext());
+        delta.base = Some(null_key("a"));
+        assert!(delta.is_fulltext());
+        delta.base = Some(key("a", "1"));
+        assert!(!delta.is_fulltext());
+    }
+
     #[test]
     fn test_strip_separate_metadata() -> Result<()> {
         let key = key("foo/bar/baz", "1234");
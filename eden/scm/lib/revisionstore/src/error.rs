@@ -20,6 +20,10 @@ use url::Url;
 #[error("Empty Mutable Pack")]
 pub struct EmptyMutablePack;
 
+#[derive(Debug, Error)]
+#[error("Mutable Pack write was cancelled")]
+pub struct CancelledMutablePack;
+
 #[derive(Error, Debug)]
 #[error("Fetch failed: {} {}", .url, .method)]
 pub struct FetchError {
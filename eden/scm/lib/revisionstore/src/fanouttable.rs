@@ -27,7 +27,7 @@ const LARGE_RAW_SIZE: usize = 262144; // LARGE_FANOUT_LENGTH * sizeof(u32)
 #[error("Fanout Table Error: {0:?}")]
 struct FanoutTableError(String);
 
-fn get_fanout_index(table_size: usize, hgid: &HgId) -> Result<u64> {
+pub(crate) fn get_fanout_index(table_size: usize, hgid: &HgId) -> Result<u64> {
     let mut cursor = Cursor::new(hgid.as_ref());
     match table_size {
         SMALL_RAW_SIZE => Ok(cursor.read_u8()? as u64),
@@ -120,8 +120,15 @@ impl FanoutTable {
             offset += entry_size as u32;
         }
 
-        // Serialize the fanout table. For fanout keys that have no value, use the previous valid
-        // value.
+        Self::write_offsets(writer, fanout_table)
+    }
+
+    /// Serializes a fanout table given the offset of the first entry for each prefix, if any.
+    pub(crate) fn write_offsets<T: Write>(
+        writer: &mut T,
+        fanout_table: Vec<Option<u32>>,
+    ) -> Result<()> {
+        // For fanout keys that have no value, use the previous valid value.
         let mut last_offset = 0;
         for offset in fanout_table.into_iter() {
             let offset = match offset {
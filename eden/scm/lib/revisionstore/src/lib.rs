@@ -159,6 +159,7 @@ pub mod packstore;
 pub mod packwriter;
 pub mod scmstore;
 pub mod trait_impls;
+pub mod uniondatapack;
 pub mod uniondatastore;
 pub mod unionhistorystore;
 pub mod util;
@@ -167,9 +168,19 @@ pub use revisionstore_types::*;
 
 pub use crate::contentstore::ContentStore;
 pub use crate::contentstore::ContentStoreBuilder;
+pub use crate::dataindex::DataIndexWriter;
+pub use crate::dataindex::DeltaLocation;
+pub use crate::datapack::shared_keys;
 pub use crate::datapack::DataEntry;
 pub use crate::datapack::DataPack;
+pub use crate::datapack::DataPackCompression;
+pub use crate::datapack::DataPackHash;
 pub use crate::datapack::DataPackVersion;
+pub use crate::datapack::DeltaChainIter;
+pub use crate::datapack::MismatchedDataIndex;
+pub use crate::datapack::MismatchedEntryHash;
+pub use crate::datapack::RawEntry;
+pub use crate::datapack::UnsupportedDataPackVersion;
 pub use crate::datastore::ContentDataStore;
 pub use crate::datastore::ContentMetadata;
 pub use crate::datastore::Delta;
@@ -198,7 +209,12 @@ pub use crate::metadatastore::MetadataStore;
 pub use crate::metadatastore::MetadataStoreBuilder;
 pub use crate::multiplexstore::MultiplexDeltaStore;
 pub use crate::multiplexstore::MultiplexHgIdHistoryStore;
+pub use crate::mutabledatapack::cleanup_stale_temps;
+#[cfg(feature = "temp-file-registry")]
+pub use crate::mutabledatapack::orphaned_temp_files;
+pub use crate::mutabledatapack::recover_from_temp;
 pub use crate::mutabledatapack::MutableDataPack;
+pub use crate::mutabledatapack::PackStats;
 pub use crate::mutablehistorypack::MutableHistoryPack;
 pub use crate::packstore::CorruptionPolicy;
 pub use crate::packstore::DataPackStore;
@@ -208,12 +224,15 @@ pub use crate::packstore::MutableHistoryPackStore;
 pub use crate::redacted::redact_if_needed;
 pub use crate::remotestore::HgIdRemoteStore;
 pub use crate::repack::repack;
+pub use crate::repack::split_datapack;
 pub use crate::repack::RepackKind;
 pub use crate::repack::RepackLocation;
 pub use crate::repack::Repackable;
 pub use crate::repack::ToKeys;
 pub use crate::types::ContentHash;
 pub use crate::types::StoreKey;
+pub use crate::uniondatapack::store_get_missing;
+pub use crate::uniondatapack::UnionDataPack;
 pub use crate::uniondatastore::UnionHgIdDataStore;
 pub use crate::util::Error;
 
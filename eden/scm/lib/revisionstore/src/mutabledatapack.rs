@@ -6,6 +6,12 @@
  */
 
 use std::collections::HashMap;
+#[cfg(feature = "temp-file-registry")]
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
@@ -13,49 +19,468 @@ use std::io::Write;
 use std::mem::replace;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
 use std::u16;
 
 use anyhow::format_err;
+use anyhow::Context;
 use anyhow::Error;
 use anyhow::Result;
 use byteorder::BigEndian;
+use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
-use lz4_pyframe::compress;
 use mpatch::mpatch::get_full_text;
+#[cfg(feature = "temp-file-registry")]
+use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 use sha1::Digest;
 use sha1::Sha1;
+use sha2::Sha256;
 use tempfile::Builder;
 use tempfile::NamedTempFile;
 use thiserror::Error;
+use tracing::info;
+use tracing::warn;
 use types::HgId;
 use types::Key;
 
 use crate::dataindex::DataIndex;
 use crate::dataindex::DeltaLocation;
 use crate::datapack::DataEntry;
+use crate::datapack::DataPack;
+use crate::datapack::DataPackCompression;
+use crate::datapack::DataPackHash;
 use crate::datapack::DataPackVersion;
+use crate::datapack::MUTABLE_DATAPACK_DELTA_CHAINS;
 use crate::datastore::Delta;
 use crate::datastore::HgIdDataStore;
 use crate::datastore::HgIdMutableDeltaStore;
 use crate::datastore::Metadata;
 use crate::datastore::StoreResult;
+use crate::error::CancelledMutablePack;
 use crate::error::EmptyMutablePack;
+use crate::localstore::ExtStoredPolicy;
 use crate::localstore::LocalStore;
+use crate::mutablepack::persist_pack;
 use crate::mutablepack::MutablePack;
 use crate::packwriter::PackWriter;
+use crate::repack::Repackable;
+use crate::repack::ToKeys;
 use crate::types::StoreKey;
 
+/// Size of the chunks the compressed deltas are written in.
+const WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Default prefix of the temporary files of a `MutableDataPack`, see
+/// `MutableDataPack::with_temp_prefix`.
+pub const DEFAULT_TEMP_PREFIX: &str = ".tmp-datapack-";
+
+/// Temporary files of the live `MutableDataPack`s of this process, see `orphaned_temp_files`.
+#[cfg(feature = "temp-file-registry")]
+static LIVE_TEMP_FILES: Lazy<Mutex<HashSet<PathBuf>>> = Lazy::new(Default::default);
+
+/// Registration of a temporary file in `LIVE_TEMP_FILES`, removed when dropped.
+///
+/// Without the `temp-file-registry` feature, nothing is registered.
+struct TempFileRegistration {
+    #[cfg(feature = "temp-file-registry")]
+    path: Option<PathBuf>,
+}
+
+impl TempFileRegistration {
+    /// Creates a temporary file in `dir` and registers it.
+    fn create_temp_file(
+        dir: &Path,
+        prefix: &str,
+        append: bool,
+    ) -> Result<(NamedTempFile, TempFileRegistration)> {
+        Self::create(|| {
+            let file = Builder::new()
+                .prefix(prefix)
+                .append(append)
+                .tempfile_in(dir)?;
+            let path = file.path().to_path_buf();
+            Ok((file, path))
+        })
+    }
+
+    /// Creates a file with `create`, which also returns its path, and registers it.
+    ///
+    /// The registry stays locked while the file is created, so that `orphaned_temp_files` never
+    /// sees it before it is registered.
+    fn create<T>(create: impl FnOnce() -> Result<(T, PathBuf)>) -> Result<(T, Self)> {
+        #[cfg(feature = "temp-file-registry")]
+        let mut live = LIVE_TEMP_FILES.lock();
+        let (file, path) = create()?;
+
+        #[cfg(feature = "temp-file-registry")]
+        {
+            // Files that can't be canonicalized are not registered, and would be reported as
+            // orphaned.
+            let path = path.canonicalize().ok();
+            if let Some(path) = path.as_ref() {
+                live.insert(path.clone());
+            }
+            Ok((file, TempFileRegistration { path }))
+        }
+        #[cfg(not(feature = "temp-file-registry"))]
+        {
+            let _ = path;
+            Ok((file, TempFileRegistration {}))
+        }
+    }
+}
+
+impl Drop for TempFileRegistration {
+    fn drop(&mut self) {
+        #[cfg(feature = "temp-file-registry")]
+        if let Some(path) = self.path.as_ref() {
+            LIVE_TEMP_FILES.lock().remove(path);
+        }
+    }
+}
+
+/// Lists the temporary files of `dir` named with `prefix` that are not owned by a live
+/// `MutableDataPack` of this process.
+///
+/// These are left behind by writers that were killed before flushing, and can be removed by a
+/// janitor. Only the writers of this process are known, so the directory must not be written to
+/// by other processes, or the files must be old enough for their writer to be dead.
+#[cfg(feature = "temp-file-registry")]
+pub fn orphaned_temp_files(dir: impl AsRef<Path>, prefix: &str) -> Result<Vec<PathBuf>> {
+    let dir = dir.as_ref().canonicalize()?;
+    // Hold the lock while listing, so that files created concurrently are either not listed or
+    // already registered.
+    let live = LIVE_TEMP_FILES.lock();
+    let mut orphaned = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        let is_temp = path
+            .file_name()
+            .map_or(false, |name| name.to_string_lossy().starts_with(prefix));
+        if is_temp && !live.contains(&path) {
+            orphaned.push(path);
+        }
+    }
+    orphaned.sort();
+    Ok(orphaned)
+}
+
+/// Removes the temporary files of `dir` named with `DEFAULT_TEMP_PREFIX` that were last modified
+/// more than `older_than` ago, and returns their paths.
+///
+/// Writers keep modifying their temporary files until they flush, so a file that hasn't been
+/// modified for long enough is assumed to be left behind by a writer that died. `older_than` must
+/// be longer than the time a live writer may stay idle. With the `temp-file-registry` feature, the
+/// files of the live writers of this process are kept regardless of their age.
+pub fn cleanup_stale_temps(dir: &Path, older_than: Duration) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let now = SystemTime::now();
+    let mut removed = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let is_temp = path.file_name().map_or(false, |name| {
+            name.to_string_lossy().starts_with(DEFAULT_TEMP_PREFIX)
+        });
+        if !is_temp {
+            continue;
+        }
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            // Removed concurrently, e.g. by its writer flushing.
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        // Files modified in the future, e.g. due to clock skew, are not stale.
+        let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+        if age <= older_than {
+            continue;
+        }
+        #[cfg(feature = "temp-file-registry")]
+        {
+            let live = LIVE_TEMP_FILES.lock();
+            if path
+                .canonicalize()
+                .map_or(false, |path| live.contains(&path))
+            {
+                continue;
+            }
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => removed.push(path),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    removed.sort();
+    Ok(removed)
+}
+
+/// Suffix appended to the path of the data temporary file of a `MutableDataPack` to name its
+/// recovery manifest, see `MutableDataPack::with_recovery_manifest`.
+pub const RECOVERY_MANIFEST_SUFFIX: &str = ".manifest";
+
+/// Length of a record of a recovery manifest:
+/// hgid(20) + delta base hgid(20) + offset(8) + size(8)
+const MANIFEST_RECORD_LEN: usize = 56;
+
+/// Record of the entries written to the data temporary file of a `MutableDataPack`, from which
+/// `recover_from_temp` can complete the pack if its writer dies before flushing it.
+///
+/// The manifest is removed when dropped, as the data temporary file is.
+struct RecoveryManifest {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    _registration: TempFileRegistration,
+}
+
+impl RecoveryManifest {
+    fn create(data_path: &Path, hash: DataPackHash) -> Result<Self> {
+        let mut path = data_path.as_os_str().to_owned();
+        path.push(RECOVERY_MANIFEST_SUFFIX);
+        let path = PathBuf::from(path);
+
+        let (file, registration) =
+            TempFileRegistration::create(|| Ok((File::create(&path)?, path.clone())))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_u8(match hash {
+            DataPackHash::Sha1 => 0,
+            DataPackHash::Sha256 => 1,
+        })?;
+        Ok(Self {
+            path,
+            writer: Some(writer),
+            _registration: registration,
+        })
+    }
+
+    fn record(&mut self, hgid: &HgId, location: &DeltaLocation) -> Result<()> {
+        let writer = self.writer.as_mut().unwrap();
+        writer.write_all(hgid.as_ref())?;
+        writer.write_all(
+            location
+                .delta_base
+                .as_ref()
+                .unwrap_or(HgId::null_id())
+                .as_ref(),
+        )?;
+        writer.write_u64::<BigEndian>(location.offset)?;
+        writer.write_u64::<BigEndian>(location.size)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.as_mut().unwrap().flush()?)
+    }
+}
+
+impl Drop for RecoveryManifest {
+    fn drop(&mut self) {
+        // Close the file first, open files can't be removed on Windows.
+        self.writer.take();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Completes the pack of a `MutableDataPack` whose writer died before flushing it, from its data
+/// temporary file and its recovery manifest, see `MutableDataPack::with_recovery_manifest`.
+///
+/// Entries whose record didn't reach the manifest, or whose data didn't reach the data file, are
+/// dropped. The pack is written to the directory of `data_temp`, after which both temporary files
+/// are removed. Returns the path of the pack, without extension. Fails if the manifest has a
+/// record that can't describe an entry of the data file, e.g. because its end overflows.
+pub fn recover_from_temp(data_temp: &Path, manifest: &Path) -> Result<PathBuf> {
+    let records = fs::read(manifest)?;
+    let (hash, records) = match records.split_first() {
+        Some((0, records)) => (DataPackHash::Sha1, records),
+        Some((1, records)) => (DataPackHash::Sha256, records),
+        _ => {
+            return Err(MutableDataPackError(format!(
+                "invalid recovery manifest '{:?}'",
+                manifest
+            ))
+            .into());
+        }
+    };
+
+    let mut data = File::open(data_temp)?;
+    let data_len = data.metadata()?.len();
+    let mut end = 1;
+    let mut mem_index = HashMap::new();
+    for mut record in records.chunks_exact(MANIFEST_RECORD_LEN) {
+        let hgid = HgId::from_slice(&record[..20])?;
+        let delta_base = HgId::from_slice(&record[20..40])?;
+        record = &record[40..];
+        let offset = record.read_u64::<BigEndian>()?;
+        let size = record.read_u64::<BigEndian>()?;
+        let entry_end = match offset.checked_add(size) {
+            // The first byte of the data file is its version.
+            Some(entry_end) if offset >= 1 => entry_end,
+            _ => {
+                return Err(MutableDataPackError(format!(
+                    "invalid entry of {} bytes at offset {} in recovery manifest '{:?}'",
+                    size, offset, manifest
+                ))
+                .into());
+            }
+        };
+        // Entries are appended, so none of the next ones made it to the data file either.
+        if entry_end > data_len {
+            break;
+        }
+        end = end.max(entry_end);
+        mem_index.insert(
+            hgid,
+            DeltaLocation {
+                delta_base: if delta_base.is_null() {
+                    None
+                } else {
+                    Some(delta_base)
+                },
+                offset,
+                size,
+            },
+        );
+    }
+    if mem_index.is_empty() {
+        return Err(EmptyMutablePack.into());
+    }
+
+    let dir = data_temp.parent().ok_or_else(|| {
+        MutableDataPackError(format!("'{:?}' has no parent directory", data_temp))
+    })?;
+    let mut hasher = PackHasher::new(hash);
+    let mut data_file = Builder::new()
+        .prefix(DEFAULT_TEMP_PREFIX)
+        .tempfile_in(dir)?;
+    // Stream the recovered entries, the data file can be much larger than memory.
+    let mut buf = vec![0; 64 * 1024];
+    let mut copied = 0;
+    while copied < end {
+        let len = buf.len().min((end - copied) as usize);
+        let len = data.read(&mut buf[..len])?;
+        if len == 0 {
+            return Err(MutableDataPackError(format!(
+                "'{:?}' was truncated while being recovered",
+                data_temp
+            ))
+            .into());
+        }
+        hasher.input(&buf[..len]);
+        data_file.write_all(&buf[..len])?;
+        copied += len as u64;
+    }
+    // Close the file first, open files can't be removed on Windows.
+    drop(data);
+    let mut index_file = PackWriter::new(
+        Builder::new()
+            .prefix(DEFAULT_TEMP_PREFIX)
+            .tempfile_in(dir)?,
+    );
+    DataIndex::write(&mut index_file, &mem_index)?;
+
+    let base_path = dir.join(hasher.result_hex());
+    persist_pack(data_file, index_file.into_inner()?, &base_path, "data")?;
+    fs::remove_file(data_temp)?;
+    fs::remove_file(manifest)?;
+    Ok(base_path)
+}
+
+/// Cleanups of a pack being closed, that must only happen once its files have been persisted.
+struct PersistGuard {
+    _registrations: [TempFileRegistration; 2],
+    _manifest: Option<RecoveryManifest>,
+}
+
 struct MutableDataPackInner {
     dir: PathBuf,
+    temp_prefix: String,
+    compression: DataPackCompression,
     data_file: PackWriter<NamedTempFile>,
+    data_registration: TempFileRegistration,
+    manifest: Option<RecoveryManifest>,
     mem_index: HashMap<HgId, DeltaLocation>,
-    hasher: Sha1,
+    hasher: PackHasher,
+    cancelled: Option<Arc<AtomicBool>>,
+    stats: PackStats,
+}
+
+#[derive(Clone)]
+pub(crate) enum PackHasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl PackHasher {
+    pub(crate) fn new(hash: DataPackHash) -> Self {
+        match hash {
+            DataPackHash::Sha1 => PackHasher::Sha1(Sha1::new()),
+            DataPackHash::Sha256 => PackHasher::Sha256(Sha256::new()),
+        }
+    }
+
+    pub(crate) fn input(&mut self, data: &[u8]) {
+        match self {
+            PackHasher::Sha1(hasher) => hasher.input(data),
+            PackHasher::Sha256(hasher) => hasher.input(data),
+        }
+    }
+
+    pub(crate) fn result_hex(self) -> String {
+        match self {
+            PackHasher::Sha1(hasher) => hex::encode(hasher.result()),
+            PackHasher::Sha256(hasher) => hex::encode(hasher.result()),
+        }
+    }
+}
+
+/// Sizes of the deltas added to a `MutableDataPack`, see `MutableDataPack::flush_with_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PackStats {
+    /// Total size of the deltas before compression.
+    pub uncompressed_bytes: u64,
+    /// Total size of the deltas once compressed, as written to the pack.
+    pub compressed_bytes: u64,
+}
+
+impl PackStats {
+    /// Ratio of uncompressed to compressed size, higher is better.
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_bytes == 0 {
+            1.0
+        } else {
+            self.uncompressed_bytes as f64 / self.compressed_bytes as f64
+        }
+    }
 }
 
+/// A datapack being written to.
+///
+/// Reads take the same lock as `add` and `flush` for their whole duration, including following a
+/// delta chain, so they always see either the pack as it was before a concurrent `flush`, or the
+/// new empty pack after it, and are never torn by it.
 pub struct MutableDataPack {
     dir: PathBuf,
     version: DataPackVersion,
+    compression: DataPackCompression,
+    hash: DataPackHash,
+    cancelled: Option<Arc<AtomicBool>>,
+    temp_prefix: String,
+    recovery_manifest: bool,
+    flush_on_drop: bool,
     inner: Mutex<Option<MutableDataPackInner>>,
 }
 
@@ -63,6 +488,14 @@ pub struct MutableDataPack {
 #[error("Mutable Data Pack Error: {0:?}")]
 struct MutableDataPackError(String);
 
+fn not_in_pack(key: &Key) -> Error {
+    MutableDataPackError(format!(
+        "cannot update metadata of {:?}, it is not in the pack",
+        key
+    ))
+    .into()
+}
+
 impl MutableDataPackInner {
     /// Creates a new MutableDataPack for producing datapack files.
     ///
@@ -70,7 +503,15 @@ impl MutableDataPackInner {
     /// when flush() is called, at which point the MutableDataPack is consumed. If
     /// flush() is not called, the temporary file is cleaned up when the object is
     /// release.
-    pub fn new(dir: impl AsRef<Path>, version: DataPackVersion) -> Result<Self> {
+    pub fn new(
+        dir: impl AsRef<Path>,
+        version: DataPackVersion,
+        compression: DataPackCompression,
+        hash: DataPackHash,
+        cancelled: Option<Arc<AtomicBool>>,
+        temp_prefix: &str,
+        recovery_manifest: bool,
+    ) -> Result<Self> {
         let dir = dir.as_ref();
         if !dir.is_dir() {
             return Err(format_err!(
@@ -83,38 +524,64 @@ impl MutableDataPackInner {
             return Err(format_err!("cannot create a v0 datapack"));
         }
 
-        let tempfile = Builder::new().append(true).tempfile_in(&dir)?;
+        let (tempfile, data_registration) =
+            TempFileRegistration::create_temp_file(dir, temp_prefix, true)?;
+        let manifest = if recovery_manifest {
+            Some(RecoveryManifest::create(tempfile.path(), hash)?)
+        } else {
+            None
+        };
         let mut data_file = PackWriter::new(tempfile);
-        let mut hasher = Sha1::new();
+        let mut hasher = PackHasher::new(hash);
         let version_u8: u8 = version.into();
         data_file.write_u8(version_u8)?;
         hasher.input(&[version_u8]);
 
         Ok(Self {
             dir: dir.to_path_buf(),
+            temp_prefix: temp_prefix.to_string(),
+            compression,
             data_file,
+            data_registration,
+            manifest,
             mem_index: HashMap::new(),
             hasher,
+            cancelled,
+            stats: PackStats::default(),
         })
     }
 
-    fn read_entry(&self, key: &Key) -> Result<Option<(Delta, Metadata)>> {
-        let location: &DeltaLocation = match self.mem_index.get(&key.hgid) {
-            None => return Ok(None),
-            Some(location) => location,
-        };
+    /// Reads the raw bytes of the entry at `location`.
+    fn read_raw(&self, location: &DeltaLocation) -> Result<Vec<u8>> {
+        // Locations passed to `MutableDataPack::read_at` come from the caller.
+        let end = location.offset.checked_add(location.size);
+        if end.map_or(true, |end| end > self.data_file.bytes_written()) {
+            return Err(MutableDataPackError(format!(
+                "{:?} is past the end of the pack",
+                location
+            ))
+            .into());
+        }
 
         // Make sure the buffers are empty so the reads below are consistent with what is being
         // written.
         self.data_file.flush_inner()?;
         let mut file = self.data_file.get_mut();
 
-        let mut data = Vec::with_capacity(location.size as usize);
-        unsafe { data.set_len(location.size as usize) };
+        let mut data = vec![0; location.size as usize];
 
         file.seek(SeekFrom::Start(location.offset))?;
         file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn read_entry(&self, key: &Key) -> Result<Option<(Delta, Metadata)>> {
+        let location: &DeltaLocation = match self.mem_index.get(&key.hgid) {
+            None => return Ok(None),
+            Some(location) => location,
+        };
 
+        let data = self.read_raw(location)?;
         let entry = DataEntry::new(&data, 0, DataPackVersion::One)?;
         Ok(Some((
             Delta {
@@ -128,68 +595,463 @@ impl MutableDataPackInner {
         )))
     }
 
+    fn read_at(&self, location: &DeltaLocation) -> Result<(Delta, Metadata)> {
+        let data = self.read_raw(location)?;
+        let entry = DataEntry::new(&data, 0, DataPackVersion::One)?;
+        let path = entry.filename().to_owned();
+        Ok((
+            Delta {
+                data: entry.delta()?,
+                base: entry
+                    .delta_base()
+                    .map(|delta_base| Key::new(path.clone(), delta_base.clone())),
+                key: Key::new(path, entry.hgid().clone()),
+            },
+            entry.metadata().clone(),
+        ))
+    }
+
     fn add(&mut self, delta: &Delta, metadata: &Metadata) -> Result<()> {
-        let path_slice = delta.key.path.as_byte_slice();
+        let compressed = self.compression.compress(&delta.data)?;
+        self.write_entry(
+            &delta.key,
+            delta.base.as_ref(),
+            delta.data.len() as u64,
+            compressed.as_slice(),
+            compressed.len() as u64,
+            metadata,
+        )
+    }
+
+    fn add_streaming(
+        &mut self,
+        key: &Key,
+        delta_reader: impl Read,
+        len: u64,
+        base: Option<&Key>,
+        metadata: &Metadata,
+    ) -> Result<()> {
+        // lz4 needs the whole delta to compress it, and the compressed length is written before
+        // the delta. Store the delta as lz4 literals instead, whose length is known up front.
+        let header = self.compression.literals_header(len)?;
+        self.write_entry(
+            key,
+            base,
+            len,
+            header.as_slice().chain(delta_reader.take(len)),
+            header.len() as u64 + len,
+            metadata,
+        )
+    }
+
+    /// Writes an entry to the data file, reading its compressed delta of `compressed_len` bytes
+    /// from `compressed` in chunks. On errors, nothing of the entry is left in the data file.
+    fn write_entry(
+        &mut self,
+        key: &Key,
+        base: Option<&Key>,
+        uncompressed_len: u64,
+        compressed: impl Read,
+        compressed_len: u64,
+        metadata: &Metadata,
+    ) -> Result<()> {
+        let path_slice = key.path.as_byte_slice();
         if path_slice.len() >= u16::MAX as usize {
             return Err(MutableDataPackError("delta path is longer than 2^16".into()).into());
         }
 
         let offset = self.data_file.bytes_written();
+        let hasher = self.hasher.clone();
+        let size = match self.write_entry_data(key, base, compressed, compressed_len, metadata) {
+            Ok(size) => size,
+            Err(e) => {
+                // Packs can be read sequentially, a partial entry would make them unreadable.
+                self.data_file.truncate(offset)?;
+                self.hasher = hasher;
+                return Err(e);
+            }
+        };
+
+        self.stats.uncompressed_bytes += uncompressed_len;
+        self.stats.compressed_bytes += compressed_len;
+
+        let delta_location = DeltaLocation {
+            delta_base: base.map(|k| k.hgid.clone()),
+            offset,
+            size,
+        };
+        if let Some(manifest) = self.manifest.as_mut() {
+            manifest.record(&key.hgid, &delta_location)?;
+        }
+        self.mem_index.insert(key.hgid.clone(), delta_location);
+        Ok(())
+    }
 
-        let compressed = compress(&delta.data)?;
+    /// Writes the entry itself for `write_entry`, returning its size.
+    fn write_entry_data(
+        &mut self,
+        key: &Key,
+        base: Option<&Key>,
+        mut compressed: impl Read,
+        compressed_len: u64,
+        metadata: &Metadata,
+    ) -> Result<u64> {
+        let path_slice = key.path.as_byte_slice();
+
+        // (namelen(2) + name + hgid(20) + hgid(20) + datalen(8))
+        let mut header = Vec::with_capacity(path_slice.len() + 50);
+        header.write_u16::<BigEndian>(path_slice.len() as u16)?;
+        header.write_all(path_slice)?;
+        header.write_all(key.hgid.as_ref())?;
+        header.write_all(base.map_or_else(|| HgId::null_id(), |k| &k.hgid).as_ref())?;
+        header.write_u64::<BigEndian>(self.compression.flag_delta_len(compressed_len))?;
+        self.data_file.write_all(&header)?;
+        self.hasher.input(&header);
+
+        let mut buf = vec![0; compressed_len.min(WRITE_CHUNK_SIZE as u64) as usize];
+        let mut remaining = compressed_len;
+        while remaining > 0 {
+            let chunk = &mut buf[..remaining.min(WRITE_CHUNK_SIZE as u64) as usize];
+            compressed.read_exact(chunk).with_context(|| {
+                format!(
+                    "delta of {:?} is shorter than expected ({} bytes missing)",
+                    key, remaining
+                )
+            })?;
+            self.data_file.write_all(chunk)?;
+            self.hasher.input(chunk);
+            remaining -= chunk.len() as u64;
+        }
 
-        // Preallocate with approximately the size we need:
-        // (namelen(2) + name + hgid(20) + hgid(20) + datalen(8) + data + metadata(~22))
-        let mut buf = Vec::with_capacity(path_slice.len() + compressed.len() + 72);
-        buf.write_u16::<BigEndian>(path_slice.len() as u16)?;
-        buf.write_all(path_slice)?;
-        buf.write_all(delta.key.hgid.as_ref())?;
+        let mut trailer = Vec::with_capacity(22);
+        metadata.write(&mut trailer)?;
+        self.data_file.write_all(&trailer)?;
+        self.hasher.input(&trailer);
 
-        buf.write_all(
-            delta
-                .base
-                .as_ref()
-                .map_or_else(|| HgId::null_id(), |k| &k.hgid)
-                .as_ref(),
-        )?;
-        buf.write_u64::<BigEndian>(compressed.len() as u64)?;
-        buf.write_all(&compressed)?;
+        Ok(header.len() as u64 + compressed_len + trailer.len() as u64)
+    }
 
+    fn update_metadata(&mut self, key: &Key, metadata: &Metadata) -> Result<()> {
+        let location = match self.mem_index.get(&key.hgid) {
+            None => return Err(not_in_pack(key)),
+            Some(location) => location.clone(),
+        };
+
+        let data = self.read_raw(&location)?;
+        let entry = DataEntry::new(&data, 0, DataPackVersion::One)?;
+        // Everything up to the end of the compressed delta is kept as is:
+        // (namelen(2) + name + hgid(20) + hgid(20) + datalen(8) + data)
+        let prefix_len =
+            2 + entry.filename().as_byte_slice().len() + 48 + entry.compressed_data().len();
+
+        let mut buf = Vec::with_capacity(prefix_len + 22);
+        buf.write_all(&data[..prefix_len])?;
         metadata.write(&mut buf)?;
 
+        let offset = self.data_file.bytes_written();
         self.data_file.write_all(&buf)?;
         self.hasher.input(&buf);
 
         let delta_location = DeltaLocation {
-            delta_base: delta.base.as_ref().map(|k| k.hgid.clone()),
+            delta_base: location.delta_base,
             offset,
             size: buf.len() as u64,
         };
-        self.mem_index
-            .insert(delta.key.hgid.clone(), delta_location);
+        if let Some(manifest) = self.manifest.as_mut() {
+            manifest.record(&key.hgid, &delta_location)?;
+        }
+        self.mem_index.insert(key.hgid.clone(), delta_location);
         Ok(())
     }
 }
 
 impl MutableDataPack {
     pub fn new(dir: impl AsRef<Path>, version: DataPackVersion) -> Self {
+        Self::with_compression(dir, version, DataPackCompression::default())
+    }
+
+    /// Creates a MutableDataPack whose deltas are compressed with `compression`.
+    ///
+    /// Packs using `DataPackCompression::Block` can only be read by the Rust implementation.
+    pub fn with_compression(
+        dir: impl AsRef<Path>,
+        version: DataPackVersion,
+        compression: DataPackCompression,
+    ) -> Self {
         Self {
             dir: dir.as_ref().to_path_buf(),
             version,
+            compression,
+            hash: DataPackHash::default(),
+            cancelled: None,
+            temp_prefix: DEFAULT_TEMP_PREFIX.to_string(),
+            recovery_manifest: false,
+            flush_on_drop: false,
             inner: Mutex::new(None),
         }
     }
 
+    /// Creates a MutableDataPack that flushes any pending entries when dropped.
+    ///
+    /// By default, a MutableDataPack that is dropped without being flushed discards its
+    /// temporary file, which is what allows aborting a write. Packs created with this function
+    /// are instead persisted on drop, for callers where forgetting to flush would silently lose
+    /// data. Errors during the automatic flush can only be logged.
+    pub fn new_flush_on_drop(dir: impl AsRef<Path>, version: DataPackVersion) -> Self {
+        let mut pack = Self::new(dir, version);
+        pack.flush_on_drop = true;
+        pack
+    }
+
+    /// Name the flushed pack after the `hash` of its content instead of its SHA-1.
+    ///
+    /// Packs named with `DataPackHash::Sha256` can only be read by the Rust implementation.
+    pub fn with_hash(mut self, hash: DataPackHash) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// Abort `flush`/`build_files` if `cancelled` is set once the data file has been written.
+    ///
+    /// A cancelled write fails with `CancelledMutablePack`, and its temporary files are removed
+    /// instead of being persisted as a half-written pack.
+    pub fn with_cancel_flag(mut self, cancelled: Arc<AtomicBool>) -> Self {
+        self.cancelled = Some(cancelled);
+        self
+    }
+
+    /// Name the temporary files of this pack with `prefix`, `DEFAULT_TEMP_PREFIX` by default.
+    ///
+    /// Temporary files are left behind by writers that crash before flushing, a recognizable
+    /// prefix lets them be cleaned up without risking to remove actual packs.
+    pub fn with_temp_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.temp_prefix = prefix.into();
+        self
+    }
+
+    /// Record the entries added to this pack in a recovery manifest next to its data temporary
+    /// file, named after it with `RECOVERY_MANIFEST_SUFFIX`.
+    ///
+    /// If the writer dies before flushing, `recover_from_temp` can complete the pack from both
+    /// files, instead of the data being lost. Records are buffered, use `checkpoint` to make the
+    /// entries added so far recoverable.
+    pub fn with_recovery_manifest(mut self) -> Self {
+        self.recovery_manifest = true;
+        self
+    }
+
+    /// Write the buffered entries and their recovery manifest records to disk, so that they can
+    /// be recovered by `recover_from_temp` if the writer dies, see `with_recovery_manifest`.
+    pub fn checkpoint(&self) -> Result<()> {
+        let mut guard = self.inner.lock();
+        if let Some(pack) = guard.as_mut() {
+            pack.data_file.flush_inner()?;
+            if let Some(manifest) = pack.manifest.as_mut() {
+                manifest.flush()?;
+            }
+        }
+        Ok(())
+    }
+
     fn get_pack<'a>(
         &self,
         inner: &'a mut Option<MutableDataPackInner>,
     ) -> Result<&'a mut MutableDataPackInner> {
         if inner.is_none() {
-            inner.replace(MutableDataPackInner::new(&self.dir, self.version.clone())?);
+            inner.replace(MutableDataPackInner::new(
+                &self.dir,
+                self.version,
+                self.compression,
+                self.hash,
+                self.cancelled.clone(),
+                &self.temp_prefix,
+                self.recovery_manifest,
+            )?);
         }
         Ok(inner.as_mut().unwrap())
     }
 
+    /// Same as `flush`, but also returns the compression statistics of the flushed pack.
+    ///
+    /// Returns `None` if nothing was added to the pack.
+    pub fn flush_with_stats(&self) -> Result<Option<(PathBuf, PackStats)>> {
+        let old_inner = self.inner.lock().take();
+        let old_inner = match old_inner {
+            Some(old_inner) => old_inner,
+            None => return Ok(None),
+        };
+
+        let stats = old_inner.stats;
+        Ok(old_inner.close_pack()?.map(|path| (path, stats)))
+    }
+
+    /// Same as `flush`, but if the pack is smaller than `min_size` bytes, it is first merged
+    /// with the smallest pack of `target_dir` that is also smaller than `min_size`, so that
+    /// flushing often doesn't accumulate many tiny packs.
+    ///
+    /// The merged pack is written to the directory of this pack, and the pack merged into it is
+    /// removed. Entries of this pack take precedence over the ones of the merged pack. Returns the
+    /// path of the flushed pack and whether a merge occurred, or `None` if nothing was added to
+    /// the pack.
+    pub fn flush_merging(
+        &self,
+        target_dir: &Path,
+        min_size: u64,
+    ) -> Result<Option<(PathBuf, bool)>> {
+        let mut guard = self.inner.lock();
+        let pack = match guard.as_mut() {
+            Some(pack) => pack,
+            None => return Ok(None),
+        };
+
+        let target = if pack.data_file.bytes_written() < min_size {
+            find_merge_target(target_dir, min_size)?
+        } else {
+            None
+        };
+
+        if let Some(target) = target.as_ref() {
+            for key in target.to_keys() {
+                let key = key?;
+                if pack.mem_index.contains_key(&key.hgid) {
+                    continue;
+                }
+
+                let delta = target
+                    .get_delta_chain(&key)?
+                    .and_then(|chain| chain.into_iter().next());
+                match (delta, target.raw_entry(&key)?) {
+                    (Some(delta), Some(entry)) => pack.add(&delta, &entry.metadata)?,
+                    _ => {
+                        return Err(MutableDataPackError(format!(
+                            "cannot read {:?} from pack '{:?}' to merge it",
+                            key,
+                            target.base_path()
+                        ))
+                        .into());
+                    }
+                }
+            }
+        }
+
+        let path = match guard.take() {
+            Some(pack) => pack.close_pack()?,
+            None => None,
+        };
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let merged = match target {
+            Some(target) => {
+                // The merged pack has different content, and thus a different name.
+                if target.base_path() != path {
+                    target.delete()?;
+                }
+                true
+            }
+            None => false,
+        };
+        Ok(Some((path, merged)))
+    }
+
+    /// Replaces the metadata of an entry already added to this pack.
+    ///
+    /// The entry is appended again with its delta copied verbatim and the new metadata, and the
+    /// index is repointed to it, so the delta isn't decompressed nor recompressed. The previous
+    /// entry is left in the pack, as it is append-only.
+    pub fn update_metadata(&self, key: &Key, metadata: &Metadata) -> Result<()> {
+        let mut guard = self.inner.lock();
+        match guard.as_mut() {
+            Some(pack) => pack.update_metadata(key, metadata),
+            // Don't create a pack just to find out that it doesn't have the entry.
+            None => Err(not_in_pack(key)),
+        }
+    }
+
+    /// Whether nothing was added to this pack since it was created or last flushed, in which case
+    /// `build_files` would fail with `EmptyMutablePack`.
+    pub fn is_empty(&self) -> bool {
+        self.inner
+            .lock()
+            .as_ref()
+            .map_or(true, |pack| pack.mem_index.is_empty())
+    }
+
+    /// Size in bytes of the data file written so far, 0 if nothing was added since the pack was
+    /// created or last flushed.
+    pub fn data_len(&self) -> u64 {
+        self.inner
+            .lock()
+            .as_ref()
+            .map_or(0, |pack| pack.data_file.bytes_written())
+    }
+
+    /// Sizes in bytes of the data and index files that flushing would write, so that callers can
+    /// check for free space beforehand. Both are 0 if nothing was added since the pack was
+    /// created or last flushed.
+    pub fn estimated_output_size(&self) -> (u64, u64) {
+        match self.inner.lock().as_ref() {
+            Some(pack) if !pack.mem_index.is_empty() => (
+                pack.data_file.bytes_written(),
+                DataIndex::written_size(pack.mem_index.len()),
+            ),
+            _ => (0, 0),
+        }
+    }
+
+    /// Adds a delta of `len` bytes read from `delta_reader`, without holding it in memory.
+    ///
+    /// The delta is written to the pack as it is read, which means it can't be compressed: it is
+    /// stored as lz4 literals, which readers decompress like any other delta. If `delta_reader`
+    /// ends before `len` bytes, an error is returned and the pack is left as it was.
+    pub fn add_streaming(
+        &self,
+        key: &Key,
+        delta_reader: impl Read,
+        len: u64,
+        base: Option<&Key>,
+        metadata: &Metadata,
+    ) -> Result<()> {
+        let mut guard = self.inner.lock();
+        let pack = self.get_pack(&mut guard)?;
+        pack.add_streaming(key, delta_reader, len, base, metadata)
+    }
+
+    /// Returns a snapshot of the entries buffered in this pack, ordered by offset.
+    ///
+    /// This is meant for diagnostics only, it doesn't flush nor otherwise modify the pack.
+    pub fn debug_dump(&self) -> Vec<(HgId, DeltaLocation)> {
+        let guard = self.inner.lock();
+        let mut entries: Vec<(HgId, DeltaLocation)> = match guard.as_ref() {
+            Some(pack) => pack
+                .mem_index
+                .iter()
+                .map(|(hgid, location)| (*hgid, location.clone()))
+                .collect(),
+            None => Vec::new(),
+        };
+        entries.sort_by_key(|(_, location)| location.offset);
+        entries
+    }
+
+    /// Reads the entry at `location`, such as one returned by `debug_dump`, without looking up
+    /// its key.
+    ///
+    /// The keys of the returned delta use the path stored in the entry.
+    pub fn read_at(&self, location: &DeltaLocation) -> Result<(Delta, Metadata)> {
+        let guard = self.inner.lock();
+        match guard.as_ref() {
+            Some(pack) => pack.read_at(location),
+            None => Err(MutableDataPackError(format!(
+                "no pending writes to read {:?} from",
+                location
+            ))
+            .into()),
+        }
+    }
+
     fn get_delta_chain(&self, key: &Key) -> Result<Option<Vec<Delta>>> {
         let mut guard = self.inner.lock();
         if let Some(pack) = guard.as_mut() {
@@ -202,20 +1064,21 @@ impl MutableDataPack {
                         if chain.is_empty() {
                             return Ok(None);
                         } else {
-                            return Ok(Some(chain));
+                            break;
                         }
                     }
                     Err(e) => {
                         if chain.is_empty() {
                             return Err(e);
                         } else {
-                            return Ok(Some(chain));
+                            break;
                         }
                     }
                 };
                 next_key = delta.base.clone();
                 chain.push(delta);
             }
+            MUTABLE_DATAPACK_DELTA_CHAINS.record(chain.len());
             Ok(Some(chain))
         } else {
             Ok(None)
@@ -223,6 +1086,31 @@ impl MutableDataPack {
     }
 }
 
+/// Find the smallest pack of `dir` that is smaller than `max_size` bytes.
+fn find_merge_target(dir: &Path, max_size: u64) -> Result<Option<DataPack>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut smallest: Option<(u64, PathBuf)> = None;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension() != Some("datapack".as_ref()) {
+            continue;
+        }
+        let size = fs::metadata(&path)?.len();
+        if size < max_size && smallest.as_ref().map_or(true, |(min, _)| size < *min) {
+            smallest = Some((size, path));
+        }
+    }
+
+    smallest
+        .map(|(_, path)| DataPack::new(path.with_extension(""), ExtStoredPolicy::Use))
+        .transpose()
+}
+
 impl HgIdMutableDeltaStore for MutableDataPack {
     /// Adds the given entry to the mutable datapack.
     fn add(&self, delta: &Delta, metadata: &Metadata) -> Result<()> {
@@ -231,6 +1119,17 @@ impl HgIdMutableDeltaStore for MutableDataPack {
         pack.add(delta, metadata)
     }
 
+    /// Adds all the entries to the mutable datapack while holding the lock only once.
+    fn add_many(&self, entries: &[(Delta, Metadata)]) -> Result<()> {
+        let mut guard = self.inner.lock();
+        let pack = self.get_pack(&mut guard)?;
+        for (i, (delta, metadata)) in entries.iter().enumerate() {
+            pack.add(delta, metadata)
+                .with_context(|| format!("failed to add entry {}", i))?;
+        }
+        Ok(())
+    }
+
     fn flush(&self) -> Result<Option<Vec<PathBuf>>> {
         let mut guard = self.inner.lock();
         let old_inner = replace(&mut *guard, None);
@@ -246,21 +1145,60 @@ impl HgIdMutableDeltaStore for MutableDataPack {
     }
 }
 
-impl MutablePack for MutableDataPackInner {
-    fn build_files(self) -> Result<(NamedTempFile, NamedTempFile, PathBuf)> {
+impl MutableDataPackInner {
+    /// Same as `build_files`, but also returns the registrations of the temporary files and the
+    /// recovery manifest, so that they can be kept until the files are persisted.
+    fn build_registered_files(
+        self,
+    ) -> Result<(NamedTempFile, NamedTempFile, PathBuf, PersistGuard)> {
         if self.mem_index.is_empty() {
             return Err(EmptyMutablePack.into());
         }
 
-        let mut index_file = PackWriter::new(NamedTempFile::new_in(&self.dir)?);
+        let data_file = self.data_file.into_inner()?;
+        if let Some(cancelled) = self.cancelled.as_ref() {
+            if cancelled.load(Ordering::Relaxed) {
+                // Dropping the temporary file removes it.
+                return Err(CancelledMutablePack.into());
+            }
+        }
+
+        let (index_file, index_registration) =
+            TempFileRegistration::create_temp_file(&self.dir, &self.temp_prefix, false)?;
+        let mut index_file = PackWriter::new(index_file);
         DataIndex::write(&mut index_file, &self.mem_index)?;
 
         Ok((
-            self.data_file.into_inner()?,
+            data_file,
             index_file.into_inner()?,
-            self.dir.join(&hex::encode(self.hasher.result())),
+            self.dir.join(&self.hasher.result_hex()),
+            PersistGuard {
+                _registrations: [self.data_registration, index_registration],
+                _manifest: self.manifest,
+            },
         ))
     }
+}
+
+impl MutablePack for MutableDataPackInner {
+    fn build_files(self) -> Result<(NamedTempFile, NamedTempFile, PathBuf)> {
+        let (data_file, index_file, base_filepath, _guard) = self.build_registered_files()?;
+        Ok((data_file, index_file, base_filepath))
+    }
+
+    fn close_pack(self) -> Result<Option<PathBuf>> {
+        let extension = self.extension();
+        let (data_file, index_file, base_filepath, _guard) = match self.build_registered_files() {
+            Err(err) if err.downcast_ref::<EmptyMutablePack>().is_some() => return Ok(None),
+            Err(err) => return Err(err),
+            Ok(files) => files,
+        };
+
+        // The registrations and the recovery manifest are only dropped once the files have been
+        // renamed, so that they are never seen as orphaned, nor left without their manifest.
+        persist_pack(data_file, index_file, &base_filepath, extension)?;
+        Ok(Some(base_filepath))
+    }
 
     fn extension(&self) -> &'static str {
         "data"
@@ -282,6 +1220,19 @@ impl MutablePack for MutableDataPack {
     }
 }
 
+impl Drop for MutableDataPack {
+    fn drop(&mut self) {
+        if !self.flush_on_drop || self.inner.lock().is_none() {
+            return;
+        }
+
+        match self.flush() {
+            Ok(paths) => info!(?paths, "flushed mutable datapack on drop"),
+            Err(e) => warn!("failed to flush mutable datapack on drop: {:?}", e),
+        }
+    }
+}
+
 impl HgIdDataStore for MutableDataPack {
     fn get(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>> {
         let key = match key {
@@ -364,6 +1315,8 @@ mod tests {
     use types::RepoPathBuf;
 
     use super::*;
+    use crate::datapack::DataPack;
+    use crate::localstore::ExtStoredPolicy;
 
     #[test]
     fn test_basic_creation() {
@@ -397,6 +1350,29 @@ mod tests {
         assert!(hash == filename_hash);
     }
 
+    #[test]
+    fn test_sha256_name() -> Result<()> {
+        let tempdir = tempdir()?;
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One)
+            .with_hash(DataPackHash::Sha256);
+        let delta = Delta {
+            data: Bytes::from(&[0, 1, 2][..]),
+            base: None,
+            key: Key::new(RepoPathBuf::new(), Default::default()),
+        };
+        mutdatapack.add(&delta, &Default::default())?;
+        let datapackbase = mutdatapack.flush()?.unwrap()[0].clone();
+
+        let mut hasher = Sha256::new();
+        hasher.input(&fs::read(datapackbase.with_extension("datapack"))?);
+        let hash = hex::encode(hasher.result());
+        assert_eq!(datapackbase.file_name().unwrap().to_str().unwrap(), hash);
+
+        let pack = DataPack::new(&datapackbase, ExtStoredPolicy::Use)?;
+        assert_eq!(pack.name_hash(), Some(DataPackHash::Sha256));
+        Ok(())
+    }
+
     #[test]
     fn test_basic_abort() {
         let tempdir = tempdir().unwrap();
@@ -413,6 +1389,36 @@ mod tests {
         assert_eq!(fs::read_dir(tempdir.path()).unwrap().count(), 0);
     }
 
+    #[test]
+    fn test_flush_on_drop() -> Result<()> {
+        let tempdir = tempdir()?;
+        let delta = Delta {
+            data: Bytes::from(&[0, 1, 2][..]),
+            base: None,
+            key: Key::new(RepoPathBuf::new(), Default::default()),
+        };
+        {
+            let mutdatapack =
+                MutableDataPack::new_flush_on_drop(tempdir.path(), DataPackVersion::One);
+            mutdatapack.add(&delta, &Default::default())?;
+        }
+
+        let datapacks = fs::read_dir(tempdir.path())?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|path| path.extension().map_or(false, |ext| ext == "datapack"))
+            .collect::<Vec<_>>();
+        assert_eq!(datapacks.len(), 1);
+
+        let pack = DataPack::new(&datapacks[0].with_extension(""), ExtStoredPolicy::Use)?;
+        assert_eq!(
+            pack.get(StoreKey::hgid(delta.key.clone()))?,
+            StoreResult::Found(delta.data.as_ref().to_vec())
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_get_delta_chain() {
         let tempdir = tempdir().unwrap();
@@ -494,6 +1500,464 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_flush_with_stats() -> Result<()> {
+        let tempdir = tempdir()?;
+
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One);
+        assert_eq!(mutdatapack.flush_with_stats()?, None);
+
+        let delta = Delta {
+            data: Bytes::from(vec![0; 1000]),
+            base: None,
+            key: Key::new(RepoPathBuf::new(), hgid("1")),
+        };
+        mutdatapack.add(&delta, &Default::default())?;
+
+        let (path, stats) = mutdatapack.flush_with_stats()?.unwrap();
+        assert!(path.with_extension("datapack").exists());
+        assert_eq!(stats.uncompressed_bytes, 1000);
+        assert!(stats.compressed_bytes < stats.uncompressed_bytes);
+        assert!(stats.ratio() > 1.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_metadata() -> Result<()> {
+        let tempdir = tempdir()?;
+
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One);
+        let delta = Delta {
+            data: Bytes::from(&[0, 1, 2][..]),
+            base: None,
+            key: Key::new(RepoPathBuf::new(), hgid("1")),
+        };
+        mutdatapack.add(&delta, &Default::default())?;
+        let meta = Metadata {
+            flags: Some(Metadata::LFS_FLAG),
+            size: Some(3),
+        };
+        mutdatapack.update_metadata(&delta.key, &meta)?;
+
+        let k = StoreKey::hgid(delta.key.clone());
+        assert_eq!(
+            mutdatapack.get_meta(k.clone())?,
+            StoreResult::Found(meta.clone())
+        );
+        assert_eq!(
+            mutdatapack.get(k.clone())?,
+            StoreResult::Found(delta.data.as_ref().to_vec())
+        );
+
+        let path = mutdatapack.flush()?.unwrap()[0].clone();
+        let pack = DataPack::new(&path, ExtStoredPolicy::Use)?;
+        assert_eq!(pack.get_meta(k)?, StoreResult::Found(meta));
+
+        assert!(mutdatapack
+            .update_metadata(&key("not", "10000"), &Default::default())
+            .is_err());
+        assert_eq!(mutdatapack.data_len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_empty() -> Result<()> {
+        let tempdir = tempdir()?;
+
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One);
+        assert!(mutdatapack.is_empty());
+        let err = MutableDataPack::new(tempdir.path(), DataPackVersion::One)
+            .build_files()
+            .unwrap_err();
+        assert!(err.downcast_ref::<EmptyMutablePack>().is_some());
+
+        let delta = Delta {
+            data: Bytes::from(&[0, 1, 2][..]),
+            base: None,
+            key: key("a", "1"),
+        };
+        mutdatapack.add(&delta, &Default::default())?;
+        assert!(!mutdatapack.is_empty());
+
+        mutdatapack.flush()?;
+        assert!(mutdatapack.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_estimated_output_size() -> Result<()> {
+        let tempdir = tempdir()?;
+
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One);
+        assert_eq!(mutdatapack.estimated_output_size(), (0, 0));
+        for i in 1..4 {
+            let delta = Delta {
+                data: Bytes::from(vec![i as u8; 100 * i]),
+                base: None,
+                key: key("a", &i.to_string()),
+            };
+            mutdatapack.add(&delta, &Default::default())?;
+        }
+
+        let (data_size, index_size) = mutdatapack.estimated_output_size();
+        let path = mutdatapack.flush()?.unwrap()[0].clone();
+        assert_eq!(
+            fs::metadata(path.with_extension("datapack"))?.len(),
+            data_size
+        );
+        assert_eq!(
+            fs::metadata(path.with_extension("dataidx"))?.len(),
+            index_size
+        );
+        assert_eq!(mutdatapack.estimated_output_size(), (0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_merging() -> Result<()> {
+        let tempdir = tempdir()?;
+        let delta = |node: &str, data: &[u8]| Delta {
+            data: Bytes::copy_from_slice(data),
+            base: None,
+            key: key("a", node),
+        };
+        let pack_count = || -> Result<usize> {
+            Ok(fs::read_dir(tempdir.path())?
+                .filter(|entry| {
+                    entry.as_ref().map_or(false, |entry| {
+                        entry.path().extension() == Some("datapack".as_ref())
+                    })
+                })
+                .count())
+        };
+
+        let first = delta("1", &[0, 1, 2]);
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One);
+        mutdatapack.add(&first, &Default::default())?;
+        let (first_path, merged) = mutdatapack
+            .flush_merging(tempdir.path(), 1024 * 1024)?
+            .unwrap();
+        assert!(!merged);
+
+        // The new pack is small, so it is merged with the first one.
+        let second = delta("2", &[3, 4, 5]);
+        mutdatapack.add(&second, &Default::default())?;
+        let (merged_path, merged) = mutdatapack
+            .flush_merging(tempdir.path(), 1024 * 1024)?
+            .unwrap();
+        assert!(merged);
+        assert!(!first_path.with_extension("datapack").exists());
+        assert_eq!(pack_count()?, 1);
+
+        let pack = DataPack::new(&merged_path, ExtStoredPolicy::Use)?;
+        for delta in [&first, &second] {
+            assert_eq!(
+                pack.get(StoreKey::hgid(delta.key.clone()))?,
+                StoreResult::Found(delta.data.as_ref().to_vec())
+            );
+        }
+
+        // The pack isn't below `min_size`, so it is flushed on its own.
+        let third = delta("3", &[6, 7, 8]);
+        mutdatapack.add(&third, &Default::default())?;
+        let (_, merged) = mutdatapack.flush_merging(tempdir.path(), 0)?.unwrap();
+        assert!(!merged);
+        assert_eq!(pack_count()?, 2);
+
+        assert!(mutdatapack
+            .flush_merging(tempdir.path(), 1024 * 1024)?
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_temp_prefix() -> Result<()> {
+        let tempdir = tempdir()?;
+        let delta = Delta {
+            data: Bytes::from(&[0, 1, 2][..]),
+            base: None,
+            key: key("a", "1"),
+        };
+
+        let temp_names = |dir: &Path| -> Result<Vec<String>> {
+            fs::read_dir(dir)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect()
+        };
+
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One);
+        mutdatapack.add(&delta, &Default::default())?;
+        let names = temp_names(tempdir.path())?;
+        assert_eq!(names.len(), 1);
+        assert!(names[0].starts_with(DEFAULT_TEMP_PREFIX));
+        drop(mutdatapack);
+
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One)
+            .with_temp_prefix("writer-1-");
+        mutdatapack.add(&delta, &Default::default())?;
+        let names = temp_names(tempdir.path())?;
+        assert_eq!(names.len(), 1);
+        assert!(names[0].starts_with("writer-1-"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_from_temp() -> Result<()> {
+        let revisions = vec![
+            Delta {
+                data: Bytes::from(&[0, 1, 2][..]),
+                base: None,
+                key: key("a", "1"),
+            },
+            Delta {
+                data: Bytes::from(&[3, 4][..]),
+                base: None,
+                key: key("b", "2"),
+            },
+        ];
+
+        let tempdir = tempdir()?;
+        let mutdatapack =
+            MutableDataPack::new(tempdir.path(), DataPackVersion::One).with_recovery_manifest();
+        for delta in revisions.iter() {
+            mutdatapack.add(delta, &Default::default())?;
+        }
+        mutdatapack.checkpoint()?;
+        // The writer dies without removing its temporary files.
+        std::mem::forget(mutdatapack);
+
+        let (manifests, data_temps): (Vec<PathBuf>, Vec<PathBuf>) = fs::read_dir(tempdir.path())?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .partition(|path| path.to_string_lossy().ends_with(RECOVERY_MANIFEST_SUFFIX));
+        assert_eq!(manifests.len(), 1);
+        assert_eq!(data_temps.len(), 1);
+
+        let path = recover_from_temp(&data_temps[0], &manifests[0])?;
+        assert!(!manifests[0].exists());
+        assert!(!data_temps[0].exists());
+        let pack = DataPack::new(&path, ExtStoredPolicy::Use)?;
+        for delta in revisions.iter() {
+            assert_eq!(pack.get_delta_chain(&delta.key)?, Some(vec![delta.clone()]));
+        }
+
+        // The recovered pack is the one that flushing would have written.
+        let flushdir = tempfile::tempdir()?;
+        let mutdatapack = MutableDataPack::new(flushdir.path(), DataPackVersion::One);
+        for delta in revisions.iter() {
+            mutdatapack.add(delta, &Default::default())?;
+        }
+        let flushed = mutdatapack.flush()?.unwrap()[0].clone();
+        assert_eq!(flushed.file_name(), path.file_name());
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_from_temp_invalid_manifest() -> Result<()> {
+        let tempdir = tempdir()?;
+        let data_temp = tempdir.path().join("data");
+        fs::write(&data_temp, [1, 0, 1, 2])?;
+        let manifest = tempdir.path().join("manifest");
+        let write_manifest = |offset: u64, size: u64| -> Result<()> {
+            let mut records = vec![0];
+            records.extend_from_slice(key("a", "1").hgid.as_ref());
+            records.extend_from_slice(HgId::null_id().as_ref());
+            records.write_u64::<BigEndian>(offset)?;
+            records.write_u64::<BigEndian>(size)?;
+            Ok(fs::write(&manifest, records)?)
+        };
+
+        write_manifest(u64::MAX, 2)?;
+        assert!(recover_from_temp(&data_temp, &manifest).is_err());
+        write_manifest(0, 2)?;
+        assert!(recover_from_temp(&data_temp, &manifest).is_err());
+        // An entry past the end of the data file didn't make it there.
+        write_manifest(1, 4)?;
+        let err = recover_from_temp(&data_temp, &manifest).unwrap_err();
+        assert!(err.downcast_ref::<EmptyMutablePack>().is_some());
+        assert!(data_temp.exists());
+        assert!(manifest.exists());
+        Ok(())
+    }
+
+    #[cfg(feature = "temp-file-registry")]
+    #[test]
+    fn test_orphaned_temp_files() -> Result<()> {
+        let tempdir = tempdir()?;
+        let delta = Delta {
+            data: Bytes::from(&[0, 1, 2][..]),
+            base: None,
+            key: key("a", "1"),
+        };
+
+        let orphan = tempdir
+            .path()
+            .join(format!("{}orphan", DEFAULT_TEMP_PREFIX));
+        File::create(&orphan)?;
+        let orphan = orphan.canonicalize()?;
+
+        // The temporary file of a live writer isn't orphaned.
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One);
+        mutdatapack.add(&delta, &Default::default())?;
+        assert_eq!(
+            orphaned_temp_files(tempdir.path(), DEFAULT_TEMP_PREFIX)?,
+            vec![orphan.clone()]
+        );
+        assert_eq!(orphaned_temp_files(tempdir.path(), "writer-1-")?, vec![]);
+
+        // Nor are the files of a flushed pack.
+        mutdatapack.flush()?;
+        assert_eq!(
+            orphaned_temp_files(tempdir.path(), DEFAULT_TEMP_PREFIX)?,
+            vec![orphan]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cleanup_stale_temps() -> Result<()> {
+        let tempdir = tempdir()?;
+        let stale = tempdir.path().join(format!("{}stale", DEFAULT_TEMP_PREFIX));
+        File::create(&stale)?;
+        let other = tempdir.path().join("other");
+        File::create(&other)?;
+
+        // The file is too recent to be considered abandoned.
+        assert_eq!(
+            cleanup_stale_temps(tempdir.path(), Duration::from_secs(3600))?,
+            Vec::<PathBuf>::new()
+        );
+        assert!(stale.exists());
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            cleanup_stale_temps(tempdir.path(), Duration::from_millis(1))?,
+            vec![stale.clone()]
+        );
+        assert!(!stale.exists());
+        assert!(other.exists());
+
+        assert_eq!(
+            cleanup_stale_temps(&tempdir.path().join("missing"), Duration::ZERO)?,
+            Vec::<PathBuf>::new()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_dump() -> Result<()> {
+        let tempdir = tempdir()?;
+
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One);
+        assert!(mutdatapack.debug_dump().is_empty());
+
+        let base = Delta {
+            data: Bytes::from(&[0, 1, 2][..]),
+            base: None,
+            key: key("a", "2"),
+        };
+        let delta = Delta {
+            data: Bytes::from(&[3, 4][..]),
+            base: Some(base.key.clone()),
+            key: key("a", "1"),
+        };
+        mutdatapack.add(&base, &Default::default())?;
+        mutdatapack.add(&delta, &Default::default())?;
+
+        let dump = mutdatapack.debug_dump();
+        assert_eq!(dump.len(), 2);
+        assert_eq!(dump[0].0, base.key.hgid);
+        assert_eq!(dump[0].1.delta_base, None);
+        assert_eq!(dump[1].0, delta.key.hgid);
+        assert_eq!(dump[1].1.delta_base, Some(base.key.hgid));
+        assert!(dump[0].1.offset < dump[1].1.offset);
+
+        // Dumping doesn't flush the pack.
+        assert_eq!(mutdatapack.flush()?.map(|paths| paths.len()), Some(1));
+        assert!(mutdatapack.debug_dump().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_at() -> Result<()> {
+        let tempdir = tempdir()?;
+
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One);
+        let base = Delta {
+            data: Bytes::from(&[0, 1, 2][..]),
+            base: None,
+            key: key("a", "2"),
+        };
+        let delta = Delta {
+            data: Bytes::from(&[3, 4][..]),
+            base: Some(base.key.clone()),
+            key: key("a", "1"),
+        };
+        let metadata = Metadata {
+            size: Some(2),
+            flags: None,
+        };
+        mutdatapack.add(&base, &Default::default())?;
+        mutdatapack.add(&delta, &metadata)?;
+
+        let dump = mutdatapack.debug_dump();
+        assert_eq!(mutdatapack.read_at(&dump[0].1)?, (base, Default::default()));
+        assert_eq!(mutdatapack.read_at(&dump[1].1)?, (delta, metadata));
+
+        // Locations past the end of the pack are rejected.
+        let data_len = mutdatapack.data_len();
+        for (offset, size) in [(data_len, 1), (dump[1].1.offset, data_len), (1, u64::MAX)] {
+            let location = DeltaLocation {
+                delta_base: None,
+                offset,
+                size,
+            };
+            assert!(mutdatapack.read_at(&location).is_err());
+        }
+
+        mutdatapack.flush()?;
+        assert!(mutdatapack.read_at(&dump[0].1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_flush_and_read() -> Result<()> {
+        let tempdir = tempdir()?;
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One);
+        let base = Delta {
+            data: Bytes::from(&b"base"[..]),
+            base: None,
+            key: key("a", "1"),
+        };
+        // mpatch delta replacing bytes 0..4 of the base with "new".
+        let delta = Delta {
+            data: Bytes::from(&b"\0\0\0\0\0\0\0\x04\0\0\0\x03new"[..]),
+            base: Some(base.key.clone()),
+            key: key("a", "2"),
+        };
+
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(|| -> Result<()> {
+                for _ in 0..100 {
+                    mutdatapack.add(&base, &Default::default())?;
+                    mutdatapack.add(&delta, &Default::default())?;
+                    mutdatapack.flush()?;
+                }
+                Ok(())
+            });
+
+            for _ in 0..100 {
+                // Either both entries are found, or none of them.
+                match mutdatapack.get(StoreKey::hgid(delta.key.clone()))? {
+                    StoreResult::Found(data) => assert_eq!(data, b"new"),
+                    StoreResult::NotFound(_) => {}
+                }
+            }
+            writer.join().unwrap()
+        })
+    }
+
     #[test]
     fn test_get_missing() {
         let tempdir = tempdir().unwrap();
@@ -513,6 +1977,192 @@ mod tests {
         assert_eq!(missing, vec![StoreKey::from(not)]);
     }
 
+    #[test]
+    fn test_add_many() -> Result<()> {
+        let tempdir = tempdir()?;
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One);
+
+        let entries = vec![
+            (
+                Delta {
+                    data: Bytes::from(&[0, 1, 2][..]),
+                    base: None,
+                    key: key("a", "1"),
+                },
+                Metadata::default(),
+            ),
+            (
+                Delta {
+                    data: Bytes::from(&[3, 4, 5][..]),
+                    base: None,
+                    key: key("b", "2"),
+                },
+                Metadata {
+                    flags: Some(2),
+                    size: Some(1000),
+                },
+            ),
+        ];
+        mutdatapack.add_many(&entries)?;
+
+        for (delta, metadata) in entries {
+            let chain = mutdatapack.get_delta_chain(&delta.key)?.unwrap();
+            assert_eq!(chain, vec![delta.clone()]);
+            assert_eq!(
+                mutdatapack.get_meta(StoreKey::hgid(delta.key))?,
+                StoreResult::Found(metadata)
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_many_reports_index() -> Result<()> {
+        let tempdir = tempdir()?;
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One);
+
+        let long_path = RepoPathBuf::from_string("a".repeat(u16::MAX as usize))?;
+        let entries = vec![
+            (
+                Delta {
+                    data: Bytes::from(&[0, 1, 2][..]),
+                    base: None,
+                    key: key("a", "1"),
+                },
+                Metadata::default(),
+            ),
+            (
+                Delta {
+                    data: Bytes::from(&[3, 4, 5][..]),
+                    base: None,
+                    key: Key::new(long_path, hgid("2")),
+                },
+                Metadata::default(),
+            ),
+        ];
+        let err = mutdatapack.add_many(&entries).unwrap_err();
+        assert_eq!(err.to_string(), "failed to add entry 1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_compression() -> Result<()> {
+        let tempdir = tempdir()?;
+        let mutdatapack = MutableDataPack::with_compression(
+            tempdir.path(),
+            DataPackVersion::One,
+            DataPackCompression::Block,
+        );
+        let delta = Delta {
+            data: Bytes::from(&b"hello world long string easy easy easy easy compress"[..]),
+            base: None,
+            key: key("a", "1"),
+        };
+        mutdatapack.add(&delta, &Default::default())?;
+        assert_eq!(
+            mutdatapack.get_delta_chain(&delta.key)?.unwrap(),
+            vec![delta.clone()]
+        );
+
+        let path = mutdatapack.flush()?.unwrap()[0].clone();
+        let pack = DataPack::new(&path, ExtStoredPolicy::Use)?;
+        assert_eq!(
+            pack.read_entry(1)?.compression(),
+            DataPackCompression::Block
+        );
+        assert_eq!(
+            pack.get(StoreKey::hgid(delta.key))?,
+            StoreResult::Found(delta.data.as_ref().to_vec())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_streaming() -> Result<()> {
+        for compression in [DataPackCompression::PyFrame, DataPackCompression::Block] {
+            let tempdir = tempdir()?;
+            let mutdatapack = MutableDataPack::with_compression(
+                tempdir.path(),
+                DataPackVersion::One,
+                compression,
+            );
+
+            let base = Delta {
+                data: Bytes::from((0..200_000).map(|i| i as u8).collect::<Vec<u8>>()),
+                base: None,
+                key: key("a", "1"),
+            };
+            let delta = Delta {
+                data: Bytes::new(),
+                base: Some(base.key.clone()),
+                key: key("a", "2"),
+            };
+            let meta = Metadata {
+                flags: None,
+                size: Some(200_000),
+            };
+            for (delta, meta) in [(&base, meta.clone()), (&delta, Default::default())] {
+                mutdatapack.add_streaming(
+                    &delta.key,
+                    delta.data.as_ref(),
+                    delta.data.len() as u64,
+                    delta.base.as_ref(),
+                    &meta,
+                )?;
+            }
+            assert_eq!(
+                mutdatapack.get_delta_chain(&delta.key)?.unwrap(),
+                vec![delta.clone(), base.clone()]
+            );
+            assert_eq!(
+                mutdatapack.get_meta(StoreKey::hgid(base.key.clone()))?,
+                StoreResult::Found(meta)
+            );
+
+            // A short read leaves nothing behind.
+            assert!(mutdatapack
+                .add_streaming(&key("b", "3"), &[0u8, 1][..], 3, None, &Default::default())
+                .is_err());
+            assert_eq!(mutdatapack.get_delta_chain(&key("b", "3"))?, None);
+
+            let path = mutdatapack.flush()?.unwrap()[0].clone();
+            let pack = DataPack::new(&path, ExtStoredPolicy::Use)?;
+            assert_eq!(
+                pack.iter_sequential().collect::<Result<Vec<_>>>()?,
+                vec![base.key.clone(), delta.key.clone()]
+            );
+            assert_eq!(
+                pack.get(StoreKey::hgid(base.key.clone()))?,
+                StoreResult::Found(base.data.as_ref().to_vec())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancelled_flush() -> Result<()> {
+        let tempdir = tempdir()?;
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mutdatapack = MutableDataPack::new(tempdir.path(), DataPackVersion::One)
+            .with_cancel_flag(cancelled.clone());
+        let delta = Delta {
+            data: Bytes::from(&[0, 1, 2][..]),
+            base: None,
+            key: key("a", "1"),
+        };
+        mutdatapack.add(&delta, &Default::default())?;
+
+        cancelled.store(true, Ordering::Relaxed);
+        let err = mutdatapack.flush().unwrap_err();
+        assert!(err.downcast_ref::<CancelledMutablePack>().is_some());
+        assert_eq!(fs::read_dir(tempdir.path())?.count(), 0);
+
+        Ok(())
+    }
+
     #[test]
     fn test_empty() {
         let tempdir = tempdir().unwrap();
@@ -133,6 +133,15 @@ impl MutableHistoryPack {
         }
     }
 
+    /// Whether nothing was added to this pack since it was created or last flushed, in which case
+    /// `build_files` would fail with `EmptyMutablePack`.
+    pub fn is_empty(&self) -> bool {
+        self.inner
+            .lock()
+            .as_ref()
+            .map_or(true, |pack| pack.mem_index.is_empty())
+    }
+
     fn get_pack<'a>(
         &self,
         inner: &'a mut Option<MutableHistoryPackInner>,
@@ -9,6 +9,7 @@ use std::fs::Permissions;
 use std::io::ErrorKind;
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::path::PathBuf;
 
 use anyhow::Result;
@@ -18,12 +19,12 @@ use crate::error::EmptyMutablePack;
 
 /// Mark the permission as read-only for user-group-other.
 #[cfg(not(unix))]
-fn make_readonly(perms: &mut Permissions) {
+pub(crate) fn make_readonly(perms: &mut Permissions) {
     perms.set_readonly(true);
 }
 
 #[cfg(unix)]
-fn make_readonly(perms: &mut Permissions) {
+pub(crate) fn make_readonly(perms: &mut Permissions) {
     perms.set_mode(0o444);
 }
 
@@ -31,7 +32,7 @@ fn make_readonly(perms: &mut Permissions) {
 ///
 /// Since packfiles are named based on their content, a rename failure due to an already existing
 /// file isn't an error, as both files have effectively the same content.
-fn persist(file: NamedTempFile, path: PathBuf) -> Result<()> {
+pub(crate) fn persist(file: NamedTempFile, path: PathBuf) -> Result<()> {
     match file.persist_noclobber(path) {
         Ok(_) => Ok(()),
         Err(e) => {
@@ -44,9 +45,16 @@ fn persist(file: NamedTempFile, path: PathBuf) -> Result<()> {
     }
 }
 
+/// A pack being written to.
+///
+/// A pack with no entries doesn't produce any files: `build_files` fails with `EmptyMutablePack`,
+/// while `close_pack` returns `Ok(None)`. Callers of `build_files` that don't want to handle the
+/// error can check the pack's `is_empty` beforehand.
 pub trait MutablePack {
     /// Make the data and index pack files with the data added to it. Also returns the fullpath of
     /// the files. After calling this function, the `MutablePack` is consumed and is no longer usable.
+    ///
+    /// Fails with `EmptyMutablePack` if nothing was added to the pack.
     fn build_files(self) -> Result<(NamedTempFile, NamedTempFile, PathBuf)>;
 
     /// Returns the extension for this kind of pack files.
@@ -54,13 +62,13 @@ pub trait MutablePack {
 
     /// Close the packfile, returning the path of the final immutable pack on disk. The
     /// `MutablePack` is no longer usable after being closed.
+    ///
+    /// Returns `None` if nothing was added to the pack.
     fn close_pack(self) -> Result<Option<PathBuf>>
     where
         Self: Sized,
     {
-        let extension = self.extension().to_string();
-        let pack_extension = extension.clone() + "pack";
-        let index_extension = extension + "idx";
+        let extension = self.extension();
 
         let (packfile, indexfile, base_filepath) = match self.build_files() {
             Err(err) => {
@@ -73,18 +81,29 @@ pub trait MutablePack {
             Ok(files) => files,
         };
 
-        let mut perms = packfile.as_file().metadata()?.permissions();
-        make_readonly(&mut perms);
+        persist_pack(packfile, indexfile, &base_filepath, extension)?;
+        Ok(Some(base_filepath))
+    }
+}
 
-        packfile.as_file().set_permissions(perms.clone())?;
-        indexfile.as_file().set_permissions(perms)?;
+/// Make the pack and index files returned by `MutablePack::build_files` read-only, and persist
+/// them next to `base_filepath` with the `extension` of the pack.
+pub(crate) fn persist_pack(
+    packfile: NamedTempFile,
+    indexfile: NamedTempFile,
+    base_filepath: &Path,
+    extension: &str,
+) -> Result<()> {
+    let mut perms = packfile.as_file().metadata()?.permissions();
+    make_readonly(&mut perms);
 
-        let packfile_path = base_filepath.with_extension(pack_extension);
-        let indexfile_path = base_filepath.with_extension(index_extension);
+    packfile.as_file().set_permissions(perms.clone())?;
+    indexfile.as_file().set_permissions(perms)?;
 
-        persist(packfile, packfile_path)?;
-        persist(indexfile, indexfile_path)?;
+    let packfile_path = base_filepath.with_extension(extension.to_string() + "pack");
+    let indexfile_path = base_filepath.with_extension(extension.to_string() + "idx");
 
-        Ok(Some(base_filepath))
-    }
+    persist(packfile, packfile_path)?;
+    persist(indexfile, indexfile_path)?;
+    Ok(())
 }
@@ -12,6 +12,7 @@ use std::collections::VecDeque;
 use std::fs::read_dir;
 use std::fs::DirEntry;
 use std::io::ErrorKind;
+use std::ops::Deref;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicU64;
@@ -22,6 +23,7 @@ use std::time::Instant;
 
 use anyhow::Result;
 use parking_lot::Mutex;
+use tracing::warn;
 use types::Key;
 use types::NodeInfo;
 
@@ -96,6 +98,20 @@ impl<T> LruStore<T> {
     }
 }
 
+impl<T> LruStore<OpenPack<T>> {
+    /// Close all the packs but the `count` most recently used ones, moving their paths to the
+    /// front of `closed`. Returns the number of packs that were closed.
+    fn close_from(&mut self, count: usize, closed: &mut VecDeque<PathBuf>) -> usize {
+        let mut closed_count = 0;
+        while self.stores.len() > count {
+            let pack = self.stores.pop_back().unwrap();
+            closed.push_front(pack.path);
+            closed_count += 1;
+        }
+        closed_count
+    }
+}
+
 impl<'a, T> IntoIterator for &'a LruStore<T> {
     type Item = &'a T;
     type IntoIter = Iter<'a, T>;
@@ -113,6 +129,21 @@ impl<T> From<Vec<T>> for LruStore<T> {
     }
 }
 
+/// An open packfile of a `PackStore`, along with its path so that it can be closed and reopened,
+/// see `PackStore::with_open_limits`.
+struct OpenPack<T> {
+    path: PathBuf,
+    pack: T,
+}
+
+impl<T> Deref for OpenPack<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.pack
+    }
+}
+
 #[derive(PartialEq)]
 pub enum CorruptionPolicy {
     IGNORE,
@@ -126,9 +157,13 @@ struct PackStoreInner<T> {
     extstored_policy: ExtStoredPolicy,
     scan_frequency: Duration,
     last_scanned: RefCell<Option<Instant>>,
-    packs: RefCell<LruStore<T>>,
+    packs: RefCell<LruStore<OpenPack<T>>>,
+    /// Paths of the packfiles closed past `max_open`, most recently used first.
+    closed: RefCell<VecDeque<PathBuf>>,
     max_bytes: Option<u64>,
     current_bytes: AtomicU64,
+    max_open: usize,
+    max_open_under_pressure: usize,
 }
 
 /// A `PackStore` automatically keeps track of packfiles in a given directory. New on-disk
@@ -205,8 +240,11 @@ impl PackStoreOptions {
                 extstored_policy: self.extstored_policy,
                 last_scanned: RefCell::new(None),
                 packs: RefCell::new(LruStore::new()),
+                closed: RefCell::new(VecDeque::new()),
                 max_bytes: self.max_bytes,
                 current_bytes: AtomicU64::new(0),
+                max_open: usize::MAX,
+                max_open_under_pressure: usize::MAX,
             }),
         }
     }
@@ -220,11 +258,51 @@ impl<T: LocalStore + Repackable + StoreFromPath> PackStore<T> {
         packstore.last_scanned.replace(None);
     }
 
+    /// Limit the number of packfiles kept open by this store.
+    ///
+    /// Only the `max_open` most recently used packfiles are kept open. The others are closed, and
+    /// are reopened for the lookups that the open ones can't answer, staying open again if they
+    /// are among the `max_open` most recently used ones. `release_idle` closes all but
+    /// `max_open_under_pressure` of them, until the next lookup. By default, all the packfiles
+    /// are kept open.
+    pub fn with_open_limits(mut self, max_open: usize, max_open_under_pressure: usize) -> Self {
+        let inner = self.inner.get_mut();
+        inner.max_open = max_open;
+        inner.max_open_under_pressure = max_open_under_pressure.min(max_open);
+        inner
+            .packs
+            .get_mut()
+            .close_from(max_open, inner.closed.get_mut());
+        self
+    }
+
+    /// Close the packfiles that are not among the `max_open_under_pressure` most recently used
+    /// ones, for instance when memory is tight, see `with_open_limits`.
+    ///
+    /// Returns the number of packfiles that were closed.
+    pub fn release_idle(&self) -> Result<usize> {
+        let inner = self.inner.lock();
+        let mut packs = inner.packs.try_borrow_mut()?;
+        let mut closed = inner.closed.try_borrow_mut()?;
+        Ok(packs.close_from(inner.max_open_under_pressure, &mut closed))
+    }
+
+    /// Number of packfiles that are open, see `with_open_limits`.
+    pub fn open_packs(&self) -> Result<usize> {
+        let inner = self.inner.lock();
+        let packs = inner.packs.try_borrow()?;
+        Ok(packs.iter().count())
+    }
+
     /// Add a packfile to this store.
-    fn add_pack(&self, pack: T) -> Result<()> {
+    fn add_pack(&self, path: PathBuf, pack: T) -> Result<()> {
         let inner = self.inner.lock();
         let size = pack.size();
-        inner.packs.borrow_mut().add(pack);
+        {
+            let mut packs = inner.packs.borrow_mut();
+            packs.add(OpenPack { path, pack });
+            packs.close_from(inner.max_open, &mut inner.closed.borrow_mut());
+        }
         let current_bytes = inner.current_bytes.fetch_add(size, Ordering::SeqCst) + size;
 
         if let Some(max_bytes) = inner.max_bytes {
@@ -289,13 +367,18 @@ impl<T: LocalStore + Repackable + StoreFromPath> PackStoreInner<T> {
 
         let mut new_size = 0;
         for entry in self.get_pack_paths()?.into_iter() {
-            if let Ok(pack) = T::from_path(&entry.path(), self.extstored_policy) {
+            let path = entry.path();
+            if let Ok(pack) = T::from_path(&path, self.extstored_policy) {
                 new_size += pack.size();
-                new_packs.push(pack);
+                new_packs.push(OpenPack { path, pack });
             }
         }
 
-        self.packs.replace(new_packs.into());
+        let mut new_packs = LruStore::from(new_packs);
+        let mut closed = VecDeque::new();
+        new_packs.close_from(self.max_open, &mut closed);
+        self.packs.replace(new_packs);
+        self.closed.replace(closed);
         self.current_bytes.store(new_size, Ordering::SeqCst);
         Ok(())
     }
@@ -381,19 +464,92 @@ impl<T: LocalStore + Repackable + StoreFromPath> PackStoreInner<T> {
         }
     }
 
+    /// Reopen the most recently used closed packfiles, as long as fewer than `max_open`
+    /// packfiles are open.
+    fn reopen_closed(&self) -> Result<()> {
+        let mut packs = self.packs.try_borrow_mut()?;
+        let mut closed = self.closed.try_borrow_mut()?;
+        while packs.stores.len() < self.max_open {
+            let path = match closed.pop_front() {
+                Some(path) => path,
+                None => break,
+            };
+            // Packfiles that can't be opened anymore are skipped, as a rescan would.
+            if let Ok(pack) = T::from_path(&path, self.extstored_policy) {
+                // Closed packfiles are less recently used than all the open ones.
+                packs.stores.push_back(OpenPack { path, pack });
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute the `op` function on the closed packfiles, opening them one at a time. The
+    /// packfile `op` returns a result for stays open, as the most recently used one.
+    fn run_closed<R, F>(&self, op: &F) -> Result<Option<R>>
+    where
+        F: Fn(&T) -> Result<Option<R>>,
+    {
+        let mut closed = self.closed.try_borrow_mut()?;
+        let mut found = None;
+        let mut corrupted = Vec::new();
+        for (index, path) in closed.iter().enumerate() {
+            let pack = match T::from_path(path, self.extstored_policy) {
+                Ok(pack) => pack,
+                Err(_) => {
+                    corrupted.push(index);
+                    continue;
+                }
+            };
+            match op(&pack) {
+                Ok(None) => continue,
+                Ok(Some(result)) => {
+                    found = Some((index, pack, result));
+                    break;
+                }
+                Err(_) => {
+                    corrupted.push(index);
+                    if self.corruption_policy == CorruptionPolicy::REMOVE {
+                        let _ = pack.delete();
+                    }
+                }
+            }
+        }
+
+        // The corrupted packfiles all come before the found one.
+        let found = found.map(|(index, pack, result)| {
+            let path = closed.remove(index).unwrap();
+            (OpenPack { path, pack }, result)
+        });
+        for index in corrupted.into_iter().rev() {
+            closed.remove(index);
+        }
+
+        match found {
+            Some((pack, result)) => {
+                let mut packs = self.packs.try_borrow_mut()?;
+                packs.add(pack);
+                packs.close_from(self.max_open, &mut closed);
+                Ok(Some(result))
+            }
+            None => Ok(None),
+        }
+    }
+
     /// Execute the `op` function. May call `rescan` when `op` fails with `KeyError`.
     fn run<R, F>(&self, op: F) -> Result<Option<R>>
     where
         F: Fn(&T) -> Result<Option<R>>,
     {
         for _ in 0..2 {
+            self.reopen_closed()?;
+
             let mut found = None;
             {
                 let mut corrupted = Vec::new();
 
                 let mut lrustore = self.packs.try_borrow_mut()?;
                 for (index, store) in lrustore.iter_mut().enumerate() {
-                    match op(store) {
+                    match op(&store.pack) {
                         Ok(None) => continue,
                         Ok(Some(result)) => {
                             found = Some((index, result));
@@ -409,7 +565,7 @@ impl<T: LocalStore + Repackable + StoreFromPath> PackStoreInner<T> {
                     for store_index in corrupted.into_iter().rev() {
                         let store = lrustore.remove(store_index);
                         if self.corruption_policy == CorruptionPolicy::REMOVE {
-                            let _ = store.delete();
+                            let _ = store.pack.delete();
                         }
                     }
                 }
@@ -420,6 +576,10 @@ impl<T: LocalStore + Repackable + StoreFromPath> PackStoreInner<T> {
                 return Ok(Some(result));
             }
 
+            if let Some(result) = self.run_closed(&op)? {
+                return Ok(Some(result));
+            }
+
             // We didn't find anything, let's try to probe the filesystem to discover new packfiles
             // and retry.
             if !self.try_scan()? {
@@ -438,15 +598,35 @@ impl<T: LocalStore + Repackable + StoreFromPath> LocalStore for PackStore<T> {
         // all the known packs.
         let packstore = self.inner.lock();
         packstore.try_scan()?;
+        packstore.reopen_closed()?;
 
         let initial_keys = Ok(keys.to_vec());
         let packs = packstore.packs.try_borrow()?;
-        packs
+        let missing_keys = packs
             .into_iter()
             .fold(initial_keys, |missing_keys, store| match missing_keys {
                 Ok(missing_keys) => store.get_missing(&missing_keys),
                 Err(e) => Err(e),
-            })
+            })?;
+
+        // The closed packfiles are only opened for the duration of the call.
+        let closed = packstore.closed.try_borrow()?;
+        let mut missing_keys = missing_keys;
+        for path in closed.iter() {
+            // Packfiles that can't be opened anymore are skipped, as in `run`.
+            let pack = match T::from_path(path, packstore.extstored_policy) {
+                Ok(pack) => pack,
+                Err(err) => {
+                    warn!(
+                        "skipping packfile '{:?}' that can't be reopened: {:?}",
+                        path, err
+                    );
+                    continue;
+                }
+            };
+            missing_keys = pack.get_missing(&missing_keys)?;
+        }
+        Ok(missing_keys)
     }
 }
 
@@ -554,7 +734,7 @@ impl MutableDataPackStore {
                     path.as_path(),
                     self.inner.pack_store.inner.lock().extstored_policy,
                 )?;
-                self.inner.pack_store.add_pack(datapack)?;
+                self.inner.pack_store.add_pack(path.clone(), datapack)?;
                 result_packs.push(path);
             }
         }
@@ -655,7 +835,7 @@ impl MutableHistoryPackStore {
             let mut result_packs = self.result_packs.lock();
             for path in paths {
                 let histpack = HistoryPack::new(path.as_path())?;
-                self.inner.pack_store.add_pack(histpack)?;
+                self.inner.pack_store.add_pack(path.clone(), histpack)?;
                 result_packs.push(path);
             }
         }
@@ -990,6 +1170,131 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_open_limits() -> Result<()> {
+        let tempdir = TempDir::new()?;
+
+        let keys = vec![key("a", "1"), key("b", "2"), key("c", "3")];
+        for k in keys.iter() {
+            let revision = (
+                Delta {
+                    data: Bytes::from(&[1, 2, 3, 4][..]),
+                    base: None,
+                    key: k.clone(),
+                },
+                Default::default(),
+            );
+            make_datapack(&tempdir, &vec![revision]);
+        }
+
+        let packstore = DataPackStore::new(
+            &tempdir,
+            CorruptionPolicy::REMOVE,
+            None,
+            ExtStoredPolicy::Use,
+        )
+        .with_open_limits(2, 1);
+        for k in keys.iter() {
+            let k = StoreKey::hgid(k.clone());
+            assert_eq!(packstore.get(k)?, StoreResult::Found(vec![1, 2, 3, 4]));
+        }
+        assert_eq!(packstore.open_packs()?, 2);
+
+        assert_eq!(packstore.release_idle()?, 1);
+        assert_eq!(packstore.open_packs()?, 1);
+
+        // Closed packs are reopened on demand.
+        let k = StoreKey::hgid(keys[0].clone());
+        assert_eq!(packstore.get(k)?, StoreResult::Found(vec![1, 2, 3, 4]));
+        assert_eq!(packstore.open_packs()?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_limits_reopened_packs_stay_open() -> Result<()> {
+        let tempdir = TempDir::new()?;
+
+        let keys = vec![key("a", "1"), key("b", "2"), key("c", "3")];
+        for k in keys.iter() {
+            let revision = (
+                Delta {
+                    data: Bytes::from(&[1, 2, 3, 4][..]),
+                    base: None,
+                    key: k.clone(),
+                },
+                Default::default(),
+            );
+            make_datapack(&tempdir, &vec![revision]);
+        }
+
+        let packstore = DataPackStore::new(
+            &tempdir,
+            CorruptionPolicy::REMOVE,
+            None,
+            ExtStoredPolicy::Use,
+        )
+        .with_open_limits(2, 0);
+        assert!(packstore.get_missing(&[])?.is_empty());
+        assert_eq!(packstore.release_idle()?, 2);
+        assert_eq!(packstore.open_packs()?, 0);
+
+        // A miss reopens the most recently used packs, which then stay open.
+        let missing = StoreKey::hgid(key("d", "4"));
+        assert_eq!(
+            packstore.get(missing.clone())?,
+            StoreResult::NotFound(missing.clone())
+        );
+        assert_eq!(packstore.open_packs()?, 2);
+
+        // Packs past the limit are still searched.
+        let mut lookup = keys
+            .iter()
+            .map(|k| StoreKey::hgid(k.clone()))
+            .collect::<Vec<_>>();
+        lookup.push(missing.clone());
+        assert_eq!(packstore.get_missing(&lookup)?, vec![missing]);
+        assert_eq!(packstore.open_packs()?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_missing_skips_unopenable_closed_packs() -> Result<()> {
+        let tempdir = TempDir::new()?;
+
+        let keys = vec![key("a", "1"), key("b", "2"), key("c", "3")];
+        for k in keys.iter() {
+            let revision = (
+                Delta {
+                    data: Bytes::from(&[1, 2, 3, 4][..]),
+                    base: None,
+                    key: k.clone(),
+                },
+                Default::default(),
+            );
+            make_datapack(&tempdir, &vec![revision]);
+        }
+
+        let packstore = DataPackStore::new(
+            &tempdir,
+            CorruptionPolicy::REMOVE,
+            None,
+            ExtStoredPolicy::Use,
+        )
+        .with_open_limits(1, 1);
+        let lookup = keys
+            .iter()
+            .map(|k| StoreKey::hgid(k.clone()))
+            .collect::<Vec<_>>();
+        assert!(packstore.get_missing(&lookup)?.is_empty());
+
+        // The closed packs are removed, and can't be reopened anymore.
+        for path in packstore.inner.lock().closed.borrow().iter() {
+            fs::remove_file(path)?;
+        }
+        assert_eq!(packstore.get_missing(&lookup)?.len(), 2);
+        Ok(())
+    }
+
     #[test]
     fn test_rescan_no_dir() -> Result<()> {
         let tempdir = TempDir::new()?;
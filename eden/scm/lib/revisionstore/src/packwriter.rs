@@ -9,10 +9,13 @@ use std::cell::RefCell;
 use std::cell::RefMut;
 use std::fmt::Debug;
 use std::io::BufWriter;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
 use std::io::{self};
 
 use anyhow::Result;
+use tempfile::NamedTempFile;
 
 /// A `PackWriter` will buffers all the writes to `T` and count the total number of bytes written.
 pub struct PackWriter<T: Write> {
@@ -53,6 +56,18 @@ impl<T: 'static + Write + Debug + Send + Sync> PackWriter<T> {
     }
 }
 
+impl PackWriter<NamedTempFile> {
+    /// Drop everything written after the first `len` bytes, the next writes start from there.
+    pub fn truncate(&mut self, len: u64) -> Result<()> {
+        self.flush_inner()?;
+        let file = self.data.get_mut().get_mut();
+        file.as_file().set_len(len)?;
+        file.seek(SeekFrom::Start(len))?;
+        self.bytes_written = len;
+        Ok(())
+    }
+}
+
 impl<T: Write> Write for PackWriter<T> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let ret = self.data.get_mut().write(buf)?;
@@ -67,8 +82,7 @@ impl<T: Write> Write for PackWriter<T> {
 
 #[cfg(test)]
 mod tests {
-    use std::io::Seek;
-    use std::io::SeekFrom;
+    use std::io::Read;
 
     use byteorder::ReadBytesExt;
     use byteorder::WriteBytesExt;
@@ -117,4 +131,19 @@ mod tests {
         let data = inner.read_u8().unwrap();
         assert_eq!(data, 10);
     }
+
+    #[test]
+    fn test_truncate() {
+        let mut file = PackWriter::new(NamedTempFile::new().unwrap());
+        file.write_all(&[1, 2, 3]).unwrap();
+        file.truncate(1).unwrap();
+        file.write_u8(4).unwrap();
+        assert_eq!(file.bytes_written(), 2);
+
+        let mut inner = file.into_inner().unwrap();
+        inner.seek(SeekFrom::Start(0)).unwrap();
+        let mut data = Vec::new();
+        inner.read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![1, 4]);
+    }
 }
@@ -24,6 +24,7 @@ use types::Key;
 
 use crate::datapack::DataPack;
 use crate::datapack::DataPackVersion;
+use crate::datastore::Delta;
 use crate::datastore::HgIdDataStore;
 use crate::datastore::HgIdMutableDeltaStore;
 use crate::datastore::StoreResult;
@@ -84,6 +85,73 @@ fn repack_datapack(data_pack: &DataPack, mut_pack: &mut MutableDataPack) -> Resu
     Ok(())
 }
 
+/// Split `pack` into packs of `dir` of at most `max_bytes` bytes each, returning their paths.
+///
+/// Delta chains are kept within a single pack: an entry whose delta base isn't in the pack being
+/// written is stored as a fulltext. A single entry larger than `max_bytes` gets a pack of its own.
+pub fn split_datapack(pack: &DataPack, dir: &Path, max_bytes: u64) -> Result<Vec<PathBuf>> {
+    let mut shard = MutableDataPack::new(dir, DataPackVersion::One);
+    let mut shard_keys = HashSet::new();
+    let mut shards = Vec::new();
+
+    for key in pack.to_keys() {
+        let key = key?;
+        let mut delta = match pack
+            .get_delta_chain(&key)?
+            .and_then(|chain| chain.into_iter().next())
+        {
+            Some(delta) => delta,
+            None => continue,
+        };
+        let meta = match pack.get_meta(StoreKey::hgid(key.clone()))? {
+            StoreResult::Found(meta) => meta,
+            StoreResult::NotFound(_) => continue,
+        };
+
+        if delta
+            .base
+            .as_ref()
+            .map_or(false, |base| !shard_keys.contains(&base.hgid))
+        {
+            make_fulltext(pack, &mut delta)?;
+        }
+
+        // The uncompressed size of the entry is an upper bound of its size in the pack, give or
+        // take a few bytes of compression overhead.
+        let entry_len = (delta.data.len() + key.path.as_byte_slice().len() + 72) as u64;
+        if !shard.is_empty() && shard.data_len() + entry_len > max_bytes {
+            shards.extend(shard.flush()?.unwrap_or_default());
+            shard_keys.clear();
+            // The base of the entry was in the pack that was just flushed.
+            if delta.base.is_some() {
+                make_fulltext(pack, &mut delta)?;
+            }
+        }
+
+        shard.add(&delta, &meta)?;
+        shard_keys.insert(key.hgid);
+    }
+
+    shards.extend(shard.flush()?.unwrap_or_default());
+    Ok(shards)
+}
+
+/// Replace `delta` by the fulltext of its key in `pack`.
+fn make_fulltext(pack: &DataPack, delta: &mut Delta) -> Result<()> {
+    match pack.get(StoreKey::hgid(delta.key.clone()))? {
+        StoreResult::Found(data) => {
+            delta.data = data.into();
+            delta.base = None;
+            Ok(())
+        }
+        StoreResult::NotFound(_) => Err(format_err!(
+            "cannot rebuild the fulltext of {:?} from pack '{:?}'",
+            delta.key,
+            pack.base_path()
+        )),
+    }
+}
+
 #[derive(Debug, Error)]
 enum RepackFailure {
     #[error("Repack failure: {0:?}")]
@@ -506,7 +574,7 @@ mod tests {
 
     use super::*;
     use crate::datapack::tests::make_datapack;
-    use crate::datastore::Delta;
+    use crate::datastore::Metadata;
     use crate::historypack::tests::get_nodes;
     use crate::historypack::tests::make_historypack;
 
@@ -628,6 +696,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_split_datapack() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let delta = |node: &str, data: &'static [u8], base: Option<Key>| -> (Delta, Metadata) {
+            (
+                Delta {
+                    data: Bytes::from_static(data),
+                    base,
+                    key: key("a", node),
+                },
+                Default::default(),
+            )
+        };
+        let revisions = vec![
+            delta("1", b"My data", None),
+            delta(
+                "2",
+                b"\x00\x00\x00\x03\x00\x00\x00\x03\x00\x00\x00\x0Adeltafied ",
+                Some(key("a", "1")),
+            ),
+            delta(
+                "3",
+                b"\x00\x00\x00\x03\x00\x00\x00\x0D\x00\x00\x00\x10still deltafied ",
+                Some(key("a", "2")),
+            ),
+        ];
+        let fulltexts: Vec<&[u8]> =
+            vec![b"My data", b"My deltafied data", b"My still deltafied data"];
+        let pack = make_datapack(&tempdir, &revisions);
+
+        // Each shard must be readable on its own.
+        let check_shards = |shards: &[PathBuf]| -> Result<()> {
+            let mut found = 0;
+            for shard in shards {
+                let shard = DataPack::new(shard, ExtStoredPolicy::Use)?;
+                for ((delta, _), fulltext) in revisions.iter().zip(fulltexts.iter()) {
+                    if let StoreResult::Found(data) =
+                        shard.get(StoreKey::hgid(delta.key.clone()))?
+                    {
+                        assert_eq!(&data[..], *fulltext);
+                        found += 1;
+                    }
+                }
+            }
+            assert_eq!(found, revisions.len());
+            Ok(())
+        };
+
+        let bigdir = TempDir::new()?;
+        let shards = split_datapack(&pack, bigdir.path(), 1024 * 1024)?;
+        assert_eq!(shards.len(), 1);
+        check_shards(&shards)?;
+
+        let smalldir = TempDir::new()?;
+        let shards = split_datapack(&pack, smalldir.path(), 100)?;
+        assert_eq!(shards.len(), revisions.len());
+        check_shards(&shards)?;
+        Ok(())
+    }
+
     #[test]
     fn test_repack_multiple_datapacks() {
         let tempdir = TempDir::new().unwrap();
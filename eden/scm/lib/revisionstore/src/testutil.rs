@@ -7,6 +7,7 @@
 
 use std::collections::HashMap;
 use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::Error;
@@ -31,6 +32,8 @@ use futures::prelude::*;
 #[cfg(test)]
 pub use lfs_mocks::*;
 use minibytes::Bytes;
+use mpatch::mpatch::get_full_text;
+use parking_lot::RwLock;
 use types::Key;
 use types::NodeInfo;
 use types::Parents;
@@ -57,6 +60,113 @@ pub fn delta(data: &str, base: Option<Key>, key: Key) -> Delta {
     }
 }
 
+/// A `HgIdDataStore` keeping its deltas in memory, for tests and benchmarks of code using data
+/// stores that don't need a pack on disk.
+#[derive(Default)]
+pub struct InMemoryDataStore {
+    entries: RwLock<HashMap<Key, (Delta, Metadata)>>,
+}
+
+impl InMemoryDataStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn get_delta(&self, key: &Key) -> Result<Option<Delta>> {
+        Ok(self.entries.read().get(key).map(|(delta, _)| delta.clone()))
+    }
+
+    /// Follow the bases of the delta of `key` for as long as they are in this store.
+    pub fn get_delta_chain(&self, key: &Key) -> Result<Option<Vec<Delta>>> {
+        let entries = self.entries.read();
+        let mut chain: Vec<Delta> = Vec::new();
+        let mut next_key = Some(key);
+        while let Some(key) = next_key {
+            let delta = match entries.get(key) {
+                Some((delta, _)) => delta,
+                None => break,
+            };
+            if chain.len() > 1000 {
+                return Err(Error::msg("Delta chain too long"));
+            }
+            chain.push(delta.clone());
+            next_key = delta.base.as_ref();
+        }
+
+        Ok(if chain.is_empty() { None } else { Some(chain) })
+    }
+}
+
+impl HgIdDataStore for InMemoryDataStore {
+    fn get(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>> {
+        let key = match key {
+            StoreKey::HgId(key) => key,
+            content => return Ok(StoreResult::NotFound(content)),
+        };
+
+        let delta_chain = match self.get_delta_chain(&key)? {
+            Some(chain) => chain,
+            None => return Ok(StoreResult::NotFound(StoreKey::HgId(key))),
+        };
+        let (basetext, deltas) = match delta_chain.split_last() {
+            Some((base, delta)) => (base, delta),
+            None => return Ok(StoreResult::NotFound(StoreKey::HgId(key))),
+        };
+        let deltas: Vec<&[u8]> = deltas
+            .iter()
+            .rev()
+            .map(|delta| delta.data.as_ref())
+            .collect();
+
+        Ok(StoreResult::Found(
+            get_full_text(basetext.data.as_ref(), &deltas).map_err(Error::msg)?,
+        ))
+    }
+
+    fn get_meta(&self, key: StoreKey) -> Result<StoreResult<Metadata>> {
+        let entries = self.entries.read();
+        let metadata = match &key {
+            StoreKey::HgId(k) => entries.get(k).map(|(_, metadata)| *metadata),
+            StoreKey::Content(_, _) => None,
+        };
+        Ok(match metadata {
+            Some(metadata) => StoreResult::Found(metadata),
+            None => StoreResult::NotFound(key),
+        })
+    }
+
+    fn refresh(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl LocalStore for InMemoryDataStore {
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        let entries = self.entries.read();
+        Ok(keys
+            .iter()
+            .filter(|k| match k {
+                StoreKey::HgId(k) => !entries.contains_key(k),
+                StoreKey::Content(_, _) => true,
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+impl HgIdMutableDeltaStore for InMemoryDataStore {
+    fn add(&self, delta: &Delta, metadata: &Metadata) -> Result<()> {
+        self.entries
+            .write()
+            .insert(delta.key.clone(), (delta.clone(), *metadata));
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<Option<Vec<PathBuf>>> {
+        Ok(None)
+    }
+}
+
 pub struct FakeHgIdRemoteStore {
     data: Option<HashMap<Key, (Bytes, Option<u64>)>>,
     hist: Option<HashMap<Key, NodeInfo>>,
@@ -583,3 +693,46 @@ mod lfs_mocks {
         config
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use types::testutil::*;
+
+    use super::*;
+
+    #[test]
+    fn test_in_memory_data_store() -> Result<()> {
+        let store = InMemoryDataStore::new();
+        let base = delta("My data", None, key("a", "1"));
+        // Inserts "deltafied " at offset 3.
+        let patch = b"\x00\x00\x00\x03\x00\x00\x00\x03\x00\x00\x00\x0Adeltafied ";
+        let child = Delta {
+            data: Bytes::from_static(patch),
+            base: Some(base.key.clone()),
+            key: key("a", "2"),
+        };
+        store.add(&base, &Default::default())?;
+        store.add(&child, &Default::default())?;
+
+        assert_eq!(store.get_delta(&child.key)?, Some(child.clone()));
+        assert_eq!(
+            store.get_delta_chain(&child.key)?,
+            Some(vec![child.clone(), base.clone()])
+        );
+        assert_eq!(
+            store.get(StoreKey::hgid(child.key.clone()))?,
+            StoreResult::Found(b"My deltafied data".to_vec())
+        );
+        assert_eq!(
+            store.get_meta(StoreKey::hgid(base.key.clone()))?,
+            StoreResult::Found(Default::default())
+        );
+
+        let missing = StoreKey::hgid(key("a", "3"));
+        assert_eq!(
+            store.get_missing(&[StoreKey::hgid(base.key), missing.clone()])?,
+            vec![missing]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,632 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A single store view over many datapacks.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use anyhow::format_err;
+use anyhow::Error;
+use anyhow::Result;
+use mpatch::mpatch::get_full_text;
+use parking_lot::Mutex;
+use types::Key;
+
+use crate::datapack::DataPack;
+use crate::datastore::Delta;
+use crate::datastore::HgIdDataStore;
+use crate::datastore::Metadata;
+use crate::datastore::StoreResult;
+use crate::localstore::ExtStoredPolicy;
+use crate::localstore::LocalStore;
+use crate::repack::ToKeys;
+use crate::types::StoreKey;
+
+/// Read-only view over a set of datapacks.
+///
+/// Reads are answered by the first pack that contains the key, in the order the packs were
+/// given. Looking a key up in a pack is a binary search in its memory mapped index, so the packs
+/// are simply queried in turn.
+pub struct UnionDataPack {
+    packs: Vec<DataPack>,
+    chain_cache: Option<Mutex<DeltaChainCache>>,
+}
+
+/// Resolved delta chains, evicted oldest first once their total size exceeds `max_bytes`.
+///
+/// Packs are immutable, so cached chains never need to be invalidated.
+struct DeltaChainCache {
+    max_bytes: usize,
+    bytes: usize,
+    chains: HashMap<Key, Vec<Delta>>,
+    order: VecDeque<Key>,
+}
+
+impl DeltaChainCache {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            bytes: 0,
+            chains: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn chain_size(chain: &[Delta]) -> usize {
+        chain.iter().map(|delta| delta.data.len()).sum()
+    }
+
+    fn get(&self, key: &Key) -> Option<Vec<Delta>> {
+        self.chains.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: Key, chain: Vec<Delta>) {
+        let size = Self::chain_size(&chain);
+        if size > self.max_bytes || self.chains.contains_key(&key) {
+            return;
+        }
+
+        while self.bytes + size > self.max_bytes {
+            let oldest = match self.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(evicted) = self.chains.remove(&oldest) {
+                self.bytes -= Self::chain_size(&evicted);
+            }
+        }
+
+        self.bytes += size;
+        self.order.push_back(key.clone());
+        self.chains.insert(key, chain);
+    }
+}
+
+impl UnionDataPack {
+    pub fn new(packs: Vec<DataPack>) -> Self {
+        Self {
+            packs,
+            chain_cache: None,
+        }
+    }
+
+    /// Creates a `UnionDataPack` that caches resolved delta chains, up to `max_bytes` of deltas.
+    ///
+    /// This speeds up repeated reads of the same keys, at the cost of keeping copies of their
+    /// deltas in memory.
+    pub fn with_chain_cache(packs: Vec<DataPack>, max_bytes: usize) -> Self {
+        Self {
+            packs,
+            chain_cache: Some(Mutex::new(DeltaChainCache::new(max_bytes))),
+        }
+    }
+
+    /// Opens all the datapacks in `dir`. A missing directory is treated as an empty store.
+    pub fn open(dir: &Path, extstored_policy: ExtStoredPolicy) -> Result<Self> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::new(vec![])),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() == Some("datapack".as_ref()) {
+                paths.push(path.with_extension(""));
+            }
+        }
+        paths.sort_unstable();
+
+        let packs = paths
+            .iter()
+            .map(|path| DataPack::new(path, extstored_policy))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::new(packs))
+    }
+
+    pub fn packs(&self) -> &[DataPack] {
+        &self.packs
+    }
+
+    pub fn len(&self) -> usize {
+        self.packs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packs.is_empty()
+    }
+
+    /// Iterate over every key stored in the packs once, with its delta and metadata.
+    ///
+    /// When a key is in several packs, the entry with the shortest delta chain within its pack
+    /// is yielded, or the one in the earliest pack among equally short chains. Keys are yielded
+    /// in the order of the packs holding their selected entry.
+    pub fn iter_deduplicated(&self) -> impl Iterator<Item = Result<(Key, Delta, Metadata)>> + '_ {
+        let (selected, error) = match self.select_shortest_chains() {
+            Ok(selected) => (selected, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        error.map(Err).into_iter().chain(
+            selected.into_iter().filter_map(move |(index, key)| {
+                self.read_entry(&self.packs[index], key).transpose()
+            }),
+        )
+    }
+
+    /// Pick the pack to read each key from for `iter_deduplicated`, returning the pack index
+    /// and key of each selected entry.
+    fn select_shortest_chains(&self) -> Result<Vec<(usize, Key)>> {
+        // Pack index and chain length of the best entry found so far for each key.
+        let mut best: HashMap<Key, (usize, usize)> = HashMap::new();
+        for (index, pack) in self.packs.iter().enumerate() {
+            for key in pack.to_keys() {
+                let key = key?;
+                let len = match pack.delta_chain_len(&key)? {
+                    Some(len) => len,
+                    None => continue,
+                };
+                match best.get(&key) {
+                    Some((_, best_len)) if *best_len <= len => {}
+                    _ => {
+                        best.insert(key, (index, len));
+                    }
+                }
+            }
+        }
+
+        let mut selected = Vec::with_capacity(best.len());
+        for (index, pack) in self.packs.iter().enumerate() {
+            for key in pack.to_keys() {
+                let key = key?;
+                if best.get(&key).map(|(best_index, _)| *best_index) == Some(index) {
+                    best.remove(&key);
+                    selected.push((index, key));
+                }
+            }
+        }
+        Ok(selected)
+    }
+
+    fn read_entry(&self, pack: &DataPack, key: Key) -> Result<Option<(Key, Delta, Metadata)>> {
+        let delta = match pack.get_delta_chain_stream(&key).next() {
+            Some(delta) => delta?,
+            // The entry is ignored by the `ExtStoredPolicy`.
+            None => return Ok(None),
+        };
+        let metadata = match pack.get_meta(StoreKey::hgid(key.clone()))? {
+            StoreResult::Found(metadata) => metadata,
+            StoreResult::NotFound(_) => return Ok(None),
+        };
+        Ok(Some((key, delta, metadata)))
+    }
+
+    /// Returns the delta chain for `key`, following delta bases across packs.
+    ///
+    /// The chain starts with the delta for `key` and ends with a fulltext, unless a base is not
+    /// present in any of the packs, in which case the partial chain found so far is returned.
+    pub(crate) fn get_delta_chain(&self, key: &Key) -> Result<Option<Vec<Delta>>> {
+        let cache = match &self.chain_cache {
+            None => return self.resolve_delta_chain(key),
+            Some(cache) => cache,
+        };
+
+        if let Some(chain) = cache.lock().get(key) {
+            return Ok(Some(chain));
+        }
+        let chain = self.resolve_delta_chain(key)?;
+        if let Some(chain) = &chain {
+            cache.lock().insert(key.clone(), chain.clone());
+        }
+        Ok(chain)
+    }
+
+    fn resolve_delta_chain(&self, key: &Key) -> Result<Option<Vec<Delta>>> {
+        let mut chain: Vec<Delta> = Default::default();
+        let mut next_key = Some(key.clone());
+        while let Some(key) = next_key {
+            // Same bound as `DataPack::get_delta_chain`, as a base pointing back to one of its
+            // descendants in another pack would otherwise loop forever.
+            if chain.len() > 1000 {
+                return Err(format_err!("Delta chain too long"));
+            }
+
+            let mut found = None;
+            for pack in self.packs.iter() {
+                if let Some(part) = pack.get_delta_chain(&key)? {
+                    found = Some(part);
+                    break;
+                }
+            }
+
+            let part = match found {
+                Some(part) => part,
+                None if chain.is_empty() => return Ok(None),
+                None => break,
+            };
+            next_key = part.last().and_then(|delta| delta.base.clone());
+            chain.extend(part);
+        }
+
+        Ok(Some(chain))
+    }
+
+    /// Checks that every entry of every pack can be read back, verifying several packs
+    /// concurrently.
+    ///
+    /// `progress` is called from the verifying threads each time a pack is done, with the number
+    /// of packs verified so far, the total number of packs, and the number of pack bytes verified
+    /// so far. Returns the path of each pack that failed verification along with the first error
+    /// found in it.
+    pub fn verify_store(
+        &self,
+        progress: &(dyn Fn(usize, usize, u64) + Sync),
+    ) -> Vec<(PathBuf, Error)> {
+        let total = self.packs.len();
+        let next = AtomicUsize::new(0);
+        let completed = AtomicUsize::new(0);
+        let bytes_verified = AtomicU64::new(0);
+        let workers = thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(total);
+
+        thread::scope(|scope| {
+            let handles = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut failures = Vec::new();
+                        loop {
+                            let index = next.fetch_add(1, Ordering::Relaxed);
+                            let pack = match self.packs.get(index) {
+                                Some(pack) => pack,
+                                None => break,
+                            };
+                            if let Err(e) = self.verify_pack(pack) {
+                                failures.push((pack.pack_path().to_path_buf(), e));
+                            }
+
+                            let bytes = bytes_verified
+                                .fetch_add(pack.len() as u64, Ordering::Relaxed)
+                                + pack.len() as u64;
+                            let done = completed.fetch_add(1, Ordering::Relaxed) + 1;
+                            progress(done, total, bytes);
+                        }
+                        failures
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("verify thread panicked"))
+                .collect()
+        })
+    }
+
+    /// Reconstructs every entry of `pack`, resolving delta bases from all the packs.
+    fn verify_pack(&self, pack: &DataPack) -> Result<()> {
+        for key in pack.to_keys() {
+            let key = StoreKey::hgid(key?);
+            if let StoreResult::NotFound(key) = self.get(key)? {
+                return Err(format_err!("cannot reconstruct {:?}", key));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl HgIdDataStore for UnionDataPack {
+    fn get(&self, key: StoreKey) -> Result<StoreResult<Vec<u8>>> {
+        let key = match key {
+            StoreKey::HgId(key) => key,
+            content => return Ok(StoreResult::NotFound(content)),
+        };
+
+        let delta_chain = match self.get_delta_chain(&key)? {
+            Some(chain) => chain,
+            None => return Ok(StoreResult::NotFound(StoreKey::hgid(key))),
+        };
+
+        // A chain that doesn't end with a fulltext can't be resolved from these packs.
+        let (basetext, deltas) = match delta_chain.split_last() {
+            Some((base, deltas)) if base.base.is_none() => (base, deltas),
+            _ => return Ok(StoreResult::NotFound(StoreKey::hgid(key))),
+        };
+
+        let deltas: Vec<&[u8]> = deltas
+            .iter()
+            .rev()
+            .map(|delta| delta.data.as_ref())
+            .collect();
+
+        Ok(StoreResult::Found(
+            get_full_text(basetext.data.as_ref(), &deltas).map_err(Error::msg)?,
+        ))
+    }
+
+    fn get_meta(&self, mut key: StoreKey) -> Result<StoreResult<Metadata>> {
+        for pack in self.packs.iter() {
+            match pack.get_meta(key)? {
+                StoreResult::Found(meta) => return Ok(StoreResult::Found(meta)),
+                StoreResult::NotFound(next) => key = next,
+            }
+        }
+
+        Ok(StoreResult::NotFound(key))
+    }
+
+    fn refresh(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl LocalStore for UnionDataPack {
+    /// A key is only missing if none of the packs contain it.
+    fn get_missing(&self, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+        let mut missing = keys.to_vec();
+        for pack in self.packs.iter() {
+            if missing.is_empty() {
+                break;
+            }
+            missing = pack.get_missing(&missing)?;
+        }
+        Ok(missing)
+    }
+}
+
+/// Returns the keys from `keys` that are in none of the datapacks in `dir`.
+///
+/// This is the store wide version of `LocalStore::get_missing` on a single pack.
+pub fn store_get_missing(dir: &Path, keys: &[StoreKey]) -> Result<Vec<StoreKey>> {
+    UnionDataPack::open(dir, ExtStoredPolicy::Use)?.get_missing(keys)
+}
+
+impl ToKeys for UnionDataPack {
+    fn to_keys(&self) -> Vec<Result<Key>> {
+        self.packs.iter().flat_map(|pack| pack.to_keys()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use minibytes::Bytes;
+    use tempfile::TempDir;
+    use types::testutil::*;
+
+    use super::*;
+    use crate::datapack::tests::make_datapack;
+
+    fn fulltext(path: &str, hgid: &str, data: &'static [u8]) -> (Delta, Metadata) {
+        (
+            Delta {
+                data: Bytes::from(data),
+                base: None,
+                key: key(path, hgid),
+            },
+            Default::default(),
+        )
+    }
+
+    #[test]
+    fn test_get() -> Result<()> {
+        let tempdir1 = TempDir::new()?;
+        let tempdir2 = TempDir::new()?;
+        let revisions1 = vec![fulltext("a", "1", b"one")];
+        let revisions2 = vec![fulltext("b", "2", b"two")];
+        let union = UnionDataPack::new(vec![
+            make_datapack(&tempdir1, &revisions1),
+            make_datapack(&tempdir2, &revisions2),
+        ]);
+
+        for (delta, metadata) in revisions1.into_iter().chain(revisions2) {
+            let k = StoreKey::hgid(delta.key);
+            assert_eq!(
+                union.get(k.clone())?,
+                StoreResult::Found(delta.data.as_ref().to_vec())
+            );
+            assert_eq!(union.get_meta(k)?, StoreResult::Found(metadata));
+        }
+
+        let not = StoreKey::hgid(key("c", "3"));
+        assert_eq!(union.get(not.clone())?, StoreResult::NotFound(not));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_deduplicated() -> Result<()> {
+        let tempdir1 = TempDir::new()?;
+        let tempdir2 = TempDir::new()?;
+        // The first pack stores "a" as a delta against "b", the second one as a fulltext.
+        let delta = Delta {
+            data: Bytes::from(&b"delta"[..]),
+            base: Some(key("a", "2")),
+            key: key("a", "1"),
+        };
+        let union = UnionDataPack::new(vec![
+            make_datapack(
+                &tempdir1,
+                &vec![
+                    fulltext("a", "2", b"two"),
+                    (delta, Default::default()),
+                    fulltext("b", "3", b"three"),
+                ],
+            ),
+            make_datapack(
+                &tempdir2,
+                &vec![fulltext("a", "1", b"one"), fulltext("b", "3", b"three")],
+            ),
+        ]);
+
+        let mut entries = union
+            .iter_deduplicated()
+            .map(|entry| entry.map(|(key, delta, _)| (key, delta.base, delta.data)))
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut expected = vec![
+            (key("a", "2"), None, Bytes::from(&b"two"[..])),
+            (key("b", "3"), None, Bytes::from(&b"three"[..])),
+            (key("a", "1"), None, Bytes::from(&b"one"[..])),
+        ];
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(entries, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_missing() -> Result<()> {
+        let tempdir1 = TempDir::new()?;
+        let tempdir2 = TempDir::new()?;
+        let union = UnionDataPack::new(vec![
+            make_datapack(&tempdir1, &vec![fulltext("a", "1", b"one")]),
+            make_datapack(&tempdir2, &vec![fulltext("b", "2", b"two")]),
+        ]);
+
+        let not = StoreKey::hgid(key("c", "3"));
+        let missing = union.get_missing(&[
+            StoreKey::hgid(key("a", "1")),
+            StoreKey::hgid(key("b", "2")),
+            not.clone(),
+        ])?;
+        assert_eq!(missing, vec![not]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_get_missing() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        make_datapack(&tempdir, &vec![fulltext("a", "1", b"one")]);
+        make_datapack(&tempdir, &vec![fulltext("b", "2", b"two")]);
+
+        let not = StoreKey::hgid(key("c", "3"));
+        let keys = vec![
+            StoreKey::hgid(key("a", "1")),
+            StoreKey::hgid(key("b", "2")),
+            not.clone(),
+        ];
+        assert_eq!(store_get_missing(tempdir.path(), &keys)?, vec![not]);
+        assert_eq!(
+            store_get_missing(&tempdir.path().join("nonexistent"), &keys)?,
+            keys
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chain_cache() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let revisions = vec![
+            fulltext("a", "1", b"one"),
+            fulltext("b", "2", b"two"),
+            fulltext("c", "3", b"three"),
+        ];
+        // Room for the first two chains, but not all three.
+        let union = UnionDataPack::with_chain_cache(vec![make_datapack(&tempdir, &revisions)], 6);
+
+        for (delta, _) in revisions.iter() {
+            assert_eq!(
+                union.get_delta_chain(&delta.key)?,
+                Some(vec![delta.clone()])
+            );
+        }
+
+        let cache = union.chain_cache.as_ref().unwrap().lock();
+        assert!(cache.bytes <= 6);
+        assert!(cache.get(&revisions[0].0.key).is_none());
+        assert!(cache.get(&revisions[2].0.key).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_store() -> Result<()> {
+        let tempdir1 = TempDir::new()?;
+        let tempdir2 = TempDir::new()?;
+        let pack1 = make_datapack(&tempdir1, &vec![fulltext("a", "1", b"one")]);
+        let pack2 = make_datapack(
+            &tempdir2,
+            &vec![(
+                Delta {
+                    data: Bytes::from(&b"delta"[..]),
+                    base: Some(key("c", "3")),
+                    key: key("b", "2"),
+                },
+                Default::default(),
+            )],
+        );
+        let bytes = (pack1.len() + pack2.len()) as u64;
+        let broken = pack2.pack_path().to_path_buf();
+        let union = UnionDataPack::new(vec![pack1, pack2]);
+
+        let calls = Mutex::new(Vec::new());
+        let failures = union
+            .verify_store(&|completed, total, bytes| calls.lock().push((completed, total, bytes)));
+
+        // The delta's base isn't in any pack.
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, broken);
+
+        let calls = calls.into_inner();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls.last().unwrap().0, 2);
+        assert!(calls.iter().all(|call| call.1 == 2));
+        assert_eq!(calls.iter().map(|call| call.2).max(), Some(bytes));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_delta_chain_across_packs() -> Result<()> {
+        let tempdir1 = TempDir::new()?;
+        let tempdir2 = TempDir::new()?;
+        let base = fulltext("a", "1", b"base");
+        // mpatch delta replacing bytes 0..4 of the base with "new".
+        let delta = Delta {
+            data: Bytes::from(&b"\0\0\0\0\0\0\0\x04\0\0\0\x03new"[..]),
+            base: Some(key("a", "1")),
+            key: key("a", "2"),
+        };
+        let union = UnionDataPack::new(vec![
+            make_datapack(&tempdir1, &vec![(delta.clone(), Default::default())]),
+            make_datapack(&tempdir2, &vec![base.clone()]),
+        ]);
+
+        assert_eq!(
+            union.get_delta_chain(&delta.key)?,
+            Some(vec![delta.clone(), base.0.clone()])
+        );
+        assert_eq!(
+            union.get(StoreKey::hgid(delta.key.clone()))?,
+            StoreResult::Found(b"new".to_vec())
+        );
+
+        // Without the pack holding the base, only the partial chain is available.
+        let partial = UnionDataPack::new(vec![make_datapack(
+            &tempdir1,
+            &vec![(delta.clone(), Default::default())],
+        )]);
+        assert_eq!(
+            partial.get_delta_chain(&delta.key)?,
+            Some(vec![delta.clone()])
+        );
+        let k = StoreKey::hgid(delta.key);
+        assert_eq!(partial.get(k.clone())?, StoreResult::NotFound(k));
+
+        Ok(())
+    }
+}
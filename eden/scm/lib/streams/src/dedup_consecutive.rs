@@ -0,0 +1,120 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Provides a stream adapter which drops items equal to the item yielded right before them.
+
+use std::pin::Pin;
+
+use futures::stream::FusedStream;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use futures::task::Context;
+use futures::task::Poll;
+
+/// Stream for the [`dedup_consecutive()`] function.
+#[must_use = "streams do nothing unless polled"]
+pub struct DedupConsecutive<St: Stream> {
+    inner: St,
+    last: Option<St::Item>,
+    done: bool,
+}
+
+/// Drop the items of `stream`, such as a `SelectAll`, which are equal to the last yielded item.
+///
+/// This is useful when several combined streams report the same state transitions. Only
+/// consecutive duplicates are dropped: an item equal to an earlier, but not the last, yielded
+/// item is yielded again.
+pub fn dedup_consecutive<St>(stream: St) -> DedupConsecutive<St>
+where
+    St: Stream + Unpin,
+    St::Item: PartialEq + Clone,
+{
+    DedupConsecutive {
+        inner: stream,
+        last: None,
+        done: false,
+    }
+}
+
+impl<St: Stream + Unpin> Unpin for DedupConsecutive<St> {}
+
+impl<St> FusedStream for DedupConsecutive<St>
+where
+    St: Stream + Unpin,
+    St::Item: PartialEq + Clone,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+impl<St> Stream for DedupConsecutive<St>
+where
+    St: Stream + Unpin,
+    St::Item: PartialEq + Clone,
+{
+    type Item = St::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            match this.inner.poll_next_unpin(cx) {
+                Poll::Ready(Some(item)) => {
+                    if this.last.as_ref() == Some(&item) {
+                        continue;
+                    }
+                    this.last = Some(item.clone());
+                    return Poll::Ready(Some(item));
+                }
+                Poll::Ready(None) => {
+                    this.done = true;
+                    this.last = None;
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.done {
+            return (0, Some(0));
+        }
+        let (lower, upper) = self.inner.size_hint();
+        (lower.min(1), upper)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use futures::stream::BoxStream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dedup_consecutive() {
+        let deduped = dedup_consecutive(stream::iter(vec![1, 1, 2, 2, 2, 1, 3, 3]));
+        assert_eq!(deduped.collect::<Vec<u8>>().await, vec![1, 2, 1, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_dedup_select_all() {
+        let a: BoxStream<'static, &str> = stream::iter(vec!["ready", "busy"]).boxed();
+        let b: BoxStream<'static, &str> = stream::iter(vec!["ready", "busy"]).boxed();
+
+        // `SelectAll` polls the streams in turn, so both report each state in a row.
+        let mut deduped = dedup_consecutive(stream::select_all(vec![a, b]));
+        assert_eq!(deduped.next().await, Some("ready"));
+        assert_eq!(deduped.next().await, Some("busy"));
+        assert_eq!(deduped.next().await, None);
+        assert!(deduped.is_terminated());
+    }
+}
@@ -0,0 +1,145 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Provides a stream adapter which yields heartbeats while the underlying stream is idle.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures::stream::FusedStream;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use futures::task::Context;
+use futures::task::Poll;
+use tokio::time::Instant;
+use tokio::time::Sleep;
+
+/// Item of the [`with_heartbeat()`] stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeartbeatItem<T> {
+    /// An item of the underlying stream.
+    Data(T),
+    /// The underlying stream didn't yield anything for an interval.
+    Heartbeat,
+}
+
+/// Stream for the [`with_heartbeat()`] function.
+#[must_use = "streams do nothing unless polled"]
+pub struct WithHeartbeat<St> {
+    inner: Option<St>,
+    interval: Duration,
+    delay: Pin<Box<Sleep>>,
+}
+
+/// Yield the items of `stream`, such as a `SelectAll`, along with a heartbeat whenever it didn't
+/// yield anything for `interval`.
+///
+/// This lets consumers tell an idle stream from a stuck one, e.g. to send keepalives. Items are
+/// yielded as soon as they are ready, heartbeats never delay them. The returned stream terminates
+/// with `stream`, i.e. once a `SelectAll` has no streams left.
+pub fn with_heartbeat<St>(stream: St, interval: Duration) -> WithHeartbeat<St>
+where
+    St: Stream + Unpin,
+{
+    assert!(interval > Duration::ZERO, "interval must be positive");
+    WithHeartbeat {
+        inner: Some(stream),
+        interval,
+        delay: Box::pin(tokio::time::sleep(interval)),
+    }
+}
+
+impl<St> FusedStream for WithHeartbeat<St>
+where
+    St: Stream + Unpin,
+{
+    fn is_terminated(&self) -> bool {
+        self.inner.is_none()
+    }
+}
+
+impl<St> Stream for WithHeartbeat<St>
+where
+    St: Stream + Unpin,
+{
+    type Item = HeartbeatItem<St::Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let inner = match this.inner.as_mut() {
+            Some(inner) => inner,
+            None => return Poll::Ready(None),
+        };
+
+        match inner.poll_next_unpin(cx) {
+            Poll::Ready(Some(item)) => {
+                let next = Instant::now() + this.interval;
+                this.delay.as_mut().reset(next);
+                return Poll::Ready(Some(HeartbeatItem::Data(item)));
+            }
+            Poll::Ready(None) => {
+                this.inner = None;
+                return Poll::Ready(None);
+            }
+            Poll::Pending => {}
+        }
+
+        match this.delay.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                let next = Instant::now() + this.interval;
+                this.delay.as_mut().reset(next);
+                Poll::Ready(Some(HeartbeatItem::Heartbeat))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use futures::stream::BoxStream;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat() {
+        let slow: BoxStream<'static, u8> = stream::once(async {
+            tokio::time::sleep(Duration::from_secs(25)).await;
+            1
+        })
+        .boxed();
+        let fast: BoxStream<'static, u8> = stream::iter(vec![2]).boxed();
+
+        let start = Instant::now();
+        let items = with_heartbeat(
+            stream::select_all(vec![slow, fast]),
+            Duration::from_secs(10),
+        )
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(
+            items,
+            vec![
+                HeartbeatItem::Data(2),
+                HeartbeatItem::Heartbeat,
+                HeartbeatItem::Heartbeat,
+                HeartbeatItem::Data(1),
+            ]
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(25));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_empty() {
+        let streams: Vec<BoxStream<'static, u8>> = Vec::new();
+        let mut heartbeats = with_heartbeat(stream::select_all(streams), Duration::from_secs(1));
+        assert_eq!(heartbeats.next().await, None);
+        assert!(heartbeats.is_terminated());
+    }
+}
@@ -6,6 +6,10 @@
  */
 
 //! `streams` provides some generic streams that can be useful in other places.
+//! - `DedupConsecutive` drops the items of a stream, such as a `SelectAll`,
+//!   which are equal to the item yielded right before them.
+//! - `WithHeartbeat` yields the items of a stream, such as a `SelectAll`,
+//!   along with heartbeats while it is idle.
 //! - `HybridStream` provides a way to use local data (with a single point get
 //!   API) and remote data (with an unordered batch get API) to resolve a stream
 //!   of input into a stream of output.
@@ -13,11 +17,40 @@
 //!   each of the combined streams after it terminates. This is useful for
 //!   preventing deadlocks when one stream is waiting on another to be dropped
 //!   to complete.
+//! - `SelectAllFailFast` provides a version of `futures::stream::SelectAll`
+//!   for streams of results, which terminates and drops all the combined
+//!   streams on the first error.
+//! - `SelectAllMap` provides a version of `futures::stream::SelectAll` which
+//!   tags the items with the key of their stream, and exposes the keys of the
+//!   streams that haven't terminated.
+//! - `TakeTotal` yields a fixed number of items from a stream, such as a
+//!   `SelectAll`, then drops it right away.
+//! - `Throttle` limits the rate at which items are yielded by a stream, such
+//!   as a `SelectAll`, buffering the items produced in the meantime.
 
+mod dedup_consecutive;
+mod heartbeat;
 mod hybrid;
+mod select_all_fail_fast;
+mod select_all_map;
 mod select_drop;
+mod take_total;
+mod throttle;
 
+pub use dedup_consecutive::dedup_consecutive;
+pub use dedup_consecutive::DedupConsecutive;
+pub use heartbeat::with_heartbeat;
+pub use heartbeat::HeartbeatItem;
+pub use heartbeat::WithHeartbeat;
 pub use hybrid::HybridResolver;
 pub use hybrid::HybridStream;
+pub use select_all_fail_fast::select_all_fail_fast;
+pub use select_all_fail_fast::SelectAllFailFast;
+pub use select_all_map::SelectAllMap;
 pub use select_drop::select_drop;
 pub use select_drop::SelectDrop;
+pub use take_total::take_total;
+pub use take_total::TakeTotal;
+pub use throttle::throttle;
+pub use throttle::Throttle;
+pub use throttle::ThrottleBufferFull;
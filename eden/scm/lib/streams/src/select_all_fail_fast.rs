@@ -0,0 +1,218 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Provides a version of `futures::stream::select_all` for streams of results which terminates
+//! on the first error, dropping all the streams.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+
+use futures::stream::FusedStream;
+use futures::stream::SelectAll;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use futures::task::Context;
+use futures::task::Poll;
+
+/// Stream for the [`select_all_fail_fast()`] function.
+#[must_use = "streams do nothing unless polled"]
+pub struct SelectAllFailFast<St: Stream> {
+    inner: Option<SelectAll<St>>,
+    /// Items yielded by the initial poll of `push_and_poll`, returned before polling `inner`.
+    ready: VecDeque<St::Item>,
+}
+
+/// Convert a list of streams of results into a stream of results from all
+/// of them, like `futures::stream::select_all`.
+///
+/// The first error from any of the streams is yielded, after which all the
+/// streams are dropped and the returned stream terminates, instead of
+/// carrying on with the other streams.
+pub fn select_all_fail_fast<I, T, E>(streams: I) -> SelectAllFailFast<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Stream<Item = Result<T, E>> + Unpin,
+{
+    SelectAllFailFast {
+        inner: Some(futures::stream::select_all(streams)),
+        ready: VecDeque::new(),
+    }
+}
+
+impl<St, T, E> SelectAllFailFast<St>
+where
+    St: Stream<Item = Result<T, E>> + Unpin,
+{
+    /// Add a stream to the set of combined streams.
+    ///
+    /// Like `SelectAll::push`, the stream isn't polled until this stream is polled next. If this
+    /// stream already terminated, the pushed stream is dropped.
+    pub fn push(&mut self, stream: St) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.push(stream);
+        }
+    }
+
+    /// Add a stream to the set of combined streams, polling it once right away with `cx`.
+    ///
+    /// Unlike `push`, this lets edge-triggered streams, which only wake their task on new data
+    /// once they have been polled, register the waker of the current task immediately, instead
+    /// of missing the wakeups that happen before this stream is polled next. An item yielded by
+    /// the initial poll is returned by the next poll of this stream, and the task is woken so it
+    /// isn't missed either. If this stream already terminated, the pushed stream is dropped
+    /// without being polled.
+    pub fn push_and_poll(&mut self, mut stream: St, cx: &mut Context<'_>) {
+        let inner = match self.inner.as_mut() {
+            Some(inner) => inner,
+            None => return,
+        };
+        match stream.poll_next_unpin(cx) {
+            Poll::Ready(Some(item)) => {
+                self.ready.push_back(item);
+                cx.waker().wake_by_ref();
+                inner.push(stream);
+            }
+            // The stream is already exhausted.
+            Poll::Ready(None) => {}
+            Poll::Pending => inner.push(stream),
+        }
+    }
+}
+
+// The items buffered by `push_and_poll` are never pinned.
+impl<St: Stream> Unpin for SelectAllFailFast<St> {}
+
+impl<St, T, E> FusedStream for SelectAllFailFast<St>
+where
+    St: Stream<Item = Result<T, E>> + Unpin,
+{
+    fn is_terminated(&self) -> bool {
+        self.inner.is_none()
+    }
+}
+
+impl<St, T, E> Stream for SelectAllFailFast<St>
+where
+    St: Stream<Item = Result<T, E>> + Unpin,
+{
+    type Item = Result<T, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let inner = match this.inner.as_mut() {
+            Some(inner) => inner,
+            None => return Poll::Ready(None),
+        };
+        let poll = match this.ready.pop_front() {
+            Some(item) => Poll::Ready(Some(item)),
+            None => inner.poll_next_unpin(cx),
+        };
+        match poll {
+            Poll::Ready(Some(Err(e))) => {
+                this.inner = None;
+                this.ready.clear();
+                Poll::Ready(Some(Err(e)))
+            }
+            Poll::Ready(None) => {
+                this.inner = None;
+                Poll::Ready(None)
+            }
+            poll => poll,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use futures::future;
+    use futures::stream;
+    use futures::stream::BoxStream;
+
+    use super::*;
+
+    /// Sets the flag when dropped.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fail_fast() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = DropFlag(dropped.clone());
+        let pending: BoxStream<'static, Result<u8, u8>> = stream::pending()
+            .map(move |x| {
+                let _ = &flag;
+                x
+            })
+            .boxed();
+        let failing: BoxStream<'static, Result<u8, u8>> =
+            stream::iter(vec![Ok(1), Err(2), Ok(3)]).boxed();
+
+        let mut combined = select_all_fail_fast(vec![pending, failing]);
+        assert_eq!(combined.next().await, Some(Ok(1)));
+        assert_eq!(combined.next().await, Some(Err(2)));
+        assert!(dropped.load(Ordering::SeqCst));
+        assert!(combined.is_terminated());
+        assert_eq!(combined.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_push_and_poll() {
+        let registered = Arc::new(AtomicBool::new(false));
+        let edge_triggered: BoxStream<'static, Result<u8, u8>> = {
+            let registered = registered.clone();
+            stream::poll_fn(move |_cx| {
+                registered.store(true, Ordering::SeqCst);
+                Poll::Pending
+            })
+            .boxed()
+        };
+        let pending: BoxStream<'static, Result<u8, u8>> = stream::pending().boxed();
+        let mut combined = select_all_fail_fast(vec![pending]);
+
+        let mut edge_triggered = Some(edge_triggered);
+        future::poll_fn(|cx| {
+            combined.push_and_poll(edge_triggered.take().unwrap(), cx);
+            Poll::Ready(())
+        })
+        .await;
+        assert!(registered.load(Ordering::SeqCst));
+
+        // An item yielded by the initial poll isn't lost.
+        let mut ready = Some(stream::iter(vec![Ok(1)]).boxed());
+        future::poll_fn(|cx| {
+            combined.push_and_poll(ready.take().unwrap(), cx);
+            Poll::Ready(())
+        })
+        .await;
+        assert_eq!(combined.next().await, Some(Ok(1)));
+    }
+
+    #[tokio::test]
+    async fn test_no_error() {
+        let streams = vec![
+            stream::iter(vec![Ok::<_, ()>(1), Ok(2)]),
+            stream::iter(vec![Ok(3)]),
+        ];
+        let mut collected = select_all_fail_fast(streams)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<u8>, ()>>()
+            .unwrap();
+        collected.sort_unstable();
+        assert_eq!(collected, vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,201 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Provides a version of `futures::stream::SelectAll` which tags the items of each stream with
+//! its key, and keeps track of the keys of the streams that haven't terminated.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::pin::Pin;
+
+use futures::stream::FusedStream;
+use futures::stream::SelectAll;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use futures::task::Context;
+use futures::task::Poll;
+
+/// A stream tagged with its key, which yields `(key, None)` once it terminates.
+struct Keyed<K, St> {
+    key: K,
+    inner: Option<St>,
+}
+
+impl<K, St> Stream for Keyed<K, St>
+where
+    K: Clone + Unpin,
+    St: Stream + Unpin,
+{
+    type Item = (K, Option<St::Item>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let inner = match this.inner.as_mut() {
+            Some(inner) => inner,
+            None => return Poll::Ready(None),
+        };
+        match inner.poll_next_unpin(cx) {
+            Poll::Ready(Some(item)) => Poll::Ready(Some((this.key.clone(), Some(item)))),
+            Poll::Ready(None) => {
+                this.inner = None;
+                Poll::Ready(Some((this.key.clone(), None)))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A set of streams identified by keys, yielding `(key, item)` pairs from all of them.
+///
+/// The keys of the streams that haven't terminated are available through `keys`, so that the
+/// set can be rebuilt, e.g. after a restart, by recreating the streams for those keys.
+#[must_use = "streams do nothing unless polled"]
+pub struct SelectAllMap<K, St> {
+    inner: SelectAll<Keyed<K, St>>,
+    /// Number of active streams for each key.
+    active: HashMap<K, usize>,
+}
+
+impl<K, St> SelectAllMap<K, St>
+where
+    K: Clone + Eq + Hash + Unpin,
+    St: Stream + Unpin,
+{
+    pub fn new() -> Self {
+        SelectAllMap {
+            inner: SelectAll::new(),
+            active: HashMap::new(),
+        }
+    }
+
+    /// Add a stream identified by `key` to the set.
+    ///
+    /// Several streams may share a key, in which case the key stays active until all of them
+    /// have terminated.
+    pub fn insert(&mut self, key: K, stream: St) {
+        *self.active.entry(key.clone()).or_default() += 1;
+        self.inner.push(Keyed {
+            key,
+            inner: Some(stream),
+        });
+    }
+
+    /// The keys of the streams that haven't terminated, in no particular order.
+    pub fn keys(&self) -> Vec<K> {
+        self.active.keys().cloned().collect()
+    }
+
+    /// Whether any stream with `key` hasn't terminated.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.active.contains_key(key)
+    }
+
+    /// Number of streams that haven't terminated.
+    pub fn len(&self) -> usize {
+        self.active.values().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+}
+
+impl<K, St> Default for SelectAllMap<K, St>
+where
+    K: Clone + Eq + Hash + Unpin,
+    St: Stream + Unpin,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, St> FromIterator<(K, St)> for SelectAllMap<K, St>
+where
+    K: Clone + Eq + Hash + Unpin,
+    St: Stream + Unpin,
+{
+    fn from_iter<I: IntoIterator<Item = (K, St)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        for (key, stream) in iter {
+            map.insert(key, stream);
+        }
+        map
+    }
+}
+
+impl<K, St> FusedStream for SelectAllMap<K, St>
+where
+    K: Clone + Eq + Hash + Unpin,
+    St: Stream + Unpin,
+{
+    fn is_terminated(&self) -> bool {
+        self.inner.is_terminated()
+    }
+}
+
+impl<K, St> Stream for SelectAllMap<K, St>
+where
+    K: Clone + Eq + Hash + Unpin,
+    St: Stream + Unpin,
+{
+    type Item = (K, St::Item);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            match this.inner.poll_next_unpin(cx) {
+                Poll::Ready(Some((key, Some(item)))) => return Poll::Ready(Some((key, item))),
+                Poll::Ready(Some((key, None))) => {
+                    if let Some(count) = this.active.get_mut(&key) {
+                        *count -= 1;
+                        if *count == 0 {
+                            this.active.remove(&key);
+                        }
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use futures::stream::BoxStream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_keys() {
+        let pending: BoxStream<'static, u8> = stream::pending().boxed();
+        let ready: BoxStream<'static, u8> = stream::iter(vec![1, 2]).boxed();
+        let mut map: SelectAllMap<&str, _> = vec![("pending", pending), ("ready", ready)]
+            .into_iter()
+            .collect();
+        let mut keys = map.keys();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["pending", "ready"]);
+
+        assert_eq!(map.next().await, Some(("ready", 1)));
+        assert_eq!(map.next().await, Some(("ready", 2)));
+        // Poll once more so the exhausted stream is noticed.
+        assert!(futures::poll!(map.next()).is_pending());
+        assert_eq!(map.keys(), vec!["pending"]);
+        assert_eq!(map.len(), 1);
+
+        // The set can be rebuilt from the keys.
+        let rebuilt: SelectAllMap<&str, BoxStream<'static, u8>> = map
+            .keys()
+            .into_iter()
+            .map(|key| (key, stream::iter(vec![3]).boxed()))
+            .collect();
+        assert_eq!(rebuilt.collect::<Vec<_>>().await, vec![("pending", 3)]);
+    }
+}
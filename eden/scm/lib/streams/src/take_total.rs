@@ -0,0 +1,140 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Provides a version of `futures::stream::StreamExt::take` which drops the underlying stream as
+//! soon as the last item has been yielded.
+
+use std::pin::Pin;
+
+use futures::stream::FusedStream;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use futures::task::Context;
+use futures::task::Poll;
+
+/// Stream for the [`take_total()`] function.
+#[must_use = "streams do nothing unless polled"]
+pub struct TakeTotal<St> {
+    inner: Option<St>,
+    remaining: usize,
+}
+
+/// Yield at most `n` items in total from `stream`, such as a `SelectAll`, then terminate.
+///
+/// Unlike `StreamExt::take`, `stream` is dropped right after yielding its `n`th item, so a
+/// `SelectAll` drops the streams it combines promptly instead of holding them until the returned
+/// stream is dropped.
+pub fn take_total<St>(stream: St, n: usize) -> TakeTotal<St>
+where
+    St: Stream + Unpin,
+{
+    TakeTotal {
+        inner: if n == 0 { None } else { Some(stream) },
+        remaining: n,
+    }
+}
+
+impl<St> FusedStream for TakeTotal<St>
+where
+    St: Stream + Unpin,
+{
+    fn is_terminated(&self) -> bool {
+        self.inner.is_none()
+    }
+}
+
+impl<St> Stream for TakeTotal<St>
+where
+    St: Stream + Unpin,
+{
+    type Item = St::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let inner = match this.inner.as_mut() {
+            Some(inner) => inner,
+            None => return Poll::Ready(None),
+        };
+        match inner.poll_next_unpin(cx) {
+            Poll::Ready(Some(item)) => {
+                this.remaining -= 1;
+                if this.remaining == 0 {
+                    this.inner = None;
+                }
+                Poll::Ready(Some(item))
+            }
+            Poll::Ready(None) => {
+                this.inner = None;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.inner {
+            Some(inner) => {
+                let (lower, upper) = inner.size_hint();
+                let upper = upper.map_or(self.remaining, |upper| upper.min(self.remaining));
+                (lower.min(self.remaining), Some(upper))
+            }
+            None => (0, Some(0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use futures::stream;
+    use futures::stream::BoxStream;
+
+    use super::*;
+
+    /// Sets the flag when dropped.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_take_total() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = DropFlag(dropped.clone());
+        let pending: BoxStream<'static, u8> = stream::pending()
+            .map(move |x| {
+                let _ = &flag;
+                x
+            })
+            .boxed();
+        let ready: BoxStream<'static, u8> = stream::iter(vec![1, 2, 3]).boxed();
+
+        let mut taken = take_total(stream::select_all(vec![pending, ready]), 2);
+        assert_eq!(taken.next().await, Some(1));
+        assert!(!dropped.load(Ordering::SeqCst));
+        assert_eq!(taken.next().await, Some(2));
+        // The combined streams are dropped without polling again.
+        assert!(dropped.load(Ordering::SeqCst));
+        assert!(taken.is_terminated());
+        assert_eq!(taken.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_fewer_items() {
+        let taken = take_total(stream::iter(vec![1, 2]), 5);
+        assert_eq!(taken.collect::<Vec<u8>>().await, vec![1, 2]);
+
+        let mut taken = take_total(stream::iter(vec![1, 2]), 0);
+        assert_eq!(taken.next().await, None);
+    }
+}
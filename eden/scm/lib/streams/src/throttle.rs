@@ -0,0 +1,165 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Provides a stream adapter which limits the rate at which items are yielded, buffering the
+//! items produced by the underlying stream in the meantime.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures::stream::FusedStream;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use futures::task::Context;
+use futures::task::Poll;
+use tokio::time::Instant;
+use tokio::time::Sleep;
+
+/// Error yielded by [`Throttle`] when the underlying stream produced more
+/// items than could be buffered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottleBufferFull {
+    pub capacity: usize,
+}
+
+impl fmt::Display for ThrottleBufferFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "throttle buffer is full ({} items)", self.capacity)
+    }
+}
+
+impl std::error::Error for ThrottleBufferFull {}
+
+/// Stream for the [`throttle()`] function.
+#[must_use = "streams do nothing unless polled"]
+pub struct Throttle<St: Stream> {
+    inner: Option<St>,
+    interval: Duration,
+    delay: Pin<Box<Sleep>>,
+    buffer: VecDeque<St::Item>,
+    capacity: usize,
+    terminated: bool,
+}
+
+/// Limit `stream` to yielding at most `items_per_sec` items per second.
+///
+/// Items that are ready in the meantime are pulled from `stream` and held
+/// in a buffer of up to `capacity` items, so that producers aren't stalled
+/// by the rate limit.  If `stream` produces an item while the buffer is
+/// full, a `ThrottleBufferFull` error is yielded and the returned stream
+/// terminates: that item and the buffered ones are lost.
+pub fn throttle<St>(stream: St, items_per_sec: u32, capacity: usize) -> Throttle<St>
+where
+    St: Stream + Unpin,
+{
+    assert!(items_per_sec > 0, "items_per_sec must be positive");
+    assert!(capacity > 0, "capacity must be positive");
+    Throttle {
+        inner: Some(stream),
+        interval: Duration::from_secs(1) / items_per_sec,
+        delay: Box::pin(tokio::time::sleep_until(Instant::now())),
+        buffer: VecDeque::new(),
+        capacity,
+        terminated: false,
+    }
+}
+
+// The buffered items are never pinned.
+impl<St: Stream + Unpin> Unpin for Throttle<St> {}
+
+impl<St> FusedStream for Throttle<St>
+where
+    St: Stream + Unpin,
+{
+    fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+}
+
+impl<St> Stream for Throttle<St>
+where
+    St: Stream + Unpin,
+{
+    type Item = Result<St::Item, ThrottleBufferFull>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.terminated {
+            return Poll::Ready(None);
+        }
+
+        while let Some(inner) = this.inner.as_mut() {
+            match inner.poll_next_unpin(cx) {
+                Poll::Ready(Some(item)) => {
+                    if this.buffer.len() >= this.capacity {
+                        this.inner = None;
+                        this.buffer.clear();
+                        this.terminated = true;
+                        return Poll::Ready(Some(Err(ThrottleBufferFull {
+                            capacity: this.capacity,
+                        })));
+                    }
+                    this.buffer.push_back(item);
+                }
+                Poll::Ready(None) => this.inner = None,
+                Poll::Pending => break,
+            }
+        }
+
+        if this.buffer.is_empty() {
+            if this.inner.is_none() {
+                this.terminated = true;
+                return Poll::Ready(None);
+            }
+            return Poll::Pending;
+        }
+
+        match this.delay.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                let next = Instant::now() + this.interval;
+                this.delay.as_mut().reset(next);
+                Poll::Ready(this.buffer.pop_front().map(Ok))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle() {
+        let streams = vec![stream::iter(vec![1, 2]), stream::iter(vec![3, 4, 5])];
+        let start = Instant::now();
+        let mut collected = throttle(stream::select_all(streams), 10, 5)
+            .map(|item| item.unwrap())
+            .collect::<Vec<u8>>()
+            .await;
+        // The first item is yielded immediately, the others 100ms apart.
+        assert_eq!(start.elapsed(), Duration::from_millis(400));
+        collected.sort_unstable();
+        assert_eq!(collected, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_buffer_full() {
+        let mut throttled = throttle(stream::iter(0..10), 10, 3);
+        assert_eq!(
+            throttled.next().await,
+            Some(Err(ThrottleBufferFull { capacity: 3 }))
+        );
+        assert!(throttled.is_terminated());
+        assert_eq!(throttled.next().await, None);
+    }
+}